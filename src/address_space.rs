@@ -1,4 +1,16 @@
-use crate::data_source::DataSource;
+use crate::asid::{Asid, AsidAllocator};
+use crate::cacher::{EvictionPolicy, PageCache};
+use crate::data_source::{DataSource, DsError, SourceRef};
+use crate::frame::{FrameAllocator, NoFrames, PhysFrame};
+use crate::from_bytes::FromBytes;
+use crate::kernel_region::{KernelMappings, KernelRegion};
+use crate::page_table::PageTable;
+use crate::sources::ZeroSource;
+use crate::swap::{SwapBackend, SwapSlot};
+use crate::tlb::TlbMaintenance;
+use core::any::Any;
+use core::num::NonZeroUsize;
+#[cfg(not(feature = "alloc"))]
 use scapegoat::SgSet;
 
 #[cfg(test)]
@@ -6,18 +18,179 @@ use scapegoat::SgSet;
 extern crate std;
 
 pub const DEFAULT_PAGE_SIZE: usize = 4096;
+/// The default `N_RESIDENT` of an `AddressSpace`: how many of its pages can be populated at once.
+pub const DEFAULT_RESIDENT_PAGES: usize = 256;
+/// Pass as the `PAGE_SIZE` of an `AddressSpace` to choose its page size at runtime instead, with
+/// `AddressSpace::with_page_size`.
+///
+/// Note that `MIN_GAP_SIZE` defaults to `PAGE_SIZE`, so such an `AddressSpace` has no guard gaps
+/// unless you also set `MIN_GAP_SIZE`.
+///
+/// ```
+/// # use reedos_address_space::{AddressSpace, address_space::RUNTIME_PAGE_SIZE};
+/// let space = AddressSpace::<1024, RUNTIME_PAGE_SIZE, 16384>::new("16K pages")
+///     .with_page_size(16384)
+///     .expect("16K is a power of two");
+/// assert_eq!(space.page_size(), 16384);
+/// ```
+pub const RUNTIME_PAGE_SIZE: usize = 0;
+
+/// Invalidating more pages than this at once invalidates the whole ASID instead; see
+/// `AddressSpace::with_tlb_maintenance`.
+pub const TLB_FLUSH_PAGES: usize = 32;
+
+/// The highest virtual address an `AddressSpace` may ever map.
 pub const VADDR_MAX: usize = (1 << 38) - 1;
 
 type VirtualAddress = usize;
-type AsError = &'static str;
 
-// ?Sized is OK: we only store &D, which is Sized.
-#[derive(Default)]
+/// Why an `AddressSpace` operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AsError {
+    /// A mapping was built without a length.
+    LengthNotSet,
+    /// A mapping was requested with zero length.
+    ZeroLength,
+    /// Some address or length arithmetic would wrap around the end of the address space.
+    Overflow,
+    /// An address or ceiling isn't suitably aligned.
+    Unaligned,
+    /// A requested alignment isn't a multiple of the page size.
+    BadAlignment,
+    /// There is no room for the mapping.
+    NoSpace,
+    /// There is no room to store another mapping, populated page, or fault handler.
+    OutOfCapacity,
+    /// There is no mapping at the given address.
+    NotMapped,
+    /// The mapping would extend past the end of its `DataSource`.
+    BeyondSource,
+    /// The mapping's `DataSource` can't honor its flags, like a shared writable mapping of a
+    /// read-only source.
+    Unsupported,
+    /// The mapping's `DataSource` can't be cached, but the mapping doesn't have the `no_cache`
+    /// flag, or a `no_cache` mapping was accessed through a cache.
+    MustNotCache,
+    /// The mapping doesn't permit the attempted access.
+    PermissionDenied,
+    /// The mapping would be both writable and executable, but the `AddressSpace` enforces W^X.
+    WriteAndExecute,
+    /// The `MappingHandle` refers to a mapping that has since been removed.
+    StaleHandle,
+    /// The mapping is sealed; see `AddressSpace::seal`.
+    Sealed,
+    /// The page size was fixed at compile time, rather than with `RUNTIME_PAGE_SIZE`.
+    PageSizeFixed,
+    /// The page size isn't a power of two.
+    BadPageSize,
+    /// The page size can't change once there are mappings.
+    HasMappings,
+    /// The ceiling is beyond the end of the address space.
+    CeilingTooHigh,
+    /// The ceiling is below an existing mapping.
+    CeilingTooLow,
+    /// The mapping's `DataSource` failed.
+    Source(DsError),
+    /// There are no free physical frames.
+    NoFrames,
+    /// There are no free swap slots.
+    NoSwapSpace,
+    /// The page isn't a private copy, so it can't be swapped out; its data is in its
+    /// `DataSource` already.
+    NotSwappable,
+    /// The address can't be translated by the page table.
+    NotCanonical,
+    /// The flags don't permit any access, so they can't be put in a page table entry.
+    NoAccess,
+    /// The page is swapped out, so it must be swapped in before its data can be accessed.
+    SwappedOut,
+    /// An `MmapRequest` is malformed, like having both or neither of `MAP_SHARED` and
+    /// `MAP_PRIVATE`, or no source for a mapping that isn't anonymous.
+    InvalidRequest,
+    /// A string has no nul terminator within the length it's allowed.
+    StringTooLong,
+    /// The operation would take the `AddressSpace` past one of its `Limits`.
+    LimitExceeded,
+    /// The mapping isn't a whole number of pages of the `AddressSpace` it's being shared into.
+    PageSizeMismatch,
+}
+
+impl core::fmt::Display for AsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::LengthNotSet => "mapping length not set",
+            Self::ZeroLength => "mapping length is zero",
+            Self::Overflow => "address arithmetic overflows",
+            Self::Unaligned => "unaligned address",
+            Self::BadAlignment => "alignment must be a multiple of the page size",
+            Self::NoSpace => "no space available",
+            Self::OutOfCapacity => "out of capacity",
+            Self::NotMapped => "no mapping at that address",
+            Self::BeyondSource => "mapping extends past the end of its source",
+            Self::Unsupported => "source can't honor mapping flags",
+            Self::MustNotCache => "uncacheable source mapped without no_cache",
+            Self::PermissionDenied => "access not permitted by mapping",
+            Self::WriteAndExecute => "mapping is both writable and executable",
+            Self::StaleHandle => "stale mapping handle",
+            Self::Sealed => "mapping is sealed",
+            Self::PageSizeFixed => "page size was fixed at compile time",
+            Self::BadPageSize => "page size must be a power of two",
+            Self::HasMappings => "cannot change page size with existing mappings",
+            Self::CeilingTooHigh => "ceiling is beyond the end of the address space",
+            Self::CeilingTooLow => "ceiling is below an existing mapping",
+            Self::Source(e) => e,
+            Self::NoFrames => "no free physical frames",
+            Self::NoSwapSpace => "no free swap slots",
+            Self::NotSwappable => "page isn't a private copy",
+            Self::NotCanonical => "address can't be translated by the page table",
+            Self::NoAccess => "flags don't permit any access",
+            Self::SwappedOut => "page is swapped out",
+            Self::InvalidRequest => "malformed mmap request",
+            Self::StringTooLong => "string is too long",
+            Self::LimitExceeded => "address space limit exceeded",
+            Self::PageSizeMismatch => "mapping isn't a whole number of pages",
+        })
+    }
+}
+
+/// The ordered set an `AddressSpace` keeps its bookkeeping in.
+///
+/// By default this is a `SgSet` with a fixed capacity of `N` elements, so it needs no allocator.
+/// With the `alloc` feature it's a heap-allocated `BTreeSet` instead, so the storage grows with
+/// the number of mappings rather than being reserved up front for the largest possible number,
+/// which makes address spaces with very many pages practical.
+#[cfg(not(feature = "alloc"))]
+type Set<T, const N: usize> = SgSet<T, N>;
+#[cfg(feature = "alloc")]
+type Set<T, const N: usize> = alloc::collections::BTreeSet<T>;
+
+// Not `Copy`, since the source may be shared through an `Arc`.
+#[derive(Clone, Default)]
 struct MapEntry<'a> {
     addr: usize,
+    // Always a whole number of pages.
     length: usize,
+    // The length the mapping was asked for, before rounding up to a whole number of pages.
+    requested_length: usize,
+    // The offset within `source` that `addr` maps to.
+    offset: usize,
     // Needs to be `Option` so we can implement `Default`, required for the `SgSet` API.
-    source: Option<&'a dyn DataSource>,
+    source: Option<SourceRef<'a>>,
+    flags: Flags,
+    // The guard space to keep free on either side of the mapping.
+    guard: usize,
+    // Distinguishes this mapping from any other that has been at the same address.
+    generation: usize,
+    // Whether the mapping can no longer be removed or modified.
+    sealed: bool,
+    // Caller-owned bookkeeping attached to the mapping, opaque to us.
+    metadata: Option<&'a dyn Any>,
+    // The index in `AddressSpace::fault_handlers` of where faults in the mapping are forwarded, if
+    // not resolved from `source`. An index rather than a reference keeps entries small.
+    fault_handler: Option<u8>,
+    // The length the mapping may grow down to on faults below it, if it grows down.
+    grows_down: Option<NonZeroUsize>,
 }
 
 #[cfg(test)]
@@ -25,18 +198,50 @@ impl std::fmt::Debug for MapEntry<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "{} -> {}: {} bit mapping",
+            "{} -> {}: {} bit mapping of {}",
             self.addr,
             self.end(),
-            self.length
+            self.length,
+            self.source
+                .as_deref()
+                .map_or("<key>", |source| source.name())
         )
     }
 }
 
-impl MapEntry<'_> {
+impl<'a> MapEntry<'a> {
+    /// A placeholder entry used to look up the real entry starting at `addr` in the `SgSet`.
+    fn key(addr: VirtualAddress) -> Self {
+        Self {
+            addr,
+            ..Self::default()
+        }
+    }
+
+    // Can't overflow: every mapping is checked to end below the ceiling before it's inserted.
     const fn end(&self) -> usize {
         self.addr + self.length
     }
+
+    fn source(&self) -> &(dyn DataSource + 'a) {
+        self.source
+            .as_deref()
+            .expect("only lookup keys have no source")
+    }
+
+    /// Get a public view of this mapping.
+    fn info(&self) -> MappingInfo<'_> {
+        MappingInfo {
+            addr: self.addr,
+            length: self.length,
+            requested_length: self.requested_length,
+            offset: self.offset,
+            source: self.source(),
+            flags: self.flags,
+            sealed: self.sealed,
+            metadata: self.metadata,
+        }
+    }
 }
 
 impl PartialEq for MapEntry<'_> {
@@ -50,7 +255,7 @@ impl Eq for MapEntry<'_> {}
 
 impl PartialOrd for MapEntry<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.addr.cmp(&other.addr))
+        Some(self.cmp(other))
     }
 }
 
@@ -60,639 +265,7092 @@ impl Ord for MapEntry<'_> {
     }
 }
 
-/// An address space.
-pub struct AddressSpace<
-    'a,
-    const N_PAGES: usize,
-    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
-    const MIN_GAP_SIZE: usize = PAGE_SIZE,
-> {
-    name: &'a str,
-    mappings: SgSet<MapEntry<'a>, N_PAGES>,
+/// The state of a page that's been populated or swapped out, kept separately from its mapping so
+/// that mappings start out unpopulated and are filled in on demand.
+#[derive(Clone, Copy, Debug, Default)]
+struct PageEntry {
+    // The first address of the page.
+    addr: VirtualAddress,
+    // The frame the page was populated into by `fault_in`, holding the data of its source.
+    frame: Option<PhysFrame>,
+    // The private copy of a copy-on-write page, which the page now maps instead of its source.
+    copy: Option<PhysFrame>,
+    // Where the private copy is kept while it's swapped out, in which case it isn't resident.
+    swap: Option<SwapSlot>,
+    // Software copies of the hardware accessed and dirty bits, fed in through `mark_accessed`.
+    accessed: bool,
+    dirty: bool,
+    // The frame isn't owned by the `AddressSpace`, like those of an identity mapping, so it's
+    // never released.
+    pinned: bool,
 }
 
-#[cfg(test)]
-impl<const N_PAGES: usize, const PAGE_SIZE: usize, const MIN_GAP_SIZE: usize> std::fmt::Debug
-    for AddressSpace<'_, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE>
-{
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        writeln!(f, "{}", self.name)?;
-        for m in self.mappings.iter() {
-            writeln!(f, "{m:?}")?;
+impl PageEntry {
+    /// A placeholder entry used to look up the real entry for the page at `addr`.
+    const fn key(addr: VirtualAddress) -> Self {
+        Self {
+            addr,
+            frame: None,
+            copy: None,
+            swap: None,
+            accessed: false,
+            dirty: false,
+            pinned: false,
         }
-        Ok(())
+    }
+
+    const fn is_resident(&self) -> bool {
+        self.swap.is_none()
+    }
+
+    /// Get the frame holding the page's data, if it's in a frame of its own: its private copy, or
+    /// the frame it was populated into, unless that's pinned, in which case it's the source's.
+    fn data_frame(&self) -> Option<PhysFrame> {
+        self.copy.or(self.frame.filter(|_| !self.pinned))
     }
 }
 
-impl<'a, const N_PAGES: usize, const PAGE_SIZE: usize, const MIN_GAP_SIZE: usize>
-    AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE>
-{
-    #[must_use]
-    pub fn new(name: &'a str) -> Self {
-        Self {
-            name,
-            mappings: SgSet::new(),
+/// A page about to be populated into a frame, with everything needed to read it, so it can be
+/// read without holding onto the `AddressSpace`; see `AddressSpace::pending_page`.
+pub(crate) struct PendingPage<'a> {
+    page: VirtualAddress,
+    mapping: MappingHandle,
+    source: SourceRef<'a>,
+    // The offset in `source` that `page` maps to.
+    offset: usize,
+    // How much of the page is backed by `source`, before the rest, which reads as zeros.
+    backed: usize,
+    page_size: usize,
+}
+
+impl PendingPage<'_> {
+    /// Read the page into a new frame from `frames`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub(crate) fn populate(&self, frames: &mut impl FrameAllocator) -> Result<PhysFrame, AsError> {
+        let frame = frames.alloc_frame().ok_or(AsError::NoFrames)?;
+        let (data, tail) = frames.frame_data(frame)[..self.page_size].split_at_mut(self.backed);
+        if let Err(e) = self.source.read(self.offset, self.backed, data) {
+            frames.free_frame(frame);
+            return Err(AsError::Source(e));
         }
+        tail.fill(0);
+        Ok(frame)
     }
+}
+
+/// The first step of resolving a page fault with `AddressSpace::begin_fault_in`.
+pub(crate) enum FaultStep<'a> {
+    /// The fault is resolved.
+    Done(FaultResolution),
+    /// The fault is resolved once this page is populated and `AddressSpace::finish_fault_in` is
+    /// called.
+    Populate(PendingPage<'a>),
+}
+
+/// Split the `length` bytes at `addr` into the pieces in each page of `page_size` bytes, as the
+/// address of each piece and its range of offsets from `addr`.
+fn page_pieces(
+    page_size: usize,
+    addr: VirtualAddress,
+    length: usize,
+) -> impl Iterator<Item = (VirtualAddress, core::ops::Range<usize>)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        (done < length).then(|| {
+            let at = addr + done;
+            let piece = done..done + (page_size - at % page_size).min(length - done);
+            done = piece.end;
+            (at, piece)
+        })
+    })
+}
 
-    const fn total_capacity() -> usize {
-        N_PAGES * PAGE_SIZE
+impl PartialEq for PageEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
     }
+}
 
-    /// Create an iterator over the bounds of free regions.
-    fn free_regions(&'a self) -> impl Iterator<Item = (VirtualAddress, VirtualAddress)> + 'a {
-        let starts = core::iter::once(0).chain(self.mappings.iter().map(MapEntry::end));
-        let ends = self
-            .mappings
-            .iter()
-            .map(|m| m.addr)
-            .chain(core::iter::once(Self::total_capacity()));
+impl Eq for PageEntry {}
+
+impl PartialOrd for PageEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        starts.zip(ends)
+impl Ord for PageEntry {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.addr.cmp(&other.addr)
     }
+}
 
-    /// Check if there is space for a mapping of length at addr.
-    fn is_space_at(&self, addr: VirtualAddress, length: usize) -> bool {
-        // Get iterators over all the starts and ends of free blocks.
-        self.free_regions()
-            // Find the first free region which ends after addr.
-            .find(|(_, e)| *e > addr)
-            // Check whether it has room.
-            .map_or(false, |(s, e)| {
-                s + MIN_GAP_SIZE <= addr && addr + length + MIN_GAP_SIZE < e
-            })
+/// Check that `[offset, offset + length)` is within `source`, if it's bounded.
+///
+/// Only the requested length counts: the rest of the last page of a mapping may extend past the
+/// end of its source, as with the tail of a file.
+fn check_source_bounds(
+    source: &dyn DataSource,
+    offset: usize,
+    length: usize,
+) -> Result<(), AsError> {
+    let end = offset.checked_add(length).ok_or(AsError::Overflow)?;
+    match source.len() {
+        Some(len) if end > len => Err(AsError::BeyondSource),
+        _ => Ok(()),
     }
+}
 
-    /// Find the space for a page of the given length.
-    fn find_space_for(&self, length: usize) -> Option<VirtualAddress> {
-        // TODO: perf
-        //
-        // Get iterators over all the starts and ends of free blocks.
-        // Find the first one with enough space.
-        self.free_regions().find_map(|(s, e)| {
-            // The smallest starting address in this range.
-            let start = (s + MIN_GAP_SIZE).next_multiple_of(PAGE_SIZE);
-            let end = e - MIN_GAP_SIZE;
-            if start > end || end - start < length {
-                // not enough space
-                None
-            } else {
-                Some(start)
-            }
-        })
+/// Check that `source` can honor a mapping with `flags`.
+///
+/// Writes to a private or copy-on-write mapping never reach its source, so only shared writable
+/// mappings need a writable source.
+fn check_source_flags(source: &dyn DataSource, flags: Flags) -> Result<(), AsError> {
+    let flags = flags.into_builder();
+    let capabilities = source.capabilities();
+    if (flags.write && !flags.private && !flags.cow && !capabilities.write)
+        || (flags.execute && !capabilities.execute)
+    {
+        return Err(AsError::Unsupported);
+    }
+    if !source.cacheable() && !flags.no_cache {
+        return Err(AsError::MustNotCache);
     }
 
-    /// An _expensive_ check to ensure that the `AddressSpace` is in a valid state, i.e.:
-    ///  * The zero page is free.
-    ///  * No mappings overlap.
-    ///  * There is at least `MIN_GAP_SIZE` space between each mapping.
-    ///  * All mappings are `PAGE_SIZE`-aligned.
-    fn assert_valid(&self) {
-        // The zero page is free.
-        assert!(!self.mappings.iter().any(|m| m.addr < PAGE_SIZE));
+    Ok(())
+}
 
-        let iter_1 = self.mappings.iter();
-        let iter_2 = self.mappings.iter().skip(1);
+/// An opaque reference to a mapping in an `AddressSpace`.
+///
+/// Operations taking a `MappingHandle` fail if the mapping it refers to has since been removed,
+/// even if another mapping has been added at the same address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MappingHandle {
+    addr: VirtualAddress,
+    generation: usize,
+}
 
-        // There is at least `MIN_GAP_SIZE` space between each mapping.
-        for (m1, m2) in iter_1.zip(iter_2) {
-            // mappings.iter is in-order, so here we're guaranteed:
-            // m1.addr <= m2.addr
-            // there is no m3 s.t. m1.addr < m3.addr < m2.addr
-            assert!(m1.end() + MIN_GAP_SIZE <= m2.addr);
+impl MappingHandle {
+    /// Get the first address of the mapping.
+    #[must_use]
+    pub const fn addr(self) -> VirtualAddress {
+        self.addr
+    }
+}
+
+/// A free region between mappings, ordered by how much can be mapped into it so that the
+/// `AddressSpace` can find a region of a given size in logarithmic time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct FreeRegion {
+    // The space available for a `PAGE_SIZE`-aligned mapping, i.e. `end - start` with `start`
+    // rounded up to a page boundary. Must come first for the ordering.
+    size: usize,
+    start: VirtualAddress,
+    end: VirtualAddress,
+}
+
+impl FreeRegion {
+    /// The least region of the given size in the index ordering.
+    const fn min_with_size(size: usize) -> Self {
+        Self {
+            size,
+            start: 0,
+            end: 0,
         }
+    }
 
-        // All mappings are `PAGE_SIZE`-aligned.
-        for m in self.mappings.iter() {
-            assert!(m.addr % PAGE_SIZE == 0);
+    /// The greatest region of the given size in the index ordering.
+    const fn max_with_size(size: usize) -> Self {
+        Self {
+            size,
+            start: usize::MAX,
+            end: usize::MAX,
         }
     }
+}
 
-    /// Add a mapping from a `DataSource` into this `AddressSpace`.
-    ///
-    /// # Errors
-    /// If the desired mapping is invalid.
-    pub fn add_mapping<D: DataSource>(
-        &mut self,
-        source: &'a D,
-        length: usize,
-    ) -> Result<VirtualAddress, AsError> {
-        let addr = self.find_space_for(length).ok_or("no space available")?;
-        debug_assert!(self.mappings.insert(MapEntry {
-            addr,
-            length,
-            source: Some(source),
-        }));
-        Ok(addr)
+/// A view of a single mapping in an `AddressSpace`.
+#[derive(Clone, Copy)]
+pub struct MappingInfo<'a> {
+    /// The first address of the mapping.
+    pub addr: VirtualAddress,
+    /// The length of the mapping, in bytes. This is always a whole number of pages.
+    pub length: usize,
+    /// The length the mapping was asked for, in bytes, before rounding up to a whole number of
+    /// pages.
+    pub requested_length: usize,
+    /// The offset within `source` that `addr` maps to.
+    pub offset: usize,
+    /// The `DataSource` backing the mapping.
+    pub source: &'a dyn DataSource,
+    /// The access flags of the mapping.
+    pub flags: Flags,
+    /// Whether the mapping is sealed; see `AddressSpace::seal`.
+    pub sealed: bool,
+    /// The metadata attached to the mapping, if any.
+    pub metadata: Option<&'a dyn Any>,
+}
+
+/// Display a mapping like a line of `/proc/<pid>/maps`: its address range, flags, offset, and
+/// the name of its `DataSource`, e.g. `00001000-00003000 r-xp 00000000 <anon>`.
+impl core::fmt::Display for MappingInfo<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:08x} {} {:08x} {}",
+            self.addr,
+            self.addr + self.length,
+            self.flags,
+            self.offset,
+            self.source.name()
+        )
     }
+}
 
-    /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address.
+/// What a kernel should do to resolve a page fault; see `AddressSpace::handle_fault`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FaultResolution {
+    /// Map the page at `page` to the data at `offset` in the mapping's `DataSource`, with
+    /// `flags`. The data can be read through a `PageCache` with `AddressSpace::cached_page`.
+    Map {
+        page: VirtualAddress,
+        offset: usize,
+        flags: Flags,
+    },
+    /// The access was a write to a copy-on-write page: map a private copy of the data at
+    /// `offset` in the mapping's `DataSource` at `page`, with `flags`, which are writable. See
+    /// `AddressSpace::resolve_cow`.
+    Copy {
+        page: VirtualAddress,
+        offset: usize,
+        flags: Flags,
+    },
+    /// Map the page at `page` to `frame`, the private copy of a copy-on-write page, with
+    /// `flags`.
+    MapFrame {
+        page: VirtualAddress,
+        frame: PhysFrame,
+        flags: Flags,
+    },
+    /// The page at `page` is swapped out to `slot`: swap it in, then map it with `flags`. See
+    /// `AddressSpace::swap_in`.
+    SwapIn {
+        page: VirtualAddress,
+        slot: SwapSlot,
+        flags: Flags,
+    },
+    /// The access isn't allowed, for the given reason, so deliver a fault to whatever made it,
+    /// like a `SIGSEGV`.
+    Deliver(AsError),
+    /// The access was just below a mapping that grows down, like a stack, so the mapping grew
+    /// down to start at `page`: map `page` to the data at `offset` in its `DataSource`, with
+    /// `flags`, as with `Map`. If `flags` need a copy for the access, map a private copy instead,
+    /// as with `Copy`. See `MappingBuilder::grows_down`.
+    Grew {
+        page: VirtualAddress,
+        offset: usize,
+        flags: Flags,
+    },
+}
+
+/// The number of distinct `FaultHandler`s an `AddressSpace` can forward faults to at once.
+pub const MAX_FAULT_HANDLERS: usize = 16;
+
+/// Logic that faults in a mapping are forwarded to instead of being resolved from its
+/// `DataSource`, like `userfaultfd`; see `AddressSpace::set_fault_handler`.
+///
+/// This suits mappings whose pages are produced on demand, as with distributed shared memory or
+/// lazy deserialization. Handlers only see `&self`, so they need interior mutability to keep
+/// state.
+pub trait FaultHandler {
+    /// Work out how to resolve a fault on an `access` to `addr`, which falls in `mapping` and is
+    /// permitted by it.
     ///
     /// # Errors
-    /// If there is insufficient room subsequent to `start`.
-    pub fn add_mapping_at<D: DataSource>(
-        &mut self,
+    /// If the fault should be resolvable but can't be.
+    fn handle_fault(
+        &self,
         addr: VirtualAddress,
-        source: &'a D,
-        length: usize,
-    ) -> Result<(), AsError> {
-        if !self.is_space_at(addr, length) {
-            return Err("no space available there");
+        access: AccessType,
+        mapping: MappingInfo<'_>,
+    ) -> Result<FaultResolution, AsError>;
+}
+
+/// The end of the address space that `AddressSpace::add_mapping` places new mappings near.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GrowthDirection {
+    /// Place new mappings at the lowest available address.
+    #[default]
+    BottomUp,
+    /// Place new mappings at the highest available address, as is typical for `mmap` regions, so
+    /// that the room above the heap stays contiguous.
+    TopDown,
+}
+
+/// How `AddressSpace::add_mapping` chooses which free region to place a new mapping in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlacementPolicy {
+    /// Use the first region with enough space, in address order (from the top of the address
+    /// space when growing top-down).
+    #[default]
+    FirstFit,
+    /// Use the smallest region with enough space, which tends to reduce fragmentation in
+    /// long-lived address spaces.
+    BestFit,
+    /// Use the largest region, leaving the biggest possible leftover region.
+    WorstFit,
+}
+
+/// A way in which an `AddressSpace` can be invalid; see `AddressSpace::check_valid`.
+#[cfg(any(test, feature = "validate"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The mapping at `addr` includes the zero page.
+    ZeroPage { addr: VirtualAddress },
+    /// The mapping at `addr` isn't page-aligned.
+    Misaligned { addr: VirtualAddress },
+    /// The mapping at `addr` extends too close to or past the ceiling.
+    AboveCeiling { addr: VirtualAddress },
+    /// The mappings at `first` and `second` overlap.
+    Overlap {
+        first: VirtualAddress,
+        second: VirtualAddress,
+    },
+    /// The mappings at `first` and `second` are closer together than one of their guard sizes.
+    GapTooSmall {
+        first: VirtualAddress,
+        second: VirtualAddress,
+    },
+    /// The internal free region index is missing the region at `start`, or has extra regions.
+    FreeIndexOutOfDate { start: VirtualAddress },
+}
+
+/// Every violation found by `AddressSpace::check_valid`.
+///
+/// Only the first `ValidityReport::CAPACITY` violations are kept, so no allocator is needed, but
+/// all of them are counted.
+#[cfg(any(test, feature = "validate"))]
+#[derive(Clone, Debug, Default)]
+pub struct ValidityReport {
+    violations: [Option<Violation>; Self::CAPACITY],
+    count: usize,
+}
+
+#[cfg(any(test, feature = "validate"))]
+impl ValidityReport {
+    /// The maximum number of violations kept in a report.
+    pub const CAPACITY: usize = 16;
+
+    fn push(&mut self, violation: Violation) {
+        if let Some(slot) = self.violations.get_mut(self.count) {
+            *slot = Some(violation);
         }
-        debug_assert!(self.mappings.insert(MapEntry {
-            addr,
-            length,
-            source: Some(source),
-        }));
+        self.count += 1;
+    }
 
-        Ok(())
+    /// Get the total number of violations found, including those not kept.
+    #[must_use]
+    pub const fn count(&self) -> usize {
+        self.count
     }
 
-    /// Remove the mapping to `DataSource` that starts at the given address.
-    ///
-    /// # Errors
-    /// If the mapping could not be removed.
-    pub fn remove_mapping(&mut self, start: VirtualAddress) -> Result<(), AsError> {
-        if !self.mappings.remove(&MapEntry {
-            addr: start,
-            length: PAGE_SIZE,
-            source: None,
-        }) {
-            return Err("no mapping at that address to remove");
+    /// Check whether some violations were found but not kept.
+    #[must_use]
+    pub const fn is_truncated(&self) -> bool {
+        self.count > Self::CAPACITY
+    }
+
+    /// Create an iterator over the violations kept, in the order they were found.
+    pub fn violations(&self) -> impl Iterator<Item = &Violation> {
+        self.violations.iter().flatten()
+    }
+}
+
+/// A raw gap between mappings, or between a mapping and either end of the address space.
+#[derive(Clone, Copy)]
+struct Gap {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    // The guard sizes of the mappings on either side, or 0 for the ends of the address space.
+    start_guard: usize,
+    end_guard: usize,
+}
+
+/// A summary of how fragmented the free space in an `AddressSpace` is; see
+/// `AddressSpace::fragmentation_stats`.
+///
+/// Sizes count only the space a page-aligned mapping could actually use, i.e. excluding guard
+/// gaps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FragmentationStats {
+    /// The number of free regions that could hold a mapping.
+    pub free_regions: usize,
+    /// The total size of all free regions, in bytes.
+    pub total_free: usize,
+    /// The size of the largest free region, in bytes; no larger mapping can be added.
+    pub largest_free: usize,
+}
+
+impl FragmentationStats {
+    /// Get the mean size of a free region, in bytes.
+    #[must_use]
+    pub const fn mean_free(&self) -> usize {
+        match self.total_free.checked_div(self.free_regions) {
+            Some(mean) => mean,
+            None => 0,
         }
+    }
 
-        Ok(())
+    /// Get the external fragmentation ratio: the fraction of free space outside the largest free
+    /// region, from 0 (all free space is contiguous) towards 1 (it's in many small pieces).
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)] // only an estimate
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.total_free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free as f64 / self.total_free as f64
+        }
     }
+}
 
-    /// Look up the `DataSource` and offset within that `DataSource` for a
-    /// `VirtualAddress` / `AccessType` in this `AddressSpace`
-    ///
-    /// # Errors
-    /// If this `VirtualAddress` does not have a valid mapping in &self,
-    /// or if this `AccessType` is not permitted by the mapping
+/// Counts of the page faults an `AddressSpace` has handled, for `getrusage`-style reporting; see
+/// `AddressSpace::fault_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Faults on pages that were already in memory: resident, or privately copied.
+    pub minor: usize,
+    /// Faults on pages that had to be read in, from their `DataSource` or from swap.
+    pub major: usize,
+    /// Writes to copy-on-write pages that had to be copied.
+    pub cow: usize,
+    /// Accesses that their mapping doesn't permit.
+    pub permission: usize,
+    /// Faults resolved by a mapping's `FaultHandler`. Faults it fails to resolve aren't counted.
+    pub delegated: usize,
+}
+
+impl FaultStats {
+    /// Get the total number of faults counted.
     #[must_use]
-    pub fn get_source_for_addr<D: DataSource>(
-        &self,
-        addr: VirtualAddress,
-        access_type: Flags,
-    ) -> Option<&dyn DataSource> {
-        self.mappings
-            .get(&MapEntry {
-                addr,
-                length: PAGE_SIZE,
-                source: None,
-            })
-            .and_then(|m| m.source)
+    pub const fn total(&self) -> usize {
+        self.minor + self.major + self.cow + self.permission + self.delegated
     }
 }
 
-// Visibility boundary to ensure private internals, so our validation scheme works properly.
-mod flags {
-    /// Build flags for address space maps.
+/// Limits on how much memory an `AddressSpace` may use, for enforcing `RLIMIT_AS`-style resource
+/// limits; see `AddressSpace::set_limits`. Each is unlimited if `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The most bytes that may be mapped, in total; see `MemoryUsage::virtual_size`.
+    pub virtual_size: Option<usize>,
+    /// The most pages that may be resident at once; see `MemoryUsage::resident`.
+    pub resident: Option<usize>,
+    /// The most mappings there may be.
+    pub mappings: Option<usize>,
+}
+
+/// How much memory an `AddressSpace` uses, for `ps`- or `top`-style reporting; see
+/// `AddressSpace::memory_usage`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The total length of the mappings, in bytes.
+    pub virtual_size: usize,
+    /// The number of resident pages, whether shared or private: the resident set size, in pages.
+    pub resident: usize,
+    /// The number of pages swapped out.
+    pub swapped: usize,
+    /// The number of resident pages holding their mapping's `DataSource`'s data, which other
+    /// address spaces mapping the same source may share.
+    pub shared: usize,
+    /// The number of resident pages of private mappings, or privately copied.
+    pub private: usize,
+}
+
+/// The arguments of an `mmap` call, for `AddressSpace::mmap`.
+#[derive(Clone, Default)]
+pub struct MmapRequest<'a> {
+    /// Where to put the mapping. With `MAP_FIXED`, it goes exactly here; otherwise, this is a
+    /// hint, followed if there's room, unless it's 0.
+    pub addr: VirtualAddress,
+    /// The length of the mapping, in bytes.
+    pub length: usize,
+    /// The `PROT_*` flags, like `Flags::PROT_READ`.
+    pub prot: u32,
+    /// The `MAP_*` flags, like `Flags::MAP_PRIVATE`: exactly one of `MAP_SHARED` and
+    /// `MAP_PRIVATE`, optionally with `MAP_FIXED` and `MAP_ANONYMOUS`.
+    pub flags: u32,
+    /// What to map, unless the mapping is anonymous, in which case it's ignored.
+    pub source: Option<SourceRef<'a>>,
+    /// The page-aligned offset within `source` to start the mapping at.
+    pub offset: usize,
+}
+
+/// A mapping being built by `AddressSpace::map`.
+///
+/// Only the length is required. By default, the mapping is placed according to the
+/// `AddressSpace`'s growth direction and placement policy, starts at offset 0 of the source, and
+/// has no flags set.
+#[must_use = "the mapping isn't added until you call `commit`"]
+pub struct MappingBuilder<
+    's,
+    'a,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize,
+    const MIN_GAP_SIZE: usize,
+    const N_RESIDENT: usize,
+> {
+    space: &'s mut AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+    source: SourceRef<'a>,
+    length: Option<usize>,
+    addr: Option<VirtualAddress>,
+    offset: usize,
+    flags: Flags,
+    align: usize,
+    guard: Option<usize>,
+    grows_down: Option<usize>,
+}
+
+impl<
+        'a,
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    > MappingBuilder<'_, 'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>
+{
+    /// Set the length of the mapping, in bytes. It's rounded up to a whole number of pages.
+    pub const fn length(mut self, length: usize) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Place the mapping at a specific address, rather than wherever there's room.
+    pub const fn at(mut self, addr: VirtualAddress) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    /// Set the offset within the source that the start of the mapping maps to.
+    pub const fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Set the access flags of the mapping.
+    pub const fn flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Require the mapping to start at a multiple of `align`, which must be a multiple of the page
+    /// size if it's larger than a page. Mappings are always at least page-aligned.
+    pub const fn align(mut self, align: usize) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Keep `guard` bytes free on either side of the mapping, instead of the `AddressSpace`'s
+    /// `MIN_GAP_SIZE`.
     ///
-    /// You should prefer the `flags` macro for constant-time creation of flags; this type is only used
-    /// for dynamic creation.
+    /// Two mappings are always kept at least as far apart as the larger of their guard sizes, so,
+    /// for example, a stack can have a large guard below it, while adjacent segments of a binary
+    /// with no guard can be mapped right next to each other.
+    pub const fn guard(mut self, guard: usize) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Let the mapping grow down, like a stack, when `AddressSpace::handle_fault` sees an access
+    /// just below it, until it's `limit` bytes long.
     ///
-    /// We recommend using this builder type as follows:
-    /// ```
-    /// # use reedos_address_space::Flags;
-    /// let flags = Flags::build()
-    ///     .toggle_read()
-    ///     .toggle_write()
-    ///     .validate();
-    /// ```
-    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-    #[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
-                                             // here because these directly correspond to yes/no
-                                             // hardware flags
-    pub struct FlagBuilder {
-        pub read: bool,
-        pub write: bool,
-        pub execute: bool,
-        pub cow: bool,
-        pub private: bool,
-        pub shared: bool,
+    /// The mapping's offset goes down as it grows, so it can only grow as far as offset 0 of the
+    /// source: for a stack of `ZeroSource`, map it at offset `limit - length`. Growth also keeps
+    /// the mapping's guard gap below it.
+    pub const fn grows_down(mut self, limit: usize) -> Self {
+        self.grows_down = Some(limit);
+        self
     }
 
-    /// Create a toggler for a `FlagBuilder` field.
-    macro_rules! flag_toggle {
-        (
-        $flag:ident,
-        $toggle:ident,
-        $setter:ident
-    ) => {
-            #[doc=concat!("Toggle the ", stringify!($flag), " flag.")]
-            #[must_use]
-            pub const fn $toggle(self) -> Self {
-                Self {
-                    $flag: !self.$flag,
-                    ..self
-                }
-            }
+    /// Add the mapping to the `AddressSpace`.
+    ///
+    /// # Errors
+    /// If no length was set, the length is zero, the mapping or source range would overflow or
+    /// extend past the end of the `DataSource`, the `DataSource` can't honor the flags, the
+    /// alignment isn't a multiple of the page size, the requested address isn't suitably aligned,
+    /// there's no room for the mapping, or the flags violate W^X when it's enforced.
+    pub fn commit(self) -> Result<MappingHandle, AsError> {
+        let Self {
+            space,
+            source,
+            length,
+            addr,
+            offset,
+            flags,
+            align,
+            guard,
+            grows_down,
+        } = self;
 
-            #[doc=concat!("Set the ", stringify!($flag), " flag.")]
-            #[must_use]
-            pub const fn $setter(self, to: bool) -> Self {
-                Self { $flag: to, ..self }
+        let requested_length = length.ok_or(AsError::LengthNotSet)?;
+        space.check_policy(flags)?;
+        let length = space.round_length(requested_length)?;
+        // The source offset of every byte in the mapping must be representable.
+        offset.checked_add(length).ok_or(AsError::Overflow)?;
+        check_source_bounds(&*source, offset, requested_length)?;
+        check_source_flags(&*source, flags)?;
+        space.check_mapping_limits(length, 1)?;
+        let guard = guard.unwrap_or(MIN_GAP_SIZE);
+        let align = align.max(space.page_size);
+        if !align.is_multiple_of(space.page_size) {
+            return Err(AsError::BadAlignment);
+        }
+
+        let addr = match addr {
+            Some(addr) => {
+                if !addr.is_multiple_of(align) {
+                    return Err(AsError::Unaligned);
+                }
+                addr.checked_add(length).ok_or(AsError::Overflow)?;
+                if !space.is_space_at(addr, length, guard) {
+                    return Err(AsError::NoSpace);
+                }
+                addr
             }
+            None => space
+                .find_space_for(length, align, guard)
+                .ok_or(AsError::NoSpace)?,
         };
+
+        let generation = space.new_generation();
+        space.insert_entry(MapEntry {
+            addr,
+            length,
+            requested_length,
+            offset,
+            source: Some(source),
+            flags,
+            guard,
+            generation,
+            sealed: false,
+            metadata: None,
+            fault_handler: None,
+            grows_down: grows_down.and_then(NonZeroUsize::new),
+        });
+
+        Ok(MappingHandle { addr, generation })
+    }
+}
+
+/// An address space.
+///
+/// The address space consists of `N_PAGES` pages of `PAGE_SIZE` bytes each, and keeps at least
+/// `MIN_GAP_SIZE` bytes free between mappings, unless a mapping is given its own guard size with
+/// `MappingBuilder::guard`. See `RUNTIME_PAGE_SIZE` for choosing the page size at runtime.
+///
+/// Without the `alloc` feature, at most `N_RESIDENT` pages can be populated, whether resident or
+/// swapped out, and at most `N_RESIDENT` released frames and swap slots can be waiting for the
+/// kernel to free them. Storage for these is reserved up front, so keeping `N_RESIDENT` well below
+/// `N_PAGES` keeps an `AddressSpace` with many pages small.
+pub struct AddressSpace<
+    'a,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    const MIN_GAP_SIZE: usize = PAGE_SIZE,
+    const N_RESIDENT: usize = DEFAULT_RESIDENT_PAGES,
+> {
+    name: &'a str,
+    mappings: Set<MapEntry<'a>, N_PAGES>,
+    // Every free region that can hold a mapping, indexed by size. Each region contains at least
+    // one distinct page, so there are fewer than `N_PAGES` of them.
+    free_by_size: Set<FreeRegion, N_PAGES>,
+    // The generation to give the next new mapping.
+    next_generation: usize,
+    // Bumped whenever a mapping is added or removed or has its flags changed.
+    mappings_generation: usize,
+    // Every resident or swapped out page, at most one per page of the address space.
+    pages: Set<PageEntry, N_RESIDENT>,
+    // Private copies of pages that have since been unmapped, to be freed by the kernel.
+    released: Set<PhysFrame, N_RESIDENT>,
+    // Swap slots of pages that have since been unmapped, to be freed by the kernel.
+    released_slots: Set<SwapSlot, N_RESIDENT>,
+    // Pages whose translation has changed since the last `install_into` or `sync_into`.
+    unsynced: Set<VirtualAddress, N_PAGES>,
+    // The starts of the huge pages mapped with a single entry by `promote`.
+    huge_pages: Set<VirtualAddress, N_RESIDENT>,
+    // Always equal to `PAGE_SIZE`, unless that's `RUNTIME_PAGE_SIZE`.
+    page_size: usize,
+    // One past the highest address that may be mapped.
+    ceiling: VirtualAddress,
+    direction: GrowthDirection,
+    placement: PlacementPolicy,
+    // Whether to reject mappings that are both writable and executable.
+    write_xor_execute: bool,
+    asid: Option<Asid>,
+    tlb: Option<&'a dyn TlbMaintenance>,
+    // The kernel's mappings, above the ceiling, shared with other `AddressSpace`s.
+    kernel: Option<&'a dyn KernelMappings>,
+    // The handlers that mappings forward faults to, referred to by index from their entries.
+    fault_handlers: [Option<&'a dyn FaultHandler>; MAX_FAULT_HANDLERS],
+    fault_stats: FaultStats,
+    limits: Limits,
+}
+
+#[cfg(test)]
+impl<
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    > std::fmt::Debug for AddressSpace<'_, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        for m in self.mappings.iter() {
+            writeln!(f, "{m:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<
+        'a,
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    > AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>
+{
+    #[must_use]
+    pub fn new(name: &'a str) -> Self {
+        let page_size = if PAGE_SIZE == RUNTIME_PAGE_SIZE {
+            DEFAULT_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+
+        let mut space = Self {
+            name,
+            mappings: Set::new(),
+            free_by_size: Set::new(),
+            next_generation: 0,
+            mappings_generation: 0,
+            pages: Set::new(),
+            released: Set::new(),
+            released_slots: Set::new(),
+            unsynced: Set::new(),
+            huge_pages: Set::new(),
+            page_size,
+            ceiling: 0,
+            direction: GrowthDirection::default(),
+            placement: PlacementPolicy::default(),
+            write_xor_execute: false,
+            asid: None,
+            tlb: None,
+            kernel: None,
+            fault_handlers: [None; MAX_FAULT_HANDLERS],
+            fault_stats: FaultStats::default(),
+            limits: Limits::default(),
+        };
+        space.reset_ceiling();
+        space
+    }
+
+    /// Set the page size of an `AddressSpace` whose `PAGE_SIZE` is `RUNTIME_PAGE_SIZE`.
+    ///
+    /// This also resets the ceiling to the default for the new page size.
+    ///
+    /// # Errors
+    /// If the page size was fixed at compile time, `page_size` isn't a power of two, or there are
+    /// already mappings in the `AddressSpace`.
+    pub fn with_page_size(mut self, page_size: usize) -> Result<Self, AsError> {
+        if PAGE_SIZE != RUNTIME_PAGE_SIZE {
+            return Err(AsError::PageSizeFixed);
+        }
+        if !page_size.is_power_of_two() {
+            return Err(AsError::BadPageSize);
+        }
+        if !self.mappings.is_empty() {
+            return Err(AsError::HasMappings);
+        }
+
+        self.page_size = page_size;
+        self.reset_ceiling();
+        Ok(self)
+    }
+
+    /// Get the size of a page in this `AddressSpace`, in bytes.
+    #[must_use]
+    pub const fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Get the ASID assigned to this `AddressSpace`, if any.
+    ///
+    /// It may be stale; use `refresh_asid` before programming it into hardware.
+    #[must_use]
+    pub const fn asid(&self) -> Option<Asid> {
+        self.asid
+    }
+
+    /// Assign an ASID to this `AddressSpace`, returning the one it replaces, if any.
+    pub fn set_asid(&mut self, asid: Asid) -> Option<Asid> {
+        self.asid.replace(asid)
+    }
+
+    /// Make sure this `AddressSpace` has a current ASID from `allocator`, allocating a new one if
+    /// it has none or its old one went stale on rollover, and return it.
+    pub fn refresh_asid<const N_ASIDS: usize>(
+        &mut self,
+        allocator: &mut AsidAllocator<N_ASIDS>,
+    ) -> Asid {
+        let asid = match self.asid {
+            Some(asid) => allocator.reassign(asid),
+            None => allocator.alloc(),
+        };
+        self.asid = Some(asid);
+        asid
+    }
+
+    /// Invalidate stale TLB entries through `tlb` whenever mappings are removed, unmapped,
+    /// protected, or shrunk.
+    ///
+    /// Pages are invalidated one at a time, tagged with the `AddressSpace`'s ASID, unless there
+    /// are more than `TLB_FLUSH_PAGES` of them, in which case the whole ASID is invalidated. With
+    /// no ASID, every translation is invalidated.
+    #[must_use]
+    pub fn with_tlb_maintenance(mut self, tlb: &'a dyn TlbMaintenance) -> Self {
+        self.tlb = Some(tlb);
+        self
+    }
+
+    /// Create an `AddressSpace` identity-mapping the `length` bytes of physical memory at
+    /// `start`, described by `source`, with `flags`, and write its entries into `pt`, as a kernel
+    /// needs while it turns on the MMU; see `add_identity_mapping`.
+    ///
+    /// # Errors
+    /// If `pt` uses a different page size, the mapping is invalid, or writing an entry fails.
+    pub fn identity_mapped<P: PageTable>(
+        name: &'a str,
+        source: impl Into<SourceRef<'a>>,
+        start: VirtualAddress,
+        length: usize,
+        flags: Flags,
+        pt: &mut P,
+    ) -> Result<Self, AsError> {
+        let mut space = Self::new(name);
+        space.add_identity_mapping(start, source, length, flags)?;
+        space.install_into(pt, &mut NoFrames)?;
+        Ok(space)
+    }
+
+    /// Share `kernel`'s mappings, lowering the ceiling to its start so that they can't overlap
+    /// this `AddressSpace`'s own.
+    ///
+    /// Faults in the region are resolved by `kernel`, and counted there, and `is_mapped` sees its
+    /// mappings, but other lookups, like `contains_range`, only see this `AddressSpace`'s own
+    /// mappings, so user pointers into the kernel aren't accepted.
+    ///
+    /// # Errors
+    /// If some mapping is above the start of the region.
+    pub fn with_kernel_region<'k: 'a, const K_PAGES: usize>(
+        mut self,
+        kernel: &'a KernelRegion<'k, K_PAGES, PAGE_SIZE>,
+    ) -> Result<Self, AsError> {
+        self.set_ceiling(kernel.start().min(self.ceiling))?;
+        self.kernel = Some(kernel);
+        Ok(self)
+    }
+
+    /// Get the kernel region shared by this `AddressSpace`, if it's sharing one and `addr` is in
+    /// it.
+    fn kernel_region_for(&self, addr: VirtualAddress) -> Option<&'a dyn KernelMappings> {
+        self.kernel.filter(|kernel| addr >= kernel.start())
+    }
+
+    /// Invalidate any TLB entries for `[start, end)`; see `with_tlb_maintenance`.
+    fn flush_tlb(&self, start: VirtualAddress, end: VirtualAddress) {
+        let Some(tlb) = self.tlb else {
+            return;
+        };
+        if start >= end {
+            return;
+        }
+        match self.asid {
+            Some(asid) if (end - start).div_ceil(self.page_size) <= TLB_FLUSH_PAGES => {
+                for page in (start..end).step_by(self.page_size) {
+                    tlb.invalidate_page(asid, page);
+                }
+            }
+            Some(asid) => tlb.invalidate_asid(asid),
+            None => tlb.invalidate_all(),
+        }
+    }
+
+    /// Reset an empty `AddressSpace` to have the default ceiling.
+    fn reset_ceiling(&mut self) {
+        debug_assert!(self.mappings.is_empty());
+
+        self.ceiling = Self::max_ceiling(self.page_size);
+        self.free_by_size.clear();
+        let whole = self.gaps().next().expect("there is always a first gap");
+        if let Some(region) = self.indexed_region(whole) {
+            self.free_by_size.insert(region);
+        }
+    }
+
+    /// Set the direction in which `add_mapping` places new mappings.
+    #[must_use]
+    pub fn with_growth_direction(mut self, direction: GrowthDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Get the direction in which `add_mapping` places new mappings.
+    #[must_use]
+    pub const fn growth_direction(&self) -> GrowthDirection {
+        self.direction
+    }
+
+    /// Set the policy `add_mapping` uses to choose a free region for new mappings.
+    #[must_use]
+    pub fn with_placement_policy(mut self, placement: PlacementPolicy) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Get the policy `add_mapping` uses to choose a free region for new mappings.
+    #[must_use]
+    pub const fn placement_policy(&self) -> PlacementPolicy {
+        self.placement
+    }
+
+    /// Enforce W^X: reject adding, sharing into, or protecting any mapping so that it's both
+    /// writable and executable, with `AsError::WriteAndExecute`.
+    #[must_use]
+    pub fn with_write_xor_execute(mut self, enforce: bool) -> Self {
+        self.write_xor_execute = enforce;
+        self
+    }
+
+    /// Check whether W^X is enforced; see `with_write_xor_execute`.
+    #[must_use]
+    pub const fn write_xor_execute(&self) -> bool {
+        self.write_xor_execute
+    }
+
+    /// Set the limits on how much memory this `AddressSpace` may use, from now on.
+    ///
+    /// Adding, sharing into, or growing a mapping fails with `AsError::LimitExceeded` if it would
+    /// take the virtual size or the number of mappings past its limit, and so does making a page
+    /// resident, with `fault_in`, `resolve_cow`, `swap_in`, `populate_mapping`, and the like, if
+    /// it would take the resident pages past their limit. A mapping that grows down doesn't grow
+    /// on a fault past any of the limits. Lowering a limit below what's already in use doesn't
+    /// take anything away, it just stops the usage from growing. Checking the resident limit takes
+    /// time linear in the number of pages, and the virtual size limit in the number of mappings.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Get the limits on how much memory this `AddressSpace` may use; see `set_limits`.
+    #[must_use]
+    pub const fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Check that `length` more bytes, in `mappings` more mappings, may be mapped under this
+    /// `AddressSpace`'s limits.
+    fn check_mapping_limits(&self, length: usize, mappings: usize) -> Result<(), AsError> {
+        let within = |limit: Option<usize>, used: usize, added: usize| {
+            limit.is_none_or(|limit| used.checked_add(added).is_some_and(|total| total <= limit))
+        };
+        let virtual_size = || self.mappings.iter().map(|m| m.length).sum();
+        if !within(self.limits.mappings, self.mappings.len(), mappings)
+            || (self.limits.virtual_size.is_some()
+                && !within(self.limits.virtual_size, virtual_size(), length))
+        {
+            return Err(AsError::LimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Check that there's room to record `added` more populated pages.
+    fn check_page_capacity(&self, added: usize) -> Result<(), AsError> {
+        #[cfg(not(feature = "alloc"))]
+        if self.pages.len() + added > N_RESIDENT {
+            return Err(AsError::OutOfCapacity);
+        }
+        Ok(())
+    }
+
+    /// Check that the page at `page` may be made resident under this `AddressSpace`'s limits, and
+    /// that there's room to record it.
+    fn check_resident_limit(&self, page: VirtualAddress) -> Result<(), AsError> {
+        if !self.pages.contains(&PageEntry::key(page)) {
+            self.check_page_capacity(1)?;
+        }
+        if self.limits.resident.is_none() || self.is_resident(page) {
+            return Ok(());
+        }
+        self.check_resident_room(1)
+    }
+
+    /// Check that `added` more pages may be made resident under this `AddressSpace`'s limits.
+    fn check_resident_room(&self, added: usize) -> Result<(), AsError> {
+        let Some(limit) = self.limits.resident else {
+            return Ok(());
+        };
+        let resident = self.pages.iter().filter(|page| page.is_resident()).count();
+        if resident.saturating_add(added) > limit {
+            return Err(AsError::LimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Check that a mapping may have `flags` under this `AddressSpace`'s policies.
+    fn check_policy(&self, flags: Flags) -> Result<(), AsError> {
+        if self.write_xor_execute
+            && flags.permits(AccessType::Write)
+            && flags.permits(AccessType::Execute)
+        {
+            return Err(AsError::WriteAndExecute);
+        }
+
+        Ok(())
+    }
+
+    /// The highest possible ceiling with pages of `page_size` bytes: the end of the last page,
+    /// capped to `VADDR_MAX`.
+    const fn max_ceiling(page_size: usize) -> VirtualAddress {
+        let total_capacity = N_PAGES.saturating_mul(page_size);
+        if total_capacity > VADDR_MAX {
+            VADDR_MAX + 1
+        } else {
+            total_capacity
+        }
+    }
+
+    /// Get the generation of the mappings, which changes whenever a mapping is added, removed,
+    /// resized, or has its flags changed, so that translations cached outside the `AddressSpace`
+    /// can be checked cheaply for staleness.
+    ///
+    /// It only ever counts up, wrapping around on overflow.
+    #[must_use]
+    pub const fn generation(&self) -> usize {
+        self.mappings_generation
+    }
+
+    /// Get the top of the usable address space: one past the highest address that may be mapped.
+    ///
+    /// By default this is the end of the last of the `N_PAGES` pages, or `VADDR_MAX + 1` if that
+    /// is lower.
+    #[must_use]
+    pub const fn ceiling(&self) -> VirtualAddress {
+        self.ceiling
+    }
+
+    /// Set the top of the usable address space: one past the highest address that may be mapped.
+    ///
+    /// # Errors
+    /// If `ceiling` isn't `PAGE_SIZE`-aligned, is higher than the default ceiling (see
+    /// `ceiling`), or is too low for some existing mapping.
+    pub fn set_ceiling(&mut self, ceiling: VirtualAddress) -> Result<(), AsError> {
+        if !ceiling.is_multiple_of(self.page_size) {
+            return Err(AsError::Unaligned);
+        }
+        if ceiling > Self::max_ceiling(self.page_size) {
+            return Err(AsError::CeilingTooHigh);
+        }
+
+        let last = self.gaps().last().expect("there is always a last gap");
+        if last.start.saturating_add(last.start_guard) > ceiling {
+            return Err(AsError::CeilingTooLow);
+        }
+
+        // Only the last free region changes.
+        if let Some(region) = self.indexed_region(last) {
+            self.free_by_size.remove(&region);
+        }
+        if let Some(region) = self.indexed_region(Gap {
+            end: ceiling,
+            ..last
+        }) {
+            self.free_by_size.insert(region);
+        }
+        self.ceiling = ceiling;
+
+        Ok(())
+    }
+
+    /// Round a requested mapping length up to a whole number of pages.
+    fn round_length(&self, length: usize) -> Result<usize, AsError> {
+        if length == 0 {
+            return Err(AsError::ZeroLength);
+        }
+        length
+            .checked_next_multiple_of(self.page_size)
+            .ok_or(AsError::Overflow)
+    }
+
+    /// Create an iterator over the raw gaps between mappings, ignoring guard space.
+    fn gaps(&self) -> impl Iterator<Item = Gap> + '_ {
+        let starts =
+            core::iter::once((0, 0)).chain(self.mappings.iter().map(|m| (m.end(), m.guard)));
+        let ends = self
+            .mappings
+            .iter()
+            .map(|m| (m.addr, m.guard))
+            .chain(core::iter::once((self.ceiling, 0)));
+
+        starts
+            .zip(ends)
+            .map(|((start, start_guard), (end, end_guard))| Gap {
+                start,
+                end,
+                start_guard,
+                end_guard,
+            })
+    }
+
+    /// Get the part of `gap` usable by a mapping with the given guard size, if one could fit.
+    fn usable_region(&self, gap: Gap, guard: usize) -> Option<FreeRegion> {
+        let start = gap
+            .start
+            .checked_add(gap.start_guard.max(guard))?
+            .max(self.page_size);
+        let end = gap.end.saturating_sub(gap.end_guard.max(guard));
+        let aligned = start.checked_next_multiple_of(self.page_size)?;
+
+        (aligned < end).then(|| FreeRegion {
+            size: end - aligned,
+            start,
+            end,
+        })
+    }
+
+    /// Get the part of `gap` usable by a mapping with the default guard size, as kept in the free
+    /// region index.
+    fn indexed_region(&self, gap: Gap) -> Option<FreeRegion> {
+        self.usable_region(gap, MIN_GAP_SIZE)
+    }
+
+    /// Get the raw gap that `addr` falls in, assuming no mapping starts at `addr`.
+    fn gap_around(&self, addr: VirtualAddress) -> Gap {
+        let (start, start_guard) = self
+            .mappings
+            .range(..MapEntry::key(addr))
+            .next_back()
+            .map_or((0, 0), |m| (m.end(), m.guard));
+        let (end, end_guard) = self
+            .mappings
+            .range(MapEntry::key(addr)..)
+            .next()
+            .map_or((self.ceiling, 0), |m| (m.addr, m.guard));
+
+        Gap {
+            start,
+            end,
+            start_guard,
+            end_guard,
+        }
+    }
+
+    /// Split `gap` around a mapping inside it.
+    fn split_gap(gap: Gap, entry: &MapEntry) -> [Gap; 2] {
+        let guard = entry.guard;
+        [
+            Gap {
+                end: entry.addr,
+                end_guard: guard,
+                ..gap
+            },
+            Gap {
+                start: entry.end(),
+                start_guard: guard,
+                ..gap
+            },
+        ]
+    }
+
+    /// Insert a new mapping, keeping the free region index up to date.
+    ///
+    /// The caller must have already checked that the mapping is valid.
+    fn insert_entry(&mut self, entry: MapEntry<'a>) {
+        let gap = self.gap_around(entry.addr);
+
+        if let Some(region) = self.indexed_region(gap) {
+            self.free_by_size.remove(&region);
+        }
+        let regions = Self::split_gap(gap, &entry).map(|g| self.indexed_region(g));
+        for region in regions.into_iter().flatten() {
+            self.free_by_size.insert(region);
+        }
+
+        let inserted = self.mappings.insert(entry);
+        debug_assert!(inserted);
+        self.bump_generation();
+    }
+
+    /// Record that the mappings have changed.
+    fn bump_generation(&mut self) {
+        self.mappings_generation = self.mappings_generation.wrapping_add(1);
+    }
+
+    /// Get a generation for a new mapping.
+    fn new_generation(&mut self) -> usize {
+        let generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1);
+        generation
+    }
+
+    /// Get the mapping a handle refers to.
+    fn entry_for(&self, handle: MappingHandle) -> Result<&MapEntry<'a>, AsError> {
+        self.mappings
+            .get(&MapEntry::key(handle.addr))
+            .filter(|m| m.generation == handle.generation)
+            .ok_or(AsError::StaleHandle)
+    }
+
+    /// Get the mapping a handle refers to, so long as it may be modified.
+    fn unsealed_entry_for(&self, handle: MappingHandle) -> Result<&MapEntry<'a>, AsError> {
+        self.entry_for(handle).and_then(|m| {
+            if m.sealed {
+                Err(AsError::Sealed)
+            } else {
+                Ok(m)
+            }
+        })
+    }
+
+    /// Remove the mapping starting at `addr`, keeping the free region index up to date.
+    fn take_entry(&mut self, addr: VirtualAddress) -> Option<MapEntry<'a>> {
+        let entry = self.mappings.take(&MapEntry::key(addr))?;
+        self.bump_generation();
+        let gap = self.gap_around(addr);
+
+        let regions = Self::split_gap(gap, &entry).map(|g| self.indexed_region(g));
+        for region in regions.into_iter().flatten() {
+            self.free_by_size.remove(&region);
+        }
+        if let Some(region) = self.indexed_region(gap) {
+            self.free_by_size.insert(region);
+        }
+
+        Some(entry)
+    }
+
+    /// Create an iterator over the free regions of this `AddressSpace`, in address order.
+    ///
+    /// Each region is a `(start, end)` pair such that a new mapping covering
+    /// `[addr, addr + length)` would be valid if `start <= addr` and `addr + length <= end`, and
+    /// `addr` is `PAGE_SIZE`-aligned, assuming the new mapping has the default `MIN_GAP_SIZE`
+    /// guard. That is, the guard space around existing mappings and the zero page have already
+    /// been excluded, and regions too small to hold any mapping are skipped.
+    pub fn free_regions(&self) -> impl Iterator<Item = (VirtualAddress, VirtualAddress)> + '_ {
+        self.usable_regions().map(|r| (r.start, r.end))
+    }
+
+    /// Create an iterator over the usable part of every gap between mappings, in address order.
+    fn usable_regions(&self) -> impl Iterator<Item = FreeRegion> + '_ {
+        self.gaps().filter_map(|g| self.indexed_region(g))
+    }
+
+    /// Summarize how fragmented the free space in this `AddressSpace` is.
+    #[must_use]
+    pub fn fragmentation_stats(&self) -> FragmentationStats {
+        FragmentationStats {
+            free_regions: self.free_by_size.len(),
+            total_free: self.free_by_size.iter().map(|r| r.size).sum(),
+            largest_free: self.free_by_size.last().map_or(0, |r| r.size),
+        }
+    }
+
+    /// Check if there is space for a mapping of length at addr with the given guard size.
+    fn is_space_at(&self, addr: VirtualAddress, length: usize, guard: usize) -> bool {
+        if self.is_mapped(addr) {
+            return false;
+        }
+
+        // Find the free region around addr and check whether it has room.
+        self.usable_region(self.gap_around(addr), guard)
+            .is_some_and(|r| {
+                r.start <= addr && addr.checked_add(length).is_some_and(|end| end <= r.end)
+            })
+    }
+
+    /// Find the space for a mapping of the given length and guard size, starting at a multiple of
+    /// `align`.
+    ///
+    /// This takes logarithmic time for the best-fit and worst-fit policies, which use the free
+    /// region index, unless `align` is larger than a page or the guard size isn't the default.
+    /// Otherwise, it has to scan the free regions in address order.
+    fn find_space_for(&self, length: usize, align: usize, guard: usize) -> Option<VirtualAddress> {
+        let align = align.max(self.page_size);
+        let indexed = align == self.page_size && guard == MIN_GAP_SIZE;
+
+        let region = match self.placement {
+            PlacementPolicy::BestFit if indexed => {
+                let smallest = self
+                    .free_by_size
+                    .range(FreeRegion::min_with_size(length)..)
+                    .next()?;
+                self.with_same_size(smallest.size)
+            }
+            PlacementPolicy::WorstFit if indexed => {
+                let largest = self.free_by_size.last().filter(|r| r.size >= length)?;
+                self.with_same_size(largest.size)
+            }
+            _ => self.scan_for_space(length, align, guard),
+        }?;
+
+        Some(match self.direction {
+            GrowthDirection::BottomUp => region.start.checked_next_multiple_of(align)?,
+            GrowthDirection::TopDown => (region.end - length) / align * align,
+        })
+    }
+
+    /// Choose a free region with room for `length` at a multiple of `align`, with the given guard
+    /// size, according to the placement policy, by scanning all of the free regions.
+    fn scan_for_space(&self, length: usize, align: usize, guard: usize) -> Option<FreeRegion> {
+        let mut fitting = self
+            .gaps()
+            .filter_map(|g| self.usable_region(g, guard))
+            .filter(|r| {
+                r.start
+                    .checked_next_multiple_of(align)
+                    .and_then(|start| start.checked_add(length))
+                    .is_some_and(|end| end <= r.end)
+            });
+
+        // How early a region comes in the growth direction.
+        let earliness = |r: &FreeRegion| match self.direction {
+            GrowthDirection::BottomUp => usize::MAX - r.start,
+            GrowthDirection::TopDown => r.start,
+        };
+
+        match (self.placement, self.direction) {
+            (PlacementPolicy::FirstFit, GrowthDirection::BottomUp) => fitting.next(),
+            (PlacementPolicy::FirstFit, GrowthDirection::TopDown) => fitting.last(),
+            (PlacementPolicy::BestFit, _) => {
+                fitting.min_by_key(|r| (r.size, core::cmp::Reverse(earliness(r))))
+            }
+            (PlacementPolicy::WorstFit, _) => fitting.max_by_key(|r| (r.size, earliness(r))),
+        }
+    }
+
+    /// Get the first free region of exactly `size` in the growth direction.
+    fn with_same_size(&self, size: usize) -> Option<FreeRegion> {
+        let mut same_size = self
+            .free_by_size
+            .range(FreeRegion::min_with_size(size)..=FreeRegion::max_with_size(size));
+
+        match self.direction {
+            GrowthDirection::BottomUp => same_size.next(),
+            GrowthDirection::TopDown => same_size.next_back(),
+        }
+        .copied()
+    }
+
+    /// Find the mapping containing `addr`, if any.
+    fn mapping_containing(&self, addr: VirtualAddress) -> Option<&MapEntry<'a>> {
+        // The only candidate is the last mapping starting at or before addr.
+        self.mappings
+            .range(..=MapEntry::key(addr))
+            .next_back()
+            .filter(|m| addr < m.end())
+    }
+
+    /// An _expensive_ check that the `AddressSpace` is in a valid state, i.e.:
+    ///  * The zero page is free.
+    ///  * There is enough guard space between the last mapping and the ceiling.
+    ///  * No mappings overlap.
+    ///  * There is enough guard space between each mapping.
+    ///  * All mappings are `PAGE_SIZE`-aligned.
+    ///  * The internal index of free regions is up to date.
+    ///
+    /// This never panics, so it can be used to debug a running kernel.
+    ///
+    /// # Errors
+    /// With a report of every violation found.
+    #[cfg(any(test, feature = "validate"))]
+    #[allow(clippy::result_large_err)] // we have no allocator to box the report with
+    pub fn check_valid(&self) -> Result<(), ValidityReport> {
+        let mut report = ValidityReport::default();
+
+        for m in self.mappings.iter() {
+            // The zero page is free.
+            if m.addr < self.page_size {
+                report.push(Violation::ZeroPage { addr: m.addr });
+            }
+
+            // All mappings are `PAGE_SIZE`-aligned.
+            if !m.addr.is_multiple_of(self.page_size) {
+                report.push(Violation::Misaligned { addr: m.addr });
+            }
+        }
+
+        // There is enough guard space between the last mapping and the ceiling.
+        if let Some(m) = self.mappings.last() {
+            if self.ceiling > VADDR_MAX + 1 || m.end().saturating_add(m.guard) > self.ceiling {
+                report.push(Violation::AboveCeiling { addr: m.addr });
+            }
+        }
+
+        let iter_1 = self.mappings.iter();
+        let iter_2 = self.mappings.iter().skip(1);
+
+        // There is enough guard space between each mapping.
+        for (m1, m2) in iter_1.zip(iter_2) {
+            // mappings.iter is in-order, so here we're guaranteed:
+            // m1.addr <= m2.addr
+            // there is no m3 s.t. m1.addr < m3.addr < m2.addr
+            if m1.end() > m2.addr {
+                report.push(Violation::Overlap {
+                    first: m1.addr,
+                    second: m2.addr,
+                });
+            } else if m1.end().saturating_add(m1.guard.max(m2.guard)) > m2.addr {
+                report.push(Violation::GapTooSmall {
+                    first: m1.addr,
+                    second: m2.addr,
+                });
+            }
+        }
+
+        // The free region index is up to date.
+        for region in self.usable_regions() {
+            if !self.free_by_size.contains(&region) {
+                report.push(Violation::FreeIndexOutOfDate {
+                    start: region.start,
+                });
+            }
+        }
+        if self.free_by_size.len() != self.usable_regions().count() {
+            report.push(Violation::FreeIndexOutOfDate { start: 0 });
+        }
+
+        if report.count() == 0 {
+            Ok(())
+        } else {
+            Err(report)
+        }
+    }
+
+    /// Panic if the `AddressSpace` is in an invalid state; see `check_valid`.
+    #[cfg(test)]
+    fn assert_valid(&self) {
+        if let Err(report) = self.check_valid() {
+            panic!("{} is invalid: {report:?}", self.name);
+        }
+    }
+
+    /// Start building a mapping from a `DataSource` into this `AddressSpace`.
+    ///
+    /// ```
+    /// # use reedos_address_space::{AddressSpace, DataSource, flags};
+    /// # struct Rom;
+    /// # impl DataSource for Rom {
+    /// #     fn read(&self, _: usize, _: usize, _: &mut [u8]) -> Result<(), &'static str> {
+    /// #         Ok(())
+    /// #     }
+    /// #     fn write(&self, _: usize, _: usize, _: &[u8]) -> Result<(), &'static str> { Ok(()) }
+    /// #     fn flush(&self, _: usize, _: usize) -> Result<(), &'static str> { Ok(()) }
+    /// # }
+    /// let rom = Rom;
+    /// let mut space = AddressSpace::<16>::new("example");
+    /// let handle = space
+    ///     .map(&rom)
+    ///     .length(8192)
+    ///     .at(16384)
+    ///     .offset(4096)
+    ///     .flags(flags![read, execute])
+    ///     .commit()
+    ///     .expect("there's room at 16384");
+    /// assert_eq!(handle.addr(), 16384);
+    /// ```
+    pub fn map(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+    ) -> MappingBuilder<'_, 'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT> {
+        MappingBuilder {
+            space: self,
+            source: source.into(),
+            length: None,
+            addr: None,
+            offset: 0,
+            flags: Flags::default(),
+            align: 1,
+            guard: None,
+            grows_down: None,
+        }
+    }
+
+    /// Add a mapping from a `DataSource` into this `AddressSpace`.
+    ///
+    /// This is shorthand for `self.map(source).length(length).flags(flags).commit()`.
+    ///
+    /// # Errors
+    /// If the desired mapping is invalid.
+    pub fn add_mapping(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        self.map(source).length(length).flags(flags).commit()
+    }
+
+    /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address.
+    ///
+    /// This is shorthand for `self.map(source).length(length).at(addr).flags(flags).commit()`.
+    ///
+    /// # Errors
+    /// If `addr` is unaligned, or there is insufficient room subsequent to it, including if the
+    /// mapping would extend past the ceiling.
+    pub fn add_mapping_at(
+        &mut self,
+        addr: VirtualAddress,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        self.map(source)
+            .length(length)
+            .at(addr)
+            .flags(flags)
+            .commit()
+    }
+
+    /// Map `length` bytes of `source` at `start`, where the physical memory it describes is, so
+    /// that each page translates to the frame at its own address, returning the mapping's handle.
+    ///
+    /// The pages are resident from the start, in frames the `AddressSpace` doesn't own: they're
+    /// never released when they're unmapped or replaced by a private copy. Pages added by growing
+    /// the mapping later are populated as usual, rather than identity-mapped.
+    ///
+    /// # Errors
+    /// As for `add_mapping_at`, or if there's no room to record that its pages are resident.
+    pub fn add_identity_mapping(
+        &mut self,
+        start: VirtualAddress,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        self.check_page_capacity(self.round_length(length)? / self.page_size)?;
+        let handle = self.add_mapping_at(start, source, length, flags)?;
+        let end = start + self.entry_for(handle)?.length;
+        for page in (start..end).step_by(self.page_size) {
+            self.set_page(PageEntry {
+                frame: Some(PhysFrame::new(page)),
+                pinned: true,
+                ..PageEntry::key(page)
+            });
+        }
+
+        Ok(handle)
+    }
+
+    /// Add a mapping as the `mmap` system call would, returning where it was placed.
+    ///
+    /// Anonymous mappings map zeros; writable ones must be private. A `MAP_FIXED` mapping
+    /// replaces whatever was mapped where it goes, as with `unmap_range`; otherwise, a non-zero
+    /// `addr` is rounded down to a page and used if there's room there, and the mapping goes
+    /// wherever there's room if not.
+    ///
+    /// # Errors
+    /// If the request is malformed, its `addr` isn't page-aligned with `MAP_FIXED`, its offset
+    /// isn't page-aligned, unmapping what it replaces fails, or the mapping is invalid, as for
+    /// `MappingBuilder::commit`. If adding a `MAP_FIXED` mapping fails, what it replaced stays
+    /// unmapped.
+    pub fn mmap(&mut self, req: MmapRequest<'a>) -> Result<VirtualAddress, AsError> {
+        let flags = Flags::from_prot_and_map(req.prot, req.flags).ok_or(AsError::InvalidRequest)?;
+        let (source, offset) = if req.flags & Flags::MAP_ANONYMOUS != 0 {
+            (SourceRef::from(&ZeroSource), 0)
+        } else {
+            (req.source.ok_or(AsError::InvalidRequest)?, req.offset)
+        };
+        if !offset.is_multiple_of(self.page_size) {
+            return Err(AsError::Unaligned);
+        }
+        let commit = |space: &mut Self, addr: Option<VirtualAddress>| {
+            let builder = space
+                .map(source.clone())
+                .length(req.length)
+                .offset(offset)
+                .flags(flags);
+            match addr {
+                Some(addr) => builder.at(addr).commit(),
+                None => builder.commit(),
+            }
+            .map(|m| m.addr())
+        };
+
+        if req.flags & Flags::MAP_FIXED != 0 {
+            if !req.addr.is_multiple_of(self.page_size) {
+                return Err(AsError::Unaligned);
+            }
+            self.unmap_range(req.addr, req.length)?;
+            return commit(self, Some(req.addr));
+        }
+        if req.addr != 0 {
+            let hint = req.addr - req.addr % self.page_size;
+            match commit(self, Some(hint)) {
+                Err(AsError::NoSpace | AsError::Overflow) => {}
+                placed => return placed,
+            }
+        }
+        commit(self, None)
+    }
+
+    /// Map the mapping that starts at `start` into `other` as well, returning where it was placed
+    /// there.
+    ///
+    /// The new mapping refers to the same `DataSource`, offset, and length, with the same flags
+    /// except that it is shared rather than private. Metadata is not copied, since it belongs to
+    /// this `AddressSpace`.
+    ///
+    /// # Errors
+    /// If there is no mapping at `start`, it isn't a whole number of `other`'s pages, there's no
+    /// room for it in `other`, its `DataSource` can't honor it being shared, or its flags violate
+    /// W^X when `other` enforces it.
+    pub fn share_mapping<
+        const OTHER_N_PAGES: usize,
+        const OTHER_PAGE_SIZE: usize,
+        const OTHER_MIN_GAP_SIZE: usize,
+        const OTHER_N_RESIDENT: usize,
+    >(
+        &self,
+        other: &mut AddressSpace<
+            'a,
+            OTHER_N_PAGES,
+            OTHER_PAGE_SIZE,
+            OTHER_MIN_GAP_SIZE,
+            OTHER_N_RESIDENT,
+        >,
+        start: VirtualAddress,
+    ) -> Result<MappingHandle, AsError> {
+        let entry = self
+            .mappings
+            .get(&MapEntry::key(start))
+            .ok_or(AsError::NotMapped)?;
+        if !entry.length.is_multiple_of(other.page_size) {
+            return Err(AsError::PageSizeMismatch);
+        }
+        let flags = entry
+            .flags
+            .into_builder()
+            .set_private(false)
+            .set_shared(true)
+            .try_validate()
+            .expect("sharing keeps flags valid");
+        other.check_policy(flags)?;
+        check_source_flags(entry.source(), flags)?;
+        other.check_mapping_limits(entry.length, 1)?;
+        let addr = other
+            .find_space_for(entry.length, other.page_size, entry.guard)
+            .ok_or(AsError::NoSpace)?;
+        let generation = other.new_generation();
+
+        other.insert_entry(MapEntry {
+            addr,
+            generation,
+            sealed: false,
+            flags,
+            metadata: None,
+            fault_handler: None,
+            ..entry.clone()
+        });
+
+        Ok(MappingHandle { addr, generation })
+    }
+
+    /// Get a handle to the mapping that starts at the given address, if there is one.
+    #[must_use]
+    pub fn handle_at(&self, start: VirtualAddress) -> Option<MappingHandle> {
+        self.mappings
+            .get(&MapEntry::key(start))
+            .map(|m| MappingHandle {
+                addr: m.addr,
+                generation: m.generation,
+            })
+    }
+
+    /// Remove a mapping.
+    ///
+    /// # Errors
+    /// If the handle is stale, the mapping is sealed, or there's no room to release its frames or
+    /// swap slots until `free_released_frames` or `free_released_slots` is called.
+    pub fn remove_mapping(&mut self, handle: MappingHandle) -> Result<(), AsError> {
+        let entry = self.unsealed_entry_for(handle)?;
+        self.check_release_capacity(entry.addr, entry.end())?;
+        let entry = self.take_entry(handle.addr).expect("handle is current");
+        self.forget_pages(entry.addr, entry.end());
+        self.flush_tlb(entry.addr, entry.end());
+
+        Ok(())
+    }
+
+    /// Change the access flags of a mapping.
+    ///
+    /// # Errors
+    /// If the handle is stale, the mapping is sealed, its `DataSource` can't honor the flags, or
+    /// the flags violate W^X when it's enforced.
+    pub fn protect(&mut self, handle: MappingHandle, flags: Flags) -> Result<(), AsError> {
+        self.check_policy(flags)?;
+        let entry = MapEntry {
+            flags,
+            ..self.unsealed_entry_for(handle)?.clone()
+        };
+        check_source_flags(entry.source(), flags)?;
+        let (start, end) = (entry.addr, entry.end());
+        self.mappings.replace(entry);
+        self.bump_generation();
+        self.flush_tlb(start, end);
+        let unsynced = &mut self.unsynced;
+        for page in self.pages.range(PageEntry::key(start)..PageEntry::key(end)) {
+            unsynced.insert(page.addr);
+        }
+
+        Ok(())
+    }
+
+    /// Grow or shrink a mapping in place, keeping its start address.
+    ///
+    /// # Errors
+    /// If the handle is stale, the mapping is sealed, `length` is zero or overflows when rounded
+    /// up to a whole number of pages, the mapping would extend past the end of its `DataSource`,
+    /// there isn't room to grow the mapping, or there's no room to release the frames or swap
+    /// slots of the pages it shrinks away from until `free_released_frames` or
+    /// `free_released_slots` is called.
+    pub fn resize_mapping(&mut self, handle: MappingHandle, length: usize) -> Result<(), AsError> {
+        let entry = self.unsealed_entry_for(handle)?;
+        let requested_length = length;
+        let length = self.round_length(requested_length)?;
+        let source = entry.source();
+        check_source_bounds(source, entry.offset, requested_length)?;
+        self.check_mapping_limits(length.saturating_sub(entry.length), 0)?;
+        self.check_release_capacity(handle.addr + length, entry.end())?;
+
+        let old = self.take_entry(handle.addr).expect("handle is current");
+        if !self.is_space_at(old.addr, length, old.guard) {
+            self.insert_entry(old);
+            return Err(AsError::NoSpace);
+        }
+        let end = old.end();
+        self.insert_entry(MapEntry {
+            length,
+            requested_length,
+            ..old
+        });
+        self.forget_pages(handle.addr + length, end);
+        self.flush_tlb(handle.addr + length, end);
+
+        Ok(())
+    }
+
+    /// Grow the mapping just above `addr` down to the page containing `addr`, if it grows down,
+    /// permits `access`, and can grow that far, returning whether it grew.
+    ///
+    /// The grown mapping keeps its generation, but since it starts at a new address, existing
+    /// handles to it go stale.
+    fn grow_down(&mut self, addr: VirtualAddress, access: AccessType) -> bool {
+        let page = addr - addr % self.page_size;
+        let Some(above) = self.mappings.range(MapEntry::key(addr)..).next() else {
+            return false;
+        };
+        let grow = above.addr - page;
+        let length = above.length + grow;
+        let fits = above.grows_down.is_some_and(|limit| length <= limit.get());
+        if !fits || above.sealed || above.offset < grow || !above.flags.permits(access) {
+            return false;
+        }
+        if self.check_mapping_limits(grow, 0).is_err() || self.check_resident_limit(page).is_err() {
+            return false;
+        }
+
+        let old = self.take_entry(above.addr).expect("the mapping exists");
+        if !self.is_space_at(page, length, old.guard) {
+            self.insert_entry(old);
+            return false;
+        }
+        self.insert_entry(MapEntry {
+            addr: page,
+            length,
+            requested_length: old.requested_length + grow,
+            offset: old.offset - grow,
+            ..old
+        });
+
+        true
+    }
+
+    /// Seal a mapping, so that it can no longer be removed, unmapped, protected, or resized.
+    ///
+    /// Kernels use this to protect critical regions, like the vDSO or signal trampolines, from
+    /// accidental or malicious modification. There is no way to unseal a mapping.
+    ///
+    /// # Errors
+    /// If the handle is stale.
+    pub fn seal(&mut self, handle: MappingHandle) -> Result<(), AsError> {
+        let entry = MapEntry {
+            sealed: true,
+            ..self.entry_for(handle)?.clone()
+        };
+        self.mappings.replace(entry);
+
+        Ok(())
+    }
+
+    /// Record that the mapping containing `addr` has been accessed, and if `write`, that it has
+    /// been written to.
+    ///
+    /// Page fault handlers and page table scanners call this to fold the hardware accessed and
+    /// dirty bits into the mapping's flags, and into the page's own bits if it's resident; see
+    /// `harvest_accessed`. This is allowed even on sealed mappings.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped.
+    pub fn mark_accessed(&mut self, addr: VirtualAddress, write: bool) -> Result<(), AsError> {
+        let mut entry = self
+            .mapping_containing(addr)
+            .ok_or(AsError::NotMapped)?
+            .clone();
+        let builder = entry.flags.into_builder();
+        entry.flags = builder
+            .set_accessed(true)
+            .set_dirty(builder.dirty || write)
+            .try_validate()
+            .expect("accessed and dirty bits don't affect validity");
+        self.mappings.replace(entry);
+
+        let page = addr - addr % self.page_size;
+        if let Some(&page) = self.pages.get(&PageEntry::key(page)) {
+            if page.is_resident() {
+                self.pages.replace(PageEntry {
+                    accessed: true,
+                    dirty: page.dirty || write,
+                    ..page
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clear the accessed bit of a mapping, returning whether it was set.
+    ///
+    /// Page replacement scanners use this to tell which mappings have been used recently.
+    ///
+    /// # Errors
+    /// If the handle is stale.
+    pub fn clear_accessed(&mut self, handle: MappingHandle) -> Result<bool, AsError> {
+        let mut entry = self.entry_for(handle)?.clone();
+        let builder = entry.flags.into_builder();
+        entry.flags = builder
+            .set_accessed(false)
+            .try_validate()
+            .expect("accessed and dirty bits don't affect validity");
+        self.mappings.replace(entry);
+
+        Ok(builder.accessed)
+    }
+
+    /// Clear the dirty bit of a mapping, returning whether it was set.
+    ///
+    /// Call this after writing a mapping back to its `DataSource`.
+    ///
+    /// # Errors
+    /// If the handle is stale.
+    pub fn clear_dirty(&mut self, handle: MappingHandle) -> Result<bool, AsError> {
+        let mut entry = self.entry_for(handle)?.clone();
+        let builder = entry.flags.into_builder();
+        entry.flags = builder
+            .set_dirty(false)
+            .try_validate()
+            .expect("accessed and dirty bits don't affect validity");
+        self.mappings.replace(entry);
+
+        Ok(builder.dirty)
+    }
+
+    /// Check whether `addr` falls inside some mapping, including those of a shared kernel region.
+    #[must_use]
+    pub fn is_mapped(&self, addr: VirtualAddress) -> bool {
+        match self.kernel_region_for(addr) {
+            Some(kernel) => kernel.is_mapped(addr),
+            None => self.mapping_containing(addr).is_some(),
+        }
+    }
+
+    /// Check whether every address in `[start, start + length)` falls inside some mapping.
+    ///
+    /// Syscall handlers should use this to validate user pointers before copying through them.
+    #[must_use]
+    pub fn contains_range(&self, start: VirtualAddress, length: usize) -> bool {
+        let Some(end) = start.checked_add(length) else {
+            return false;
+        };
+
+        // Walk forward one mapping at a time until we've covered the whole range.
+        let mut addr = start;
+        while addr < end {
+            match self.mapping_containing(addr) {
+                Some(m) => addr = m.end(),
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Check that every byte of the `length` bytes at `addr` is mapped, by mappings with at least
+    /// the flags in `required`.
+    fn check_access(
+        &self,
+        addr: VirtualAddress,
+        length: usize,
+        required: Flags,
+    ) -> Result<(), AsError> {
+        let end = addr.checked_add(length).ok_or(AsError::Overflow)?;
+        if !self.contains_range(addr, length) {
+            return Err(AsError::NotMapped);
+        }
+        if self
+            .mappings_in_range(addr, end)
+            .any(|m| !m.flags.contains(required))
+        {
+            return Err(AsError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Copy `buffer.len()` bytes at `addr` into `buffer`, like `copy_from_user`, checking first
+    /// that they're all mapped by mappings with at least the flags in `required`, like
+    /// `flags![read, user]`.
+    ///
+    /// The copy is split across pages and mappings. Pages in a frame of their own, like private
+    /// copies, are read from it through `frames`; the rest are read from their mapping's
+    /// `DataSource`, with the part past the requested length of the mapping reading as zeros.
+    ///
+    /// # Errors
+    /// If some byte isn't mapped, a mapping lacks some of the `required` flags, a page is swapped
+    /// out, or reading a page fails. `buffer` may have been partly written.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn read_bytes(
+        &self,
+        addr: VirtualAddress,
+        buffer: &mut [u8],
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        self.check_access(addr, buffer.len(), required)?;
+        for (at, piece) in page_pieces(self.page_size, addr, buffer.len()) {
+            self.read_in_page(at, &mut buffer[piece], frames)?;
+        }
+        Ok(())
+    }
+
+    /// Read `buffer.len()` bytes at `addr`, which are mapped and all in one page.
+    fn read_in_page(
+        &self,
+        addr: VirtualAddress,
+        buffer: &mut [u8],
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        let m = self.mapping_containing(addr).expect("addr is mapped");
+        let page = addr - addr % self.page_size;
+        match self.pages.get(&PageEntry::key(page)) {
+            Some(entry) if !entry.is_resident() => Err(AsError::SwappedOut),
+            Some(entry) if entry.data_frame().is_some() => {
+                let frame = entry.data_frame().expect("the page has a frame");
+                let start = addr - page;
+                buffer.copy_from_slice(&frames.frame_data(frame)[start..start + buffer.len()]);
+                Ok(())
+            }
+            _ => Self::read_range(m, addr, buffer),
+        }
+    }
+
+    /// Copy `bytes` to `addr`, like `copy_to_user`, checking first that they're all mapped by
+    /// mappings with at least the flags in `required`, like `flags![write, user]`.
+    ///
+    /// The copy is split across pages and mappings. Pages in a frame of their own are written
+    /// through `frames`. Pages of private mappings are first copied with `resolve_cow`, if they're
+    /// copy-on-write, or populated into a new frame otherwise; pages of other mappings are written
+    /// to their `DataSource`.
+    ///
+    /// # Errors
+    /// If some byte isn't mapped, a mapping lacks some of the `required` flags, a page is swapped
+    /// out, there are no free frames, or reading or writing a page fails. The bytes before the
+    /// failure may have been written.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn write_bytes(
+        &mut self,
+        addr: VirtualAddress,
+        bytes: &[u8],
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        self.check_access(addr, bytes.len(), required)?;
+        for (at, piece) in page_pieces(self.page_size, addr, bytes.len()) {
+            self.write_in_page(at, &bytes[piece], frames)?;
+        }
+        Ok(())
+    }
+
+    /// Write `bytes` to `addr`, which are mapped and all in one page.
+    fn write_in_page(
+        &mut self,
+        addr: VirtualAddress,
+        bytes: &[u8],
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        let m = self.mapping_containing(addr).expect("addr is mapped");
+        let flags = m.flags;
+        let page = addr - addr % self.page_size;
+        let entry = self.pages.get(&PageEntry::key(page)).copied();
+        if entry.is_some_and(|entry| !entry.is_resident()) {
+            return Err(AsError::SwappedOut);
+        }
+        let private = flags.into_builder().private || flags.into_builder().cow;
+
+        let frame = if let Some(frame) = entry.and_then(|entry| entry.data_frame()) {
+            frame
+        } else if flags.needs_copy(AccessType::Write) {
+            self.resolve_cow(page, frames)?
+        } else if private && !entry.is_some_and(|entry| entry.pinned) {
+            self.populate_frame(page, frames)?
+        } else {
+            return m
+                .source()
+                .write(m.offset + (addr - m.addr), bytes.len(), bytes)
+                .map_err(AsError::Source);
+        };
+        let start = addr - page;
+        frames.frame_data(frame)[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Copy `length` bytes at `src_addr` in `src` to `addr` in this address space, like
+    /// `process_vm_readv`, for a debugger peeking at another task or for passing a message between
+    /// tasks.
+    ///
+    /// Both ranges are checked up front, as for `read_bytes` and `write_bytes`: the source must be
+    /// mapped with at least the flags in `required` plus `read`, and the destination with at least
+    /// the flags in `required` plus `write`. The bytes are then copied a small chunk at a time,
+    /// through a buffer on the stack, with the pages on both sides read and written as for
+    /// `read_bytes` and `write_bytes`.
+    ///
+    /// # Errors
+    /// As for `read_bytes` and `write_bytes`, on either side. The bytes before the failure may
+    /// have been copied.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn copy_from_space<
+        const SRC_PAGES: usize,
+        const SRC_PAGE_SIZE: usize,
+        const SRC_GAP: usize,
+        const SRC_RESIDENT: usize,
+    >(
+        &mut self,
+        addr: VirtualAddress,
+        src: &AddressSpace<'_, SRC_PAGES, SRC_PAGE_SIZE, SRC_GAP, SRC_RESIDENT>,
+        src_addr: VirtualAddress,
+        length: usize,
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        const CHUNK: usize = 256;
+        let builder = required.into_builder();
+        let read = builder
+            .set_read(true)
+            .try_validate()
+            .expect("valid flags stay valid when readable");
+        let write = builder
+            .set_write(true)
+            .try_validate()
+            .expect("valid flags stay valid when writable");
+        src.check_access(src_addr, length, read)?;
+        self.check_access(addr, length, write)?;
+
+        let mut buffer = [0; CHUNK];
+        let mut done = 0;
+        while done < length {
+            let chunk = &mut buffer[..CHUNK.min(length - done)];
+            src.read_bytes(src_addr + done, chunk, read, frames)?;
+            self.write_bytes(addr + done, chunk, write, frames)?;
+            done += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Read the nul-terminated string at `addr` into `buffer`, like a path passed to `open`,
+    /// returning its length, without the terminator.
+    ///
+    /// The string is read a page at a time, checking as for `read_bytes` that each page is mapped
+    /// with at least the flags in `required` before reading it, so the string can end right before
+    /// an unmapped page. At most `max_len` bytes, and at most `buffer.len()`, are read, including
+    /// the terminator.
+    ///
+    /// # Errors
+    /// If there's no terminator within the bytes allowed, or as for `read_bytes`, for any page
+    /// before the terminator. `buffer` may have been partly written.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn read_cstr(
+        &self,
+        addr: VirtualAddress,
+        max_len: usize,
+        buffer: &mut [u8],
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<usize, AsError> {
+        let cap = max_len.min(buffer.len());
+        if addr.checked_add(cap).is_none() {
+            return Err(AsError::Overflow);
+        }
+        for (at, piece) in page_pieces(self.page_size, addr, cap) {
+            self.check_access(at, piece.len(), required)?;
+            let start = piece.start;
+            let chunk = &mut buffer[piece];
+            self.read_in_page(at, chunk, frames)?;
+            if let Some(nul) = chunk.iter().position(|byte| *byte == 0) {
+                return Ok(start + nul);
+            }
+        }
+        Err(AsError::StringTooLong)
+    }
+
+    /// Read a `T` at `addr`, like a `timespec` passed in by a user program, as for `read_bytes`.
+    ///
+    /// # Errors
+    /// If `addr` isn't aligned for `T`, or as for `read_bytes`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn read_val<T: FromBytes>(
+        &self,
+        addr: VirtualAddress,
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<T, AsError> {
+        if !addr.is_multiple_of(core::mem::align_of::<T>()) {
+            return Err(AsError::Unaligned);
+        }
+        let mut value = T::zeroed();
+        self.read_bytes(addr, value.as_bytes_mut(), required, frames)?;
+        Ok(value)
+    }
+
+    /// Write `value` to `addr`, as for `write_bytes`.
+    ///
+    /// # Errors
+    /// If `addr` isn't aligned for `T`, or as for `write_bytes`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn write_val<T: FromBytes>(
+        &mut self,
+        addr: VirtualAddress,
+        value: &T,
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        if !addr.is_multiple_of(core::mem::align_of::<T>()) {
+            return Err(AsError::Unaligned);
+        }
+        self.write_bytes(addr, value.as_bytes(), required, frames)
+    }
+
+    /// Create an iterator over every mapping intersecting `[start, end)`, in address order.
+    ///
+    /// This only visits the mappings that actually intersect the range, rather than scanning the
+    /// whole `AddressSpace`.
+    pub fn mappings_in_range(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> impl Iterator<Item = MappingInfo<'_>> + '_ {
+        let end = end.max(start);
+
+        // At most one mapping starting before the range can reach into it.
+        let straddling = self
+            .mappings
+            .range(..MapEntry::key(start))
+            .next_back()
+            .filter(|m| start < end && start < m.end());
+
+        straddling
+            .into_iter()
+            .chain(
+                self.mappings
+                    .range(MapEntry::key(start)..MapEntry::key(end)),
+            )
+            .map(MapEntry::info)
+    }
+
+    /// Look up the `DataSource` and offset within that `DataSource` for a
+    /// `VirtualAddress` / `AccessType` in this `AddressSpace`
+    ///
+    /// A write to a copy-on-write mapping is permitted; check `Flags::needs_copy` to see whether
+    /// the page must be copied first.
+    ///
+    /// # Errors
+    /// If this `VirtualAddress` does not have a valid mapping in &self,
+    /// or if this `AccessType` is not permitted by the mapping
+    pub fn get_source_for_addr(
+        &self,
+        addr: VirtualAddress,
+        access_type: AccessType,
+    ) -> Result<(&(dyn DataSource + 'a), usize), AsError> {
+        let m = self.mapping_containing(addr).ok_or(AsError::NotMapped)?;
+        if !m.flags.permits(access_type) {
+            return Err(AsError::PermissionDenied);
+        }
+
+        let source = m.source();
+        Ok((source, m.offset + (addr - m.addr)))
+    }
+
+    /// Get the address range of the mapping containing `addr`, along with the offset in its
+    /// `DataSource` that its start maps to, its flags, and the source itself, so its translations
+    /// can be cached outside the `AddressSpace`.
+    pub(crate) fn mapping_source(
+        &self,
+        addr: VirtualAddress,
+    ) -> Option<(
+        core::ops::Range<VirtualAddress>,
+        usize,
+        Flags,
+        SourceRef<'a>,
+    )> {
+        let m = self.mapping_containing(addr)?;
+        let source = m.source.clone().expect("only lookup keys have no source");
+        Some((m.addr..m.end(), m.offset, m.flags, source))
+    }
+
+    /// Get the page of the source mapped at `addr` out of `cache`, reading it into the cache if
+    /// it isn't cached, along with the offset of `addr`'s byte in it.
+    ///
+    /// Pages are cached by source and page index rather than by address, so any number of
+    /// `AddressSpace`s can share one cache, and those mapping the same source share its pages.
+    /// The page is the `CACHE_PAGE_SIZE`-byte page of the source holding `addr`'s byte, which
+    /// needn't line up with the mapping's pages.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, the mapping doesn't permit `access_type` or is `no_cache`, or
+    /// reading the page fails.
+    pub fn cached_page<'c, const N_FRAMES: usize, const CACHE_PAGE_SIZE: usize, P>(
+        &self,
+        cache: &'c mut PageCache<'a, N_FRAMES, CACHE_PAGE_SIZE, P>,
+        addr: VirtualAddress,
+        access_type: AccessType,
+    ) -> Result<(&'c [u8; CACHE_PAGE_SIZE], usize), AsError>
+    where
+        P: EvictionPolicy,
+    {
+        let m = self.mapping_containing(addr).ok_or(AsError::NotMapped)?;
+        if !m.flags.permits(access_type) {
+            return Err(AsError::PermissionDenied);
+        }
+        if m.flags.into_builder().no_cache {
+            return Err(AsError::MustNotCache);
+        }
+
+        let offset = m.offset + (addr - m.addr);
+        let source = m.source.clone().expect("only lookup keys have no source");
+        let page = cache
+            .read(source, offset / CACHE_PAGE_SIZE)
+            .map_err(AsError::Source)?;
+        Ok((page, offset % CACHE_PAGE_SIZE))
+    }
+
+    /// Populate every page of the mapping that starts at `start` up front, by reading the pages
+    /// of its source into `cache`, returning how many pages weren't already resident.
+    ///
+    /// This is like `MAP_POPULATE`: latency-sensitive mappings don't take major faults later, as
+    /// long as `cache` doesn't evict their pages. Pin them in `cache` to be sure of that.
+    ///
+    /// # Errors
+    /// If there is no mapping at `start`, it's `no_cache`, there's no room to record its pages,
+    /// making them resident would exceed the resident limit, or reading a page fails. Pages read before the failure stay cached, but none are made
+    /// resident.
+    pub fn populate_mapping<const N_FRAMES: usize, const CACHE_PAGE_SIZE: usize, P>(
+        &mut self,
+        start: VirtualAddress,
+        cache: &mut PageCache<'a, N_FRAMES, CACHE_PAGE_SIZE, P>,
+    ) -> Result<usize, AsError>
+    where
+        P: EvictionPolicy,
+    {
+        let m = self
+            .mappings
+            .get(&MapEntry::key(start))
+            .ok_or(AsError::NotMapped)?;
+        if m.flags.into_builder().no_cache {
+            return Err(AsError::MustNotCache);
+        }
+        let unpopulated = (m.addr..m.end())
+            .step_by(self.page_size)
+            .filter(|&page| !self.pages.contains(&PageEntry::key(page)))
+            .count();
+        self.check_page_capacity(unpopulated)?;
+        self.check_resident_room(unpopulated)?;
+
+        let source = m.source.clone().expect("only lookup keys have no source");
+        let source_pages = if m.requested_length == 0 {
+            0..0
+        } else {
+            m.offset / CACHE_PAGE_SIZE..(m.offset + m.requested_length).div_ceil(CACHE_PAGE_SIZE)
+        };
+        for page_index in source_pages {
+            cache
+                .read(source.clone(), page_index)
+                .map_err(AsError::Source)?;
+        }
+
+        let mut populated = 0;
+        for page in (m.addr..m.end()).step_by(self.page_size) {
+            if !self.pages.contains(&PageEntry::key(page)) {
+                self.set_page(PageEntry::key(page));
+                populated += 1;
+            }
+        }
+
+        Ok(populated)
+    }
+
+    /// Populate the page containing `addr`, if it isn't resident yet, by reading its data from
+    /// the mapping's `DataSource` into `frame`, returning whether it was populated.
+    ///
+    /// Mappings start out with no resident pages, and kernels call this on the first access to
+    /// each, to fill the frame they'll map it to. The part of the page past the requested length
+    /// of the mapping is zeroed.
+    ///
+    /// A page that's swapped out isn't populated; see `swap_in`.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, or reading from the `DataSource` fails, in which case the page
+    /// stays non-resident.
+    ///
+    /// # Panics
+    /// If `frame` isn't a page long.
+    pub fn populate_page(
+        &mut self,
+        addr: VirtualAddress,
+        frame: &mut [u8],
+    ) -> Result<bool, AsError> {
+        assert_eq!(frame.len(), self.page_size, "frame must be a page long");
+        let m = self.mapping_containing(addr).ok_or(AsError::NotMapped)?;
+        let page = addr - addr % self.page_size;
+        if self.pages.contains(&PageEntry::key(page)) {
+            return Ok(false);
+        }
+
+        self.check_resident_limit(page)?;
+        self.read_page(m, page, frame)?;
+        self.set_page(PageEntry::key(page));
+
+        Ok(true)
+    }
+
+    /// Record the new state of a page, returning its old state, if any.
+    fn set_page(&mut self, entry: PageEntry) -> Option<PageEntry> {
+        self.unsynced.insert(entry.addr);
+        self.pages.replace(entry)
+    }
+
+    /// Write a page table entry into `pt` for every resident page, returning how many were
+    /// written.
+    ///
+    /// Pages are mapped to their private copy, or to the frame `fault_in` populated them into.
+    /// Resident pages with neither, like those populated with `populate_mapping`, are populated
+    /// into new frames from `frames` first. Pages of mappings that don't permit any access are
+    /// left out. Nothing is removed from `pt`, so it should start out empty; after that, keep it
+    /// up to date with `sync_into`. Huge pages promoted in other tables are forgotten.
+    ///
+    /// # Errors
+    /// If `pt` uses a different page size, there are no free frames, reading a page fails, or
+    /// writing an entry fails. Entries written before the failure stay in `pt`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn install_into<P: PageTable, F: FrameAllocator>(
+        &mut self,
+        pt: &mut P,
+        frames: &mut F,
+    ) -> Result<usize, AsError> {
+        if P::PAGE_SIZE != self.page_size {
+            return Err(AsError::BadPageSize);
+        }
+        self.unsynced.clear();
+        self.huge_pages.clear();
+
+        let mut installed = 0;
+        let mut next = self.pages.first().map(|page| page.addr);
+        while let Some(page) = next {
+            next = self
+                .pages
+                .range(PageEntry::key(page + 1)..)
+                .next()
+                .map(|page| page.addr);
+            if self.install_page(page, pt, frames)? {
+                installed += 1;
+            }
+        }
+
+        Ok(installed)
+    }
+
+    /// Bring `pt` up to date with the pages whose translation has changed since it was last
+    /// written by `install_into` or this, returning how many entries were written or removed.
+    ///
+    /// Huge pages containing any of them are split first; see `promote`.
+    ///
+    /// # Errors
+    /// As for `install_into`. The pages that weren't synced yet are synced by the next call.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn sync_into<P: PageTable, F: FrameAllocator>(
+        &mut self,
+        pt: &mut P,
+        frames: &mut F,
+    ) -> Result<usize, AsError> {
+        if P::PAGE_SIZE != self.page_size {
+            return Err(AsError::BadPageSize);
+        }
+
+        // Pages are synced one by one, so huge pages containing them are split first.
+        if let Some(huge) = P::HUGE_PAGE_SIZE {
+            let stale =
+                |start: &VirtualAddress| self.unsynced.range(*start..start + huge).next().is_some();
+            while let Some(start) = self.huge_pages.iter().copied().find(stale) {
+                pt.split_huge(start)?;
+                self.huge_pages.remove(&start);
+            }
+        }
+
+        let mut synced = 0;
+        while let Some(page) = self.unsynced.pop_first() {
+            match self.install_page(page, pt, frames) {
+                Ok(_) => synced += 1,
+                Err(e) => {
+                    self.unsynced.insert(page);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(synced)
+    }
+
+    /// Write the entry for the page at `page` into `pt`, or remove it if the page shouldn't be
+    /// mapped, returning whether it was written.
+    fn install_page<P: PageTable, F: FrameAllocator>(
+        &mut self,
+        page: VirtualAddress,
+        pt: &mut P,
+        frames: &mut F,
+    ) -> Result<bool, AsError> {
+        let entry = self.pages.get(&PageEntry::key(page)).copied();
+        let flags = self.mapping_containing(page).map(|m| m.flags);
+        let accessible = |flags: &Flags| {
+            let flags = flags.into_builder();
+            flags.read || flags.write || flags.execute
+        };
+        let (Some(entry), Some(flags)) = (
+            entry.filter(PageEntry::is_resident),
+            flags.filter(accessible),
+        ) else {
+            pt.unmap(page);
+            return Ok(false);
+        };
+
+        let (frame, flags) = match (entry.copy, entry.frame) {
+            (Some(copy), _) => {
+                let flags = flags
+                    .into_builder()
+                    .set_cow(false)
+                    .try_validate()
+                    .expect("clearing cow keeps flags valid");
+                (copy, flags)
+            }
+            (None, Some(frame)) => (frame, flags),
+            (None, None) => (self.populate_frame(page, frames)?, flags),
+        };
+        pt.map(page, frame, flags)?;
+
+        Ok(true)
+    }
+
+    /// Map the huge page of `pt` containing `addr` with a single entry, returning whether it
+    /// could be: every page in it must be resident in the same mapping, in physically contiguous
+    /// frames starting at a multiple of the huge page size, with the same flags.
+    ///
+    /// The huge page is split again by the next `sync_into` after the translation of any page in
+    /// it changes, like when part of it is unmapped or protected, or a page in it is copied.
+    ///
+    /// # Errors
+    /// If `pt` uses a different page size, `addr` isn't mapped, or writing the entry fails.
+    pub fn promote<P: PageTable>(
+        &mut self,
+        addr: VirtualAddress,
+        pt: &mut P,
+    ) -> Result<bool, AsError> {
+        if P::PAGE_SIZE != self.page_size {
+            return Err(AsError::BadPageSize);
+        }
+        let m = self.mapping_containing(addr).ok_or(AsError::NotMapped)?;
+        let Some(huge) = P::HUGE_PAGE_SIZE else {
+            return Ok(false);
+        };
+        let start = addr - addr % huge;
+        if start < m.addr || m.addr + m.length - start < huge {
+            return Ok(false);
+        }
+        let Some((first, flags)) = self.page_frame(m, start) else {
+            return Ok(false);
+        };
+        let uniform = (start..start + huge).step_by(self.page_size).all(|page| {
+            let frame = PhysFrame::new(first.addr() + (page - start));
+            self.page_frame(m, page) == Some((frame, flags))
+        });
+        if !uniform || !first.addr().is_multiple_of(huge) {
+            return Ok(false);
+        }
+
+        pt.map_huge(start, first, flags)?;
+        self.huge_pages.insert(start);
+        self.unsynced
+            .retain(|page| !(start..start + huge).contains(page));
+        self.flush_tlb(start, start + huge);
+
+        Ok(true)
+    }
+
+    /// Get the frame the resident page at `page`, in `m`, is mapped to, and the flags it's mapped
+    /// with, if it has a frame and `m` permits some access.
+    fn page_frame(&self, m: &MapEntry, page: VirtualAddress) -> Option<(PhysFrame, Flags)> {
+        let entry = self.pages.get(&PageEntry::key(page))?;
+        let flags = m.flags.into_builder();
+        if !entry.is_resident() || !(flags.read || flags.write || flags.execute) {
+            return None;
+        }
+        match (entry.copy, entry.frame) {
+            (Some(copy), _) => {
+                let flags = flags
+                    .set_cow(false)
+                    .try_validate()
+                    .expect("clearing cow keeps flags valid");
+                Some((copy, flags))
+            }
+            (None, Some(frame)) => Some((frame, m.flags)),
+            (None, None) => None,
+        }
+    }
+
+    /// Read the page at `page`, in `m`, from its `DataSource` into `frame`, zeroing the part past
+    /// the requested length of the mapping.
+    fn read_page(
+        &self,
+        m: &MapEntry,
+        page: VirtualAddress,
+        frame: &mut [u8],
+    ) -> Result<(), AsError> {
+        Self::read_range(m, page, &mut frame[..self.page_size])
+    }
+
+    /// Read `buffer.len()` bytes at `addr`, in `m`, from its `DataSource`, zeroing the part past
+    /// the requested length of the mapping.
+    fn read_range(m: &MapEntry, addr: VirtualAddress, buffer: &mut [u8]) -> Result<(), AsError> {
+        let backed = (m.addr + m.requested_length)
+            .saturating_sub(addr)
+            .min(buffer.len());
+        let (data, tail) = buffer.split_at_mut(backed);
+        m.source()
+            .read(m.offset + (addr - m.addr), backed, data)
+            .map_err(AsError::Source)?;
+        tail.fill(0);
+        Ok(())
+    }
+
+    /// Write a snapshot of this `AddressSpace` to `sink`, from offset 0, for post-mortem
+    /// debugging, returning how many bytes were written.
+    ///
+    /// The snapshot starts with the magic bytes `COREDUMP`, the page size, and the number of
+    /// mappings. Each mapping follows, in address order: its address, length, offset, flag bits,
+    /// number of resident pages, and the length of its source's name, then the name, then the
+    /// address and contents of each resident page. Numbers are little-endian 64-bit integers.
+    ///
+    /// Pages with a private copy, or populated into a frame of their own, are read from their
+    /// frame through `frames`; the rest, including identity-mapped pages, are read from their
+    /// mapping's `DataSource`. Swapped out pages aren't included.
+    ///
+    /// # Errors
+    /// If reading a page or writing to `sink` fails. What was written before the failure stays
+    /// in `sink`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn write_core_dump(
+        &self,
+        sink: &dyn DataSource,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<usize, AsError> {
+        let mut written = 0;
+        let mut write = |bytes: &[u8]| {
+            sink.write(written, bytes.len(), bytes)
+                .map_err(AsError::Source)?;
+            written += bytes.len();
+            Ok(())
+        };
+        let number = |n: usize| (n as u64).to_le_bytes();
+
+        write(b"COREDUMP")?;
+        write(&number(self.page_size))?;
+        write(&number(self.mappings.len()))?;
+        for m in self.mappings.iter() {
+            let pages = || {
+                self.pages
+                    .range(PageEntry::key(m.addr)..PageEntry::key(m.end()))
+                    .filter(|page| page.is_resident())
+            };
+            let name = m.source().name().as_bytes();
+            for n in [
+                m.addr,
+                m.length,
+                m.offset,
+                m.flags.bits().into(),
+                pages().count(),
+                name.len(),
+            ] {
+                write(&number(n))?;
+            }
+            write(name)?;
+
+            for page in pages() {
+                write(&number(page.addr))?;
+                match page.data_frame() {
+                    Some(frame) => write(&frames.frame_data(frame)[..self.page_size])?,
+                    None => {
+                        let mut buffer = [0; 512];
+                        for start in (0..self.page_size).step_by(buffer.len()) {
+                            let chunk = (self.page_size - start).min(buffer.len());
+                            let chunk = &mut buffer[..chunk];
+                            Self::read_range(m, page.addr + start, chunk)?;
+                            write(chunk)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Check whether the page containing `addr` is resident; see `populate_page`.
+    #[must_use]
+    pub fn is_resident(&self, addr: VirtualAddress) -> bool {
+        self.pages
+            .get(&PageEntry::key(addr - addr % self.page_size))
+            .is_some_and(PageEntry::is_resident)
+    }
+
+    /// Get the number of resident pages of a mapping; see `populate_page`.
+    ///
+    /// # Errors
+    /// If the handle is stale.
+    pub fn resident_pages(&self, handle: MappingHandle) -> Result<usize, AsError> {
+        let m = self.entry_for(handle)?;
+        Ok(self
+            .pages
+            .range(PageEntry::key(m.addr)..PageEntry::key(m.end()))
+            .filter(|page| page.is_resident())
+            .count())
+    }
+
+    /// Create an iterator over the first address of every resident page in `[start, end)` that
+    /// has been accessed since its accessed bit was last cleared, in address order.
+    ///
+    /// Page replacement algorithms use this, with `clear_accessed_pages`, to find the pages that
+    /// are in use.
+    pub fn harvest_accessed(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> impl Iterator<Item = VirtualAddress> + '_ {
+        self.pages_in(start, end)
+            .filter(|page| page.accessed)
+            .map(|page| page.addr)
+    }
+
+    /// Create an iterator over the first address of every resident page in `[start, end)` that
+    /// has been written to since its dirty bit was last cleared, in address order.
+    ///
+    /// Writeback uses this, with `clear_dirty_pages`, to find the pages to write back.
+    pub fn harvest_dirty(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> impl Iterator<Item = VirtualAddress> + '_ {
+        self.pages_in(start, end)
+            .filter(|page| page.dirty)
+            .map(|page| page.addr)
+    }
+
+    /// Clear the accessed bits of the resident pages in `[start, end)`, returning how many were
+    /// set.
+    pub fn clear_accessed_pages(&mut self, start: VirtualAddress, end: VirtualAddress) -> usize {
+        self.clear_page_bits(start, end, |page| {
+            core::mem::replace(&mut page.accessed, false)
+        })
+    }
+
+    /// Clear the dirty bits of the resident pages in `[start, end)`, returning how many were set.
+    ///
+    /// Call this after writing the pages back to their `DataSource`.
+    pub fn clear_dirty_pages(&mut self, start: VirtualAddress, end: VirtualAddress) -> usize {
+        self.clear_page_bits(start, end, |page| {
+            core::mem::replace(&mut page.dirty, false)
+        })
+    }
+
+    /// Create an iterator over the resident pages starting in `[start, end)`.
+    fn pages_in(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> impl Iterator<Item = &PageEntry> + '_ {
+        self.pages
+            .range(PageEntry::key(start)..PageEntry::key(end.max(start)))
+            .filter(|page| page.is_resident())
+    }
+
+    /// Apply `clear` to the resident pages in `[start, end)`, returning how many it returned
+    /// `true` for.
+    fn clear_page_bits(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        clear: impl Fn(&mut PageEntry) -> bool,
+    ) -> usize {
+        let mut count = 0;
+        let mut next = self.pages_in(start, end).next().copied();
+        while let Some(mut page) = next {
+            next = self.pages_in(page.addr + 1, end).next().copied();
+            if clear(&mut page) {
+                self.pages.replace(page);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Take a snapshot of how much memory this `AddressSpace` uses: its virtual size, and how
+    /// many of its pages are resident, swapped out, shared, and private.
+    ///
+    /// The counts always reflect the faults, copies, and evictions so far, since they're worked
+    /// out from the pages themselves, in time linear in the number of mappings and pages.
+    #[must_use]
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            virtual_size: self.mappings.iter().map(|m| m.length).sum(),
+            ..MemoryUsage::default()
+        };
+        for page in self.pages.iter() {
+            if !page.is_resident() {
+                usage.swapped += 1;
+                continue;
+            }
+            usage.resident += 1;
+            let private = page.copy.is_some()
+                || self.mapping_containing(page.addr).is_some_and(|m| {
+                    let flags = m.flags.into_builder();
+                    flags.private || flags.cow
+                });
+            if private {
+                usage.private += 1;
+            } else {
+                usage.shared += 1;
+            }
+        }
+        usage
+    }
+
+    /// Get the number of pages swapped out of this `AddressSpace`.
+    #[must_use]
+    pub fn swapped_pages(&self) -> usize {
+        self.pages.iter().filter(|page| !page.is_resident()).count()
+    }
+
+    /// Swap out the private copy of the page containing `addr`: write it to a new slot in
+    /// `swap`, free its frame to `frames`, and record the slot in its place, returning it.
+    ///
+    /// The kernel must already have unmapped the page, and made sure no TLB still maps it.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, the page isn't a resident private copy, there are no free swap
+    /// slots, or writing to swap fails, in which case the page stays resident.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn swap_out<D: DataSource, const SLOTS: usize, const SWAP_PAGE_SIZE: usize>(
+        &mut self,
+        addr: VirtualAddress,
+        swap: &mut SwapBackend<D, SLOTS, SWAP_PAGE_SIZE>,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<SwapSlot, AsError> {
+        if !self.is_mapped(addr) {
+            return Err(AsError::NotMapped);
+        }
+        let page = addr - addr % self.page_size;
+        let entry = self.pages.get(&PageEntry::key(page));
+        let Some(frame) = entry.and_then(|entry| entry.copy) else {
+            return Err(AsError::NotSwappable);
+        };
+
+        let slot = swap.alloc_slot().ok_or(AsError::NoSwapSpace)?;
+        if let Err(e) = swap.write_slot(slot, &frames.frame_data(frame)[..self.page_size]) {
+            swap.free_slot(slot);
+            return Err(AsError::Source(e));
+        }
+        frames.free_frame(frame);
+        self.set_page(PageEntry {
+            swap: Some(slot),
+            ..PageEntry::key(page)
+        });
+
+        Ok(slot)
+    }
+
+    /// Swap the page containing `addr` back in: read it from its slot in `swap` into a new frame
+    /// from `frames`, and free the slot, returning the frame for the kernel to map.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, the page isn't swapped out, there are no free frames, or reading
+    /// from swap fails, in which case the page stays swapped out.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn swap_in<D: DataSource, const SLOTS: usize, const SWAP_PAGE_SIZE: usize>(
+        &mut self,
+        addr: VirtualAddress,
+        swap: &mut SwapBackend<D, SLOTS, SWAP_PAGE_SIZE>,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<PhysFrame, AsError> {
+        if !self.is_mapped(addr) {
+            return Err(AsError::NotMapped);
+        }
+        let page = addr - addr % self.page_size;
+        let entry = self.pages.get(&PageEntry::key(page));
+        let Some(slot) = entry.and_then(|entry| entry.swap) else {
+            return Err(AsError::NotSwappable);
+        };
+        self.check_resident_limit(page)?;
+
+        let frame = frames.alloc_frame().ok_or(AsError::NoFrames)?;
+        if let Err(e) = swap.read_slot(slot, &mut frames.frame_data(frame)[..self.page_size]) {
+            frames.free_frame(frame);
+            return Err(AsError::Source(e));
+        }
+        swap.free_slot(slot);
+        self.set_page(PageEntry {
+            copy: Some(frame),
+            ..PageEntry::key(page)
+        });
+
+        Ok(frame)
+    }
+
+    /// Check that there's room to release the frames, private copies, and swap slots of the pages
+    /// in `[start, end)` until the kernel frees them with `free_released_frames` and
+    /// `free_released_slots`.
+    fn check_release_capacity(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+    ) -> Result<(), AsError> {
+        #[cfg(not(feature = "alloc"))]
+        {
+            let pages = self
+                .pages
+                .range(PageEntry::key(start)..PageEntry::key(end.max(start)));
+            let (frames, slots) = pages.fold((0, 0), |(frames, slots), page| {
+                let released_frames = usize::from(page.frame.is_some() && !page.pinned)
+                    + usize::from(page.copy.is_some());
+                (
+                    frames + released_frames,
+                    slots + usize::from(page.swap.is_some()),
+                )
+            });
+            if self.released.len() + frames > N_RESIDENT
+                || self.released_slots.len() + slots > N_RESIDENT
+            {
+                return Err(AsError::OutOfCapacity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the state of the pages in `[start, end)`, which are no longer mapped, releasing
+    /// their frames, private copies, and swap slots.
+    fn forget_pages(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        let (released, released_slots) = (&mut self.released, &mut self.released_slots);
+        let unsynced = &mut self.unsynced;
+        self.pages.retain(|page| {
+            let unmapped = (start..end).contains(&page.addr);
+            if unmapped {
+                unsynced.insert(page.addr);
+                let frame = page.frame.filter(|_| !page.pinned);
+                for frame in [frame, page.copy].into_iter().flatten() {
+                    released.insert(frame);
+                }
+                if let Some(slot) = page.swap {
+                    released_slots.insert(slot);
+                }
+            }
+            !unmapped
+        });
+    }
+
+    /// Resolve a write fault on the copy-on-write page containing `addr`: copy the page from
+    /// the mapping's `DataSource` into a new frame from `frames`, and map the page to that frame
+    /// from now on, returning it so the kernel can point the page table entry at it.
+    ///
+    /// If the page has already been copied, its copy is returned.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, its mapping isn't writable and copy-on-write, there are no free
+    /// frames, reading the page fails, in which case the new frame is freed, or there's no room
+    /// to release the frame the copy replaces until `free_released_frames` is called.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn resolve_cow(
+        &mut self,
+        addr: VirtualAddress,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<PhysFrame, AsError> {
+        let m = self.mapping_containing(addr).ok_or(AsError::NotMapped)?;
+        if !m.flags.needs_copy(AccessType::Write) {
+            return Err(AsError::PermissionDenied);
+        }
+        let page = addr - addr % self.page_size;
+        if let Some(frame) = self
+            .pages
+            .get(&PageEntry::key(page))
+            .and_then(|entry| entry.copy)
+        {
+            return Ok(frame);
+        }
+        self.check_resident_limit(page)?;
+        self.check_release_capacity(page, page + self.page_size)?;
+
+        let frame = frames.alloc_frame().ok_or(AsError::NoFrames)?;
+        if let Err(e) = self.read_page(m, page, frames.frame_data(frame)) {
+            frames.free_frame(frame);
+            return Err(e);
+        }
+        // The page's frame of source data is replaced by the copy.
+        let entry = self.set_page(PageEntry {
+            copy: Some(frame),
+            ..PageEntry::key(page)
+        });
+        if let Some(old) = entry
+            .filter(|entry| !entry.pinned)
+            .and_then(|entry| entry.frame)
+        {
+            self.released.insert(old);
+        }
+
+        Ok(frame)
+    }
+
+    /// Resolve a page fault on an `access` to `addr` as far as the `AddressSpace` can, getting
+    /// frames from `frames`, so the kernel only has to map the frame it resolves to.
+    ///
+    /// This is `handle_fault`, except that a `Map` is populated into a new frame, and a `Copy` is
+    /// copied with `resolve_cow`, both resolving to `MapFrame` instead. Other resolutions are
+    /// returned as they are; in particular, after a `Grew`, calling this again populates the
+    /// page.
+    ///
+    /// # Errors
+    /// If the mapping's `FaultHandler` fails, there are no free frames, or reading the page
+    /// fails, in which case the page stays unpopulated.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn fault_in(
+        &mut self,
+        addr: VirtualAddress,
+        access: AccessType,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<FaultResolution, AsError> {
+        match self.begin_fault_in(addr, access, frames)? {
+            FaultStep::Done(resolution) => Ok(resolution),
+            FaultStep::Populate(pending) => {
+                let frame = pending.populate(frames)?;
+                Ok(self
+                    .finish_fault_in(&pending, frame)
+                    .expect("nothing changed while populating the page"))
+            }
+        }
+    }
+
+    /// Populate the page at `page` into a new frame from `frames`, recording it as the page's
+    /// frame.
+    fn populate_frame(
+        &mut self,
+        page: VirtualAddress,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<PhysFrame, AsError> {
+        self.check_resident_limit(page)?;
+        let frame = self
+            .pending_page(page)
+            .expect("page is mapped")
+            .populate(frames)?;
+        self.record_frame(page, frame);
+        Ok(frame)
+    }
+
+    /// Record `frame` as the frame the page at `page` was populated into.
+    fn record_frame(&mut self, page: VirtualAddress, frame: PhysFrame) {
+        let entry = self.pages.get(&PageEntry::key(page)).copied();
+        self.set_page(PageEntry {
+            frame: Some(frame),
+            ..entry.unwrap_or(PageEntry::key(page))
+        });
+    }
+
+    /// Capture what's needed to populate the page at `page` into a frame, if it's mapped.
+    pub(crate) fn pending_page(&self, page: VirtualAddress) -> Option<PendingPage<'a>> {
+        let m = self.mapping_containing(page)?;
+        Some(PendingPage {
+            page,
+            mapping: MappingHandle {
+                addr: m.addr,
+                generation: m.generation,
+            },
+            source: m.source.clone().expect("only lookup keys have no source"),
+            offset: m.offset + (page - m.addr),
+            backed: (m.addr + m.requested_length)
+                .saturating_sub(page)
+                .min(self.page_size),
+            page_size: self.page_size,
+        })
+    }
+
+    /// Resolve a page fault like `fault_in`, except that a page to populate into a frame is
+    /// returned instead, so it can be read without holding the `AddressSpace`, then recorded with
+    /// `finish_fault_in`.
+    pub(crate) fn begin_fault_in(
+        &mut self,
+        addr: VirtualAddress,
+        access: AccessType,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<FaultStep<'a>, AsError> {
+        if let Some(kernel) = self.kernel_region_for(addr) {
+            return kernel.fault_in(addr, access, frames).map(FaultStep::Done);
+        }
+        let resolution = match self.handle_fault(addr, access)? {
+            FaultResolution::Map { page, .. } => {
+                self.check_resident_limit(page)?;
+                return Ok(FaultStep::Populate(
+                    self.pending_page(page).expect("page is mapped"),
+                ));
+            }
+            FaultResolution::Copy { page, flags, .. } => FaultResolution::MapFrame {
+                page,
+                frame: self.resolve_cow(page, frames)?,
+                flags,
+            },
+            resolution => resolution,
+        };
+
+        Ok(FaultStep::Done(resolution))
+    }
+
+    /// Record `frame`, which `pending` was populated into, as its page's frame, returning the
+    /// resolution of the fault, unless the page's mapping was changed or the page populated
+    /// since `begin_fault_in`, in which case nothing is recorded.
+    pub(crate) fn finish_fault_in(
+        &mut self,
+        pending: &PendingPage,
+        frame: PhysFrame,
+    ) -> Option<FaultResolution> {
+        let m = self.entry_for(pending.mapping).ok()?;
+        let flags = m.flags;
+        let unchanged =
+            m.end() > pending.page && m.offset + (pending.page - m.addr) == pending.offset;
+        let populated = self
+            .pages
+            .get(&PageEntry::key(pending.page))
+            .is_some_and(|entry| {
+                entry.frame.is_some() || entry.copy.is_some() || !entry.is_resident()
+            });
+        if !unchanged || populated {
+            return None;
+        }
+        self.record_frame(pending.page, frame);
+        Some(FaultResolution::MapFrame {
+            page: pending.page,
+            frame,
+            flags,
+        })
+    }
+
+    /// Free the frames and private copies of pages that have been unmapped since this was last
+    /// called, or replaced by a private copy, returning how many were freed.
+    ///
+    /// Unmapping can't free them itself, since the kernel must first make sure no TLB still
+    /// maps them. Without the `alloc` feature, unmapping fails with `AsError::OutOfCapacity` while
+    /// `N_RESIDENT` frames are waiting to be freed.
+    pub fn free_released_frames(&mut self, frames: &mut impl FrameAllocator) -> usize {
+        let mut count = 0;
+        while let Some(frame) = self.released.pop_first() {
+            frames.free_frame(frame);
+            count += 1;
+        }
+        count
+    }
+
+    /// Free the swap slots of pages that have been unmapped since this was last called,
+    /// returning how many were freed.
+    ///
+    /// Without the `alloc` feature, unmapping fails with `AsError::OutOfCapacity` while
+    /// `N_RESIDENT` slots are waiting to be freed.
+    pub fn free_released_slots<D: DataSource, const SLOTS: usize, const SWAP_PAGE_SIZE: usize>(
+        &mut self,
+        swap: &mut SwapBackend<D, SLOTS, SWAP_PAGE_SIZE>,
+    ) -> usize {
+        let mut count = 0;
+        while let Some(slot) = self.released_slots.pop_first() {
+            swap.free_slot(slot);
+            count += 1;
+        }
+        count
+    }
+
+    /// Work out how to resolve a page fault on an `access` to `addr`.
+    ///
+    /// The mapping containing `addr` is marked accessed, and dirty for a write, as with
+    /// `mark_accessed`, and the page is to be mapped with those bits set. Faults that shouldn't be
+    /// resolved, because `addr` isn't mapped or the mapping doesn't permit the access, resolve to
+    /// `FaultResolution::Deliver`.
+    ///
+    /// Each fault is counted in the `fault_stats`; a `Map` of a page that isn't resident counts as
+    /// a major fault, since it has to be read from its `DataSource`.
+    ///
+    /// Permitted faults in a mapping with a `FaultHandler` are forwarded to it instead, after the
+    /// mapping is marked accessed. A permitted fault below a mapping that grows down, within its
+    /// limit, grows the mapping to cover the faulting page, if there's room, and resolves to
+    /// `FaultResolution::Grew`, counted as a major fault. Faults in a shared kernel region are
+    /// resolved by the region.
+    ///
+    /// # Errors
+    /// If the mapping's `FaultHandler` fails.
+    pub fn handle_fault(
+        &mut self,
+        addr: VirtualAddress,
+        access: AccessType,
+    ) -> Result<FaultResolution, AsError> {
+        if let Some(kernel) = self.kernel_region_for(addr) {
+            return kernel.handle_fault(addr, access);
+        }
+        if !self.is_mapped(addr) && self.grow_down(addr, access) {
+            self.mark_accessed(addr, access == AccessType::Write)?;
+            let m = self
+                .mapping_containing(addr)
+                .expect("the mapping grew to addr");
+            let resolution = FaultResolution::Grew {
+                page: m.addr,
+                offset: m.offset,
+                flags: m.flags,
+            };
+            self.fault_stats.major += 1;
+            return Ok(resolution);
+        }
+        let Some(m) = self.mapping_containing(addr) else {
+            return Ok(FaultResolution::Deliver(AsError::NotMapped));
+        };
+        if !m.flags.permits(access) {
+            self.fault_stats.permission += 1;
+            return Ok(FaultResolution::Deliver(AsError::PermissionDenied));
+        }
+        self.mark_accessed(addr, access == AccessType::Write)?;
+        let m = self.mapping_containing(addr).expect("addr is mapped");
+        if let Some(slot) = m.fault_handler {
+            let handler = self.fault_handlers[usize::from(slot)].expect("slot is in use");
+            let resolution = handler.handle_fault(addr, access, m.info())?;
+            self.fault_stats.delegated += 1;
+            return Ok(resolution);
+        }
+
+        let page = addr - addr % self.page_size;
+        let offset = m.offset + (page - m.addr);
+        let entry = self.pages.get(&PageEntry::key(page));
+        let copied_flags = || {
+            m.flags
+                .into_builder()
+                .set_cow(false)
+                .try_validate()
+                .expect("clearing cow keeps flags valid")
+        };
+        let resolution = if let Some(frame) = entry.and_then(|entry| entry.copy) {
+            FaultResolution::MapFrame {
+                page,
+                frame,
+                flags: copied_flags(),
+            }
+        } else if let Some(slot) = entry.and_then(|entry| entry.swap) {
+            FaultResolution::SwapIn {
+                page,
+                slot,
+                flags: copied_flags(),
+            }
+        } else if m.flags.needs_copy(access) {
+            FaultResolution::Copy {
+                page,
+                offset,
+                flags: copied_flags(),
+            }
+        } else if let Some(frame) = entry.and_then(|entry| entry.frame) {
+            FaultResolution::MapFrame {
+                page,
+                frame,
+                flags: m.flags,
+            }
+        } else {
+            FaultResolution::Map {
+                page,
+                offset,
+                flags: m.flags,
+            }
+        };
+
+        let stats = &mut self.fault_stats;
+        match resolution {
+            FaultResolution::MapFrame { .. } => stats.minor += 1,
+            FaultResolution::SwapIn { .. } => stats.major += 1,
+            FaultResolution::Copy { .. } => stats.cow += 1,
+            FaultResolution::Map { .. } if entry.is_some() => stats.minor += 1,
+            _ => stats.major += 1,
+        }
+
+        Ok(resolution)
+    }
+
+    /// Get the counts of the page faults handled by `handle_fault` since the `AddressSpace` was
+    /// created, or since `reset_fault_stats`.
+    #[must_use]
+    pub const fn fault_stats(&self) -> FaultStats {
+        self.fault_stats
+    }
+
+    /// Reset the counts of page faults to zero, returning what they were.
+    pub fn reset_fault_stats(&mut self) -> FaultStats {
+        core::mem::take(&mut self.fault_stats)
+    }
+
+    /// Remove every mapping in `[start, start + length)`.
+    ///
+    /// Mappings entirely inside the range are removed, and mappings partially covered by the range
+    /// are trimmed, or split in two if the range is in their middle. `length` is rounded up to a
+    /// multiple of `PAGE_SIZE`. It is not an error for the range to contain no mappings.
+    ///
+    /// # Errors
+    /// If `start` is not `PAGE_SIZE`-aligned, if the range overflows, if it contains a sealed
+    /// mapping, if a mapping would need to be split but there is no room for another mapping, or
+    /// if there's no room to release the frames or swap slots of the pages in the range until
+    /// `free_released_frames` or `free_released_slots` is called. In any of these cases the
+    /// `AddressSpace` is left unchanged.
+    pub fn unmap_range(&mut self, start: VirtualAddress, length: usize) -> Result<(), AsError> {
+        if !start.is_multiple_of(self.page_size) {
+            return Err(AsError::Unaligned);
+        }
+        let end = length
+            .checked_next_multiple_of(self.page_size)
+            .and_then(|l| start.checked_add(l))
+            .ok_or(AsError::Overflow)?;
+
+        // Check everything that could fail before modifying anything.
+        if self.mappings_in_range(start, end).any(|m| m.sealed) {
+            return Err(AsError::Sealed);
+        }
+        #[cfg(not(feature = "alloc"))]
+        {
+            let needs_split = self
+                .mapping_containing(start)
+                .is_some_and(|m| m.addr < start && end < m.end());
+            if needs_split && self.mappings.len() >= N_PAGES {
+                return Err(AsError::OutOfCapacity);
+            }
+        }
+        self.check_release_capacity(start, end)?;
+
+        // Each iteration removes one mapping intersecting the range, putting back only the parts
+        // outside of it, so this terminates.
+        loop {
+            let Some(addr) = self.mappings_in_range(start, end).next().map(|m| m.addr) else {
+                break;
+            };
+            let m = self.take_entry(addr).expect("mapping was just found");
+            self.flush_tlb(m.addr.max(start), m.end().min(end));
+
+            if m.addr < start {
+                self.insert_entry(MapEntry {
+                    length: start - m.addr,
+                    requested_length: m.requested_length.min(start - m.addr),
+                    ..m.clone()
+                });
+            }
+
+            if end < m.end() {
+                let generation = self.new_generation();
+                self.insert_entry(MapEntry {
+                    addr: end,
+                    generation,
+                    length: m.end() - end,
+                    requested_length: m.addr + m.requested_length - end,
+                    offset: m.offset + (end - m.addr),
+                    ..m
+                });
+            }
+        }
+        self.forget_pages(start, end);
+
+        Ok(())
+    }
+
+    /// Attach caller-defined metadata to the mapping that starts at the given address, replacing
+    /// any metadata previously attached to it.
+    ///
+    /// This lets kernels associate their own bookkeeping (VMA operations, file structures, etc.)
+    /// with a mapping without maintaining a parallel structure keyed by address.
+    ///
+    /// # Errors
+    /// If there is no mapping at that address.
+    pub fn set_metadata(
+        &mut self,
+        start: VirtualAddress,
+        metadata: &'a dyn Any,
+    ) -> Result<(), AsError> {
+        let mut entry = self
+            .mappings
+            .get(&MapEntry::key(start))
+            .ok_or(AsError::NotMapped)?
+            .clone();
+        entry.metadata = Some(metadata);
+        self.mappings.replace(entry);
+
+        Ok(())
+    }
+
+    /// Forward faults in the mapping that starts at `start` to `handler`, or resolve them from its
+    /// `DataSource` again if it's `None`; see `FaultHandler`.
+    ///
+    /// # Errors
+    /// If there is no mapping at that address, it's sealed, or other mappings already forward
+    /// faults to `MAX_FAULT_HANDLERS` other handlers.
+    pub fn set_fault_handler(
+        &mut self,
+        start: VirtualAddress,
+        handler: Option<&'a dyn FaultHandler>,
+    ) -> Result<(), AsError> {
+        let mut entry = self
+            .mappings
+            .get(&MapEntry::key(start))
+            .ok_or(AsError::NotMapped)?
+            .clone();
+        if entry.sealed {
+            return Err(AsError::Sealed);
+        }
+        entry.fault_handler = match handler {
+            Some(handler) => Some(self.fault_handler_slot(start, handler)?),
+            None => None,
+        };
+        self.mappings.replace(entry);
+
+        Ok(())
+    }
+
+    /// Find the slot of `fault_handlers` holding `handler`, putting it in one that's empty or only
+    /// used by the mapping at `start` if it isn't there yet.
+    fn fault_handler_slot(
+        &mut self,
+        start: VirtualAddress,
+        handler: &'a dyn FaultHandler,
+    ) -> Result<u8, AsError> {
+        let held = |slot: usize| {
+            self.fault_handlers[slot].is_some_and(|held| core::ptr::eq(held, handler))
+        };
+        let unused = |slot: usize| {
+            self.mappings
+                .iter()
+                .all(|m| m.addr == start || m.fault_handler.map(usize::from) != Some(slot))
+        };
+        let slot = (0..MAX_FAULT_HANDLERS)
+            .find(|&slot| held(slot))
+            .or_else(|| (0..MAX_FAULT_HANDLERS).find(|&slot| unused(slot)))
+            .ok_or(AsError::OutOfCapacity)?;
+        self.fault_handlers[slot] = Some(handler);
+
+        Ok(u8::try_from(slot).expect("MAX_FAULT_HANDLERS fits in a u8"))
+    }
+
+    /// Check whether faults in the mapping that starts at the given address are forwarded to a
+    /// `FaultHandler`.
+    #[must_use]
+    pub fn has_fault_handler(&self, start: VirtualAddress) -> bool {
+        self.mappings
+            .get(&MapEntry::key(start))
+            .is_some_and(|m| m.fault_handler.is_some())
+    }
+
+    /// Get the metadata attached to the mapping that starts at the given address, if any.
+    ///
+    /// Use `downcast_ref` to recover the concrete type.
+    #[must_use]
+    pub fn metadata(&self, start: VirtualAddress) -> Option<&'a dyn Any> {
+        self.mappings
+            .get(&MapEntry::key(start))
+            .and_then(|m| m.metadata)
+    }
+}
+
+// Visibility boundary to ensure private internals, so our validation scheme works properly.
+mod flags {
+    /// Build flags for address space maps.
+    ///
+    /// You should prefer the `flags` macro for constant-time creation of flags; this type is only used
+    /// for dynamic creation.
+    ///
+    /// We recommend using this builder type as follows:
+    /// ```
+    /// # use reedos_address_space::Flags;
+    /// let flags = Flags::build()
+    ///     .toggle_read()
+    ///     .toggle_write()
+    ///     .try_validate()
+    ///     .expect("read-write flags are valid");
+    /// ```
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
+                                             // here because these directly correspond to yes/no
+                                             // hardware flags
+    pub struct FlagBuilder {
+        pub read: bool,
+        pub write: bool,
+        pub execute: bool,
+        /// Whether user mode may access the mapping, rather than only the kernel.
+        pub user: bool,
+        /// Whether the mapping is the same in every address space, so its TLB entries needn't be
+        /// flushed on a switch.
+        pub global: bool,
+        /// Whether the mapping is device memory, like MMIO registers, which must not be cached.
+        pub no_cache: bool,
+        pub cow: bool,
+        pub private: bool,
+        pub shared: bool,
+        /// Whether the mapping has been read or written since this was last cleared.
+        pub accessed: bool,
+        /// Whether the mapping has been written since this was last cleared.
+        pub dirty: bool,
+    }
+
+    /// Why a `FlagBuilder` doesn't represent valid flags.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FlagError {
+        /// A mapping can't be both private and shared.
+        PrivateAndShared,
+        /// Only user mappings can be copy-on-write.
+        KernelCow,
+        /// Uncached device memory can't be copy-on-write.
+        UncachedCow,
+        /// Raw flag bits have bits set that don't correspond to any flag.
+        UnknownBits,
+        /// A string isn't in the format of `Flags`' `Display` implementation.
+        Syntax,
+    }
+
+    impl core::fmt::Display for FlagError {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.write_str(match self {
+                Self::PrivateAndShared => "mapping is both private and shared",
+                Self::KernelCow => "kernel mapping is copy-on-write",
+                Self::UncachedCow => "uncached mapping is copy-on-write",
+                Self::UnknownBits => "unknown flag bits",
+                Self::Syntax => "malformed flags string",
+            })
+        }
+    }
+
+    /// Create a toggler for a `FlagBuilder` field.
+    macro_rules! flag_toggle {
+        (
+        $flag:ident,
+        $toggle:ident,
+        $setter:ident
+    ) => {
+            #[doc=concat!("Toggle the ", stringify!($flag), " flag.")]
+            #[must_use]
+            pub const fn $toggle(self) -> Self {
+                Self {
+                    $flag: !self.$flag,
+                    ..self
+                }
+            }
+
+            #[doc=concat!("Set the ", stringify!($flag), " flag.")]
+            #[must_use]
+            pub const fn $setter(self, to: bool) -> Self {
+                Self { $flag: to, ..self }
+            }
+        };
+    }
+
+    impl FlagBuilder {
+        /// Create a new `FlagBuilder` with all flags toggled off.
+        #[must_use]
+        pub const fn new() -> Self {
+            Self {
+                read: false,
+                write: false,
+                execute: false,
+                user: false,
+                global: false,
+                no_cache: false,
+                cow: false,
+                private: false,
+                shared: false,
+                accessed: false,
+                dirty: false,
+            }
+        }
+
+        /// Validate that the `FlagBuilder` represents valid flags.
+        ///
+        /// # Panics
+        /// If the `FlagBuilder` represents invalid flags; see `try_validate`.
+        #[must_use]
+        #[deprecated(
+            since = "0.1.0",
+            note = "use `try_validate`, which returns an error instead of panicking"
+        )]
+        pub fn validate(self) -> Flags {
+            match self.try_validate() {
+                Ok(flags) => flags,
+                Err(e) => panic!("invalid flags: {e}"),
+            }
+        }
+
+        /// Validate that the `FlagBuilder` represents valid flags.
+        ///
+        /// # Errors
+        /// If the flags are both private and shared, if they're copy-on-write but not
+        /// user-accessible, since only user mappings are ever copied on write, or if they're both
+        /// copy-on-write and uncached, since device memory can't be copied.
+        pub const fn try_validate(self) -> Result<Flags, FlagError> {
+            if self.private && self.shared {
+                return Err(FlagError::PrivateAndShared);
+            }
+            if self.cow && !self.user {
+                return Err(FlagError::KernelCow);
+            }
+            if self.cow && self.no_cache {
+                return Err(FlagError::UncachedCow);
+            }
+
+            Ok(Flags::pack(self))
+        }
+
+        flag_toggle!(read, toggle_read, set_read);
+        flag_toggle!(write, toggle_write, set_write);
+        flag_toggle!(execute, toggle_execute, set_execute);
+        flag_toggle!(user, toggle_user, set_user);
+        flag_toggle!(global, toggle_global, set_global);
+        flag_toggle!(no_cache, toggle_no_cache, set_no_cache);
+        flag_toggle!(cow, toggle_cow, set_cow);
+        flag_toggle!(private, toggle_private, set_private);
+        flag_toggle!(shared, toggle_shared, set_shared);
+        flag_toggle!(accessed, toggle_accessed, set_accessed);
+        flag_toggle!(dirty, toggle_dirty, set_dirty);
+
+        /// Combine two `FlagBuilder`s by boolean or-ing each of their flags.
+        ///
+        /// This is, somewhat counter-intuitively, named `and`, so that the following code reads
+        /// correctly:
+        ///
+        /// ```
+        /// # use reedos_address_space::Flags;
+        /// let read = Flags::read();
+        /// let execute = Flags::execute();
+        /// let new = read.and(execute);
+        /// assert_eq!(new, Flags::build().toggle_read().toggle_execute());
+        /// ```
+        #[must_use]
+        pub const fn and(self, other: Self) -> Self {
+            let read = self.read || other.read;
+            let write = self.write || other.write;
+            let execute = self.execute || other.execute;
+            let user = self.user || other.user;
+            let global = self.global || other.global;
+            let no_cache = self.no_cache || other.no_cache;
+            let cow = self.cow || other.cow;
+            let private = self.private || other.private;
+            let shared = self.shared || other.shared;
+            let accessed = self.accessed || other.accessed;
+            let dirty = self.dirty || other.dirty;
+
+            Self {
+                read,
+                write,
+                execute,
+                user,
+                global,
+                no_cache,
+                cow,
+                private,
+                shared,
+                accessed,
+                dirty,
+            }
+        }
+
+        /// Turn off all flags in self that are on in other.
+        ///
+        /// You can think of this as `self &! other` on each field.
+        ///
+        /// ```
+        /// # use reedos_address_space::Flags;
+        /// let read_execute = Flags::read().toggle_execute();
+        /// let execute = Flags::execute();
+        /// let new = read_execute.but_not(execute);
+        /// assert_eq!(new, Flags::build().toggle_read());
+        /// ```
+        #[must_use]
+        pub const fn but_not(self, other: Self) -> Self {
+            let read = self.read && !other.read;
+            let write = self.write && !other.write;
+            let execute = self.execute && !other.execute;
+            let user = self.user && !other.user;
+            let global = self.global && !other.global;
+            let no_cache = self.no_cache && !other.no_cache;
+            let cow = self.cow && !other.cow;
+            let private = self.private && !other.private;
+            let shared = self.shared && !other.shared;
+            let accessed = self.accessed && !other.accessed;
+            let dirty = self.dirty && !other.dirty;
+
+            Self {
+                read,
+                write,
+                execute,
+                user,
+                global,
+                no_cache,
+                cow,
+                private,
+                shared,
+                accessed,
+                dirty,
+            }
+        }
+    }
+
+    /// Operators for composing flags, so you can write, for example:
+    ///
+    /// ```
+    /// # use reedos_address_space::{flags, Flags};
+    /// let rw = Flags::read() | Flags::write();
+    /// assert_eq!(rw - Flags::write(), Flags::read());
+    /// assert_eq!(rw & !Flags::read(), Flags::write());
+    /// assert_eq!((flags![read] | flags![write]).try_validate(), Ok(flags![read, write]));
+    /// ```
+    impl core::ops::BitOr for FlagBuilder {
+        type Output = Self;
+
+        /// Turn on every flag that's on in either operand; see `and`.
+        fn bitor(self, other: Self) -> Self {
+            self.and(other)
+        }
+    }
+
+    impl core::ops::BitAnd for FlagBuilder {
+        type Output = Self;
+
+        /// Turn on only the flags that are on in both operands.
+        fn bitand(self, other: Self) -> Self {
+            self.but_not(self.but_not(other))
+        }
+    }
+
+    impl core::ops::Sub for FlagBuilder {
+        type Output = Self;
+
+        /// Turn off every flag that's on in `other`; see `but_not`.
+        fn sub(self, other: Self) -> Self {
+            self.but_not(other)
+        }
+    }
+
+    impl core::ops::Not for FlagBuilder {
+        type Output = Self;
+
+        /// Flip every flag. The result is generally invalid until some flags are turned off.
+        fn not(self) -> Self {
+            Self {
+                read: !self.read,
+                write: !self.write,
+                execute: !self.execute,
+                user: !self.user,
+                global: !self.global,
+                no_cache: !self.no_cache,
+                cow: !self.cow,
+                private: !self.private,
+                shared: !self.shared,
+                accessed: !self.accessed,
+                dirty: !self.dirty,
+            }
+        }
+    }
+
+    impl core::ops::BitOr for Flags {
+        type Output = FlagBuilder;
+
+        /// Combine two sets of flags, which will need to be re-validated.
+        fn bitor(self, other: Self) -> FlagBuilder {
+            self.into_builder() | other.into_builder()
+        }
+    }
+
+    // The letters `Flags` are displayed with after the `/proc/maps`-style permissions.
+    const EXTRA_LETTERS: [(char, u16); 6] = [
+        ('u', USER),
+        ('g', GLOBAL),
+        ('n', NO_CACHE),
+        ('c', COW),
+        ('a', ACCESSED),
+        ('d', DIRTY),
+    ];
+
+    /// Display flags like the permissions in `/proc/<pid>/maps`, e.g. `rw-p` or `r-xs`, with `-`
+    /// in the last place for mappings that are neither private nor shared. Then come letters for
+    /// any other flags that are set, in order: `u`ser, `g`lobal, `n`o-cache, `c`ow, `a`ccessed, and
+    /// `d`irty.
+    ///
+    /// ```
+    /// # use reedos_address_space::{flags, Flags};
+    /// assert_eq!(flags![read, write, user, cow, private].to_string(), "rw-puc");
+    /// assert_eq!("r-x-g".parse(), Ok(flags![read, execute, global]));
+    /// ```
+    impl core::fmt::Display for Flags {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            use core::fmt::Write;
+
+            let letter = |bit, letter| if self.has(bit) { letter } else { '-' };
+            f.write_char(letter(READ, 'r'))?;
+            f.write_char(letter(WRITE, 'w'))?;
+            f.write_char(letter(EXECUTE, 'x'))?;
+            f.write_char(if self.has(SHARED) {
+                's'
+            } else {
+                letter(PRIVATE, 'p')
+            })?;
+
+            for (letter, bit) in EXTRA_LETTERS {
+                if self.has(bit) {
+                    f.write_char(letter)?;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl core::str::FromStr for Flags {
+        type Err = FlagError;
+
+        /// Parse flags in the format they're displayed in. The extra letters may come in any order.
+        fn from_str(s: &str) -> Result<Self, FlagError> {
+            let mut chars = s.chars();
+            let mut bits = 0;
+            for (letter, bit) in [('r', READ), ('w', WRITE), ('x', EXECUTE)] {
+                match chars.next() {
+                    Some(c) if c == letter => bits |= bit,
+                    Some('-') => {}
+                    _ => return Err(FlagError::Syntax),
+                }
+            }
+            match chars.next() {
+                Some('p') => bits |= PRIVATE,
+                Some('s') => bits |= SHARED,
+                Some('-') => {}
+                _ => return Err(FlagError::Syntax),
+            }
+
+            for c in chars {
+                let (_, bit) = EXTRA_LETTERS
+                    .into_iter()
+                    .find(|&(letter, _)| letter == c)
+                    .ok_or(FlagError::Syntax)?;
+                if bits & bit != 0 {
+                    return Err(FlagError::Syntax);
+                }
+                bits |= bit;
+            }
+
+            Self::from_bits(bits)
+        }
+    }
+
+    /// A kind of memory access, to check against a mapping's `Flags`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum AccessType {
+        /// Loading data.
+        Read,
+        /// Storing data.
+        Write,
+        /// Fetching instructions.
+        Execute,
+    }
+
+    /// Create a constructor for a `Flags` object.
+    macro_rules! flag_constructor {
+        (
+        $flag:ident
+    ) => {
+            #[doc=concat!("Turn on only the ", stringify!($flag), " flag.")]
+            #[must_use]
+            pub fn $flag() -> FlagBuilder {
+                FlagBuilder {
+                    $flag: true,
+                    ..FlagBuilder::default()
+                }
+            }
+        };
+    }
+
+    /// Access flags for virtual memory.
+    ///
+    /// Mappings are kernel-only unless the `user` flag is set.
+    ///
+    /// There are two ways to create a `Flags`:
+    ///
+    /// 1. The `FlagBuilder` type, in particular `Flags::build`, which has public fields and allows
+    ///    dynamic creation of flags.
+    /// 2. The `flags` macro.
+    ///
+    /// Flags are stored packed into the bits of a `u16`; see `Flags::bits`.
+    #[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct Flags {
+        bits: u16,
+    }
+
+    // The bit of `Flags::bits` for each flag. These must never change, since they may be stored
+    // outside of this crate.
+    const READ: u16 = 1 << 0;
+    const WRITE: u16 = 1 << 1;
+    const EXECUTE: u16 = 1 << 2;
+    const USER: u16 = 1 << 3;
+    const GLOBAL: u16 = 1 << 4;
+    const NO_CACHE: u16 = 1 << 5;
+    const COW: u16 = 1 << 6;
+    const PRIVATE: u16 = 1 << 7;
+    const SHARED: u16 = 1 << 8;
+    const ACCESSED: u16 = 1 << 9;
+    const DIRTY: u16 = 1 << 10;
+    const ALL: u16 = (1 << 11) - 1;
+
+    impl core::fmt::Debug for Flags {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            let FlagBuilder {
+                read,
+                write,
+                execute,
+                user,
+                global,
+                no_cache,
+                cow,
+                private,
+                shared,
+                accessed,
+                dirty,
+            } = self.into_builder();
+
+            f.debug_struct("Flags")
+                .field("read", &read)
+                .field("write", &write)
+                .field("execute", &execute)
+                .field("user", &user)
+                .field("global", &global)
+                .field("no_cache", &no_cache)
+                .field("cow", &cow)
+                .field("private", &private)
+                .field("shared", &shared)
+                .field("accessed", &accessed)
+                .field("dirty", &dirty)
+                .finish()
+        }
+    }
+
+    impl Flags {
+        #[must_use]
+        pub fn build() -> FlagBuilder {
+            FlagBuilder::new()
+        }
+
+        /// Convert a `Flags` into a `FlagBuilder`, whose fields can be modified; it will need to be
+        /// re-validated to get back a `Flags` object.
+        #[must_use]
+        pub const fn into_builder(self) -> FlagBuilder {
+            FlagBuilder {
+                read: self.has(READ),
+                write: self.has(WRITE),
+                execute: self.has(EXECUTE),
+                user: self.has(USER),
+                global: self.has(GLOBAL),
+                no_cache: self.has(NO_CACHE),
+                cow: self.has(COW),
+                private: self.has(PRIVATE),
+                shared: self.has(SHARED),
+                accessed: self.has(ACCESSED),
+                dirty: self.has(DIRTY),
+            }
+        }
+
+        /// Check whether a flag's bit is set.
+        const fn has(self, bit: u16) -> bool {
+            self.bits & bit != 0
+        }
+
+        /// Pack the flags of a `FlagBuilder`, without validating them.
+        const fn pack(flags: FlagBuilder) -> Self {
+            const fn bit(on: bool, bit: u16) -> u16 {
+                if on {
+                    bit
+                } else {
+                    0
+                }
+            }
+
+            Self {
+                bits: bit(flags.read, READ)
+                    | bit(flags.write, WRITE)
+                    | bit(flags.execute, EXECUTE)
+                    | bit(flags.user, USER)
+                    | bit(flags.global, GLOBAL)
+                    | bit(flags.no_cache, NO_CACHE)
+                    | bit(flags.cow, COW)
+                    | bit(flags.private, PRIVATE)
+                    | bit(flags.shared, SHARED)
+                    | bit(flags.accessed, ACCESSED)
+                    | bit(flags.dirty, DIRTY),
+            }
+        }
+
+        /// Check whether every flag in `other` is also in these flags.
+        #[must_use]
+        pub const fn contains(self, other: Self) -> bool {
+            self.bits & other.bits == other.bits
+        }
+
+        /// Check whether a mapping with these flags permits an access.
+        ///
+        /// Writes to a writable copy-on-write mapping are permitted, but need the page copied
+        /// first; see `needs_copy`.
+        #[must_use]
+        pub const fn permits(self, access: AccessType) -> bool {
+            match access {
+                AccessType::Read => self.has(READ),
+                AccessType::Write => self.has(WRITE),
+                AccessType::Execute => self.has(EXECUTE),
+            }
+        }
+
+        /// Check whether an access to a mapping with these flags must copy the page first, i.e.
+        /// whether it's a permitted write to a copy-on-write mapping.
+        #[must_use]
+        pub const fn needs_copy(self, access: AccessType) -> bool {
+            matches!(access, AccessType::Write) && self.has(WRITE) && self.has(COW)
+        }
+
+        /// Get the raw bits of these flags, for example to stash them in the software bits of a
+        /// page table entry, or to serialize them.
+        #[must_use]
+        pub const fn bits(self) -> u16 {
+            self.bits
+        }
+
+        /// Recover flags from their raw bits, as returned by `bits`.
+        ///
+        /// # Errors
+        /// If `bits` has bits set that don't correspond to any flag, or represents invalid flags;
+        /// see `FlagBuilder::try_validate`.
+        pub const fn from_bits(bits: u16) -> Result<Self, FlagError> {
+            if bits & !ALL != 0 {
+                return Err(FlagError::UnknownBits);
+            }
+            Self { bits }.into_builder().try_validate()
+        }
+
+        flag_constructor!(read);
+        flag_constructor!(write);
+        flag_constructor!(execute);
+        flag_constructor!(user);
+        flag_constructor!(global);
+        flag_constructor!(no_cache);
+        flag_constructor!(cow);
+        flag_constructor!(private);
+        flag_constructor!(shared);
+        flag_constructor!(accessed);
+        flag_constructor!(dirty);
+    }
+
+    // RISC-V Sv39/Sv48 PTE bits; see the privileged spec, section 4.3.1 and chapter 10 (Svpbmt).
+    const RISCV_PTE_R: u64 = 1 << 1;
+    const RISCV_PTE_W: u64 = 1 << 2;
+    const RISCV_PTE_X: u64 = 1 << 3;
+    const RISCV_PTE_U: u64 = 1 << 4;
+    const RISCV_PTE_G: u64 = 1 << 5;
+    const RISCV_PTE_A: u64 = 1 << 6;
+    const RISCV_PTE_D: u64 = 1 << 7;
+    const RISCV_PTE_PBMT_SHIFT: u64 = 61;
+    const RISCV_PTE_PBMT_MASK: u64 = 0b11 << RISCV_PTE_PBMT_SHIFT;
+    const RISCV_PTE_PBMT_NC: u64 = 1 << RISCV_PTE_PBMT_SHIFT;
+    const RISCV_PTE_PBMT_IO: u64 = 2 << RISCV_PTE_PBMT_SHIFT;
+
+    impl Flags {
+        /// Get the R/W/X/U/G/A/D bits of a RISC-V Sv39/Sv48 leaf PTE for a mapping with these
+        /// flags, plus the Svpbmt IO memory type for uncached mappings.
+        ///
+        /// The valid bit is left for the page table to set. Copy-on-write mappings aren't writable,
+        /// so that writes to them fault and can be copied.
+        #[must_use]
+        pub const fn to_riscv_pte_bits(self) -> u64 {
+            let flags = self.into_builder();
+            let mut bits = 0;
+            if flags.read {
+                bits |= RISCV_PTE_R;
+            }
+            if flags.write && !flags.cow {
+                bits |= RISCV_PTE_W;
+            }
+            if flags.execute {
+                bits |= RISCV_PTE_X;
+            }
+            if flags.user {
+                bits |= RISCV_PTE_U;
+            }
+            if flags.global {
+                bits |= RISCV_PTE_G;
+            }
+            if flags.accessed {
+                bits |= RISCV_PTE_A;
+            }
+            if flags.dirty {
+                bits |= RISCV_PTE_D;
+            }
+            if flags.no_cache {
+                bits |= RISCV_PTE_PBMT_IO;
+            }
+            bits
+        }
+
+        /// Recover the flags of a RISC-V Sv39/Sv48 leaf PTE, ignoring the valid bit, the physical
+        /// page number, and the software bits.
+        ///
+        /// Returns `None` for a non-leaf PTE, which has none of R/W/X set, or for a reserved
+        /// encoding: writable but not readable, or the reserved Svpbmt memory type. Either Svpbmt
+        /// non-default memory type counts as uncached.
+        #[must_use]
+        pub const fn from_riscv_pte_bits(bits: u64) -> Option<Self> {
+            let read = bits & RISCV_PTE_R != 0;
+            let write = bits & RISCV_PTE_W != 0;
+            let execute = bits & RISCV_PTE_X != 0;
+            let pbmt = bits & RISCV_PTE_PBMT_MASK;
+            let leaf = read || write || execute;
+            let readable_if_writable = !write || read;
+            if !(leaf && readable_if_writable) || pbmt == RISCV_PTE_PBMT_MASK {
+                return None;
+            }
+
+            Some(Self::pack(FlagBuilder {
+                read,
+                write,
+                execute,
+                user: bits & RISCV_PTE_U != 0,
+                global: bits & RISCV_PTE_G != 0,
+                no_cache: pbmt == RISCV_PTE_PBMT_NC || pbmt == RISCV_PTE_PBMT_IO,
+                accessed: bits & RISCV_PTE_A != 0,
+                dirty: bits & RISCV_PTE_D != 0,
+                cow: false,
+                private: false,
+                shared: false,
+            }))
+        }
+    }
+
+    // x86_64 4-level and 5-level paging entry bits; see the Intel SDM, volume 3, section 4.5.
+    const X86_64_PTE_RW: u64 = 1 << 1;
+    const X86_64_PTE_US: u64 = 1 << 2;
+    const X86_64_PTE_PWT: u64 = 1 << 3;
+    const X86_64_PTE_PCD: u64 = 1 << 4;
+    const X86_64_PTE_A: u64 = 1 << 5;
+    const X86_64_PTE_D: u64 = 1 << 6;
+    const X86_64_PTE_G: u64 = 1 << 8;
+    const X86_64_PTE_NX: u64 = 1 << 63;
+
+    impl Flags {
+        /// Get the R/W, U/S, PWT/PCD, A, D, G, and NX bits of an x86_64 page table entry for a
+        /// mapping with these flags.
+        ///
+        /// The present bit is left for the page table to set. Every present page is readable on
+        /// x86_64, and executable unless the NX bit is set, which needs `EFER.NXE`. Copy-on-write
+        /// mappings aren't writable, so that writes to them fault and can be copied. Uncached
+        /// mappings get both PWT and PCD, making them strong uncacheable under the default PAT.
+        #[must_use]
+        pub const fn to_x86_64_pte_bits(self) -> u64 {
+            let flags = self.into_builder();
+            let mut bits = 0;
+            if flags.write && !flags.cow {
+                bits |= X86_64_PTE_RW;
+            }
+            if flags.user {
+                bits |= X86_64_PTE_US;
+            }
+            if flags.no_cache {
+                bits |= X86_64_PTE_PWT | X86_64_PTE_PCD;
+            }
+            if flags.accessed {
+                bits |= X86_64_PTE_A;
+            }
+            if flags.dirty {
+                bits |= X86_64_PTE_D;
+            }
+            if flags.global {
+                bits |= X86_64_PTE_G;
+            }
+            if !flags.execute {
+                bits |= X86_64_PTE_NX;
+            }
+            bits
+        }
     }
 
     impl FlagBuilder {
-        /// Create a new `FlagBuilder` with all flags toggled off.
+        /// Start building flags from the bits of an x86_64 page table entry, ignoring the present
+        /// bit, the physical address, and the software bits.
+        ///
+        /// The result is always readable. Any of PWT or PCD counts as uncached. The page table
+        /// has no notion of copy-on-write, private, or shared mappings, so set those before
+        /// validating if needed.
+        #[must_use]
+        pub const fn from_x86_64_pte_bits(bits: u64) -> Self {
+            Self {
+                read: true,
+                write: bits & X86_64_PTE_RW != 0,
+                execute: bits & X86_64_PTE_NX == 0,
+                user: bits & X86_64_PTE_US != 0,
+                global: bits & X86_64_PTE_G != 0,
+                no_cache: bits & (X86_64_PTE_PWT | X86_64_PTE_PCD) != 0,
+                cow: false,
+                private: false,
+                shared: false,
+                accessed: bits & X86_64_PTE_A != 0,
+                dirty: bits & X86_64_PTE_D != 0,
+            }
+        }
+    }
+
+    // ARMv8-A stage 1 block and page descriptor attributes; see the Arm ARM, section D8.3.
+    const AARCH64_ATTR_INDX_SHIFT: u64 = 2;
+    const AARCH64_ATTR_INDX_MASK: u64 = 0b111 << AARCH64_ATTR_INDX_SHIFT;
+    const AARCH64_AP_EL0: u64 = 1 << 6;
+    const AARCH64_AP_RO: u64 = 1 << 7;
+    const AARCH64_SH_INNER: u64 = 0b11 << 8;
+    const AARCH64_AF: u64 = 1 << 10;
+    const AARCH64_NG: u64 = 1 << 11;
+    const AARCH64_PXN: u64 = 1 << 53;
+    const AARCH64_UXN: u64 = 1 << 54;
+
+    impl Flags {
+        /// The `MAIR_EL1` attribute index that `to_aarch64_descriptor_attrs` uses for normal
+        /// memory, which the kernel should program as write-back cacheable.
+        pub const AARCH64_NORMAL_ATTR_INDEX: u64 = 0;
+        /// The `MAIR_EL1` attribute index that `to_aarch64_descriptor_attrs` uses for uncached
+        /// mappings, which the kernel should program as Device-nGnRnE.
+        pub const AARCH64_DEVICE_ATTR_INDEX: u64 = 1;
+
+        /// Get the AttrIndx, AP, SH, AF, nG, PXN, and UXN attributes of an ARMv8-A stage 1 page
+        /// or block descriptor for a mapping with these flags.
+        ///
+        /// The descriptor type bits are left for the page table to set. Every valid page is
+        /// readable at the exception levels AP allows. User mappings are never executable by the
+        /// kernel, and kernel mappings never by user mode. Copy-on-write mappings aren't writable,
+        /// so that writes to them fault and can be copied. Uncached mappings use
+        /// `AARCH64_DEVICE_ATTR_INDEX`; the dirty flag has no equivalent without `FEAT_HAFDBS`.
+        #[must_use]
+        pub const fn to_aarch64_descriptor_attrs(self) -> u64 {
+            let flags = self.into_builder();
+            let mut bits = AARCH64_PXN | AARCH64_UXN;
+            if flags.execute {
+                bits &= !if flags.user { AARCH64_UXN } else { AARCH64_PXN };
+            }
+            if flags.user {
+                bits |= AARCH64_AP_EL0;
+            }
+            if !flags.write || flags.cow {
+                bits |= AARCH64_AP_RO;
+            }
+            if flags.no_cache {
+                bits |= Self::AARCH64_DEVICE_ATTR_INDEX << AARCH64_ATTR_INDX_SHIFT;
+            } else {
+                bits |=
+                    Self::AARCH64_NORMAL_ATTR_INDEX << AARCH64_ATTR_INDX_SHIFT | AARCH64_SH_INNER;
+            }
+            if flags.accessed {
+                bits |= AARCH64_AF;
+            }
+            if !flags.global {
+                bits |= AARCH64_NG;
+            }
+            bits
+        }
+
+        /// Recover the flags of an ARMv8-A stage 1 page or block descriptor, ignoring the
+        /// descriptor type, the output address, and the software bits.
+        ///
+        /// Returns `None` if the attribute index is neither `AARCH64_NORMAL_ATTR_INDEX` nor
+        /// `AARCH64_DEVICE_ATTR_INDEX`.
+        #[must_use]
+        pub const fn from_aarch64_descriptor_attrs(bits: u64) -> Option<Self> {
+            let no_cache = match (bits & AARCH64_ATTR_INDX_MASK) >> AARCH64_ATTR_INDX_SHIFT {
+                Self::AARCH64_NORMAL_ATTR_INDEX => false,
+                Self::AARCH64_DEVICE_ATTR_INDEX => true,
+                _ => return None,
+            };
+            let user = bits & AARCH64_AP_EL0 != 0;
+            let never_execute = if user { AARCH64_UXN } else { AARCH64_PXN };
+
+            Some(Self::pack(FlagBuilder {
+                read: true,
+                write: bits & AARCH64_AP_RO == 0,
+                execute: bits & never_execute == 0,
+                user,
+                global: bits & AARCH64_NG == 0,
+                no_cache,
+                cow: false,
+                private: false,
+                shared: false,
+                accessed: bits & AARCH64_AF != 0,
+                dirty: false,
+            }))
+        }
+    }
+
+    impl Flags {
+        /// POSIX `PROT_*` and `MAP_*` values, as on Linux.
+        pub const PROT_NONE: u32 = 0;
+        pub const PROT_READ: u32 = 1;
+        pub const PROT_WRITE: u32 = 2;
+        pub const PROT_EXEC: u32 = 4;
+        pub const MAP_SHARED: u32 = 1;
+        pub const MAP_PRIVATE: u32 = 2;
+        pub const MAP_FIXED: u32 = 0x10;
+        pub const MAP_ANONYMOUS: u32 = 0x20;
+
+        /// Translate the `prot` and `flags` arguments of `mmap` into the flags of a user mapping.
+        ///
+        /// Writable private mappings are copy-on-write. `MAP_*` flags other than `MAP_SHARED` and
+        /// `MAP_PRIVATE` only affect where the mapping goes, so they're ignored.
+        ///
+        /// Returns `None` if `prot` has unknown bits, or `map_flags` doesn't have exactly one of
+        /// `MAP_SHARED` and `MAP_PRIVATE`.
+        #[must_use]
+        pub const fn from_prot_and_map(prot: u32, map_flags: u32) -> Option<Self> {
+            if prot & !(Self::PROT_READ | Self::PROT_WRITE | Self::PROT_EXEC) != 0 {
+                return None;
+            }
+            let shared = map_flags & Self::MAP_SHARED != 0;
+            let private = map_flags & Self::MAP_PRIVATE != 0;
+            if shared == private {
+                return None;
+            }
+            let write = prot & Self::PROT_WRITE != 0;
+
+            Some(Self::pack(FlagBuilder {
+                read: prot & Self::PROT_READ != 0,
+                write,
+                execute: prot & Self::PROT_EXEC != 0,
+                user: true,
+                global: false,
+                no_cache: false,
+                cow: private && write,
+                private,
+                shared,
+                accessed: false,
+                dirty: false,
+            }))
+        }
+
+        /// Translate these flags back into the `prot` and `flags` arguments of `mmap`.
+        ///
+        /// The `MAP_*` flags are empty if the mapping is neither private nor shared.
         #[must_use]
-        pub fn new() -> Self {
-            Self::default()
+        pub const fn to_prot_and_map(self) -> (u32, u32) {
+            let flags = self.into_builder();
+            let mut prot = Self::PROT_NONE;
+            if flags.read {
+                prot |= Self::PROT_READ;
+            }
+            if flags.write {
+                prot |= Self::PROT_WRITE;
+            }
+            if flags.execute {
+                prot |= Self::PROT_EXEC;
+            }
+
+            let map_flags = if flags.shared {
+                Self::MAP_SHARED
+            } else if flags.private {
+                Self::MAP_PRIVATE
+            } else {
+                0
+            };
+
+            (prot, map_flags)
+        }
+    }
+
+    /// Create a new `Flag`s object.
+    ///
+    /// ```
+    /// # use reedos_address_space::{Flags, flags};
+    /// assert_eq!(
+    ///     Ok(flags![read, write]),
+    ///     Flags::build().toggle_read().toggle_write().try_validate()
+    /// );
+    /// ```
+    ///
+    /// The flags are validated at compile time, so invalid flags don't compile:
+    ///
+    /// ```compile_fail
+    /// # use reedos_address_space::flags;
+    /// let flags = flags![read, private, shared];
+    /// ```
+    #[macro_export]
+    macro_rules! flags [
+    ($($flag:ident),*) => {{
+        const BUILDER: $crate::address_space::FlagBuilder = $crate::address_space::FlagBuilder {
+            $(
+                $flag: true,
+            )*
+            ..$crate::address_space::FlagBuilder::new()
+        };
+        const FLAGS: $crate::address_space::Flags = match BUILDER.try_validate() {
+            Ok(flags) => flags,
+            Err(_) => panic!("invalid flags"),
+        };
+        FLAGS
+    }};
+    ];
+}
+
+pub use crate::flags;
+pub use flags::{AccessType, FlagBuilder, FlagError, Flags};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_source::DsError;
+    use crate::frame::BitmapAllocator;
+    use parking_lot::RwLock;
+
+    use std::vec::Vec;
+
+    /// A proxy data soucre for testing.
+    #[derive(Debug)]
+    struct ProxyDs<const CAPACITY: usize> {
+        buffer: RwLock<[u8; CAPACITY]>,
+    }
+
+    impl<const CAPACITY: usize> ProxyDs<CAPACITY> {
+        const fn new() -> Self {
+            Self {
+                buffer: RwLock::new([0; CAPACITY]),
+            }
+        }
+
+        pub fn assert_eq(&self, other: [u8; CAPACITY]) {
+            assert_eq!(*self.buffer.read(), other);
+        }
+    }
+
+    impl<const CAPACITY: usize> DataSource for ProxyDs<CAPACITY> {
+        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+            assert!(offset + length <= CAPACITY);
+
+            buffer.copy_from_slice(&self.buffer.read()[offset..offset + length]);
+            Ok(())
+        }
+
+        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+            assert!(offset + length <= CAPACITY);
+
+            self.buffer.write()[offset..offset + length].copy_from_slice(buffer);
+            Ok(())
+        }
+
+        fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+            assert!(offset + length <= CAPACITY);
+
+            self.buffer.write()[offset..offset + length].fill(0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn proxy_ds_works() -> Result<(), DsError> {
+        const TEST_DS_CAPACITY: usize = 32;
+
+        let ds = ProxyDs::<32>::new();
+
+        // write all 1s
+        ds.write(0, 32, &[1; 32])?;
+        ds.assert_eq([1; 32]);
+
+        // read 15 bytes from the middle
+        let mut buffer = [0; 15];
+        ds.read(10, 15, &mut buffer)?;
+        assert_eq!(buffer, [1; 15]);
+
+        // write from 0 to 31
+        let mut write_from = [0u8; 32];
+        for i in 0..32 {
+            write_from[i as usize] = i;
+        }
+        ds.write(0, 32, &write_from)?;
+        ds.assert_eq(write_from);
+
+        // read 12 bytes from the middle
+        let mut read_into = [0; 12];
+        ds.read(13, 12, &mut read_into)?;
+        assert_eq!(read_into, write_from[13..25]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn constructor() {
+        // Construct an address space with capacity 20.
+        let space = AddressSpace::<1200>::new("my first address space");
+        assert_eq!(space.name, "my first address space");
+    }
+
+    fn test_add_mapping_once(length: usize) -> Result<(), AsError> {
+        const DS_CAPACITY: usize = 16;
+        const N_PAGES: usize = 1200;
+        const PAGE_SIZE: usize = 20;
+
+        let mut space = AddressSpace::<N_PAGES, PAGE_SIZE>::new("test space");
+        let source = ProxyDs::<DS_CAPACITY>::new();
+
+        let addr = space.add_mapping(&source, length, flags![read])?.addr();
+
+        space.assert_valid();
+
+        assert_ne!(addr, 0);
+        assert!(!space.mappings.is_empty());
+
+        let mapping = space.mappings.first().expect("source was mapped");
+
+        assert_eq!(mapping.addr, addr);
+        assert_eq!(mapping.length, length.next_multiple_of(PAGE_SIZE));
+        assert_eq!(mapping.requested_length, length);
+        // TODO: check DS equality
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_mapping_once_works() -> Result<(), AsError> {
+        test_add_mapping_once(1)?;
+        test_add_mapping_once(200)?;
+
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+        assert!(space.add_mapping(&source, 0, flags![read]).is_err());
+        assert!(space
+            .add_mapping(&source, usize::MAX, flags![read])
+            .is_err());
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_mapping_works() -> Result<(), AsError> {
+        const N_ADDRS: usize = 100;
+        const DS_CAPACITY: usize = 16;
+        const N_PAGES: usize = 1200;
+        const PAGE_SIZE: usize = 20;
+
+        let mut space = AddressSpace::<N_PAGES, PAGE_SIZE>::new("test space");
+        let source = ProxyDs::<DS_CAPACITY>::new();
+
+        let mut addrs = Vec::new();
+
+        for l in 1..=N_ADDRS {
+            addrs.push(space.add_mapping(&source, l, flags![read])?.addr());
+            space.assert_valid();
+        }
+
+        // Assert none are 0.
+        assert!(!addrs.contains(&0));
+
+        // Assert all are distinct.
+        assert!(addrs.len() == N_ADDRS);
+
+        // assert_eq!(mapping.addr, addr);
+        // assert_eq!(mapping.length, length);
+        // TODO: check DS equality
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_mapping_at_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<6, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        space.insert_entry(MapEntry {
+            addr: 20,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        let addr = 60;
+        let length = 20;
+
+        space.add_mapping_at(addr, &source, length, flags![read])?;
+        let mapping = space.mappings.iter().nth(1).expect("second mapping exists");
+
+        assert_eq!(mapping.addr, addr);
+        assert_eq!(mapping.length, length);
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_mapping_at_err_works() {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        space.insert_entry(MapEntry {
+            addr: 20,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        assert!(space.add_mapping_at(20, &source, 20, flags![read]).is_err());
+        space.assert_valid();
+    }
+
+    #[test]
+    fn remove_mapping_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        space.insert_entry(MapEntry {
+            addr: 20,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        space.insert_entry(MapEntry {
+            addr: 60,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        space.insert_entry(MapEntry {
+            addr: 100,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        let handle = space.handle_at(60).expect("mapping exists");
+        space.remove_mapping(handle)?;
+        assert!(space.remove_mapping(handle).is_err());
+
+        assert_eq!(space.mappings.len(), 2);
+
+        assert_eq!(space.mappings.first().expect("first exists").addr, 20);
+        assert_eq!(space.mappings.last().expect("last exists").addr, 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_works() -> Result<(), AsError> {
+        #[derive(Debug, PartialEq)]
+        struct Vma {
+            id: usize,
+        }
+
+        let first = Vma { id: 1 };
+        let second = Vma { id: 2 };
+
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let addr = space.add_mapping(&source, 20, flags![read])?.addr();
+        assert!(space.metadata(addr).is_none());
+
+        space.set_metadata(addr, &first)?;
+        assert_eq!(
+            space.metadata(addr).and_then(|m| m.downcast_ref()),
+            Some(&first)
+        );
+
+        space.set_metadata(addr, &second)?;
+        assert_eq!(
+            space.metadata(addr).and_then(|m| m.downcast_ref()),
+            Some(&second)
+        );
+
+        assert!(space.set_metadata(addr + 20, &first).is_err());
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn is_mapped_works() {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        space.insert_entry(MapEntry {
+            addr: 20,
+            length: 20,
+            source: Some(SourceRef::Borrowed(&source)),
+            ..MapEntry::default()
+        });
+
+        assert!(!space.is_mapped(0));
+        assert!(!space.is_mapped(19));
+        assert!(space.is_mapped(20));
+        assert!(space.is_mapped(30));
+        assert!(space.is_mapped(39));
+        assert!(!space.is_mapped(40));
+    }
+
+    #[test]
+    fn contains_range_works() {
+        let mut space = AddressSpace::<10, 20, 0>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        // Two adjacent mappings, then a hole, then another.
+        for (addr, length) in [(20, 20), (40, 40), (100, 20)] {
+            space.insert_entry(MapEntry {
+                addr,
+                length,
+                source: Some(SourceRef::Borrowed(&source)),
+                ..MapEntry::default()
+            });
+        }
+
+        assert!(space.contains_range(20, 20));
+        assert!(space.contains_range(25, 50));
+        assert!(space.contains_range(100, 20));
+        assert!(space.contains_range(50, 0));
+        assert!(!space.contains_range(70, 40));
+        assert!(!space.contains_range(110, 20));
+        assert!(!space.contains_range(0, 30));
+        assert!(!space.contains_range(usize::MAX, 2));
+    }
+
+    #[test]
+    fn free_regions_works() {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        assert_eq!(space.free_regions().collect::<Vec<_>>(), [(20, 180)]);
+
+        for (addr, length) in [(60, 20), (120, 20)] {
+            space.insert_entry(MapEntry {
+                addr,
+                length,
+                source: Some(SourceRef::Borrowed(&source)),
+                ..MapEntry::default()
+            });
+        }
+
+        // The gap between the two mappings is entirely guard space, so it's skipped.
+        assert_eq!(
+            space.free_regions().collect::<Vec<_>>(),
+            [(20, 40), (160, 180)]
+        );
+    }
+
+    #[test]
+    fn mappings_in_range_works() {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        for (addr, length) in [(20, 40), (100, 20), (160, 60)] {
+            space.insert_entry(MapEntry {
+                addr,
+                length,
+                source: Some(SourceRef::Borrowed(&source)),
+                ..MapEntry::default()
+            });
+        }
+
+        let addrs_in = |start, end| {
+            space
+                .mappings_in_range(start, end)
+                .map(|m| m.addr)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(addrs_in(0, 400), [20, 100, 160]);
+        assert_eq!(addrs_in(40, 101), [20, 100]);
+        assert_eq!(addrs_in(60, 100), []);
+        assert_eq!(addrs_in(119, 161), [100, 160]);
+        assert_eq!(addrs_in(200, 210), [160]);
+        assert_eq!(addrs_in(30, 30), []);
+        assert_eq!(addrs_in(220, 400), []);
+    }
+
+    #[test]
+    fn unmap_range_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        for (addr, length) in [(20, 40), (100, 20), (160, 100)] {
+            space.insert_entry(MapEntry {
+                addr,
+                length,
+                requested_length: length,
+                source: Some(SourceRef::Borrowed(&source)),
+                ..MapEntry::default()
+            });
+        }
+
+        // Trim the end of the first, remove the second, and trim the start of the third.
+        space.unmap_range(40, 140)?;
+        space.assert_valid();
+
+        let layout = |space: &AddressSpace<20, 20>| {
+            space
+                .mappings
+                .iter()
+                .map(|m| (m.addr, m.length, m.offset))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(layout(&space), [(20, 20, 0), (180, 80, 20)]);
+
+        // Split the remainder of the third in two; the length is rounded up.
+        space.unmap_range(200, 15)?;
+        space.assert_valid();
+        assert_eq!(layout(&space), [(20, 20, 0), (180, 20, 20), (220, 40, 60)]);
+
+        // Unmapping nothing is fine.
+        space.unmap_range(300, 20)?;
+        assert_eq!(space.mappings.len(), 3);
+
+        assert!(space.unmap_range(30, 20).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn top_down_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space")
+            .with_growth_direction(GrowthDirection::TopDown);
+        let source = ProxyDs::<16>::new();
+
+        // The last page is reserved as guard space.
+        assert_eq!(space.add_mapping(&source, 20, flags![read])?.addr(), 160);
+        assert_eq!(space.add_mapping(&source, 30, flags![read])?.addr(), 100);
+        assert_eq!(space.add_mapping(&source, 5, flags![read])?.addr(), 60);
+        assert_eq!(space.add_mapping(&source, 20, flags![read])?.addr(), 20);
+        space.assert_valid();
+
+        assert!(space.add_mapping(&source, 20, flags![read]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn placement_policy_works() -> Result<(), AsError> {
+        let source = ProxyDs::<16>::new();
+
+        // Leaves free regions of 2, 1 and 4 pages, in that order.
+        let setup = |placement, direction| {
+            let mut space = AddressSpace::<20, 20>::new("test space")
+                .with_placement_policy(placement)
+                .with_growth_direction(direction);
+            for (addr, length) in [(80, 20), (160, 20), (300, 80)] {
+                space.insert_entry(MapEntry {
+                    addr,
+                    length,
+                    source: Some(SourceRef::Borrowed(&source)),
+                    ..MapEntry::default()
+                });
+            }
+            space
+        };
+
+        let place = |placement, direction| {
+            let mut space = setup(placement, direction);
+            let addr = space
+                .add_mapping(&source, 20, flags![read])
+                .map(MappingHandle::addr);
+            space.assert_valid();
+            addr
+        };
+
+        use GrowthDirection::{BottomUp, TopDown};
+        use PlacementPolicy::{BestFit, FirstFit, WorstFit};
+
+        assert_eq!(place(FirstFit, BottomUp)?, 20);
+        assert_eq!(place(FirstFit, TopDown)?, 260);
+        assert_eq!(place(BestFit, BottomUp)?, 120);
+        assert_eq!(place(BestFit, TopDown)?, 120);
+        assert_eq!(place(WorstFit, BottomUp)?, 200);
+        assert_eq!(place(WorstFit, TopDown)?, 260);
+
+        Ok(())
+    }
+
+    #[test]
+    fn free_region_index_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<500, 20>::new("test space")
+            .with_placement_policy(PlacementPolicy::BestFit);
+        let source = ProxyDs::<16>::new();
+
+        let mut addrs = Vec::new();
+        for l in 1..=40 {
+            addrs.push(space.add_mapping(&source, l * 5, flags![read])?.addr());
+            space.assert_valid();
+        }
+
+        // Punch holes of various sizes, then refill them.
+        for addr in addrs.iter().step_by(3) {
+            space.remove_mapping(space.handle_at(*addr).expect("mapping exists"))?;
+            space.assert_valid();
+        }
+        for l in (1..=10).rev() {
+            space.add_mapping(&source, l * 10, flags![read])?;
+            space.assert_valid();
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn large_address_space_works() -> Result<(), AsError> {
+        // Far too many pages to reserve fixed storage for.
+        let mut space = AddressSpace::<{ 1 << 26 }>::new("test space")
+            .with_growth_direction(GrowthDirection::TopDown);
+        let source = ProxyDs::<16>::new();
+
+        let addr = space
+            .add_mapping(&source, DEFAULT_PAGE_SIZE, flags![read])?
+            .addr();
+        assert_eq!(addr, (1 << 38) - 2 * DEFAULT_PAGE_SIZE);
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn ceiling_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space")
+            .with_growth_direction(GrowthDirection::TopDown);
+        let source = ProxyDs::<16>::new();
+
+        assert_eq!(space.ceiling(), 200);
+        assert!(space.set_ceiling(220).is_err());
+        assert!(space.set_ceiling(110).is_err());
+
+        space.set_ceiling(120)?;
+        space.assert_valid();
+        assert_eq!(space.add_mapping(&source, 20, flags![read])?.addr(), 80);
+        assert!(space
+            .add_mapping_at(100, &source, 20, flags![read])
+            .is_err());
+        space.assert_valid();
+
+        assert!(space.set_ceiling(100).is_err());
+        space.set_ceiling(200)?;
+        space.add_mapping_at(160, &source, 20, flags![read])?;
+        space.assert_valid();
+
+        // The default ceiling respects `VADDR_MAX`.
+        assert_eq!(
+            AddressSpace::<{ 1 << 30 }>::max_ceiling(DEFAULT_PAGE_SIZE),
+            VADDR_MAX + 1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_page_size_works() -> Result<(), AsError> {
+        let source = ProxyDs::<16>::new();
+
+        assert!(AddressSpace::<10, 20>::new("test space")
+            .with_page_size(32)
+            .is_err());
+        assert!(AddressSpace::<10, RUNTIME_PAGE_SIZE>::new("test space")
+            .with_page_size(20)
+            .is_err());
+
+        let mut space =
+            AddressSpace::<10, RUNTIME_PAGE_SIZE, 32>::new("test space").with_page_size(32)?;
+        assert_eq!(space.page_size(), 32);
+        assert_eq!(space.ceiling(), 320);
+
+        assert_eq!(space.add_mapping(&source, 20, flags![read])?.addr(), 32);
+        assert_eq!(space.add_mapping(&source, 40, flags![read])?.addr(), 96);
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_asid_works() {
+        let mut asids = AsidAllocator::<3>::new();
+        let mut first = AddressSpace::<10, 20>::new("first");
+        let mut second = AddressSpace::<10, 20>::new("second");
+        let mut third = AddressSpace::<10, 20>::new("third");
+
+        assert!(first.asid().is_none());
+        let asid = first.refresh_asid(&mut asids);
+        assert_eq!(first.refresh_asid(&mut asids), asid);
+
+        second.refresh_asid(&mut asids);
+        // Rolls over, invalidating the first space's ASID.
+        third.refresh_asid(&mut asids);
+        assert!(!asids.is_current(first.asid().expect("was assigned")));
+        let asid = first.refresh_asid(&mut asids);
+        assert!(asids.is_current(asid));
+    }
+
+    #[test]
+    fn share_mapping_works() -> Result<(), AsError> {
+        let mut first = AddressSpace::<10, 20>::new("first");
+        let mut second =
+            AddressSpace::<20, 20>::new("second").with_growth_direction(GrowthDirection::TopDown);
+        let source = ProxyDs::<16>::new();
+
+        let addr = first
+            .add_mapping(&source, 40, flags![read, write, private])?
+            .addr();
+        let shared = first.share_mapping(&mut second, addr)?.addr();
+        second.assert_valid();
+
+        let mapping = second
+            .mappings_in_range(shared, shared + 1)
+            .next()
+            .expect("mapping was shared");
+        assert_eq!(mapping.addr, 340);
+        assert_eq!(mapping.length, 40);
+        assert_eq!(mapping.offset, 0);
+        assert_eq!(mapping.flags, flags![read, write, shared]);
+        assert!(core::ptr::addr_eq(mapping.source, &source));
+
+        assert!(first.share_mapping(&mut second, addr + 20).is_err());
+
+        // The mapping must be a whole number of the other space's pages.
+        let mut small = AddressSpace::<20, RUNTIME_PAGE_SIZE>::new("small").with_page_size(8)?;
+        let mut large = AddressSpace::<10, RUNTIME_PAGE_SIZE>::new("large").with_page_size(32)?;
+        assert_eq!(
+            first.share_mapping(&mut large, addr),
+            Err(AsError::PageSizeMismatch)
+        );
+        assert!(first
+            .share_mapping(&mut small, addr)?
+            .addr()
+            .is_multiple_of(8));
+        small.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn stale_handles_are_rejected() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let old = space.add_mapping(&source, 20, flags![read])?;
+        space.remove_mapping(old)?;
+
+        // A new mapping at the same address doesn't revive the old handle.
+        let new = space.add_mapping(&source, 20, flags![read])?;
+        assert_eq!(old.addr(), new.addr());
+        assert!(space.remove_mapping(old).is_err());
+        assert!(space.protect(old, flags![write]).is_err());
+        assert!(space.resize_mapping(old, 40).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn protect_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let handle = space.add_mapping(&source, 20, flags![read])?;
+        space.protect(handle, flags![read, write])?;
+
+        let mapping = space
+            .mappings_in_range(handle.addr(), handle.addr() + 1)
+            .next()
+            .expect("mapping exists");
+        assert_eq!(mapping.flags, flags![read, write]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resize_mapping_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let first = space.add_mapping(&source, 20, flags![read])?;
+        space.add_mapping_at(100, &source, 20, flags![read])?;
+
+        // Grow into the free space, but not into the guard gap of the next mapping.
+        space.resize_mapping(first, 60)?;
+        space.assert_valid();
+        assert!(space.resize_mapping(first, 61).is_err());
+        space.assert_valid();
+        assert!(space.resize_mapping(first, 0).is_err());
+
+        space.resize_mapping(first, 10)?;
+        space.assert_valid();
+        let mapping = space.mappings.first().expect("mapping exists");
+        assert_eq!(mapping.length, 20);
+        assert_eq!(mapping.requested_length, 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mapping_builder_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let handle = space
+            .map(&source)
+            .length(30)
+            .offset(8)
+            .flags(flags![read, write])
+            .align(80)
+            .commit()?;
+        assert_eq!(handle.addr(), 80);
+
+        let mapping = space
+            .mappings_in_range(80, 81)
+            .next()
+            .expect("mapping exists");
+        assert_eq!(mapping.length, 40);
+        assert_eq!(mapping.requested_length, 30);
+        assert_eq!(mapping.offset, 8);
+        assert_eq!(mapping.flags, flags![read, write]);
+
+        assert_eq!(space.map(&source).length(20).at(160).commit()?.addr(), 160);
+        space.assert_valid();
+
+        assert!(space.map(&source).at(260).commit().is_err());
+        assert!(space.map(&source).length(20).at(250).commit().is_err());
+        assert!(space.map(&source).length(20).align(30).commit().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_valid_works() {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+        assert!(space.check_valid().is_ok());
+
+        // Bypass the checks and the free region index to corrupt the space.
+        for (addr, length) in [(0, 20), (45, 20), (60, 30), (100, 20)] {
+            space.mappings.insert(MapEntry {
+                addr,
+                length,
+                source: Some(SourceRef::Borrowed(&source)),
+                guard: 20,
+                ..MapEntry::default()
+            });
+        }
+
+        let report = space.check_valid().expect_err("space is invalid");
+        let violations = report.violations().copied().collect::<Vec<_>>();
+        assert!(violations.contains(&Violation::ZeroPage { addr: 0 }));
+        assert!(violations.contains(&Violation::Misaligned { addr: 45 }));
+        assert!(violations.contains(&Violation::Overlap {
+            first: 45,
+            second: 60
+        }));
+        assert!(violations.contains(&Violation::GapTooSmall {
+            first: 60,
+            second: 100
+        }));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::FreeIndexOutOfDate { .. })));
+        assert!(!report.is_truncated());
+    }
+
+    #[test]
+    fn seal_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<10, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let handle = space.add_mapping(&source, 20, flags![read, execute])?;
+        space.seal(handle)?;
+
+        assert_eq!(space.remove_mapping(handle), Err(AsError::Sealed));
+        assert_eq!(space.protect(handle, flags![read]), Err(AsError::Sealed));
+        assert_eq!(space.resize_mapping(handle, 40), Err(AsError::Sealed));
+        assert_eq!(space.unmap_range(0, 200), Err(AsError::Sealed));
+
+        let mapping = space
+            .mappings_in_range(0, 200)
+            .next()
+            .expect("mapping survived");
+        assert_eq!(mapping.flags, flags![read, execute]);
+        assert!(mapping.sealed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fragmentation_stats_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let stats = space.fragmentation_stats();
+        assert_eq!(stats.free_regions, 1);
+        assert_eq!(stats.largest_free, 360);
+        assert!(stats.external_fragmentation() < f64::EPSILON);
+
+        // Leaves usable free regions of 1, 3, and 6 pages.
+        space.add_mapping_at(60, &source, 20, flags![read])?;
+        space.add_mapping_at(180, &source, 60, flags![read])?;
+
+        let stats = space.fragmentation_stats();
+        assert_eq!(
+            stats,
+            FragmentationStats {
+                free_regions: 3,
+                total_free: 200,
+                largest_free: 120,
+            }
+        );
+        assert_eq!(stats.mean_free(), 66);
+        assert!((stats.external_fragmentation() - 0.4).abs() < f64::EPSILON);
+
+        Ok(())
+    }
+
+    #[test]
+    fn per_mapping_guard_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        // Segments with no guard can be adjacent.
+        let text = space.map(&source).length(40).at(20).guard(0).commit()?;
+        let data = space.map(&source).length(20).at(60).guard(0).commit()?;
+        space.assert_valid();
+
+        // But not next to a mapping with the default guard.
+        assert!(space.map(&source).length(20).at(80).commit().is_err());
+        space.map(&source).length(20).at(100).commit()?;
+        space.assert_valid();
+
+        // A big guard is honored on both sides, whichever mapping is placed first.
+        assert!(space
+            .map(&source)
+            .length(20)
+            .at(160)
+            .guard(60)
+            .commit()
+            .is_err());
+        let stack = space.map(&source).length(20).at(200).guard(60).commit()?;
+        assert!(space
+            .add_mapping_at(260, &source, 20, flags![read])
+            .is_err());
+        space.add_mapping_at(280, &source, 20, flags![read])?;
+        space.assert_valid();
+
+        // Placement honors guards too.
+        space.remove_mapping(data)?;
+        assert_eq!(space.map(&source).length(20).guard(0).commit()?.addr(), 60);
+        space.remove_mapping(stack)?;
+        assert_eq!(
+            space.map(&source).length(20).guard(40).commit()?.addr(),
+            160
+        );
+        space.assert_valid();
+
+        space.remove_mapping(text)?;
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_is_rejected() {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let near_max = usize::MAX / 20 * 20;
+        assert_eq!(
+            space.add_mapping_at(near_max, &source, 40, flags![read]),
+            Err(AsError::Overflow)
+        );
+        assert_eq!(
+            space.map(&source).length(20).offset(usize::MAX).commit(),
+            Err(AsError::Overflow)
+        );
+        assert_eq!(
+            space.add_mapping(&source, usize::MAX, flags![read]),
+            Err(AsError::Overflow)
+        );
+        assert_eq!(
+            space.map(&source).length(20).guard(usize::MAX).commit(),
+            Err(AsError::NoSpace)
+        );
+        assert_eq!(space.unmap_range(near_max, 40), Err(AsError::Overflow));
+        assert!(!space.contains_range(near_max, 40));
+        space.assert_valid();
+    }
+
+    #[test]
+    fn user_flag_works() {
+        let flags = flags![read, write, user];
+        assert!(flags.into_builder().user);
+        assert!(!flags![read, write].into_builder().user);
+        assert_eq!(
+            Flags::read().and(Flags::user()).try_validate(),
+            Ok(flags![read, user])
+        );
+
+        // Only user mappings may be copy-on-write.
+        let _ = flags![read, user, cow];
+        assert_eq!(
+            Flags::read().toggle_cow().try_validate(),
+            Err(FlagError::KernelCow)
+        );
+    }
+
+    #[test]
+    fn accessed_dirty_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+
+        let handle = space.add_mapping_at(40, &source, 40, flags![read, write, global])?;
+        let flags = |space: &AddressSpace<20, 20>| {
+            space
+                .mappings_in_range(40, 41)
+                .next()
+                .expect("mapping exists")
+                .flags
+                .into_builder()
+        };
+        assert!(flags(&space).global);
+        assert!(!flags(&space).accessed);
+
+        space.mark_accessed(50, false)?;
+        assert!(flags(&space).accessed);
+        assert!(!flags(&space).dirty);
+
+        space.mark_accessed(79, true)?;
+        assert!(flags(&space).dirty);
+        assert!(space.mark_accessed(80, true).is_err());
+
+        assert!(space.clear_accessed(handle)?);
+        assert!(!space.clear_accessed(handle)?);
+        assert!(space.clear_dirty(handle)?);
+        assert!(!space.clear_dirty(handle)?);
+        assert_eq!(flags(&space), flags![read, write, global].into_builder());
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_cache_flag_works() {
+        assert!(flags![read, write, no_cache].into_builder().no_cache);
+        assert_eq!(
+            (Flags::user() | Flags::cow() | Flags::no_cache()).try_validate(),
+            Err(FlagError::UncachedCow)
+        );
+    }
+
+    #[test]
+    fn riscv_pte_bits_work() {
+        let flags = flags![read, write, user, accessed, dirty];
+        assert_eq!(flags.to_riscv_pte_bits(), 0b1101_0110);
+        assert_eq!(Flags::from_riscv_pte_bits(0b1101_0111), Some(flags));
+
+        // Copy-on-write mappings are read-only until copied.
+        assert_eq!(flags![read, write, user, cow].to_riscv_pte_bits(), 0b1_0010);
+        assert_eq!(flags![read, no_cache].to_riscv_pte_bits(), 2 << 61 | 0b10);
+        assert_eq!(
+            Flags::from_riscv_pte_bits(1 << 61 | 0b1010),
+            Some(flags![read, execute, no_cache])
+        );
+
+        // Non-leaf and reserved encodings.
+        assert_eq!(Flags::from_riscv_pte_bits(0b1_0001), None);
+        assert_eq!(Flags::from_riscv_pte_bits(0b100), None);
+        assert_eq!(Flags::from_riscv_pte_bits(3 << 61 | 0b10), None);
+    }
+
+    #[test]
+    fn x86_64_pte_bits_work() {
+        let flags = flags![read, write, user, global, accessed];
+        assert_eq!(flags.to_x86_64_pte_bits(), 1 << 63 | 0b1_0010_0110);
+        assert_eq!(
+            FlagBuilder::from_x86_64_pte_bits(1 << 63 | 0b1_0010_0111).try_validate(),
+            Ok(flags)
+        );
+
+        // Copy-on-write mappings are read-only until copied.
+        assert_eq!(
+            flags![read, write, execute, user, cow].to_x86_64_pte_bits(),
+            0b100
+        );
+        assert_eq!(
+            flags![read, execute, no_cache, dirty].to_x86_64_pte_bits(),
+            0b101_1000
+        );
+        assert_eq!(
+            FlagBuilder::from_x86_64_pte_bits(0b1_0000).try_validate(),
+            Ok(flags![read, execute, no_cache])
+        );
+    }
+
+    #[test]
+    fn aarch64_descriptor_attrs_work() {
+        let user_code = flags![read, execute, user, accessed];
+        let attrs = 1 << 53 | 1 << 11 | 1 << 10 | 0b11 << 8 | 0b11 << 6;
+        assert_eq!(user_code.to_aarch64_descriptor_attrs(), attrs);
+        assert_eq!(Flags::from_aarch64_descriptor_attrs(attrs), Some(user_code));
+
+        let kernel_data = flags![read, write, global];
+        let attrs = 1 << 54 | 1 << 53 | 0b11 << 8;
+        assert_eq!(kernel_data.to_aarch64_descriptor_attrs(), attrs);
+        assert_eq!(
+            Flags::from_aarch64_descriptor_attrs(attrs),
+            Some(kernel_data)
+        );
+
+        let device = flags![read, write, no_cache];
+        let attrs = 1 << 54 | 1 << 53 | 1 << 11 | 1 << 2;
+        assert_eq!(device.to_aarch64_descriptor_attrs(), attrs);
+        assert_eq!(Flags::from_aarch64_descriptor_attrs(attrs), Some(device));
+
+        // Copy-on-write mappings are read-only until copied.
+        assert_ne!(
+            flags![read, write, user, cow].to_aarch64_descriptor_attrs() & 1 << 7,
+            0
+        );
+        assert_eq!(Flags::from_aarch64_descriptor_attrs(2 << 2), None);
+    }
+
+    #[test]
+    fn prot_and_map_work() {
+        let prot = Flags::PROT_READ | Flags::PROT_WRITE;
+        let flags = Flags::from_prot_and_map(prot, Flags::MAP_PRIVATE);
+        assert_eq!(flags, Some(flags![read, write, user, cow, private]));
+        assert_eq!(
+            flags.map(Flags::to_prot_and_map),
+            Some((prot, Flags::MAP_PRIVATE))
+        );
+
+        let prot = Flags::PROT_READ | Flags::PROT_EXEC;
+        let flags = Flags::from_prot_and_map(prot, Flags::MAP_SHARED | 0x20);
+        assert_eq!(flags, Some(flags![read, execute, user, shared]));
+        assert_eq!(
+            flags.map(Flags::to_prot_and_map),
+            Some((prot, Flags::MAP_SHARED))
+        );
+
+        assert_eq!(Flags::from_prot_and_map(8, Flags::MAP_SHARED), None);
+        assert_eq!(Flags::from_prot_and_map(Flags::PROT_READ, 0), None);
+        assert_eq!(Flags::from_prot_and_map(Flags::PROT_READ, 3), None);
+    }
+
+    #[test]
+    fn try_validate_works() {
+        assert_eq!(
+            Flags::read()
+                .toggle_private()
+                .toggle_shared()
+                .try_validate(),
+            Err(FlagError::PrivateAndShared)
+        );
+        assert_eq!(
+            Flags::read().toggle_cow().try_validate(),
+            Err(FlagError::KernelCow)
+        );
+        assert_eq!(
+            Flags::user().toggle_cow().toggle_no_cache().try_validate(),
+            Err(FlagError::UncachedCow)
+        );
+        assert_eq!(
+            Flags::user().toggle_cow().try_validate(),
+            Ok(flags![user, cow])
+        );
+    }
+
+    #[test]
+    fn flag_operators_work() {
+        let rwx = Flags::read() | Flags::write() | Flags::execute();
+        assert_eq!(rwx, Flags::read().and(Flags::write()).and(Flags::execute()));
+        assert_eq!(rwx - Flags::write(), Flags::read() | Flags::execute());
+        assert_eq!(rwx & (Flags::write() | Flags::user()), Flags::write());
+        assert_eq!(!rwx & Flags::user(), Flags::user());
+        assert_eq!(
+            (flags![read, user] | flags![write]).try_validate(),
+            Ok(flags![read, write, user])
+        );
+    }
+
+    #[test]
+    fn flag_bits_work() {
+        let flags = flags![read, write, user, cow, private];
+        assert_eq!(flags.bits(), 0b1100_1011);
+        assert_eq!(Flags::from_bits(flags.bits()), Ok(flags));
+        assert_eq!(Flags::from_bits(0), Ok(Flags::default()));
+        assert_eq!(Flags::from_bits(1 << 11), Err(FlagError::UnknownBits));
+        assert_eq!(
+            Flags::from_bits(0b1_1000_0000),
+            Err(FlagError::PrivateAndShared)
+        );
+    }
+
+    #[test]
+    fn permits_works() {
+        let flags = flags![read, execute];
+        assert!(flags.permits(AccessType::Read));
+        assert!(!flags.permits(AccessType::Write));
+        assert!(flags.permits(AccessType::Execute));
+
+        let cow = flags![read, write, user, cow];
+        assert!(cow.permits(AccessType::Write));
+        assert!(cow.needs_copy(AccessType::Write));
+        assert!(!cow.needs_copy(AccessType::Read));
+        assert!(!flags![read, write].needs_copy(AccessType::Write));
+    }
+
+    #[test]
+    fn get_source_for_addr_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = ProxyDs::<16>::new();
+        space
+            .map(&source)
+            .length(40)
+            .at(40)
+            .offset(4)
+            .flags(flags![read])
+            .commit()?;
+
+        let (_, offset) = space.get_source_for_addr(65, AccessType::Read)?;
+        assert_eq!(offset, 29);
+        assert_eq!(
+            space
+                .get_source_for_addr(65, AccessType::Write)
+                .map(|(_, o)| o),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            space
+                .get_source_for_addr(80, AccessType::Read)
+                .map(|(_, o)| o),
+            Err(AsError::NotMapped)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn flags_display_works() {
+        use std::string::ToString;
+
+        for (flags, string) in [
+            (Flags::default(), "----"),
+            (flags![read, write, private], "rw-p"),
+            (flags![read, execute, shared], "r-xs"),
+            (flags![read, write, user, cow, private, dirty], "rw-pucd"),
+            (flags![write, global, no_cache, accessed], "-w--gna"),
+        ] {
+            assert_eq!(flags.to_string(), string);
+            assert_eq!(string.parse(), Ok(flags));
         }
 
-        /// Validate that the `FlagBuilder` represents valid flags.
-        ///
-        /// # Panics
-        /// If the `FlagBuilder` represents invalid flags.
-        #[must_use]
-        pub fn validate(self) -> Flags {
-            assert!(!(self.private && self.shared));
-            Flags {
-                read: self.read,
-                write: self.write,
-                execute: self.execute,
-                cow: self.cow,
-                private: self.private,
-                shared: self.shared,
-            }
+        assert_eq!(
+            "r-x-ag".parse(),
+            Ok(flags![read, execute, global, accessed])
+        );
+        for bad in ["", "rw", "wr-p", "rw-pq", "rw-puu", "rw-p "] {
+            assert_eq!(bad.parse::<Flags>(), Err(FlagError::Syntax));
         }
+        assert_eq!("rw-pc".parse::<Flags>(), Err(FlagError::KernelCow));
+    }
 
-        flag_toggle!(read, toggle_read, set_read);
-        flag_toggle!(write, toggle_write, set_write);
-        flag_toggle!(execute, toggle_execute, set_execute);
-        flag_toggle!(cow, toggle_cow, set_cow);
-        flag_toggle!(private, toggle_private, set_private);
-        flag_toggle!(shared, toggle_shared, set_shared);
+    #[test]
+    fn mapping_info_display_works() -> Result<(), AsError> {
+        use std::string::ToString;
 
-        /// Combine two `FlagBuilder`s by boolean or-ing each of their flags.
-        ///
-        /// This is, somewhat counter-intuitively, named `and`, so that the following code reads
-        /// correctly:
-        ///
-        /// ```
-        /// # use reedos_address_space::Flags;
-        /// let read = Flags::read();
-        /// let execute = Flags::execute();
-        /// let new = read.and(execute);
-        /// assert_eq!(new, Flags::build().toggle_read().toggle_execute());
-        /// ```
-        #[must_use]
-        pub const fn and(self, other: Self) -> Self {
-            let read = self.read || other.read;
-            let write = self.write || other.write;
-            let execute = self.execute || other.execute;
-            let cow = self.cow || other.cow;
-            let private = self.private || other.private;
-            let shared = self.shared || other.shared;
+        let proxy = ProxyDs::<16>::new();
+        let zero = crate::sources::ZeroSource;
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        space.map(&proxy).length(30).at(40).offset(4).commit()?;
+        space.map(&zero).length(20).at(100).commit()?;
 
-            Self {
-                read,
-                write,
-                execute,
-                cow,
-                private,
-                shared,
+        let lines: std::vec::Vec<_> = space
+            .mappings_in_range(0, usize::MAX)
+            .map(|m| m.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "00000028-00000050 ---- 00000004 <anon>",
+                "00000064-00000078 ---- 00000000 <zero>",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_xor_execute_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let mut strict = AddressSpace::<20, 20>::new("strict space").with_write_xor_execute(true);
+        let source = ProxyDs::<16>::new();
+
+        // Only enforced when asked for.
+        let jit = space.add_mapping(&source, 20, flags![read, write, execute])?;
+        assert!(!space.write_xor_execute());
+        assert!(strict.write_xor_execute());
+
+        assert_eq!(
+            strict.add_mapping(&source, 20, flags![read, write, execute]),
+            Err(AsError::WriteAndExecute)
+        );
+        let code = strict.add_mapping(&source, 20, flags![read, execute])?;
+        assert_eq!(
+            strict.protect(code, flags![read, write, execute]),
+            Err(AsError::WriteAndExecute)
+        );
+        strict.protect(code, flags![read, write])?;
+        assert_eq!(
+            space.share_mapping(&mut strict, jit.addr()),
+            Err(AsError::WriteAndExecute)
+        );
+        strict.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_bounds_work() -> Result<(), AsError> {
+        struct Bounded(ProxyDs<16>);
+        impl DataSource for Bounded {
+            fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+                self.0.read(offset, length, buffer)
+            }
+            fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+                self.0.write(offset, length, buffer)
+            }
+            fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+                self.0.flush(offset, length)
+            }
+            fn len(&self) -> Option<usize> {
+                Some(16)
             }
         }
 
-        /// Turn off all flags in self that are on in other.
-        ///
-        /// You can think of this as `self &! other` on each field.
-        ///
-        /// ```
-        /// # use reedos_address_space::Flags;
-        /// let read_execute = Flags::read().toggle_execute();
-        /// let execute = Flags::execute();
-        /// let new = read_execute.but_not(execute);
-        /// assert_eq!(new, Flags::build().toggle_read());
-        /// ```
-        #[must_use]
-        pub const fn but_not(self, other: Self) -> Self {
-            let read = self.read && !other.read;
-            let write = self.write && !other.write;
-            let execute = self.execute && !other.execute;
-            let cow = self.cow && !other.cow;
-            let private = self.private && !other.private;
-            let shared = self.shared && !other.shared;
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let source = Bounded(ProxyDs::new());
 
-            Self {
-                read,
-                write,
-                execute,
-                cow,
-                private,
-                shared,
+        // The rest of the last page may extend past the end of the source.
+        let handle = space.map(&source).length(12).offset(4).commit()?;
+        assert_eq!(
+            space.map(&source).length(13).offset(4).commit(),
+            Err(AsError::BeyondSource)
+        );
+        assert_eq!(
+            space.add_mapping(&source, 17, flags![read]),
+            Err(AsError::BeyondSource)
+        );
+        assert_eq!(space.resize_mapping(handle, 13), Err(AsError::BeyondSource));
+        space.resize_mapping(handle, 8)?;
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn uncacheable_source_needs_no_cache() -> Result<(), AsError> {
+        #[repr(align(8))]
+        struct Registers([u8; 8]);
+
+        let mut registers = Registers([0; 8]);
+        // SAFETY: the registers are valid and aligned, and outlive the source.
+        let source = unsafe { crate::sources::MmioSource::new(registers.0.as_mut_ptr(), 8) };
+        let mut space = AddressSpace::<20, 20>::new("test space");
+
+        assert_eq!(
+            space.add_mapping(&source, 8, flags![read, write]),
+            Err(AsError::MustNotCache)
+        );
+        space.add_mapping(&source, 8, flags![read, write, no_cache])?;
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_capabilities_are_enforced() -> Result<(), AsError> {
+        let rom = crate::sources::SliceSource::new(&[0; 64]);
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let mut other = AddressSpace::<20, 20>::new("other space");
+
+        assert_eq!(
+            space.add_mapping(&rom, 64, flags![read, write, shared]),
+            Err(AsError::Unsupported)
+        );
+        let handle = space
+            .map(&rom)
+            .length(64)
+            .flags(flags![read, write, private])
+            .commit()?;
+        assert_eq!(
+            space.share_mapping(&mut other, handle.addr),
+            Err(AsError::Unsupported)
+        );
+        assert_eq!(
+            space.protect(handle, flags![read, write]),
+            Err(AsError::Unsupported)
+        );
+        space.protect(handle, flags![read, execute])?;
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn shared_sources_work() -> Result<(), AsError> {
+        use alloc::sync::Arc;
+
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let mut other = AddressSpace::<20, 20>::new("other space");
+        let source = Arc::new(crate::sources::SliceSource::new(&[7; 64]));
+        let handle = space.add_mapping(Arc::clone(&source), 64, flags![read])?;
+        space.share_mapping(&mut other, handle.addr)?;
+        assert_eq!(Arc::strong_count(&source), 3);
+        drop(source);
+
+        let (source, offset) = other.get_source_for_addr(20, AccessType::Read)?;
+        let mut buffer = [0; 4];
+        source.read(offset, 4, &mut buffer).expect("in bounds");
+        assert_eq!(buffer, [7; 4]);
+        space.assert_valid();
+
+        Ok(())
+    }
+
+    #[test]
+    fn downcasting_sources_works() -> Result<(), AsError> {
+        use crate::sources::{SliceSource, SparseSource, ZeroSource};
+
+        let sparse = SparseSource::<1, 20>::new(20);
+        let slice = SliceSource::new(&[0; 20]);
+        let mut space = AddressSpace::<20, 20>::new("test space");
+        let addr = space.add_mapping(&sparse, 20, flags![read])?.addr();
+        let slice_addr = space.add_mapping(&slice, 20, flags![read])?.addr();
+
+        let (source, _) = space.get_source_for_addr(addr, AccessType::Read)?;
+        assert!(source.downcast_ref::<SparseSource<1, 20>>().is_some());
+        assert!(source.downcast_ref::<ZeroSource>().is_none());
+        // Sources that borrow data can't be `Any`.
+        let (source, _) = space.get_source_for_addr(slice_addr, AccessType::Read)?;
+        assert!(source.as_any().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn address_spaces_share_cached_pages() -> Result<(), AsError> {
+        use crate::cacher::SourceId;
+        use crate::sources::SliceSource;
+
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut cache = PageCache::<4, 16>::new();
+        let mut first = AddressSpace::<20, 16>::new("first");
+        let mut second = AddressSpace::<20, 16>::new("second");
+        let first_addr = first.add_mapping(&source, 64, flags![read])?.addr();
+        let second_addr = second
+            .map(&source)
+            .length(32)
+            .offset(32)
+            .flags(flags![read, no_cache])
+            .commit()?
+            .addr();
+
+        let (page, offset) = first.cached_page(&mut cache, first_addr + 35, AccessType::Read)?;
+        assert_eq!(page[offset], 35);
+        assert!(cache.contains(SourceId::of(&source), 2));
+        assert_eq!(
+            second.cached_page(&mut cache, second_addr, AccessType::Read),
+            Err(AsError::MustNotCache)
+        );
+        second.protect(
+            second.handle_at(second_addr).ok_or(AsError::NotMapped)?,
+            flags![read],
+        )?;
+
+        // The second space finds the page the first read in.
+        let (page, offset) = second.cached_page(&mut cache, second_addr + 3, AccessType::Read)?;
+        assert_eq!(page[offset], 35);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            first.cached_page(&mut cache, first_addr, AccessType::Write),
+            Err(AsError::PermissionDenied)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn handle_fault_works() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let addr = space
+            .map(&source)
+            .length(32)
+            .offset(64)
+            .flags(flags![read, write, private])
+            .commit()?
+            .addr();
+        let cow_addr = space
+            .add_mapping(&source, 16, flags![read, write, user, cow, private])?
+            .addr();
+
+        assert_eq!(
+            space.handle_fault(addr + 20, AccessType::Read)?,
+            FaultResolution::Map {
+                page: addr + 16,
+                offset: 80,
+                flags: flags![read, write, private, accessed],
+            }
+        );
+        assert_eq!(
+            space.handle_fault(addr, AccessType::Execute)?,
+            FaultResolution::Deliver(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            space.handle_fault(0, AccessType::Read)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
+
+        // Writes to copy-on-write mappings copy the page, and reads don't.
+        assert!(matches!(
+            space.handle_fault(cow_addr + 1, AccessType::Read)?,
+            FaultResolution::Map { .. }
+        ));
+        assert_eq!(
+            space.handle_fault(cow_addr + 1, AccessType::Write)?,
+            FaultResolution::Copy {
+                page: cow_addr,
+                offset: 0,
+                flags: flags![read, write, user, private, accessed, dirty],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn demand_paging_works() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
+
+        let data: [u8; 40] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let handle = space
+            .map(&source)
+            .length(20)
+            .offset(8)
+            .flags(flags![read])
+            .commit()?;
+        let addr = handle.addr();
+        assert_eq!(space.resident_pages(handle)?, 0);
+
+        // Only the requested length of the mapping is read.
+        let mut frame = [0xff; 16];
+        assert!(space.populate_page(addr + 17, &mut frame)?);
+        assert_eq!(frame[..4], data[24..28]);
+        assert_eq!(frame[4..], [0; 12]);
+        assert!(!space.populate_page(addr + 16, &mut frame)?);
+        assert!(space.is_resident(addr + 31) && !space.is_resident(addr));
+        assert_eq!(space.resident_pages(handle)?, 1);
+        assert_eq!(space.populate_page(0, &mut frame), Err(AsError::NotMapped));
+
+        // Unmapped pages stop being resident.
+        space.populate_page(addr, &mut frame)?;
+        assert_eq!(frame, data[8..24]);
+        space.resize_mapping(handle, 16)?;
+        assert_eq!(space.resident_pages(handle)?, 1);
+        space.remove_mapping(handle)?;
+        assert!(!space.is_resident(addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_cow_works() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
+
+        let data: [u8; 24] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut memory = [0xff; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let flags = flags![read, write, user, cow, private];
+        let addr = space.add_mapping(&source, 24, flags)?.addr();
+        let read_only = space.add_mapping(&source, 16, flags![read])?.addr();
+
+        assert!(matches!(
+            space.handle_fault(addr + 16, AccessType::Write)?,
+            FaultResolution::Copy { .. }
+        ));
+        let frame = space.resolve_cow(addr + 17, &mut frames)?;
+        assert_eq!(frames.frame_data(frame)[..8], data[16..]);
+        assert_eq!(frames.frame_data(frame)[8..], [0; 8]);
+        assert_eq!(space.resolve_cow(addr + 16, &mut frames)?, frame);
+        assert_eq!(
+            space.resolve_cow(read_only, &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+
+        // From now on, the page maps the copy, writably.
+        assert_eq!(
+            space.handle_fault(addr + 16, AccessType::Read)?,
+            FaultResolution::MapFrame {
+                page: addr + 16,
+                frame,
+                flags: flags![read, write, user, private, accessed, dirty],
             }
+        );
+
+        // The copy is freed once it's unmapped and the kernel says so.
+        space.unmap_range(addr, 32)?;
+        assert_eq!(frames.free_frames(), 3);
+        assert_eq!(space.free_released_frames(&mut frames), 1);
+        assert_eq!(frames.free_frames(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn released_frames_are_bounded() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<16, 16, 16, 3>::new("test space");
+        let flags = flags![read, user];
+
+        // Each mapping releases two frames, so only one's fit before the kernel frees them.
+        let first = space.add_mapping(&source, 32, flags)?;
+        let second = space.add_mapping(&source, 32, flags)?;
+        for page in [first.addr(), first.addr() + 16] {
+            space.fault_in(page, AccessType::Read, &mut frames)?;
+        }
+        space.remove_mapping(first)?;
+        for page in [second.addr(), second.addr() + 16] {
+            space.fault_in(page, AccessType::Read, &mut frames)?;
         }
+        assert_eq!(space.remove_mapping(second), Err(AsError::OutOfCapacity));
+        assert_eq!(
+            space.unmap_range(second.addr(), 32),
+            Err(AsError::OutOfCapacity)
+        );
+        assert_eq!(space.resident_pages(second), Ok(2));
+        space.assert_valid();
+        // Releasing just one more still fits.
+        space.resize_mapping(second, 16)?;
+
+        assert_eq!(space.free_released_frames(&mut frames), 3);
+        space.remove_mapping(second)?;
+        assert_eq!(space.free_released_frames(&mut frames), 1);
+        assert_eq!(frames.free_frames(), 4);
+
+        Ok(())
     }
 
-    /// Create a constructor for a `Flags` object.
-    macro_rules! flag_constructor {
-        (
-        $flag:ident
-    ) => {
-            #[doc=concat!("Turn on only the ", stringify!($flag), " flag.")]
-            #[must_use]
-            pub fn $flag() -> FlagBuilder {
-                FlagBuilder {
-                    $flag: true,
-                    ..FlagBuilder::default()
-                }
+    #[test]
+    fn swapping_works() -> Result<(), AsError> {
+        use crate::sources::{SliceSource, SparseSource};
+
+        let data: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut swap = SwapBackend::<_, 2, 16>::new(SparseSource::<2, 16>::new(32))
+            .map_err(AsError::Source)?;
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let flags = flags![read, write, user, cow, private];
+        let addr = space.add_mapping(&source, 32, flags)?.addr();
+
+        // Only private copies can be swapped.
+        assert_eq!(
+            space.swap_out(addr, &mut swap, &mut frames),
+            Err(AsError::NotSwappable)
+        );
+        let frame = space.resolve_cow(addr, &mut frames)?;
+        frames.frame_data(frame)[0] = 99;
+        let slot = space.swap_out(addr, &mut swap, &mut frames)?;
+        assert!(!space.is_resident(addr) && frames.free_frames() == 4);
+        assert_eq!((space.swapped_pages(), swap.free_slots()), (1, 1));
+        assert_eq!(
+            space.handle_fault(addr, AccessType::Read)?,
+            FaultResolution::SwapIn {
+                page: addr,
+                slot,
+                flags: flags![read, write, user, private, accessed],
+            }
+        );
+
+        let frame = space.swap_in(addr, &mut swap, &mut frames)?;
+        assert_eq!(frames.frame_data(frame)[..2], [99, 1]);
+        assert_eq!((space.swapped_pages(), swap.free_slots()), (0, 2));
+        assert!(space.is_resident(addr));
+
+        // Unmapping a swapped out page releases its slot.
+        space.swap_out(addr, &mut swap, &mut frames)?;
+        space.unmap_range(addr, 16)?;
+        assert_eq!(space.free_released_slots(&mut swap), 1);
+        assert_eq!(swap.free_slots(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "alloc"))]
+    fn released_slots_are_bounded() -> Result<(), AsError> {
+        use crate::sources::{SliceSource, SparseSource};
+
+        let data = [7; 16];
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 32];
+        let mut frames = BitmapAllocator::<2, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut swap = SwapBackend::<_, 2, 16>::new(SparseSource::<2, 16>::new(32))
+            .map_err(AsError::Source)?;
+        let mut space = AddressSpace::<16, 16, 16, 1>::new("test space");
+        let flags = flags![read, write, user, cow, private];
+
+        let first = space.add_mapping(&source, 16, flags)?;
+        let second = space.add_mapping(&source, 16, flags)?;
+        space.resolve_cow(first.addr(), &mut frames)?;
+        space.swap_out(first.addr(), &mut swap, &mut frames)?;
+        space.remove_mapping(first)?;
+        space.resolve_cow(second.addr(), &mut frames)?;
+        space.swap_out(second.addr(), &mut swap, &mut frames)?;
+        assert_eq!(space.remove_mapping(second), Err(AsError::OutOfCapacity));
+        assert_eq!(space.swapped_pages(), 1);
+
+        assert_eq!(space.free_released_slots(&mut swap), 1);
+        space.remove_mapping(second)?;
+        assert_eq!(space.free_released_slots(&mut swap), 1);
+        assert_eq!(swap.free_slots(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_usage_works() -> Result<(), AsError> {
+        use crate::sources::{SliceSource, SparseSource};
+
+        let data = [1; 64];
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut swap = SwapBackend::<_, 2, 16>::new(SparseSource::<2, 16>::new(32))
+            .map_err(AsError::Source)?;
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        assert_eq!(space.memory_usage(), MemoryUsage::default());
+        let shared = space.add_mapping(&source, 40, flags![read, user])?.addr();
+        let cow = space
+            .add_mapping(&source, 32, flags![read, write, user, cow, private])?
+            .addr();
+        space.fault_in(shared, AccessType::Read, &mut frames)?;
+        space.fault_in(shared + 16, AccessType::Read, &mut frames)?;
+        space.fault_in(cow, AccessType::Read, &mut frames)?;
+        space.fault_in(cow + 16, AccessType::Write, &mut frames)?;
+        assert_eq!(
+            space.memory_usage(),
+            MemoryUsage {
+                virtual_size: 80,
+                resident: 4,
+                swapped: 0,
+                shared: 2,
+                private: 2,
             }
+        );
+
+        space.swap_out(cow + 16, &mut swap, &mut frames)?;
+        space.unmap_range(shared, 16)?;
+        assert_eq!(
+            space.memory_usage(),
+            MemoryUsage {
+                virtual_size: 64,
+                resident: 2,
+                swapped: 1,
+                shared: 1,
+                private: 1,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits_work() -> Result<(), AsError> {
+        let source = ProxyDs::<64>::new();
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let limits = Limits {
+            virtual_size: Some(64),
+            resident: Some(2),
+            mappings: Some(2),
+        };
+        space.set_limits(limits);
+        assert_eq!(space.limits(), limits);
+
+        let flags = flags![read, write, user, private];
+        let handle = space.add_mapping(&source, 32, flags)?;
+        assert_eq!(
+            space.add_mapping(&source, 48, flags),
+            Err(AsError::LimitExceeded)
+        );
+        let other = space.add_mapping(&source, 32, flags)?;
+        assert_eq!(
+            space.resize_mapping(handle, 48),
+            Err(AsError::LimitExceeded)
+        );
+        space.resize_mapping(other, 16)?;
+        assert_eq!(
+            space.add_mapping(&source, 1, flags),
+            Err(AsError::LimitExceeded)
+        );
+
+        // Faulting in a page that's already resident doesn't count against the limit.
+        let addr = handle.addr();
+        space.fault_in(addr, AccessType::Read, &mut frames)?;
+        space.fault_in(addr + 16, AccessType::Read, &mut frames)?;
+        space.fault_in(addr, AccessType::Write, &mut frames)?;
+        assert_eq!(
+            space.fault_in(other.addr(), AccessType::Read, &mut frames),
+            Err(AsError::LimitExceeded)
+        );
+        assert_eq!(frames.free_frames(), 2);
+
+        // Lifting the limits lets the usage grow again.
+        space.set_limits(Limits::default());
+        space.fault_in(other.addr(), AccessType::Read, &mut frames)?;
+        space.add_mapping(&source, 48, flags)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn page_bits_work() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let addr = space
+            .add_mapping(&source, 64, flags![read, write, user, private])?
+            .addr();
+        let end = addr + 64;
+        let mut frame = [0; 16];
+        for page in [addr, addr + 16, addr + 32] {
+            space.populate_page(page, &mut frame)?;
+        }
+
+        // Only resident pages have their own bits.
+        space.mark_accessed(addr + 5, false)?;
+        space.mark_accessed(addr + 32, true)?;
+        space.mark_accessed(addr + 48, true)?;
+        assert!(space.harvest_accessed(addr, end).eq([addr, addr + 32]));
+        assert!(space.harvest_dirty(addr, end).eq([addr + 32]));
+        assert!(space.harvest_accessed(addr + 16, addr + 32).eq([]));
+
+        assert_eq!(space.clear_accessed_pages(addr, addr + 16), 1);
+        assert!(space.harvest_accessed(addr, end).eq([addr + 32]));
+        assert_eq!(space.clear_accessed_pages(addr, end), 1);
+        assert_eq!(space.harvest_accessed(addr, end).count(), 0);
+        assert!(space.harvest_dirty(addr, end).eq([addr + 32]));
+        assert_eq!(space.clear_dirty_pages(addr, end), 1);
+        assert_eq!(space.harvest_dirty(addr, end).count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fault_stats_work() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let addr = space
+            .add_mapping(&source, 32, flags![read, write, user, cow, private])?
+            .addr();
+
+        let mut frame = [0; 16];
+        space.handle_fault(addr, AccessType::Read)?;
+        space.populate_page(addr, &mut frame)?;
+        space.handle_fault(addr, AccessType::Read)?;
+        space.handle_fault(addr + 16, AccessType::Write)?;
+        space.resolve_cow(addr + 16, &mut frames)?;
+        space.handle_fault(addr + 16, AccessType::Write)?;
+        space.handle_fault(addr, AccessType::Execute)?;
+        space.handle_fault(addr + 32, AccessType::Read)?;
+
+        let stats = FaultStats {
+            minor: 2,
+            major: 1,
+            cow: 1,
+            permission: 1,
+            delegated: 0,
         };
+        assert_eq!(space.fault_stats(), stats);
+        assert_eq!(stats.total(), 5);
+        assert_eq!(space.reset_fault_stats(), stats);
+        assert_eq!(space.fault_stats(), FaultStats::default());
+
+        Ok(())
     }
 
-    /// Access flags for virtual memory.
-    ///
-    /// There are two ways to create a `Flags`:
-    ///
-    /// 1. The `FlagBuilder` type, in particular `Flags::build`, which has public fields and allows
-    ///    dynamic creation of flags.
-    /// 2. The `flags` macro.
-    #[allow(clippy::struct_excessive_bools)]
-    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-    pub struct Flags {
-        read: bool,
-        write: bool,
-        execute: bool,
-        cow: bool,
-        private: bool,
-        shared: bool,
+    #[test]
+    fn populate_mapping_works() -> Result<(), AsError> {
+        use crate::cacher::SourceId;
+        use crate::sources::SliceSource;
+
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut cache = PageCache::<4, 16>::new();
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let handle = space
+            .map(&source)
+            .length(40)
+            .offset(8)
+            .flags(flags![read])
+            .commit()?;
+        let addr = handle.addr();
+
+        let mut frame = [0; 16];
+        space.populate_page(addr, &mut frame)?;
+        assert_eq!(space.populate_mapping(addr, &mut cache), Ok(2));
+        assert_eq!(space.resident_pages(handle), Ok(3));
+        assert_eq!(cache.len(), 3);
+        assert!((0..3).all(|page| cache.contains(SourceId::of(&source), page)));
+        assert_eq!(space.populate_mapping(addr, &mut cache), Ok(0));
+        assert_eq!(
+            space.populate_mapping(addr + 16, &mut cache),
+            Err(AsError::NotMapped)
+        );
+
+        // Populated pages don't take major faults.
+        space.handle_fault(addr + 32, AccessType::Read)?;
+        assert_eq!(space.fault_stats().major, 0);
+
+        // Populating is all or nothing under the resident limit.
+        let other = space.add_mapping(&source, 32, flags![read])?;
+        space.set_limits(Limits {
+            resident: Some(4),
+            ..Limits::default()
+        });
+        assert_eq!(
+            space.populate_mapping(other.addr(), &mut cache),
+            Err(AsError::LimitExceeded)
+        );
+        assert_eq!(space.resident_pages(other), Ok(0));
+
+        Ok(())
     }
 
-    impl Flags {
-        #[must_use]
-        pub fn build() -> FlagBuilder {
-            FlagBuilder::new()
+    #[test]
+    fn fault_handlers_work() -> Result<(), AsError> {
+        use core::cell::Cell;
+
+        struct Lazy {
+            frame: PhysFrame,
+            faults: Cell<usize>,
         }
 
-        /// Convert a `Flags` into a `FlagBuilder`, whose fields can be modified; it will need to be
-        /// re-validated to get back a `Flags` object.
-        #[must_use]
-        pub const fn into_builder(self) -> FlagBuilder {
-            FlagBuilder {
-                read: self.read,
-                write: self.write,
-                execute: self.execute,
-                cow: self.cow,
-                private: self.private,
-                shared: self.shared,
+        impl FaultHandler for Lazy {
+            fn handle_fault(
+                &self,
+                addr: VirtualAddress,
+                _: AccessType,
+                mapping: MappingInfo<'_>,
+            ) -> Result<FaultResolution, AsError> {
+                self.faults.set(self.faults.get() + 1);
+                Ok(FaultResolution::MapFrame {
+                    page: addr - addr % 16,
+                    frame: self.frame,
+                    flags: mapping.flags,
+                })
             }
         }
 
-        flag_constructor!(read);
-        flag_constructor!(write);
-        flag_constructor!(execute);
-        flag_constructor!(cow);
-        flag_constructor!(private);
-        flag_constructor!(shared);
-    }
+        struct Broken;
 
-    /// Create a new `Flag`s object.
-    ///
-    /// ```
-    /// # use reedos_address_space::{Flags, flags};
-    /// assert_eq!(flags![read, write], Flags::build().toggle_read().toggle_write().validate());
-    /// ```
-    #[macro_export]
-    macro_rules! flags [
-    ($($flag:ident),*) => {
-        $crate::address_space::FlagBuilder {
-            $(
-                $flag: true,
-            )*
-            ..$crate::address_space::FlagBuilder::new()
+        impl FaultHandler for Broken {
+            fn handle_fault(
+                &self,
+                _: VirtualAddress,
+                _: AccessType,
+                _: MappingInfo<'_>,
+            ) -> Result<FaultResolution, AsError> {
+                Err(AsError::NoSpace)
             }
-            .validate()
+        }
+
+        let source = crate::sources::ZeroSource;
+        let handler = Lazy {
+            frame: PhysFrame::new(0x1000),
+            faults: Cell::new(0),
         };
-    ];
-}
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let handle = space.add_mapping(&source, 32, flags![read, user])?;
+        let addr = handle.addr();
 
-pub use crate::flags;
-pub use flags::{FlagBuilder, Flags};
+        space.set_fault_handler(addr, Some(&handler))?;
+        assert!(space.has_fault_handler(addr));
+        assert_eq!(
+            space.handle_fault(addr + 20, AccessType::Read)?,
+            FaultResolution::MapFrame {
+                page: addr + 16,
+                frame: PhysFrame::new(0x1000),
+                flags: flags![read, user, accessed],
+            }
+        );
+        // Faults the mapping doesn't permit aren't forwarded.
+        assert_eq!(
+            space.handle_fault(addr, AccessType::Write)?,
+            FaultResolution::Deliver(AsError::PermissionDenied)
+        );
+        assert_eq!(handler.faults.get(), 1);
+        assert_eq!(space.fault_stats().delegated, 1);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::data_source::DsError;
-    use parking_lot::RwLock;
+        // Faults the handler fails to resolve aren't counted.
+        space.set_fault_handler(addr, Some(&Broken))?;
+        assert_eq!(
+            space.handle_fault(addr, AccessType::Read),
+            Err(AsError::NoSpace)
+        );
+        assert_eq!(space.fault_stats().delegated, 1);
 
-    use std::vec::Vec;
+        space.set_fault_handler(addr, None)?;
+        assert!(matches!(
+            space.handle_fault(addr, AccessType::Read)?,
+            FaultResolution::Map { .. }
+        ));
+        space.seal(handle)?;
+        assert_eq!(
+            space.set_fault_handler(addr, Some(&handler)),
+            Err(AsError::Sealed)
+        );
 
-    /// A proxy data soucre for testing.
-    #[derive(Debug)]
-    struct ProxyDs<const CAPACITY: usize> {
-        buffer: RwLock<[u8; CAPACITY]>,
+        // Each distinct handler takes up a slot until no mapping forwards faults to it.
+        let handlers: [Lazy; MAX_FAULT_HANDLERS + 1] = core::array::from_fn(|_| Lazy {
+            frame: PhysFrame::new(0x1000),
+            faults: Cell::new(0),
+        });
+        let mut space = AddressSpace::<64, 16>::new("test space");
+        let addrs = handlers
+            .iter()
+            .map(|_| Ok(space.add_mapping(&source, 16, flags![read, user])?.addr()))
+            .collect::<Result<Vec<_>, AsError>>()?;
+        for (&addr, handler) in addrs.iter().zip(&handlers[..MAX_FAULT_HANDLERS]) {
+            space.set_fault_handler(addr, Some(handler))?;
+        }
+        let last = addrs[MAX_FAULT_HANDLERS];
+        space.set_fault_handler(last, Some(&handlers[0]))?;
+        assert_eq!(
+            space.set_fault_handler(last, Some(&handlers[MAX_FAULT_HANDLERS])),
+            Err(AsError::OutOfCapacity)
+        );
+        space.set_fault_handler(addrs[1], None)?;
+        space.set_fault_handler(last, Some(&handlers[MAX_FAULT_HANDLERS]))?;
+        space.handle_fault(last, AccessType::Read)?;
+        assert_eq!(handlers[MAX_FAULT_HANDLERS].faults.get(), 1);
+        assert_eq!(handlers[1].faults.get(), 0);
+
+        Ok(())
     }
 
-    impl<const CAPACITY: usize> ProxyDs<CAPACITY> {
-        const fn new() -> Self {
-            Self {
-                buffer: RwLock::new([0; CAPACITY]),
+    #[test]
+    fn stacks_grow_down() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let flags = flags![read, write, user, private];
+        space.add_mapping_at(64, &source, 16, flags)?;
+        let stack = space
+            .map(&source)
+            .length(32)
+            .at(160)
+            .offset(64)
+            .flags(flags)
+            .grows_down(96)
+            .commit()?;
+        space
+            .map(&source)
+            .length(16)
+            .at(288)
+            .offset(64)
+            .flags(flags)
+            .grows_down(32)
+            .commit()?;
+
+        assert_eq!(
+            space.handle_fault(150, AccessType::Write)?,
+            FaultResolution::Grew {
+                page: 144,
+                offset: 48,
+                flags: flags![read, write, user, private, accessed, dirty],
             }
-        }
+        );
+        assert!(space.handle_at(144).is_some() && space.entry_for(stack).is_err());
+        assert!(matches!(
+            space.handle_fault(100, AccessType::Read)?,
+            FaultResolution::Grew { page: 96, .. }
+        ));
+        assert_eq!(space.fault_stats().major, 2);
 
-        pub fn assert_eq(&self, other: [u8; CAPACITY]) {
-            assert_eq!(*self.buffer.read(), other);
-        }
+        // Growth keeps the guard gap, and stays within the limit.
+        assert_eq!(
+            space.handle_fault(85, AccessType::Read)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
+        space.set_limits(Limits {
+            resident: Some(0),
+            ..Limits::default()
+        });
+        assert_eq!(
+            space.handle_fault(272, AccessType::Read)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
+        space.set_limits(Limits::default());
+        assert!(matches!(
+            space.handle_fault(272, AccessType::Read)?,
+            FaultResolution::Grew { page: 272, .. }
+        ));
+        assert_eq!(
+            space.handle_fault(260, AccessType::Read)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
+        assert_eq!(
+            space.handle_fault(50, AccessType::Read)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
+        space.assert_valid();
+
+        Ok(())
     }
 
-    impl<const CAPACITY: usize> DataSource for ProxyDs<CAPACITY> {
-        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
-            assert!(offset + length <= CAPACITY);
+    #[test]
+    fn fault_in_works() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
 
-            buffer.copy_from_slice(&self.buffer.read()[offset..offset + length]);
-            Ok(())
-        }
+        let data: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let addr = space
+            .add_mapping(&source, 32, flags![read, write, user, cow, private])?
+            .addr();
 
-        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
-            assert!(offset + length <= CAPACITY);
+        let FaultResolution::MapFrame { frame, flags, .. } =
+            space.fault_in(addr + 20, AccessType::Read, &mut frames)?
+        else {
+            panic!("a read should populate a frame");
+        };
+        assert_eq!(frames.frame_data(frame)[..], data[16..]);
+        assert_eq!(flags, flags![read, write, user, cow, private, accessed]);
+        assert!(space.is_resident(addr + 16));
+        assert_eq!(
+            space.fault_in(addr + 16, AccessType::Read, &mut frames)?,
+            FaultResolution::MapFrame {
+                page: addr + 16,
+                frame,
+                flags,
+            }
+        );
 
-            self.buffer.write()[offset..offset + length].copy_from_slice(buffer);
-            Ok(())
-        }
+        // A write copies the page, releasing the frame it was populated into.
+        let FaultResolution::MapFrame { frame: copy, .. } =
+            space.fault_in(addr + 16, AccessType::Write, &mut frames)?
+        else {
+            panic!("a write should copy the page");
+        };
+        assert_ne!(copy, frame);
+        assert_eq!(space.free_released_frames(&mut frames), 1);
+        assert_eq!(
+            space.fault_in(addr + 32, AccessType::Read, &mut frames)?,
+            FaultResolution::Deliver(AsError::NotMapped)
+        );
 
-        fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
-            assert!(offset + length <= CAPACITY);
+        space.unmap_range(addr, 32)?;
+        assert_eq!(space.free_released_frames(&mut frames), 1);
+        assert_eq!(frames.free_frames(), 4);
 
-            self.buffer.write()[offset..offset + length].fill(0);
-            Ok(())
-        }
+        Ok(())
     }
 
     #[test]
-    fn proxy_ds_works() -> Result<(), DsError> {
-        const TEST_DS_CAPACITY: usize = 32;
+    fn tlb_maintenance_works() -> Result<(), AsError> {
+        use core::cell::RefCell;
 
-        let ds = ProxyDs::<32>::new();
+        #[derive(Debug, PartialEq, Eq)]
+        enum Invalidation {
+            Page(usize),
+            Asid,
+            All,
+        }
 
-        // write all 1s
-        ds.write(0, 32, &[1; 32])?;
-        ds.assert_eq([1; 32]);
+        #[derive(Default)]
+        struct Recorder(RefCell<Vec<Invalidation>>);
 
-        // read 15 bytes from the middle
-        let mut buffer = [0; 15];
-        ds.read(10, 15, &mut buffer)?;
-        assert_eq!(buffer, [1; 15]);
+        impl TlbMaintenance for Recorder {
+            fn invalidate_page(&self, _: Asid, vaddr: usize) {
+                self.0.borrow_mut().push(Invalidation::Page(vaddr));
+            }
 
-        // write from 0 to 31
-        let mut write_from = [0u8; 32];
-        for i in 0..32 {
-            write_from[i as usize] = i;
+            fn invalidate_asid(&self, _: Asid) {
+                self.0.borrow_mut().push(Invalidation::Asid);
+            }
+
+            fn invalidate_all(&self) {
+                self.0.borrow_mut().push(Invalidation::All);
+            }
         }
-        ds.write(0, 32, &write_from)?;
-        ds.assert_eq(write_from);
 
-        // read 12 bytes from the middle
-        let mut read_into = [0; 12];
-        ds.read(13, 12, &mut read_into)?;
-        assert_eq!(read_into, write_from[13..25]);
+        let source = crate::sources::ZeroSource;
+        let tlb = Recorder::default();
+        let mut asids = AsidAllocator::<4>::new();
+        let mut space = AddressSpace::<100, 16>::new("test space").with_tlb_maintenance(&tlb);
+        let small = space.add_mapping(&source, 48, flags![read, write, private])?;
+        let big = space.add_mapping(&source, 16 * (TLB_FLUSH_PAGES + 1), flags![read])?;
+
+        // Without an ASID, everything goes.
+        space.protect(small, flags![read])?;
+        assert_eq!(tlb.0.take(), [Invalidation::All]);
+
+        space.refresh_asid(&mut asids);
+        space.resize_mapping(small, 16)?;
+        let addr = small.addr();
+        assert_eq!(
+            tlb.0.take(),
+            [Invalidation::Page(addr + 16), Invalidation::Page(addr + 32)]
+        );
+        // Only the mapped part of the range is invalidated.
+        space.unmap_range(addr, 32)?;
+        assert_eq!(tlb.0.take(), [Invalidation::Page(addr)]);
+        space.remove_mapping(big)?;
+        assert_eq!(tlb.0.take(), [Invalidation::Asid]);
 
         Ok(())
     }
 
     #[test]
-    fn constructor() {
-        // Construct an address space with capacity 20.
-        let space = AddressSpace::<1200>::new("my first address space");
-        assert_eq!(space.name, "my first address space");
-    }
+    fn install_into_works() -> Result<(), AsError> {
+        use crate::page_table::Sv39;
+        use crate::sources::SliceSource;
 
-    fn test_add_mapping_once(length: usize) -> Result<(), AsError> {
-        const DS_CAPACITY: usize = 16;
-        const N_PAGES: usize = 1200;
-        const PAGE_SIZE: usize = 20;
+        let data: [u8; 8192] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut table_memory = [0; 4 * 4096];
+        let tables = BitmapAllocator::<4>::new(PhysFrame::new(0x8000_0000), &mut table_memory);
+        let mut pt = Sv39::new(tables)?;
+        let mut memory = [0; 4 * 4096];
+        let mut frames = BitmapAllocator::<4>::new(PhysFrame::new(0x9000_0000), &mut memory);
+        let mut space = AddressSpace::<20>::new("test space");
+        let addr = space
+            .add_mapping(&source, 8192, flags![read, write, user, cow, private])?
+            .addr();
+        let none = space.add_mapping(&source, 4096, flags![])?.addr();
 
-        let mut space = AddressSpace::<N_PAGES, PAGE_SIZE>::new("test space");
-        let source = ProxyDs::<DS_CAPACITY>::new();
+        let FaultResolution::MapFrame { frame, .. } =
+            space.fault_in(addr, AccessType::Read, &mut frames)?
+        else {
+            panic!("a read should populate a frame");
+        };
+        let mut cache = PageCache::<2, 4096>::new();
+        space.populate_mapping(none, &mut cache)?;
+        assert_eq!(space.install_into(&mut pt, &mut frames)?, 1);
+        // Copy-on-write pages are mapped read-only.
+        assert_eq!(pt.query(addr), Some((frame, flags![read, user, accessed])));
+        assert_eq!(pt.query(none), None);
+        assert_eq!(space.sync_into(&mut pt, &mut frames)?, 0);
 
-        let addr = space.add_mapping(&source, length)?;
+        let FaultResolution::MapFrame { frame: copy, .. } =
+            space.fault_in(addr + 4096, AccessType::Write, &mut frames)?
+        else {
+            panic!("a write should copy the page");
+        };
+        space.unmap_range(addr, 4096)?;
+        assert_eq!(space.sync_into(&mut pt, &mut frames)?, 2);
+        assert_eq!(pt.query(addr), None);
+        assert_eq!(
+            pt.query(addr + 4096),
+            Some((copy, flags![read, write, user, accessed, dirty]))
+        );
 
-        space.assert_valid();
+        Ok(())
+    }
 
-        assert_ne!(addr, 0);
-        assert!(!space.mappings.is_empty());
+    #[test]
+    fn identity_mapping_works() -> Result<(), AsError> {
+        use crate::page_table::Sv39;
 
-        let mapping = space.mappings.first().expect("source was mapped");
+        let source = crate::sources::ZeroSource;
+        let mut table_memory = [0; 4 * 4096];
+        let tables = BitmapAllocator::<4>::new(PhysFrame::new(0x8000_0000), &mut table_memory);
+        let mut pt = Sv39::new(tables)?;
+        let mut space = AddressSpace::<20>::identity_mapped(
+            "boot",
+            &source,
+            0x4000,
+            5000,
+            flags![read, execute, global],
+            &mut pt,
+        )?;
+        for page in [0x4000, 0x5000] {
+            assert_eq!(
+                pt.query(page),
+                Some((PhysFrame::new(page), flags![read, execute, global]))
+            );
+            assert_eq!(
+                space.handle_fault(page, AccessType::Execute)?,
+                FaultResolution::MapFrame {
+                    page,
+                    frame: PhysFrame::new(page),
+                    flags: flags![read, execute, global, accessed],
+                }
+            );
+        }
+        assert_eq!(
+            AddressSpace::<20>::new("test space").add_identity_mapping(
+                0x4800,
+                &source,
+                1,
+                flags![]
+            ),
+            Err(AsError::Unaligned)
+        );
 
-        assert_eq!(mapping.addr, addr);
-        assert_eq!(mapping.length, length);
-        // TODO: check DS equality
+        // The frames aren't the space's to free.
+        space.unmap_range(0x4000, 8192)?;
+        assert_eq!(space.free_released_frames(&mut NoFrames), 0);
+        assert_eq!(space.sync_into(&mut pt, &mut NoFrames)?, 2);
+        assert_eq!(pt.query(0x4000), None);
 
         Ok(())
     }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn add_mapping_once_works() -> Result<(), AsError> {
-        test_add_mapping_once(1)?;
-        test_add_mapping_once(200)?;
+    fn huge_pages_work() -> Result<(), AsError> {
+        // Two huge pages' worth of small pages is too big for fixed storage on the test stack.
+        use crate::page_table::Sv39;
+        const HUGE: usize = 1 << 21;
+
+        let source = crate::sources::ZeroSource;
+        let mut table_memory = [0; 4 * 4096];
+        let tables = BitmapAllocator::<4>::new(PhysFrame::new(0x8000_0000), &mut table_memory);
+        let mut pt = Sv39::new(tables)?;
+        let flags = flags![read, execute];
+        let mut space =
+            AddressSpace::<1536>::identity_mapped("boot", &source, HUGE, HUGE, flags, &mut pt)?;
+        let small = space.add_mapping(&source, 4096, flags)?.addr();
+        assert_eq!(pt.frames().free_frames(), 1);
+
+        assert!(space.promote(HUGE + 0x1234, &mut pt)?);
+        // The last-level table is freed.
+        assert_eq!(pt.frames().free_frames(), 2);
+        assert_eq!(
+            pt.query(HUGE + 0x5000),
+            Some((PhysFrame::new(HUGE + 0x5000), flags))
+        );
+        assert!(!space.promote(small, &mut pt)?);
+        assert_eq!(space.promote(0, &mut pt), Err(AsError::NotMapped));
+        assert_eq!(space.sync_into(&mut pt, &mut NoFrames)?, 0);
+
+        // Unmapping part of it splits it again.
+        space.unmap_range(HUGE, 4096)?;
+        assert_eq!(space.sync_into(&mut pt, &mut NoFrames)?, 1);
+        assert_eq!(pt.query(HUGE), None);
+        assert_eq!(
+            pt.query(HUGE + 0x5000),
+            Some((PhysFrame::new(HUGE + 0x5000), flags))
+        );
+        assert!(!space.promote(HUGE + 0x5000, &mut pt)?);
 
         Ok(())
     }
 
     #[test]
-    fn add_mapping_works() -> Result<(), AsError> {
-        const N_ADDRS: usize = 100;
-        const DS_CAPACITY: usize = 16;
-        const N_PAGES: usize = 1200;
-        const PAGE_SIZE: usize = 20;
+    fn mmap_works() -> Result<(), AsError> {
+        let data = [7; 8192];
+        let source = crate::sources::SliceSource::new(&data);
+        let mut space = AddressSpace::<20>::new("test space");
+        let anonymous = MmapRequest {
+            length: 100,
+            prot: Flags::PROT_READ | Flags::PROT_WRITE,
+            flags: Flags::MAP_PRIVATE | Flags::MAP_ANONYMOUS,
+            ..MmapRequest::default()
+        };
+        let addr = space.mmap(anonymous.clone())?;
+        let m = space.mappings_in_range(addr, addr + 1).next();
+        assert!(m
+            .is_some_and(|m| m.flags == flags![read, write, user, cow, private]
+                && m.source.name() == "<zero>"));
 
-        let mut space = AddressSpace::<N_PAGES, PAGE_SIZE>::new("test space");
-        let source = ProxyDs::<DS_CAPACITY>::new();
+        // Hints are followed if there's room.
+        let hinted = MmapRequest {
+            addr: 0x8123,
+            ..anonymous.clone()
+        };
+        assert_eq!(space.mmap(hinted.clone())?, 0x8000);
+        assert_ne!(space.mmap(hinted)?, 0x8000);
 
-        let mut addrs = Vec::new();
+        // Fixed mappings replace what's there.
+        let file = MmapRequest {
+            addr: 0x8000,
+            length: 4096,
+            prot: Flags::PROT_READ,
+            flags: Flags::MAP_SHARED | Flags::MAP_FIXED,
+            source: Some((&source).into()),
+            offset: 4096,
+        };
+        assert_eq!(space.mmap(file.clone())?, 0x8000);
+        let m = space.mappings_in_range(0x8000, 0x8001).next();
+        assert!(m.is_some_and(|m| m.offset == 4096 && m.flags == flags![read, user, shared]));
 
-        for l in 1..=N_ADDRS {
-            addrs.push(space.add_mapping(&source, l)?);
-            space.assert_valid();
+        for (req, err) in [
+            (
+                MmapRequest {
+                    flags: Flags::MAP_SHARED | Flags::MAP_PRIVATE,
+                    ..file.clone()
+                },
+                AsError::InvalidRequest,
+            ),
+            (
+                MmapRequest {
+                    source: None,
+                    ..file.clone()
+                },
+                AsError::InvalidRequest,
+            ),
+            (
+                MmapRequest {
+                    offset: 100,
+                    ..file.clone()
+                },
+                AsError::Unaligned,
+            ),
+            (
+                MmapRequest {
+                    addr: 0x8100,
+                    ..file.clone()
+                },
+                AsError::Unaligned,
+            ),
+            (
+                MmapRequest {
+                    prot: Flags::PROT_WRITE,
+                    flags: Flags::MAP_SHARED | Flags::MAP_ANONYMOUS,
+                    ..anonymous
+                },
+                AsError::Unsupported,
+            ),
+        ] {
+            assert_eq!(space.mmap(req), Err(err));
         }
 
-        // Assert none are 0.
-        assert!(!addrs.iter().any(|&n| n == 0));
+        Ok(())
+    }
 
-        // Assert all are distinct.
-        assert!(addrs.len() == N_ADDRS);
+    #[test]
+    fn core_dumps_work() -> Result<(), AsError> {
+        use crate::sources::{CellSliceSource, SliceSource, ZeroSource};
+        use core::cell::Cell;
 
-        // assert_eq!(mapping.addr, addr);
-        // assert_eq!(mapping.length, length);
-        // TODO: check DS equality
+        let data = [7; 4096];
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let file = space.add_mapping(&source, 20, flags![read])?.addr();
+        let anon = space
+            .add_mapping(&ZeroSource, 32, flags![read, write, private])?
+            .addr();
+        let mut page = [0; 16];
+        space.populate_page(file + 16, &mut page)?;
+        let FaultResolution::MapFrame { frame, .. } =
+            space.fault_in(anon, AccessType::Write, &mut frames)?
+        else {
+            panic!("a write should populate a frame");
+        };
+        frames.frame_data(frame).fill(9);
+
+        let mut dump = [0; 512];
+        let sink = CellSliceSource::new(Cell::from_mut(&mut dump[..]).as_slice_of_cells());
+        let written = space.write_core_dump(&sink, &mut frames)?;
+        let number = |at: usize| u64::from_le_bytes(dump[at..at + 8].try_into().expect("8 bytes"));
+        assert_eq!(&dump[..8], b"COREDUMP");
+        assert_eq!((number(8), number(16)), (16, 2));
+
+        // The file mapping's second page only has 4 bytes of data.
+        let fields: [u64; 6] = core::array::from_fn(|i| number(24 + 8 * i));
+        assert_eq!(
+            fields,
+            [file as u64, 32, 0, flags![read].bits().into(), 1, 6]
+        );
+        assert_eq!(&dump[72..78], b"<anon>");
+        assert_eq!(number(78), file as u64 + 16);
+        assert_eq!(
+            dump[86..102],
+            [7, 7, 7, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // The anonymous mapping's page is read from its frame.
+        assert_eq!(number(102), anon as u64);
+        assert_eq!(number(102 + 32), 1);
+        assert_eq!(&dump[150..156], b"<zero>");
+        assert_eq!(number(156), anon as u64);
+        assert_eq!(dump[164..180], [9; 16]);
+        assert_eq!(written, 180);
 
         Ok(())
     }
 
     #[test]
-    fn add_mapping_at_works() -> Result<(), AsError> {
-        let mut space = AddressSpace::<6, 20>::new("test space");
-        let source = ProxyDs::<16>::new();
+    fn user_copies_work() -> Result<(), AsError> {
+        use crate::sources::{CellSliceSource, SliceSource, ZeroSource};
+        use core::cell::Cell;
 
-        space.mappings.insert(MapEntry {
-            addr: 20,
-            length: 20,
-            source: Some(&source),
-        });
+        let mut data = [5; 32];
+        let shared = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let file_data = [3; 16];
+        let file = SliceSource::new(&file_data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        space
+            .map(&shared)
+            .at(0x100)
+            .length(32)
+            .flags(flags![read, write, user, shared])
+            .guard(0)
+            .commit()?;
+        space
+            .map(&ZeroSource)
+            .at(0x120)
+            .length(32)
+            .flags(flags![read, write, user, private])
+            .guard(0)
+            .commit()?;
 
-        let addr = 60;
-        let length = 20;
+        // Writes go to the shared source, and to a new frame for the private page.
+        let bytes: [u8; 16] = core::array::from_fn(|i| i as u8 + 1);
+        space.write_bytes(0x118, &bytes, flags![write, user], &mut frames)?;
+        assert_eq!(frames.free_frames(), 3);
+        let mut buffer = [0; 40];
+        space.read_bytes(0x110, &mut buffer, flags![read, user], &mut frames)?;
+        assert_eq!(buffer[..8], [5; 8]);
+        assert_eq!(buffer[8..24], bytes);
+        assert_eq!(buffer[24..], [0; 16]);
+        assert_eq!(data[24..], bytes[..8]);
 
-        space.add_mapping_at(addr, &source, length)?;
-        let mapping = space.mappings.iter().nth(1).expect("second mapping exists");
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let cow = space
+            .add_mapping(&file, 16, flags![read, write, user, cow, private])?
+            .addr();
+        space.write_bytes(cow + 4, &[9; 4], flags![write], &mut frames)?;
+        let mut buffer = [0; 12];
+        space.read_bytes(cow, &mut buffer, flags![], &mut frames)?;
+        assert_eq!(buffer, [3, 3, 3, 3, 9, 9, 9, 9, 3, 3, 3, 3]);
+        assert_eq!(file_data, [3; 16]);
 
-        assert_eq!(mapping.addr, addr);
-        assert_eq!(mapping.length, length);
-        space.assert_valid();
+        assert_eq!(
+            space.read_bytes(cow + 8, &mut buffer, flags![], &mut frames),
+            Err(AsError::NotMapped)
+        );
+        assert_eq!(
+            space.read_bytes(cow, &mut buffer, flags![execute], &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            space.read_bytes(usize::MAX, &mut buffer, flags![], &mut frames),
+            Err(AsError::Overflow)
+        );
 
         Ok(())
     }
 
     #[test]
-    fn add_mapping_at_err_works() {
-        let mut space = AddressSpace::<10, 20>::new("test space");
-        let source = ProxyDs::<16>::new();
+    fn typed_user_copies_work() -> Result<(), AsError> {
+        use crate::sources::ZeroSource;
 
-        space.mappings.insert(MapEntry {
-            addr: 20,
-            length: 20,
-            source: Some(&source),
-        });
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(C)]
+        struct Timespec {
+            sec: i64,
+            nsec: i64,
+        }
+        // SAFETY: two `i64`s, with no padding between them.
+        unsafe impl FromBytes for Timespec {}
 
-        assert!(space.add_mapping_at(20, &source, 20).is_err());
-        space.assert_valid();
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let addr = space
+            .add_mapping(&ZeroSource, 48, flags![read, write, user, private])?
+            .addr();
+        let time = Timespec { sec: -1, nsec: 5 };
+        // The value straddles two pages.
+        space.write_val(addr + 8, &time, flags![write, user], &mut frames)?;
+        assert_eq!(
+            space.read_val(addr + 8, flags![read], &mut frames),
+            Ok(time)
+        );
+        assert_eq!(
+            space.read_val::<[u8; 4]>(addr + 16, flags![], &mut frames),
+            Ok(5_i64.to_ne_bytes()[..4]
+                .try_into()
+                .expect("slice is 4 bytes"))
+        );
+        assert_eq!(
+            space.read_val::<u32>(addr + 32, flags![], &mut frames),
+            Ok(0)
+        );
+
+        assert_eq!(
+            space.read_val::<Timespec>(addr + 4, flags![], &mut frames),
+            Err(AsError::Unaligned)
+        );
+        assert_eq!(
+            space.write_val(addr + 2, &1_u16, flags![execute], &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            space.read_val::<u64>(addr + 48, flags![], &mut frames),
+            Err(AsError::NotMapped)
+        );
+
+        Ok(())
     }
 
     #[test]
-    fn remove_mapping_works() -> Result<(), AsError> {
-        let mut space = AddressSpace::<10, 20>::new("test space");
-        let source = ProxyDs::<16>::new();
+    fn read_cstr_works() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
 
-        space.mappings.insert(MapEntry {
-            addr: 20,
-            length: 20,
-            source: Some(&source),
-        });
+        let data = *b"/bin/sh\0etc/passwd";
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let flags = flags![read, user];
+        let addr = space.add_mapping(&source, 18, flags)?.addr();
+        let mut buffer = [0xff; 32];
+        assert_eq!(
+            space.read_cstr(addr, 32, &mut buffer, flags, &mut frames),
+            Ok(7)
+        );
+        assert_eq!(&buffer[..8], b"/bin/sh\0");
 
-        space.mappings.insert(MapEntry {
-            addr: 60,
-            length: 20,
-            source: Some(&source),
-        });
+        // The string crosses into the next page, where the rest of the mapping reads as zeros.
+        assert_eq!(
+            space.read_cstr(addr + 8, 32, &mut buffer, flags, &mut frames),
+            Ok(10)
+        );
+        assert_eq!(&buffer[..11], b"etc/passwd\0");
+        assert_eq!(
+            space.read_cstr(addr + 8, 10, &mut buffer, flags, &mut frames),
+            Err(AsError::StringTooLong)
+        );
+        assert_eq!(
+            space.read_cstr(addr, 32, &mut buffer[..4], flags, &mut frames),
+            Err(AsError::StringTooLong)
+        );
+        assert_eq!(
+            space.read_cstr(addr + 8, 32, &mut buffer, flags![write], &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            space.read_cstr(addr + 32, 32, &mut buffer, flags, &mut frames),
+            Err(AsError::NotMapped)
+        );
 
-        space.mappings.insert(MapEntry {
-            addr: 100,
-            length: 20,
-            source: Some(&source),
-        });
+        Ok(())
+    }
+
+    #[test]
+    fn copy_from_space_works() -> Result<(), AsError> {
+        use crate::sources::{SliceSource, ZeroSource};
 
-        space.remove_mapping(60)?;
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 4096];
+        let mut frames = BitmapAllocator::<4, 1024>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut debuggee = AddressSpace::<20, 256>::new("debuggee");
+        let from = debuggee
+            .add_mapping(&source, 300, flags![read, user, private])?
+            .addr();
+        let mut debugger = AddressSpace::<20, 256>::new("debugger");
+        let to = debugger
+            .add_mapping(&ZeroSource, 512, flags![read, write, user, private])?
+            .addr();
 
-        assert_eq!(space.mappings.len(), 2);
+        // The copy crosses pages on both sides, and needs more than one chunk.
+        debugger.copy_from_space(
+            to + 100,
+            &debuggee,
+            from + 10,
+            290,
+            flags![user],
+            &mut frames,
+        )?;
+        let mut buffer = [0; 290];
+        debugger.read_bytes(to + 100, &mut buffer, flags![], &mut frames)?;
+        assert_eq!(buffer, data[10..]);
+        assert_eq!(
+            debugger.read_val::<u8>(to + 99, flags![], &mut frames),
+            Ok(0)
+        );
 
-        assert_eq!(space.mappings.first().expect("first exists").addr, 20);
-        assert_eq!(space.mappings.last().expect("last exists").addr, 100);
+        // The debuggee's memory is read-only, so it can't be copied into.
+        assert_eq!(
+            debuggee.copy_from_space(from, &debugger, to, 16, flags![], &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            debugger.copy_from_space(to, &debuggee, from, 16, flags![execute], &mut frames),
+            Err(AsError::PermissionDenied)
+        );
+        assert_eq!(
+            debugger.copy_from_space(to, &debuggee, from + 512, 16, flags![], &mut frames),
+            Err(AsError::NotMapped)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn generation_works() -> Result<(), AsError> {
+        let source = ProxyDs::<16>::new();
+        let mut space = AddressSpace::<20, 16>::new("test space");
+        let mut generation = space.generation();
+        let mut changed = |space: &AddressSpace<20, 16>| {
+            let old = core::mem::replace(&mut generation, space.generation());
+            generation != old
+        };
+
+        let handle = space.add_mapping(&source, 32, flags![read, write])?;
+        assert!(changed(&space));
+        space.protect(handle, flags![read])?;
+        assert!(changed(&space));
+        space.resize_mapping(handle, 16)?;
+        assert!(changed(&space));
+        space.unmap_range(handle.addr(), 16)?;
+        assert!(changed(&space));
+
+        // Nothing else changes it.
+        let handle = space.add_mapping(&source, 16, flags![read])?;
+        assert!(changed(&space));
+        space.mark_accessed(handle.addr(), false)?;
+        space.seal(handle)?;
+        assert!(!space.is_mapped(0));
+        assert!(!changed(&space));
+        assert_eq!(space.protect(handle, flags![]), Err(AsError::Sealed));
+        assert!(!changed(&space));
+
+        Ok(())
+    }
+
+    #[test]
+    fn kernel_regions_are_shared() -> Result<(), AsError> {
+        let source = crate::sources::ZeroSource;
+        let kernel = KernelRegion::<64, 16>::new(512)?;
+        let mut first = AddressSpace::<64, 16>::new("first").with_kernel_region(&kernel)?;
+        let mut second = AddressSpace::<64, 16>::new("second").with_kernel_region(&kernel)?;
+        assert_eq!(first.ceiling(), 512);
+        assert_eq!(
+            first.add_mapping_at(512, &source, 16, flags![read]),
+            Err(AsError::NoSpace)
+        );
+        assert_eq!(
+            kernel.add_mapping(256, &source, 16, flags![read]),
+            Err(AsError::NoSpace)
+        );
+
+        // Changes to the region are seen by every space sharing it.
+        let handle = kernel.add_mapping(528, &source, 32, flags![read, write, private])?;
+        assert!(first.is_mapped(530) && second.is_mapped(550));
+        assert!(!first.contains_range(528, 16));
+        assert_eq!(
+            second.handle_fault(530, AccessType::Write)?,
+            FaultResolution::Map {
+                page: 528,
+                offset: 0,
+                flags: flags![read, write, global, private, accessed, dirty],
+            }
+        );
+        kernel.protect(handle, flags![read, private])?;
+        assert_eq!(
+            first.handle_fault(530, AccessType::Write)?,
+            FaultResolution::Deliver(AsError::PermissionDenied)
+        );
+        assert_eq!(kernel.inspect(|space| space.fault_stats().total()), 2);
+        kernel.remove_mapping(handle)?;
+        assert!(!first.is_mapped(530) && !second.is_mapped(530));
 
         Ok(())
     }
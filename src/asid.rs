@@ -0,0 +1,167 @@
+//! Address-space identifiers (ASIDs), which tag TLB entries with the address space they belong
+//! to, so switching address spaces doesn't require flushing the whole TLB.
+
+/// An address-space identifier, together with the allocator generation it was allocated in.
+///
+/// An `Asid` is only valid while its generation is the allocator's current generation; see
+/// `AsidAllocator::is_current`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Asid {
+    id: usize,
+    generation: usize,
+}
+
+impl Asid {
+    /// Get the raw identifier, suitable for programming into hardware.
+    #[must_use]
+    pub const fn id(self) -> usize {
+        self.id
+    }
+
+    /// Get the allocator generation this `Asid` was allocated in.
+    #[must_use]
+    pub const fn generation(self) -> usize {
+        self.generation
+    }
+}
+
+/// Allocates the `N_ASIDS` hardware ASIDs, recycling them once they run out.
+///
+/// ASID 0 is reserved, e.g. for the kernel, and never allocated. When every other ASID is in use,
+/// the allocator rolls over to a new generation, which frees every ASID at once. All `Asid`s from
+/// older generations are then stale and must be reassigned with `reassign`, and since the same
+/// raw identifiers will be handed out again, the caller must flush the entire TLB.
+#[derive(Clone, Debug)]
+pub struct AsidAllocator<const N_ASIDS: usize> {
+    used: [bool; N_ASIDS],
+    generation: usize,
+    // Where to start searching for a free ASID.
+    next: usize,
+}
+
+impl<const N_ASIDS: usize> Default for AsidAllocator<N_ASIDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N_ASIDS: usize> AsidAllocator<N_ASIDS> {
+    /// Create an allocator with every ASID but 0 free.
+    ///
+    /// # Panics
+    /// If `N_ASIDS < 2`, since then there's nothing to allocate.
+    #[must_use]
+    pub const fn new() -> Self {
+        assert!(N_ASIDS >= 2, "need at least one allocatable ASID");
+        Self {
+            used: [false; N_ASIDS],
+            generation: 0,
+            next: 1,
+        }
+    }
+
+    /// Get the current generation.
+    #[must_use]
+    pub const fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// Check whether `asid` was allocated in the current generation, and so is still valid.
+    #[must_use]
+    pub const fn is_current(&self, asid: Asid) -> bool {
+        asid.generation == self.generation
+    }
+
+    /// Allocate a free ASID.
+    ///
+    /// If none are free, this rolls over to a new generation first; check for this by comparing
+    /// `generation` before and after, and flush the TLB if it changed.
+    pub fn alloc(&mut self) -> Asid {
+        let free = (self.next..N_ASIDS)
+            .chain(1..self.next)
+            .find(|&id| !self.used[id]);
+
+        let id = free.unwrap_or_else(|| {
+            self.roll_over();
+            1
+        });
+
+        self.used[id] = true;
+        self.next = if id + 1 == N_ASIDS { 1 } else { id + 1 };
+        Asid {
+            id,
+            generation: self.generation,
+        }
+    }
+
+    /// Release `asid` so it can be allocated again. Stale `Asid`s are ignored, since rolling over
+    /// already released them.
+    pub fn free(&mut self, asid: Asid) {
+        if self.is_current(asid) {
+            self.used[asid.id] = false;
+        }
+    }
+
+    /// Get a current `Asid` in place of `asid`: `asid` itself if it's still current, or else a
+    /// newly-allocated one.
+    pub fn reassign(&mut self, asid: Asid) -> Asid {
+        if self.is_current(asid) {
+            asid
+        } else {
+            self.alloc()
+        }
+    }
+
+    /// Start a new generation, freeing every ASID.
+    fn roll_over(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.used = [false; N_ASIDS];
+        self.next = 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_works() {
+        let mut asids = AsidAllocator::<4>::new();
+
+        let a = asids.alloc();
+        let b = asids.alloc();
+        let c = asids.alloc();
+        assert_eq!([a.id(), b.id(), c.id()], [1, 2, 3]);
+        assert_eq!(asids.generation(), 0);
+
+        // Freed ASIDs are recycled without rolling over.
+        asids.free(b);
+        let d = asids.alloc();
+        assert_eq!(d.id(), 2);
+        assert_eq!(asids.generation(), 0);
+        assert!(asids.is_current(a));
+    }
+
+    #[test]
+    fn rollover_works() {
+        let mut asids = AsidAllocator::<3>::new();
+
+        let a = asids.alloc();
+        let b = asids.alloc();
+        let c = asids.alloc();
+        assert_eq!(asids.generation(), 1);
+        assert_eq!(c.id(), 1);
+        assert!(!asids.is_current(a));
+        assert!(!asids.is_current(b));
+
+        // Stale ASIDs are reassigned, current ones are kept.
+        assert_eq!(asids.reassign(c), c);
+        let a = asids.reassign(a);
+        assert_eq!(a.id(), 2);
+        assert!(asids.is_current(a));
+
+        // Freeing a stale ASID doesn't free the current holder of its id.
+        asids.free(b);
+        assert_eq!(asids.alloc().generation(), 2);
+    }
+}
@@ -1,6 +1,1333 @@
-// This is the one that needs the most design.
-// It coordinates allocating physical pages to cache data from DataSources
-// It asks DataSources to fetch data, in response to a page fault, and when in arrives, it adds
-// the Physical Page that now has the data to the PageTableEntry of the requesting AddressSpace
-// There could be a further division of labor here, or refactoring, which could simplify things.
-// I'm open to ideas!
+//! A page cache, which keeps recently used pages of `DataSource`s in memory so that accesses to
+//! mapped data don't have to go to the source every time.
+
+use crate::address_space::DEFAULT_PAGE_SIZE;
+use crate::data_source::{DataSource, DsError, SourceRef, WritePolicy};
+use crate::sources::ModificationListener;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::ops::Range;
+
+/// Identifies a `DataSource` in a `PageCache`.
+///
+/// Two `SourceId`s are equal exactly when they were made from the same source object, so a
+/// source must stay in place while any of its pages are cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SourceId(usize);
+
+impl SourceId {
+    /// Get the `SourceId` of `source`.
+    #[must_use]
+    pub fn of(source: &dyn DataSource) -> Self {
+        Self(core::ptr::from_ref(source).cast::<()>() as usize)
+    }
+}
+
+/// Decides which page a `PageCache` evicts when it's full.
+///
+/// Pages are identified by the index of the frame holding them, which is always less than the
+/// cache's `N_FRAMES`. The cache tells its policy when frames are filled, accessed, and emptied.
+pub trait EvictionPolicy {
+    /// A new page was put in `frame`.
+    fn inserted(&mut self, frame: usize);
+
+    /// The page in `frame` was accessed.
+    fn accessed(&mut self, frame: usize);
+
+    /// The page in `frame` was removed.
+    fn removed(&mut self, frame: usize) {}
+
+    /// Choose a frame to evict, when every frame is full, out of those for which `evictable`
+    /// holds, or `None` if there are none.
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// Evicts the least recently used page.
+#[derive(Clone, Debug)]
+pub struct Lru<const N_FRAMES: usize> {
+    // When each frame was last used, by `clock`.
+    last_used: [u64; N_FRAMES],
+    clock: u64,
+}
+
+impl<const N_FRAMES: usize> Lru<N_FRAMES> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            last_used: [0; N_FRAMES],
+            clock: 0,
+        }
+    }
+}
+
+impl<const N_FRAMES: usize> Default for Lru<N_FRAMES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N_FRAMES: usize> EvictionPolicy for Lru<N_FRAMES> {
+    fn inserted(&mut self, frame: usize) {
+        self.accessed(frame);
+    }
+
+    fn accessed(&mut self, frame: usize) {
+        self.clock += 1;
+        self.last_used[frame] = self.clock;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..N_FRAMES)
+            .filter(|&frame| evictable(frame))
+            .min_by_key(|&frame| self.last_used[frame])
+    }
+}
+
+/// Evicts pages in the order they were cached, regardless of use.
+#[derive(Clone, Debug)]
+pub struct Fifo<const N_FRAMES: usize> {
+    // When each frame was filled, by `clock`.
+    inserted: [u64; N_FRAMES],
+    clock: u64,
+}
+
+impl<const N_FRAMES: usize> Fifo<N_FRAMES> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            inserted: [0; N_FRAMES],
+            clock: 0,
+        }
+    }
+}
+
+impl<const N_FRAMES: usize> Default for Fifo<N_FRAMES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N_FRAMES: usize> EvictionPolicy for Fifo<N_FRAMES> {
+    fn inserted(&mut self, frame: usize) {
+        self.clock += 1;
+        self.inserted[frame] = self.clock;
+    }
+
+    fn accessed(&mut self, frame: usize) {}
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..N_FRAMES)
+            .filter(|&frame| evictable(frame))
+            .min_by_key(|&frame| self.inserted[frame])
+    }
+}
+
+/// The CLOCK, or second-chance, approximation of LRU: a hand sweeps the frames, evicting the
+/// first page that hasn't been accessed since the hand last passed it.
+#[derive(Clone, Debug)]
+pub struct Clock<const N_FRAMES: usize> {
+    referenced: [bool; N_FRAMES],
+    hand: usize,
+}
+
+impl<const N_FRAMES: usize> Clock<N_FRAMES> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            referenced: [false; N_FRAMES],
+            hand: 0,
+        }
+    }
+}
+
+impl<const N_FRAMES: usize> Default for Clock<N_FRAMES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N_FRAMES: usize> EvictionPolicy for Clock<N_FRAMES> {
+    fn inserted(&mut self, frame: usize) {
+        self.referenced[frame] = true;
+    }
+
+    fn accessed(&mut self, frame: usize) {
+        self.referenced[frame] = true;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // Two sweeps are enough to clear every evictable frame's reference bit.
+        for _ in 0..2 * N_FRAMES {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % N_FRAMES;
+            if evictable(frame) && !core::mem::replace(&mut self.referenced[frame], false) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+/// A frame of a `PageCache`, and the page it holds, if any.
+struct Frame<'a, const PAGE_SIZE: usize> {
+    page: Option<(SourceRef<'a>, usize)>,
+    data: [u8; PAGE_SIZE],
+    // Whether `data` has been modified since it was last written back.
+    dirty: bool,
+    // How many times the page has been pinned, and not unpinned since.
+    pins: usize,
+}
+
+impl<const PAGE_SIZE: usize> Frame<'_, PAGE_SIZE> {
+    fn holds(&self, source: SourceId, page_index: usize) -> bool {
+        self.page
+            .as_ref()
+            .is_some_and(|(s, index)| SourceId::of(&**s) == source && *index == page_index)
+    }
+
+    /// Check whether the frame holds a page of `source` that overlaps the byte range `range`.
+    fn overlaps(&self, source: SourceId, range: &Range<usize>) -> bool {
+        self.page.as_ref().is_some_and(|(s, page_index)| {
+            let start = page_index.saturating_mul(PAGE_SIZE);
+            SourceId::of(&**s) == source
+                && start < range.end
+                && range.start < start.saturating_add(PAGE_SIZE)
+        })
+    }
+}
+
+/// A `ModificationListener` that invalidates a `PageCache`'s pages of a source when it's
+/// modified, to keep the cache coherent with writers that don't go through it.
+///
+/// Register it with the `NotifyingSource` whose pages are cached. Modifications made by the
+/// cache itself, while it's borrowed, are skipped, since it already has the new data.
+pub struct Invalidator<'c, 'a, const N_FRAMES: usize, const PAGE_SIZE: usize, P> {
+    cache: &'c RefCell<PageCache<'a, N_FRAMES, PAGE_SIZE, P>>,
+    source: SourceId,
+}
+
+impl<'c, 'a, const N_FRAMES: usize, const PAGE_SIZE: usize, P>
+    Invalidator<'c, 'a, N_FRAMES, PAGE_SIZE, P>
+{
+    /// Create a listener that invalidates `cache`'s pages of `source`.
+    #[must_use]
+    pub const fn new(
+        cache: &'c RefCell<PageCache<'a, N_FRAMES, PAGE_SIZE, P>>,
+        source: SourceId,
+    ) -> Self {
+        Self { cache, source }
+    }
+}
+
+impl<const N_FRAMES: usize, const PAGE_SIZE: usize, P: EvictionPolicy> ModificationListener
+    for Invalidator<'_, '_, N_FRAMES, PAGE_SIZE, P>
+{
+    fn modified(&self, range: Range<usize>) {
+        if let Ok(mut cache) = self.cache.try_borrow_mut() {
+            // A pinned page that can't be read again keeps its old data; there's nobody to
+            // report the error to.
+            let _ = cache.invalidate(self.source, range);
+        }
+    }
+}
+
+/// A cache of up to `N_FRAMES` pages of `PAGE_SIZE` bytes, from any number of `DataSource`s.
+///
+/// The frames are stored inline, so the cache needs no allocator. When every frame is full,
+/// adding a page evicts another, chosen by the `EvictionPolicy` `P`. By default, that's the page
+/// that was cached first.
+///
+/// Pages are keyed by their source's `SourceId` and their index in it, so one cache can serve
+/// several `AddressSpace`s, which share the pages of sources they both map; see
+/// `AddressSpace::cached_page`. A cached page holds on to its source, so shared sources stay
+/// alive while they have pages cached.
+///
+/// Writes are cached too: modified pages are marked dirty, and only written back to their source
+/// when they're flushed or evicted.
+///
+/// Pages can be pinned, e.g. while a device is accessing them, which keeps them from being
+/// evicted.
+///
+/// A dirty ratio can be set, past which `needs_writeback` asks for some of the dirty pages to be
+/// written back, e.g. by a background flusher, so that eviction doesn't have to.
+///
+/// With readahead enabled, a miss right after a miss on the previous page of the same source is
+/// taken as a sequential scan, and loads a window of pages at once.
+pub struct PageCache<
+    'a,
+    const N_FRAMES: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    P = Fifo<N_FRAMES>,
+> {
+    frames: [Frame<'a, PAGE_SIZE>; N_FRAMES],
+    policy: P,
+    // How many pages to load on a sequential miss.
+    readahead: usize,
+    // The last page loaded by a miss, to detect sequential scans.
+    last_miss: Option<(SourceId, usize)>,
+    // The percentage of frames that may be dirty before writeback is needed.
+    dirty_ratio: usize,
+}
+
+impl<const N_FRAMES: usize, const PAGE_SIZE: usize, P: EvictionPolicy + Default> Default
+    for PageCache<'_, N_FRAMES, PAGE_SIZE, P>
+{
+    fn default() -> Self {
+        Self::with_policy(P::default())
+    }
+}
+
+impl<const N_FRAMES: usize, const PAGE_SIZE: usize> PageCache<'_, N_FRAMES, PAGE_SIZE> {
+    /// Create an empty cache that evicts pages in the order they were cached.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self::with_policy(Fifo::new())
+    }
+}
+
+impl<'a, const N_FRAMES: usize, const PAGE_SIZE: usize, P: EvictionPolicy>
+    PageCache<'a, N_FRAMES, PAGE_SIZE, P>
+{
+    /// Create an empty cache that evicts pages according to `policy`.
+    #[must_use]
+    pub const fn with_policy(policy: P) -> Self {
+        Self {
+            frames: [const {
+                Frame {
+                    page: None,
+                    data: [0; PAGE_SIZE],
+                    dirty: false,
+                    pins: 0,
+                }
+            }; N_FRAMES],
+            policy,
+            readahead: 0,
+            last_miss: None,
+            dirty_ratio: 100,
+        }
+    }
+
+    /// Get how many pages a sequential miss loads; 0 or 1 means readahead is disabled.
+    #[must_use]
+    pub const fn readahead(&self) -> usize {
+        self.readahead
+    }
+
+    /// Set how many pages a sequential miss loads; 0 or 1 disables readahead.
+    pub const fn set_readahead(&mut self, pages: usize) {
+        self.readahead = pages;
+    }
+
+    /// Get the eviction policy.
+    pub const fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    /// Get the number of pages the cache can hold.
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N_FRAMES
+    }
+
+    /// Get the number of pages in the cache.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.frames.iter().filter(|f| f.page.is_some()).count()
+    }
+
+    /// Check whether the cache holds no pages.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn frame_of(&self, source: SourceId, page_index: usize) -> Option<usize> {
+        self.frames.iter().position(|f| f.holds(source, page_index))
+    }
+
+    /// Check whether the `page_index`th page of `source` is cached.
+    #[must_use]
+    pub fn contains(&self, source: SourceId, page_index: usize) -> bool {
+        self.frame_of(source, page_index).is_some()
+    }
+
+    /// Get the cached data of the `page_index`th page of `source`, if it's cached.
+    #[must_use]
+    pub fn get(&self, source: SourceId, page_index: usize) -> Option<&[u8; PAGE_SIZE]> {
+        self.frame_of(source, page_index)
+            .map(|frame| &self.frames[frame].data)
+    }
+
+    /// Get the cached data of the `page_index`th page of `source` to modify, if it's cached,
+    /// which counts as an access and marks the page dirty.
+    pub fn get_mut(&mut self, source: SourceId, page_index: usize) -> Option<&mut [u8; PAGE_SIZE]> {
+        let frame = self.frame_of(source, page_index)?;
+        self.policy.accessed(frame);
+        self.frames[frame].dirty = true;
+        Some(&mut self.frames[frame].data)
+    }
+
+    /// Check whether the `page_index`th page of `source` is cached and has been modified since
+    /// it was last written back.
+    #[must_use]
+    pub fn is_dirty(&self, source: SourceId, page_index: usize) -> bool {
+        self.frame_of(source, page_index)
+            .is_some_and(|frame| self.frames[frame].dirty)
+    }
+
+    /// Get the number of dirty pages in the cache.
+    #[must_use]
+    pub fn dirty_pages(&self) -> usize {
+        self.frames.iter().filter(|f| f.dirty).count()
+    }
+
+    /// Get the percentage of the cache's frames that may hold dirty pages before writeback is
+    /// needed.
+    #[must_use]
+    pub const fn dirty_ratio(&self) -> usize {
+        self.dirty_ratio
+    }
+
+    /// Set the percentage of the cache's frames that may hold dirty pages before writeback is
+    /// needed. 100, the default, never needs writeback.
+    ///
+    /// # Errors
+    /// If `percent` is over 100.
+    pub const fn set_dirty_ratio(&mut self, percent: usize) -> Result<(), DsError> {
+        if percent > 100 {
+            return Err("dirty ratio must be a percentage");
+        }
+        self.dirty_ratio = percent;
+        Ok(())
+    }
+
+    /// Get how many pages may be dirty before writeback is needed.
+    const fn dirty_limit(&self) -> usize {
+        N_FRAMES * self.dirty_ratio / 100
+    }
+
+    /// Check whether more pages are dirty than the dirty ratio allows.
+    #[must_use]
+    pub fn needs_writeback(&self) -> bool {
+        self.dirty_pages() > self.dirty_limit()
+    }
+
+    /// List dirty pages, by source and page index, that need writing back to bring the cache
+    /// within its dirty ratio, or nothing if it's within it already.
+    ///
+    /// Nothing is written here: a flusher can write each page back with `flush` when
+    /// convenient.
+    pub fn writeback_work(&self) -> impl Iterator<Item = (SourceId, usize)> + '_ {
+        let excess = self.dirty_pages().saturating_sub(self.dirty_limit());
+        self.frames
+            .iter()
+            .filter(|frame| frame.dirty)
+            .filter_map(|frame| frame.page.as_ref())
+            .map(|(source, page_index)| (SourceId::of(&**source), *page_index))
+            .take(excess)
+    }
+
+    /// Tell the eviction policy that the `page_index`th page of `source` was accessed, e.g. by
+    /// a fault handler or when the page table's accessed bit is found set, returning whether
+    /// it's cached.
+    pub fn touch(&mut self, source: SourceId, page_index: usize) -> bool {
+        self.frame_of(source, page_index)
+            .map(|frame| self.policy.accessed(frame))
+            .is_some()
+    }
+
+    /// Get the `page_index`th page of `source`, reading it into the cache if it isn't cached.
+    /// Either way, this counts as an access.
+    ///
+    /// # Errors
+    /// If reading the page fails, in which case nothing is cached.
+    pub fn read(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        page_index: usize,
+    ) -> Result<&[u8; PAGE_SIZE], DsError> {
+        let source = source.into();
+        let id = SourceId::of(&*source);
+        if let Some(frame) = self.frame_of(id, page_index) {
+            self.policy.accessed(frame);
+            return Ok(&self.frames[frame].data);
+        }
+
+        let sequential = page_index
+            .checked_sub(1)
+            .is_some_and(|previous| self.last_miss == Some((id, previous)));
+        let loaded = if sequential && self.readahead > 1 {
+            self.prefetch(source.clone(), page_index, self.readahead)?
+        } else {
+            0
+        };
+        self.last_miss = Some((id, page_index + loaded.max(1) - 1));
+
+        let frame = match self.frame_of(id, page_index) {
+            Some(frame) => frame,
+            None => {
+                let frame = self.free_frame()?;
+                source.read_page(page_index, &mut self.frames[frame].data)?;
+                self.fill(frame, source, page_index);
+                frame
+            }
+        };
+        Ok(&self.frames[frame].data)
+    }
+
+    /// Load up to `n_pages` pages of `source`, starting with the `start_page`th, into the cache
+    /// with a single `DataSource::read_vectored`, returning how many were loaded.
+    ///
+    /// Loading stops at the first page that's already cached, at the end of `source`, and at
+    /// the cache's capacity. Nothing is returned but the count, so it can be issued ahead of the
+    /// accesses it's meant to speed up.
+    ///
+    /// # Errors
+    /// If reading fails, in which case no pages are loaded, or there are no frames to load into.
+    pub fn prefetch(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        start_page: usize,
+        n_pages: usize,
+    ) -> Result<usize, DsError> {
+        let source = source.into();
+        let id = SourceId::of(&*source);
+        let offset = start_page.checked_mul(PAGE_SIZE).ok_or("offset overflow")?;
+        let available = source
+            .len()
+            .map_or(usize::MAX, |len| len.saturating_sub(offset));
+        let n_pages = n_pages.min(N_FRAMES).min(available.div_ceil(PAGE_SIZE));
+
+        // Claim a frame for each page first, so later claims can't reuse earlier ones.
+        let mut frames = [0; N_FRAMES];
+        let mut count = 0;
+        while count < n_pages && !self.contains(id, start_page + count) {
+            let frame = match self.free_frame() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    frames[..count].iter().for_each(|&frame| self.empty(frame));
+                    return Err(e);
+                }
+            };
+            // If the policy chose a frame we just claimed, that page was dropped, so stop there.
+            if let Some(claimed) = frames[..count].iter().position(|&f| f == frame) {
+                frames[claimed + 1..count]
+                    .iter()
+                    .for_each(|&frame| self.empty(frame));
+                count = claimed;
+                break;
+            }
+            self.fill(frame, source.clone(), start_page + count);
+            frames[count] = frame;
+            count += 1;
+        }
+
+        let mut buffers: [&mut [u8]; N_FRAMES] = core::array::from_fn(|_| Default::default());
+        for (index, frame) in self.frames.iter_mut().enumerate() {
+            if let Some(i) = frames[..count].iter().position(|&f| f == index) {
+                let length = PAGE_SIZE.min(available - i * PAGE_SIZE);
+                frame.data[length..].fill(0);
+                buffers[i] = &mut frame.data[..length];
+            }
+        }
+        if let Err(e) = source.read_vectored(offset, &mut buffers[..count]) {
+            frames[..count].iter().for_each(|&frame| self.empty(frame));
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    /// Write `data` to `source` at `offset`, through the cache, following `source`'s
+    /// `write_policy`; see `write_with`.
+    ///
+    /// # Errors
+    /// See `write_with`.
+    pub fn write(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), DsError> {
+        let source = source.into();
+        let policy = source.write_policy();
+        self.write_with(source, offset, data, policy)
+    }
+
+    /// Write `data` to `source` at `offset`, through the cache: the affected pages are read into
+    /// the cache if they aren't cached and modified there. With `WritePolicy::WriteBack`, they're
+    /// marked dirty; with `WritePolicy::WriteThrough`, `data` is also written to `source`.
+    ///
+    /// This lets a mapping choose a different policy than its source's.
+    ///
+    /// # Errors
+    /// If reading a page fails, or there's no frame to put it in, or writing through to `source`
+    /// fails. Earlier pages may have been written.
+    pub fn write_with(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        offset: usize,
+        data: &[u8],
+        policy: WritePolicy,
+    ) -> Result<(), DsError> {
+        let source = source.into();
+        let id = SourceId::of(&*source);
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset.checked_add(done).ok_or("offset overflow")?;
+            let (page_index, page_offset) = (position / PAGE_SIZE, position % PAGE_SIZE);
+            let piece = (PAGE_SIZE - page_offset).min(data.len() - done);
+            self.read(source.clone(), page_index)?;
+            let frame = self.frame_of(id, page_index).expect("page was just read");
+            self.policy.accessed(frame);
+            let data = &data[done..done + piece];
+            if policy == WritePolicy::WriteThrough {
+                source.write(position, piece, data)?;
+            } else {
+                self.frames[frame].dirty = true;
+            }
+            self.frames[frame].data[page_offset..page_offset + piece].copy_from_slice(data);
+            done += piece;
+        }
+        Ok(())
+    }
+
+    /// Cache `data` as the `page_index`th page of `source`, replacing what's cached for it.
+    ///
+    /// `data` is taken to be what's in `source`, so the page isn't dirty afterwards.
+    ///
+    /// # Errors
+    /// If there's no frame to put it in.
+    pub fn insert(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        page_index: usize,
+        data: &[u8; PAGE_SIZE],
+    ) -> Result<(), DsError> {
+        let source = source.into();
+        let frame = match self.frame_of(SourceId::of(&*source), page_index) {
+            Some(frame) => {
+                self.policy.accessed(frame);
+                frame
+            }
+            None => {
+                let frame = self.free_frame()?;
+                self.fill(frame, source, page_index);
+                frame
+            }
+        };
+        self.frames[frame].data.copy_from_slice(data);
+        self.frames[frame].dirty = false;
+        Ok(())
+    }
+
+    /// Drop the `page_index`th page of `source` from the cache, writing it back first if it's
+    /// dirty, returning whether it was cached.
+    ///
+    /// # Errors
+    /// If the page is pinned, or writing it back fails, in which case it stays cached.
+    pub fn evict(&mut self, source: SourceId, page_index: usize) -> Result<bool, DsError> {
+        let Some(frame) = self.frame_of(source, page_index) else {
+            return Ok(false);
+        };
+        if self.frames[frame].pins > 0 {
+            return Err("page is pinned");
+        }
+        self.write_back(frame)?;
+        self.empty(frame);
+        Ok(true)
+    }
+
+    /// Pin the `page_index`th page of `source`, reading it into the cache if it isn't cached, so
+    /// it isn't evicted until it's unpinned as many times as it's been pinned.
+    ///
+    /// At least one frame is always left unpinned, so the cache can still load other pages.
+    ///
+    /// # Errors
+    /// If reading the page fails, or pinning it would leave every frame pinned.
+    pub fn pin(
+        &mut self,
+        source: impl Into<SourceRef<'a>>,
+        page_index: usize,
+    ) -> Result<&[u8; PAGE_SIZE], DsError> {
+        let source = source.into();
+        let id = SourceId::of(&*source);
+        let pinned = self.pinned_pages();
+        if !self.is_pinned(id, page_index) && pinned + 1 >= N_FRAMES {
+            return Err("too many pinned pages");
+        }
+        self.read(source, page_index)?;
+        let frame = self.frame_of(id, page_index).expect("page was just read");
+        self.frames[frame].pins += 1;
+        Ok(&self.frames[frame].data)
+    }
+
+    /// Undo one `pin` of the `page_index`th page of `source`, returning whether it was pinned.
+    pub fn unpin(&mut self, source: SourceId, page_index: usize) -> bool {
+        match self.frame_of(source, page_index) {
+            Some(frame) if self.frames[frame].pins > 0 => {
+                self.frames[frame].pins -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check whether the `page_index`th page of `source` is cached and pinned.
+    #[must_use]
+    pub fn is_pinned(&self, source: SourceId, page_index: usize) -> bool {
+        self.frame_of(source, page_index)
+            .is_some_and(|frame| self.frames[frame].pins > 0)
+    }
+
+    /// Get the number of pinned pages in the cache.
+    #[must_use]
+    pub fn pinned_pages(&self) -> usize {
+        self.frames.iter().filter(|f| f.pins > 0).count()
+    }
+
+    /// Write back the dirty pages of `source` that overlap the byte range `range`.
+    ///
+    /// # Errors
+    /// If writing a page back fails. Other pages may have been written back.
+    pub fn flush(&mut self, source: SourceId, range: Range<usize>) -> Result<(), DsError> {
+        for frame in 0..N_FRAMES {
+            if self.frames[frame].overlaps(source, &range) {
+                self.write_back(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the cached pages of `source` that overlap the byte range `range`, because the
+    /// source was modified without going through the cache. Pinned pages can't be dropped, so
+    /// they're read again in place instead.
+    ///
+    /// Unwritten modifications to the pages are lost.
+    ///
+    /// # Errors
+    /// If reading a pinned page again fails. Other pages may have been dropped.
+    pub fn invalidate(&mut self, source: SourceId, range: Range<usize>) -> Result<(), DsError> {
+        for frame in 0..N_FRAMES {
+            if !self.frames[frame].overlaps(source, &range) {
+                continue;
+            }
+            let Frame {
+                page: Some((s, page_index)),
+                data,
+                dirty,
+                pins,
+            } = &mut self.frames[frame]
+            else {
+                unreachable!("an overlapping frame holds a page");
+            };
+            if *pins > 0 {
+                *dirty = false;
+                s.read_page(*page_index, data)?;
+            } else {
+                self.empty(frame);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write back every dirty page.
+    ///
+    /// # Errors
+    /// If writing a page back fails. Other pages may have been written back.
+    pub fn flush_all(&mut self) -> Result<(), DsError> {
+        (0..N_FRAMES).try_for_each(|frame| self.write_back(frame))
+    }
+
+    /// Write the page in `frame` back to its source, if it's dirty.
+    fn write_back(&mut self, frame: usize) -> Result<(), DsError> {
+        let frame = &mut self.frames[frame];
+        if let (Some((source, page_index)), true) = (&frame.page, frame.dirty) {
+            source.write_page(*page_index, &frame.data)?;
+            frame.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Record that `frame` now holds the `page_index`th page of `source`.
+    fn fill(&mut self, frame: usize, source: SourceRef<'a>, page_index: usize) {
+        self.frames[frame].page = Some((source, page_index));
+        self.frames[frame].dirty = false;
+        self.policy.inserted(frame);
+    }
+
+    fn empty(&mut self, frame: usize) {
+        self.frames[frame].page = None;
+        self.frames[frame].dirty = false;
+        self.frames[frame].pins = 0;
+        self.policy.removed(frame);
+    }
+
+    /// Find a frame to put a new page in, evicting a page if every frame is full.
+    fn free_frame(&mut self) -> Result<usize, DsError> {
+        if let Some(frame) = self.frames.iter().position(|f| f.page.is_none()) {
+            return Ok(frame);
+        }
+        if N_FRAMES == 0 {
+            return Err("cache has no frames");
+        }
+        let frames = &self.frames;
+        let frame = self
+            .policy
+            .victim(&|frame| frames[frame].pins == 0)
+            .ok_or("every frame is pinned")?;
+        self.write_back(frame)?;
+        self.empty(frame);
+        Ok(frame)
+    }
+}
+
+/// A lock that a `ShardedCache` can keep each shard behind, or a `SharedAddressSpace` its address
+/// space, so that they can use whatever lock the kernel provides.
+pub trait ShardLock<T> {
+    /// Create a lock holding `value`.
+    fn new(value: T) -> Self;
+
+    /// Run `f` on the locked value, holding the lock until it returns.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+
+/// For a single core, where the cache only needs to be shared, not synchronized.
+impl<T> ShardLock<T> for RefCell<T> {
+    fn new(value: T) -> Self {
+        Self::new(value)
+    }
+
+    /// # Panics
+    /// If `f` reenters the cache's shard.
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> ShardLock<T> for std::sync::Mutex<T> {
+    fn new(value: T) -> Self {
+        Self::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        // A panic while holding the lock can't leave the cache inconsistent enough to matter.
+        f(&mut self
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner))
+    }
+}
+
+/// A `PageCache` split into `SHARDS` independent shards of `N_FRAMES` frames, each behind its own
+/// `ShardLock` `L`, so that accesses to different pages don't all contend for one lock.
+///
+/// Each page belongs to one shard, chosen by its source and index, with consecutive pages in
+/// consecutive shards. Eviction, pinning, and the dirty ratio are per shard. Whether the cache can
+/// be shared between cores depends on `L`, and on its sources.
+pub struct ShardedCache<
+    'a,
+    L,
+    const SHARDS: usize,
+    const N_FRAMES: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    P = Fifo<N_FRAMES>,
+> {
+    shards: [L; SHARDS],
+    _cache: PhantomData<fn() -> PageCache<'a, N_FRAMES, PAGE_SIZE, P>>,
+}
+
+impl<
+        'a,
+        L: ShardLock<PageCache<'a, N_FRAMES, PAGE_SIZE, P>>,
+        const SHARDS: usize,
+        const N_FRAMES: usize,
+        const PAGE_SIZE: usize,
+        P: EvictionPolicy + Default,
+    > Default for ShardedCache<'a, L, SHARDS, N_FRAMES, PAGE_SIZE, P>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        'a,
+        L: ShardLock<PageCache<'a, N_FRAMES, PAGE_SIZE, P>>,
+        const SHARDS: usize,
+        const N_FRAMES: usize,
+        const PAGE_SIZE: usize,
+        P: EvictionPolicy,
+    > ShardedCache<'a, L, SHARDS, N_FRAMES, PAGE_SIZE, P>
+{
+    /// Create an empty cache, with each shard's eviction policy made by `P::default`.
+    ///
+    /// # Panics
+    /// If `SHARDS` is zero.
+    #[must_use]
+    pub fn new() -> Self
+    where
+        P: Default,
+    {
+        assert!(SHARDS > 0, "a sharded cache needs shards");
+        Self {
+            shards: core::array::from_fn(|_| L::new(PageCache::with_policy(P::default()))),
+            _cache: PhantomData,
+        }
+    }
+
+    /// Get the number of pages the cache can hold.
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        SHARDS * N_FRAMES
+    }
+
+    /// Get the number of pages in the cache.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.with(|cache| cache.len()))
+            .sum()
+    }
+
+    /// Check whether the cache holds no pages.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the shard the `page_index`th page of `source` belongs to.
+    const fn shard_of(source: SourceId, page_index: usize) -> usize {
+        // The low bits of a source's address are mostly alignment, so they're dropped.
+        (source.0 >> 3).wrapping_add(page_index) % SHARDS
+    }
+
+    /// Run `f` on the shard holding the `page_index`th page of `source`, with its lock held.
+    pub fn with_shard<R>(
+        &self,
+        source: SourceId,
+        page_index: usize,
+        f: impl FnOnce(&mut PageCache<'a, N_FRAMES, PAGE_SIZE, P>) -> R,
+    ) -> R {
+        self.shards[Self::shard_of(source, page_index)].with(f)
+    }
+
+    /// Copy the `page_index`th page of `source` into `page`, reading it into the cache if it
+    /// isn't cached; see `PageCache::read`.
+    ///
+    /// # Errors
+    /// If reading the page fails.
+    pub fn read(
+        &self,
+        source: impl Into<SourceRef<'a>>,
+        page_index: usize,
+        page: &mut [u8; PAGE_SIZE],
+    ) -> Result<(), DsError> {
+        let source = source.into();
+        self.with_shard(SourceId::of(&*source), page_index, |cache| {
+            page.copy_from_slice(cache.read(source, page_index)?);
+            Ok(())
+        })
+    }
+
+    /// Write `data` to `source` at `offset` through the cache, locking each page's shard in
+    /// turn; see `PageCache::write`.
+    ///
+    /// # Errors
+    /// If writing a page fails. Earlier pages may have been written.
+    pub fn write(
+        &self,
+        source: impl Into<SourceRef<'a>>,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), DsError> {
+        let source = source.into();
+        let id = SourceId::of(&*source);
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset.checked_add(done).ok_or("offset overflow")?;
+            let (page_index, page_offset) = (position / PAGE_SIZE, position % PAGE_SIZE);
+            let piece = (PAGE_SIZE - page_offset).min(data.len() - done);
+            self.with_shard(id, page_index, |cache| {
+                cache.write(source.clone(), position, &data[done..done + piece])
+            })?;
+            done += piece;
+        }
+        Ok(())
+    }
+
+    /// Write back the dirty pages of `source` that overlap the byte range `range`.
+    ///
+    /// # Errors
+    /// If writing a page back fails. Other pages may have been written back.
+    pub fn flush(&self, source: SourceId, range: Range<usize>) -> Result<(), DsError> {
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.with(|cache| cache.flush(source, range.clone())))
+    }
+
+    /// Write back every dirty page.
+    ///
+    /// # Errors
+    /// If writing a page back fails. Other pages may have been written back.
+    pub fn flush_all(&self) -> Result<(), DsError> {
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.with(PageCache::flush_all))
+    }
+
+    /// Forget the cached pages of `source` that overlap the byte range `range`; see
+    /// `PageCache::invalidate`.
+    ///
+    /// # Errors
+    /// If reading a pinned page again fails. Other pages may have been dropped.
+    pub fn invalidate(&self, source: SourceId, range: Range<usize>) -> Result<(), DsError> {
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.with(|cache| cache.invalidate(source, range.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::{CellSliceSource, NotifyingSource, SliceSource, ZeroSource};
+    use core::cell::Cell;
+
+    #[test]
+    fn page_cache_works() -> Result<(), DsError> {
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let slice = SliceSource::new(&data);
+        let zero = ZeroSource;
+        let (slice_id, zero_id) = (SourceId::of(&slice), SourceId::of(&zero));
+        let mut cache = PageCache::<2, 16>::new();
+        assert!(cache.is_empty());
+
+        assert_eq!(cache.read(&slice, 1)?[0], 16);
+        assert_eq!(cache.get(slice_id, 1).map(|page| page[15]), Some(31));
+        assert!(cache.get(zero_id, 1).is_none());
+        assert!(cache.read(&slice, 4).is_err());
+        assert_eq!(cache.len(), 1);
+
+        cache.insert(&slice, 1, &[99; 16])?;
+        assert_eq!(cache.read(&slice, 1)?[0], 99);
+
+        // Once the cache is full, the page that was cached first is evicted.
+        cache.insert(&zero, 0, &[7; 16])?;
+        cache.read(&slice, 2)?;
+        assert!(!cache.contains(slice_id, 1));
+        assert!(cache.contains(zero_id, 0) && cache.contains(slice_id, 2));
+        assert!(cache.evict(zero_id, 0)? && !cache.evict(zero_id, 0)?);
+        assert_eq!(cache.len(), 1);
+
+        Ok(())
+    }
+
+    /// Fill a cache of three pages, touch the first, then add a fourth, returning which of the
+    /// first three pages are still cached.
+    fn survivors<P: EvictionPolicy>(policy: P) -> Result<[bool; 3], DsError> {
+        let zero = ZeroSource;
+        let id = SourceId::of(&zero);
+        let mut cache = PageCache::<3, 16, P>::with_policy(policy);
+        for page in 0..3 {
+            cache.read(&zero, page)?;
+        }
+        assert!(cache.touch(id, 0) && !cache.touch(id, 3));
+        cache.read(&zero, 3)?;
+        Ok([0, 1, 2].map(|page| cache.contains(id, page)))
+    }
+
+    #[test]
+    fn eviction_policies_work() -> Result<(), DsError> {
+        assert_eq!(survivors(Fifo::<3>::new())?, [false, true, true]);
+        assert_eq!(survivors(Lru::<3>::new())?, [true, false, true]);
+        // Every page has been referenced, so the hand clears them all and comes back to the
+        // first.
+        assert_eq!(survivors(Clock::<3>::new())?, [false, true, true]);
+
+        let mut clock = Clock::<3>::new();
+        clock.inserted(0);
+        clock.inserted(1);
+        clock.inserted(2);
+        assert_eq!(clock.victim(&|_| true), Some(0));
+        clock.accessed(2);
+        clock.inserted(0);
+        assert_eq!(clock.victim(&|_| true), Some(1));
+        assert_eq!(clock.victim(&|frame| frame == 0), Some(0));
+        assert_eq!(clock.victim(&|_| false), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_back_works() -> Result<(), DsError> {
+        let mut data = [0; 48];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let id = SourceId::of(&source);
+        let mut cache = PageCache::<2, 16>::new();
+
+        // Writes stay in the cache until they're flushed.
+        cache.write(&source, 14, &[1; 4])?;
+        assert!(cache.is_dirty(id, 0) && cache.is_dirty(id, 1));
+        let mut buffer = [0; 4];
+        source.read(14, 4, &mut buffer)?;
+        assert_eq!(buffer, [0; 4]);
+        cache.flush(id, 0..16)?;
+        assert!(!cache.is_dirty(id, 0) && cache.is_dirty(id, 1));
+        source.read(14, 4, &mut buffer)?;
+        assert_eq!(buffer, [1, 1, 0, 0]);
+
+        // Evicting a dirty page writes it back.
+        cache.get_mut(id, 0).ok_or("cached")?[0] = 2;
+        cache.read(&source, 2)?;
+        assert!(!cache.contains(id, 0));
+        assert_eq!(cache.dirty_pages(), 1);
+        cache.flush_all()?;
+        assert_eq!(cache.dirty_pages(), 0);
+        source.read(14, 4, &mut buffer)?;
+        assert_eq!(buffer, [1; 4]);
+        source.read(0, 1, &mut buffer)?;
+        assert_eq!(buffer[0], 2);
+
+        // A page that can't be written back stays cached.
+        let slice = SliceSource::new(&[0; 16]);
+        cache.write(&slice, 0, &[3])?;
+        assert!(cache.evict(SourceId::of(&slice), 0).is_err());
+        assert!(cache.contains(SourceId::of(&slice), 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_through_works() -> Result<(), DsError> {
+        let mut data = [0; 32];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let id = SourceId::of(&source);
+        let mut cache = PageCache::<2, 16>::new();
+
+        cache.write_with(&source, 15, &[1; 2], WritePolicy::WriteThrough)?;
+        assert_eq!(cache.dirty_pages(), 0);
+        assert_eq!(cache.read(&source, 1)?[0], 1);
+        let mut buffer = [0; 2];
+        source.read(15, 2, &mut buffer)?;
+        assert_eq!(buffer, [1; 2]);
+
+        // Writing through doesn't clean a page that's already dirty.
+        cache.write(&source, 0, &[2])?;
+        cache.write_with(&source, 1, &[3], WritePolicy::WriteThrough)?;
+        assert!(cache.is_dirty(id, 0));
+        source.read(0, 2, &mut buffer)?;
+        assert_eq!(buffer, [0, 3]);
+
+        Ok(())
+    }
+
+    /// Counts how many requests reach the wrapped source.
+    struct Counting<'a> {
+        inner: SliceSource<'a>,
+        requests: Cell<usize>,
+    }
+
+    impl DataSource for Counting<'_> {
+        fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+            self.requests.set(self.requests.get() + 1);
+            self.inner.read(offset, length, buffer)
+        }
+
+        fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+            self.inner.write(offset, length, buffer)
+        }
+
+        fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+            self.inner.flush(offset, length)
+        }
+
+        fn read_vectored(&self, offset: usize, buffers: &mut [&mut [u8]]) -> Result<(), DsError> {
+            self.requests.set(self.requests.get() + 1);
+            let mut offset = offset;
+            for buffer in buffers {
+                self.inner.read(offset, buffer.len(), buffer)?;
+                offset += buffer.len();
+            }
+            Ok(())
+        }
+
+        fn len(&self) -> Option<usize> {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn readahead_works() -> Result<(), DsError> {
+        let data: [u8; 72] = core::array::from_fn(|i| i as u8);
+        let source = Counting {
+            inner: SliceSource::new(&data),
+            requests: Cell::new(0),
+        };
+        let id = SourceId::of(&source);
+        let mut cache = PageCache::<8, 16>::new();
+
+        // The last page is cut short by the end of the source.
+        assert_eq!(cache.prefetch(&source, 2, 8)?, 3);
+        assert_eq!(source.requests.get(), 1);
+        assert_eq!(cache.get(id, 4).ok_or("cached")?[..8], data[64..]);
+        assert_eq!(cache.get(id, 4).ok_or("cached")?[8..], [0; 8]);
+        // Prefetching stops at a cached page.
+        assert_eq!(cache.prefetch(&source, 0, 8)?, 2);
+        assert_eq!(cache.prefetch(&source, 0, 8)?, 0);
+
+        // Sequential misses load a window of pages.
+        let mut cache = PageCache::<8, 16>::new();
+        cache.set_readahead(3);
+        source.requests.set(0);
+        cache.read(&source, 0)?;
+        cache.read(&source, 1)?;
+        assert_eq!(source.requests.get(), 2);
+        assert!(cache.contains(id, 3) && !cache.contains(id, 4));
+        cache.read(&source, 2)?;
+        cache.read(&source, 3)?;
+        cache.read(&source, 4)?;
+        assert_eq!(source.requests.get(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pinning_works() -> Result<(), DsError> {
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let source = SliceSource::new(&data);
+        let id = SourceId::of(&source);
+        let mut cache = PageCache::<3, 16, _>::with_policy(Lru::<3>::new());
+
+        assert_eq!(cache.pin(&source, 0)?[0], 0);
+        cache.pin(&source, 0)?;
+        cache.pin(&source, 1)?;
+        // The last frame has to stay unpinned.
+        assert!(cache.pin(&source, 2).is_err());
+        assert_eq!(cache.pinned_pages(), 2);
+        assert!(cache.evict(id, 0).is_err());
+
+        // Pinned pages aren't evicted, however old they are.
+        cache.read(&source, 2)?;
+        cache.read(&source, 3)?;
+        assert!(cache.contains(id, 0) && cache.contains(id, 1) && !cache.contains(id, 2));
+
+        // Pins nest.
+        assert!(cache.unpin(id, 0) && cache.is_pinned(id, 0));
+        assert!(cache.unpin(id, 0) && !cache.is_pinned(id, 0));
+        assert!(!cache.unpin(id, 0));
+        cache.read(&source, 2)?;
+        assert!(!cache.contains(id, 0) && cache.contains(id, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalidation_works() -> Result<(), DsError> {
+        let mut data = [0; 48];
+        let cells = Cell::from_mut(&mut data[..]).as_slice_of_cells();
+        let source = NotifyingSource::<_, 1>::new(CellSliceSource::new(cells));
+        let id = SourceId::of(&source);
+        let cache = RefCell::new(PageCache::<3, 16>::new());
+        let invalidator = Invalidator::new(&cache, id);
+        source.on_modified(0..48, &invalidator)?;
+
+        {
+            let mut cache = cache.borrow_mut();
+            cache.read(&source, 0)?;
+            cache.pin(&source, 1)?;
+            cache.read(&source, 2)?;
+            // The cache's own writes don't invalidate anything.
+            cache.write_with(&source, 32, &[1], WritePolicy::WriteThrough)?;
+            assert_eq!(cache.len(), 3);
+        }
+
+        // Writing to the source behind the cache's back drops unpinned pages and refreshes
+        // pinned ones.
+        source.write(8, 16, &[2; 16])?;
+        let mut cache = cache.borrow_mut();
+        assert!(!cache.contains(id, 0) && cache.contains(id, 2));
+        assert_eq!(cache.get(id, 1).ok_or("pinned")?[..8], [2; 8]);
+
+        cache.invalidate(id, 0..usize::MAX)?;
+        assert_eq!(cache.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_ratio_works() -> Result<(), DsError> {
+        let mut data = [0; 64];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let id = SourceId::of(&source);
+        let mut cache = PageCache::<4, 16>::new();
+        assert!(cache.set_dirty_ratio(101).is_err());
+
+        // By default, every page may be dirty.
+        cache.write(&source, 0, &[1; 64])?;
+        assert!(!cache.needs_writeback());
+        assert_eq!(cache.writeback_work().count(), 0);
+
+        cache.set_dirty_ratio(50)?;
+        assert!(cache.needs_writeback());
+        let mut work = [(id, 0); 4];
+        let mut count = 0;
+        for (page, slot) in cache.writeback_work().zip(&mut work) {
+            *slot = page;
+            count += 1;
+        }
+        assert_eq!(count, 2);
+        for (source, page_index) in work.into_iter().take(count) {
+            cache.flush(source, page_index * 16..(page_index + 1) * 16)?;
+        }
+        assert!(!cache.needs_writeback());
+        assert_eq!(cache.dirty_pages(), 2);
+
+        Ok(())
+    }
+
+    impl<T> ShardLock<T> for parking_lot::Mutex<T> {
+        fn new(value: T) -> Self {
+            Self::new(value)
+        }
+
+        fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+            f(&mut self.lock())
+        }
+    }
+
+    #[test]
+    fn sharded_cache_works() -> Result<(), DsError> {
+        let mut data = [0; 64];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let id = SourceId::of(&source);
+        let cache = ShardedCache::<parking_lot::Mutex<_>, 2, 2, 16>::new();
+        assert_eq!(cache.capacity(), 4);
+
+        // Consecutive pages land in different shards.
+        cache.write(&source, 8, &[1; 16])?;
+        assert_eq!(cache.len(), 2);
+        assert!(cache.with_shard(id, 0, |shard| shard.contains(id, 0)
+            && !shard.contains(id, 1)));
+        assert!(cache.with_shard(id, 1, |shard| shard.contains(id, 1)));
+
+        let mut page = [0; 16];
+        cache.read(&source, 1, &mut page)?;
+        assert_eq!(page[..8], [1; 8]);
+        source.read(8, 1, &mut page)?;
+        assert_eq!(page[0], 0);
+        cache.flush_all()?;
+        source.read(8, 1, &mut page)?;
+        assert_eq!(page[0], 1);
+
+        cache.invalidate(id, 0..64)?;
+        assert!(cache.is_empty());
+
+        // A single core can use a `RefCell`.
+        let cache = ShardedCache::<RefCell<_>, 3, 1, 16>::default();
+        cache.read(&source, 3, &mut page)?;
+        assert_eq!(cache.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,72 @@
+#[cfg(feature = "alloc")]
+use alloc::sync::Arc;
+use core::any::Any;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, Range};
+
 pub type DsError = &'static str;
 
+/// Get how much of the page of `page_size` bytes at `offset` is within a source of length `len`.
+fn page_length(len: Option<usize>, offset: usize, page_size: usize) -> Result<usize, DsError> {
+    match len {
+        None => Ok(page_size),
+        Some(len) if offset < len => Ok(page_size.min(len - offset)),
+        Some(_) => Err("page past the end of the source"),
+    }
+}
+
+/// What a `DataSource` supports, beyond reading; see `DataSource::capabilities`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    /// Data can be written back to the source.
+    pub write: bool,
+    /// Data can be executed, so the source can back executable mappings.
+    pub execute: bool,
+    /// `flush` does something, so it's worth calling.
+    pub flush: bool,
+    /// The source can have holes, which read as zeros and take no storage.
+    pub sparse: bool,
+}
+
+impl Capabilities {
+    /// A source that can only be read and executed, like a ROM.
+    pub const READ_ONLY: Self = Self {
+        write: false,
+        execute: true,
+        flush: false,
+        sparse: false,
+    };
+
+    /// A source that can be read, written, executed, and flushed, like a file.
+    pub const READ_WRITE: Self = Self {
+        write: true,
+        execute: true,
+        flush: true,
+        sparse: false,
+    };
+}
+
+/// How a cache should handle stores to a `DataSource`'s pages; see `DataSource::write_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WritePolicy {
+    /// Stores only modify the cached page, which is written to the source when it's flushed or
+    /// evicted.
+    #[default]
+    WriteBack,
+    /// Stores are written to the source straight away, as well as to the cached page.
+    WriteThrough,
+}
+
+/// Treat a buffer whose bytes have all been initialized as an ordinary byte buffer.
+///
+/// # Safety
+/// Every byte of `buffer` must be initialized.
+pub(crate) unsafe fn assume_init_mut(buffer: &mut [MaybeUninit<u8>]) -> &mut [u8] {
+    // SAFETY: `MaybeUninit<u8>` has the same layout as `u8`, and the caller guarantees the bytes
+    // are initialized.
+    unsafe { &mut *(core::ptr::from_mut(buffer) as *mut [u8]) }
+}
+
 pub trait DataSource {
     // Constructors are left to each implementation, once you have one, you can:
 
@@ -20,4 +87,339 @@ pub trait DataSource {
     /// # Errors
     /// If flushing fails.
     fn flush(&self, offset: usize, length: usize) -> Result<(), DsError>;
+
+    /// Read `buffer.len()` bytes starting at `offset` into a possibly uninitialized buffer,
+    /// returning it initialized.
+    ///
+    /// This lets a freshly allocated page frame be filled without zeroing it first. Sources that
+    /// can fill the buffer directly should override this; the default zeroes the buffer and then
+    /// uses `read`.
+    ///
+    /// # Errors
+    /// If reading fails.
+    fn read_uninit<'b>(
+        &self,
+        offset: usize,
+        buffer: &'b mut [MaybeUninit<u8>],
+    ) -> Result<&'b [u8], DsError> {
+        for byte in &mut *buffer {
+            byte.write(0);
+        }
+        // SAFETY: every byte was just initialized.
+        let buffer = unsafe { assume_init_mut(buffer) };
+        self.read(offset, buffer.len(), buffer)?;
+        Ok(buffer)
+    }
+
+    /// Write `length` zeros starting at `offset`.
+    ///
+    /// Sparse sources and block devices should override this to zero without transferring
+    /// data. The default writes a small buffer of zeros repeatedly, so it needs no large
+    /// allocation.
+    ///
+    /// # Errors
+    /// If writing fails. Part of the range may have been zeroed.
+    fn write_zeros(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        const ZEROS: [u8; 512] = [0; 512];
+        let mut done = 0;
+        while done < length {
+            let chunk = (length - done).min(ZEROS.len());
+            let offset = offset.checked_add(done).ok_or("offset overflow")?;
+            self.write(offset, chunk, &ZEROS[..chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Read consecutive data starting at `offset` into each of `buffers` in turn, filling each
+    /// completely.
+    ///
+    /// Sources where a single large request is cheaper than many small ones, like block devices,
+    /// should override this. The default reads each buffer separately.
+    ///
+    /// # Errors
+    /// If any read fails. Buffers before the failing one may have been filled.
+    fn read_vectored(&self, offset: usize, buffers: &mut [&mut [u8]]) -> Result<(), DsError> {
+        let mut offset = offset;
+        for buffer in buffers {
+            self.read(offset, buffer.len(), buffer)?;
+            offset = offset.checked_add(buffer.len()).ok_or("offset overflow")?;
+        }
+        Ok(())
+    }
+
+    /// Write each of `buffers` in turn to consecutive data starting at `offset`.
+    ///
+    /// Like `read_vectored`, the default writes each buffer separately.
+    ///
+    /// # Errors
+    /// If any write fails. Buffers before the failing one may have been written.
+    fn write_vectored(&self, offset: usize, buffers: &[&[u8]]) -> Result<(), DsError> {
+        let mut offset = offset;
+        for buffer in buffers {
+            self.write(offset, buffer.len(), buffer)?;
+            offset = offset.checked_add(buffer.len()).ok_or("offset overflow")?;
+        }
+        Ok(())
+    }
+
+    /// Read the `page_index`th page of the `DataSource` into `page`, whose length is the page
+    /// size.
+    ///
+    /// This is the granularity faults and caching work at, so sources should override it if
+    /// they can do better than an arbitrary `read`. The default reads the page with `read`, and
+    /// zero-fills any part of it past the end of a bounded source, like the tail of a mapping's
+    /// last page.
+    ///
+    /// # Errors
+    /// If the page's offset overflows, the page starts past the end of the source, or reading
+    /// fails.
+    fn read_page(&self, page_index: usize, page: &mut [u8]) -> Result<(), DsError> {
+        let offset = page_index
+            .checked_mul(page.len())
+            .ok_or("offset overflow")?;
+        let length = page_length(self.len(), offset, page.len())?;
+        let (data, tail) = page.split_at_mut(length);
+        tail.fill(0);
+        self.read(offset, length, data)
+    }
+
+    /// Write `page`, whose length is the page size, to the `page_index`th page of the
+    /// `DataSource`.
+    ///
+    /// Like `read_page`, the default uses `write`, and drops any part of the page past the end
+    /// of a bounded source.
+    ///
+    /// # Errors
+    /// If the page's offset overflows, the page starts past the end of the source, or writing
+    /// fails.
+    fn write_page(&self, page_index: usize, page: &[u8]) -> Result<(), DsError> {
+        let offset = page_index
+            .checked_mul(page.len())
+            .ok_or("offset overflow")?;
+        let length = page_length(self.len(), offset, page.len())?;
+        self.write(offset, length, &page[..length])
+    }
+
+    /// Get the length of the `DataSource` in bytes, or `None` if it's unbounded, like a device
+    /// that can be read at any offset.
+    ///
+    /// An `AddressSpace` refuses to map past the end of a bounded `DataSource`. The default is
+    /// unbounded.
+    fn len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Get a name for the `DataSource`, like a file path, to say what is mapped in debug output
+    /// and error messages. The default is `"<anon>"`.
+    fn name(&self) -> &str {
+        "<anon>"
+    }
+
+    /// Get what the `DataSource` supports.
+    ///
+    /// An `AddressSpace` refuses to map a `DataSource` with flags it can't honor, like a shared
+    /// writable mapping of a read-only source. The default is `Capabilities::READ_WRITE`.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::READ_WRITE
+    }
+
+    /// Check whether the `DataSource`'s data may be cached. Device memory, whose contents can
+    /// change under us, or where accesses have side effects, must not be.
+    ///
+    /// An `AddressSpace` only maps an uncacheable `DataSource` with the `no_cache` flag. The
+    /// default is cacheable.
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    /// Get how a cache should handle stores to the `DataSource`'s pages. Sources whose writes
+    /// must reach their storage promptly, like a journal, should be `WriteThrough`.
+    ///
+    /// The default is `WriteBack`.
+    fn write_policy(&self) -> WritePolicy {
+        WritePolicy::WriteBack
+    }
+
+    /// Check whether the `DataSource` has data for any of `[offset, offset + length)`, rather
+    /// than only holes that read as zeros.
+    ///
+    /// Layered sources like `sources::OverlaySource` use this to find which layer holds a page.
+    /// The default is that everything within the source's length has data.
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        length > 0 && self.len().is_none_or(|len| offset < len)
+    }
+
+    /// Get the first range at or after `offset` that has data rather than holes, like
+    /// `SEEK_DATA`, or `None` if there's no more data.
+    ///
+    /// The default is that everything within the source's length has data.
+    fn next_data(&self, offset: usize) -> Option<Range<usize>> {
+        match self.len() {
+            None => Some(offset..usize::MAX),
+            Some(len) => (offset < len).then_some(offset..len),
+        }
+    }
+
+    /// Discard the data in `[offset, offset + length)`, so that it reads as zeros and, if the
+    /// source is sparse, takes no storage, like TRIM or punching a hole in a file.
+    ///
+    /// # Errors
+    /// If discarding fails. By default, sources can't discard data.
+    fn discard(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        Err("cannot discard data")
+    }
+
+    /// Get the `DataSource` as `Any`, so that its concrete type can be recovered with
+    /// `downcast_ref`, e.g. to reach a file source's inode number from a mapping.
+    ///
+    /// Only `'static` types can be `Any`, so this is opt-in: implement it as `Some(self)`. The
+    /// default is `None`.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+
+    /// Check whether the `DataSource` is bounded and has no data.
+    fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl dyn DataSource + '_ {
+    /// Get the `DataSource` as a `T`, if it is one and supports `as_any`.
+    #[must_use]
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.as_any()?.downcast_ref()
+    }
+}
+
+/// A reference to a `DataSource` is a `DataSource` too, so combinators like
+/// `sources::CowSource` can wrap a source without owning it.
+impl<D: DataSource + ?Sized> DataSource for &D {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        (**self).read(offset, length, buffer)
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        (**self).write(offset, length, buffer)
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        (**self).flush(offset, length)
+    }
+
+    fn read_uninit<'b>(
+        &self,
+        offset: usize,
+        buffer: &'b mut [MaybeUninit<u8>],
+    ) -> Result<&'b [u8], DsError> {
+        (**self).read_uninit(offset, buffer)
+    }
+
+    fn write_zeros(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        (**self).write_zeros(offset, length)
+    }
+
+    fn read_vectored(&self, offset: usize, buffers: &mut [&mut [u8]]) -> Result<(), DsError> {
+        (**self).read_vectored(offset, buffers)
+    }
+
+    fn write_vectored(&self, offset: usize, buffers: &[&[u8]]) -> Result<(), DsError> {
+        (**self).write_vectored(offset, buffers)
+    }
+
+    fn read_page(&self, page_index: usize, page: &mut [u8]) -> Result<(), DsError> {
+        (**self).read_page(page_index, page)
+    }
+
+    fn write_page(&self, page_index: usize, page: &[u8]) -> Result<(), DsError> {
+        (**self).write_page(page_index, page)
+    }
+
+    fn len(&self) -> Option<usize> {
+        (**self).len()
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn cacheable(&self) -> bool {
+        (**self).cacheable()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        (**self).write_policy()
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        (**self).has_data(offset, length)
+    }
+
+    fn next_data(&self, offset: usize) -> Option<Range<usize>> {
+        (**self).next_data(offset)
+    }
+
+    fn discard(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        (**self).discard(offset, length)
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        (**self).as_any()
+    }
+}
+
+/// How a mapping holds its `DataSource`: borrowed, so it must outlive the `AddressSpace`, or,
+/// with the `alloc` feature, shared ownership through an `Arc`, so it lives as long as the
+/// mappings that use it. A shared source can't borrow anything itself, since it may outlive
+/// everything but the mappings.
+///
+/// Anything that converts into a `SourceRef` can be passed to `AddressSpace::map`.
+#[derive(Clone)]
+pub enum SourceRef<'a> {
+    Borrowed(&'a dyn DataSource),
+    #[cfg(feature = "alloc")]
+    Shared(Arc<dyn DataSource>),
+}
+
+impl<'a> Deref for SourceRef<'a> {
+    type Target = dyn DataSource + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(source) => *source,
+            #[cfg(feature = "alloc")]
+            Self::Shared(source) => &**source,
+        }
+    }
+}
+
+impl<'a, D: DataSource> From<&'a D> for SourceRef<'a> {
+    fn from(source: &'a D) -> Self {
+        Self::Borrowed(source)
+    }
+}
+
+impl<'a> From<&'a dyn DataSource> for SourceRef<'a> {
+    fn from(source: &'a dyn DataSource) -> Self {
+        Self::Borrowed(source)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<D: DataSource + 'static> From<Arc<D>> for SourceRef<'_> {
+    fn from(source: Arc<D>) -> Self {
+        Self::Shared(source)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl From<Arc<dyn DataSource>> for SourceRef<'_> {
+    fn from(source: Arc<dyn DataSource>) -> Self {
+        Self::Shared(source)
+    }
 }
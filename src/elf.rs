@@ -0,0 +1,343 @@
+//! Loading ELF executables into an `AddressSpace`, by mapping their segments straight from the
+//! `DataSource` holding the image.
+//!
+//! Only little-endian 64-bit images are supported.
+
+use crate::address_space::{AddressSpace, AsError, Flags};
+use crate::data_source::{DsError, SourceRef};
+use crate::sources::ZeroSource;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+// The source of the pages of a segment past the end of its data in the image, its BSS.
+static ZERO: ZeroSource = ZeroSource;
+
+/// Why an ELF image couldn't be loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ElfError {
+    /// The image doesn't start with the ELF magic number.
+    NotElf,
+    /// The image isn't a little-endian 64-bit executable or position-independent executable.
+    Unsupported,
+    /// A program header is malformed, like a segment with more data in the image than in memory,
+    /// or whose address and offset in the image aren't equal modulo the page size.
+    BadSegment,
+    /// Reading the image failed.
+    Source(DsError),
+    /// Mapping a segment failed.
+    Map(AsError),
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NotElf => f.write_str("not an ELF image"),
+            Self::Unsupported => f.write_str("unsupported ELF image"),
+            Self::BadSegment => f.write_str("malformed ELF segment"),
+            Self::Source(e) => f.write_str(e),
+            Self::Map(e) => write!(f, "failed to map ELF segment: {e}"),
+        }
+    }
+}
+
+/// A loadable segment, as described by its program header.
+struct Segment {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().expect("slice is 4 bytes"))
+}
+
+fn usize_at(bytes: &[u8], at: usize) -> Result<usize, ElfError> {
+    let value = u64::from_le_bytes(bytes[at..at + 8].try_into().expect("slice is 8 bytes"));
+    usize::try_from(value).map_err(|_| ElfError::BadSegment)
+}
+
+/// Map every loadable segment of the ELF image in `image` into `space`, at its address plus
+/// `bias`, returning the entry point, likewise biased.
+///
+/// Executables are usually loaded with a `bias` of zero, and position-independent executables
+/// wherever there's room for them. Each segment is mapped with `flags`, like `user`, plus the
+/// access flags from its program header, as a private mapping: its data is mapped from `image`,
+/// and the rest of it, its BSS, reads as zeros. Segments are mapped right next to each other,
+/// with no guard between them.
+///
+/// # Errors
+/// If the image isn't a supported ELF image, reading it fails, one of its program headers is
+/// malformed, or mapping a segment fails, like when there's no room for it. The segments mapped
+/// before the failure stay mapped.
+pub fn load<
+    'a,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize,
+    const MIN_GAP_SIZE: usize,
+    const N_RESIDENT: usize,
+>(
+    space: &mut AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+    image: impl Into<SourceRef<'a>>,
+    bias: usize,
+    flags: Flags,
+) -> Result<usize, ElfError> {
+    let image = image.into();
+    let mut header = [0; HEADER_SIZE];
+    image
+        .read(0, HEADER_SIZE, &mut header)
+        .map_err(ElfError::Source)?;
+    if header[..4] != MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    let elf_type = u16_at(&header, 16);
+    if header[4] != CLASS_64
+        || header[5] != DATA_LSB
+        || !(elf_type == TYPE_EXEC || elf_type == TYPE_DYN)
+    {
+        return Err(ElfError::Unsupported);
+    }
+    let entry = usize_at(&header, 24)?;
+    let program_headers = usize_at(&header, 32)?;
+    let entry_size = usize::from(u16_at(&header, 54));
+    let entries = u16_at(&header, 56);
+    if entry_size < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadSegment);
+    }
+
+    for i in 0..usize::from(entries) {
+        let offset = i
+            .checked_mul(entry_size)
+            .and_then(|offset| offset.checked_add(program_headers))
+            .ok_or(ElfError::BadSegment)?;
+        let mut entry = [0; PROGRAM_HEADER_SIZE];
+        image
+            .read(offset, PROGRAM_HEADER_SIZE, &mut entry)
+            .map_err(ElfError::Source)?;
+        if u32_at(&entry, 0) != PT_LOAD {
+            continue;
+        }
+        let segment = Segment {
+            flags: u32_at(&entry, 4),
+            offset: usize_at(&entry, 8)?,
+            vaddr: usize_at(&entry, 16)?
+                .checked_add(bias)
+                .ok_or(ElfError::BadSegment)?,
+            file_size: usize_at(&entry, 32)?,
+            mem_size: usize_at(&entry, 40)?,
+        };
+        map_segment(space, &image, &segment, flags)?;
+    }
+
+    entry.checked_add(bias).ok_or(ElfError::BadSegment)
+}
+
+/// Map `segment` of `image` into `space`: its data from `image`, then a mapping of zeros for
+/// whatever of its BSS doesn't fit in the last page of its data.
+fn map_segment<
+    'a,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize,
+    const MIN_GAP_SIZE: usize,
+    const N_RESIDENT: usize,
+>(
+    space: &mut AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+    image: &SourceRef<'a>,
+    segment: &Segment,
+    flags: Flags,
+) -> Result<(), ElfError> {
+    if segment.mem_size == 0 {
+        return Ok(());
+    }
+    let page_size = space.page_size();
+    let skew = segment.vaddr % page_size;
+    if segment.file_size > segment.mem_size || segment.offset % page_size != skew {
+        return Err(ElfError::BadSegment);
+    }
+    let flags = flags
+        .into_builder()
+        .set_read(segment.flags & PF_R != 0)
+        .set_write(segment.flags & PF_W != 0)
+        .set_execute(segment.flags & PF_X != 0)
+        .set_shared(false)
+        .set_private(true)
+        .try_validate()
+        .expect("valid flags stay valid when private");
+    let start = segment.vaddr - skew;
+    let end = segment
+        .vaddr
+        .checked_add(segment.mem_size)
+        .ok_or(ElfError::BadSegment)?;
+
+    // The rest of the last page of data reads as zeros, since it's past the requested length.
+    let mut data_end = start;
+    if segment.file_size > 0 {
+        space
+            .map(image.clone())
+            .at(start)
+            .offset(segment.offset - skew)
+            .length(skew + segment.file_size)
+            .flags(flags)
+            .guard(0)
+            .commit()
+            .map_err(ElfError::Map)?;
+        data_end = start + (skew + segment.file_size).div_ceil(page_size) * page_size;
+    }
+    if end > data_end {
+        space
+            .map(&ZERO)
+            .at(data_end)
+            .length(end - data_end)
+            .flags(flags)
+            .guard(0)
+            .commit()
+            .map_err(ElfError::Map)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags;
+    use crate::sources::SliceSource;
+
+    fn put(image: &mut [u8], at: usize, bytes: &[u8]) {
+        image[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Write a program header at `at` of `image`.
+    fn put_segment(image: &mut [u8], at: usize, kind: u32, flags: u32, segment: [u64; 4]) {
+        put(image, at, &kind.to_le_bytes());
+        put(image, at + 4, &flags.to_le_bytes());
+        let [offset, vaddr, file_size, mem_size] = segment;
+        put(image, at + 8, &offset.to_le_bytes());
+        put(image, at + 16, &vaddr.to_le_bytes());
+        put(image, at + 32, &file_size.to_le_bytes());
+        put(image, at + 40, &mem_size.to_le_bytes());
+    }
+
+    /// Build an executable with a text segment, a note, and a data segment with some BSS.
+    fn image() -> [u8; 0x2100] {
+        let mut image = [0xaa; 0x2100];
+        put(&mut image, 0, &MAGIC);
+        put(&mut image, 4, &[CLASS_64, DATA_LSB]);
+        put(&mut image, 16, &TYPE_EXEC.to_le_bytes());
+        put(&mut image, 24, &0x10_0040_u64.to_le_bytes());
+        put(&mut image, 32, &64_u64.to_le_bytes());
+        put(&mut image, 54, &56_u16.to_le_bytes());
+        put(&mut image, 56, &3_u16.to_le_bytes());
+        put_segment(
+            &mut image,
+            64,
+            PT_LOAD,
+            PF_R | PF_X,
+            [0, 0x10_0000, 0x1000, 0x1000],
+        );
+        put_segment(&mut image, 120, 4, PF_R, [0x200, 0, 0x20, 0x20]);
+        put_segment(
+            &mut image,
+            176,
+            PT_LOAD,
+            PF_R | PF_W,
+            [0x1100, 0x10_2100, 0x1000, 0x3000],
+        );
+        image
+    }
+
+    #[test]
+    fn load_works() -> Result<(), ElfError> {
+        let image = image();
+        let source = SliceSource::new(&image);
+        let mut space = AddressSpace::<1024>::new("test space");
+        assert_eq!(load(&mut space, &source, 0, flags![user])?, 0x10_0040);
+
+        let segments = [
+            (0x10_0000, 0x1000, 0, flags![read, execute, user, private]),
+            (
+                0x10_2000,
+                0x2000,
+                0x1000,
+                flags![read, write, user, private],
+            ),
+            (0x10_4000, 0x2000, 0, flags![read, write, user, private]),
+        ];
+        let mappings = space.mappings_in_range(0, space.ceiling());
+        for (m, (addr, length, offset, flags)) in mappings.zip(segments) {
+            assert_eq!(
+                (m.addr, m.length, m.offset, m.flags),
+                (addr, length, offset, flags)
+            );
+        }
+        assert_eq!(space.mappings_in_range(0, space.ceiling()).count(), 3);
+
+        // The data ends partway through its last page; the rest of it is zeroed.
+        let mut page = [0xff; 4096];
+        space
+            .populate_page(0x10_3000, &mut page)
+            .map_err(ElfError::Map)?;
+        assert!(page[..0x100].iter().all(|byte| *byte == 0xaa));
+        assert!(page[0x100..].iter().all(|byte| *byte == 0));
+
+        // Position-independent executables can go anywhere.
+        let mut space = AddressSpace::<1024>::new("test space");
+        assert_eq!(load(&mut space, &source, 0x20_0000, flags![])?, 0x30_0040);
+        assert!(space.is_mapped(0x30_0000) && space.is_mapped(0x30_5fff));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_images_are_rejected() {
+        let mut space = AddressSpace::<1024>::new("test space");
+        let mut image = image();
+        image[3] = b'f';
+        let source = SliceSource::new(&image);
+        assert_eq!(
+            load(&mut space, &source, 0, flags![]),
+            Err(ElfError::NotElf)
+        );
+
+        let mut image = self::image();
+        image[4] = 1;
+        let source = SliceSource::new(&image);
+        assert_eq!(
+            load(&mut space, &source, 0, flags![]),
+            Err(ElfError::Unsupported)
+        );
+
+        // The data segment's offset and address are a page apart.
+        let mut image = self::image();
+        put(&mut image, 176 + 8, &0x1200_u64.to_le_bytes());
+        let source = SliceSource::new(&image);
+        assert_eq!(
+            load(&mut space, &source, 0, flags![]),
+            Err(ElfError::BadSegment)
+        );
+
+        let mut image = self::image();
+        put(&mut image, 32, &0x2100_u64.to_le_bytes());
+        let source = SliceSource::new(&image);
+        assert!(matches!(
+            load(&mut space, &source, 0, flags![]),
+            Err(ElfError::Source(_))
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+//! Physical frames, and the allocators that hand them out, for the parts of the crate that need
+//! real memory behind a page, like copying a copy-on-write page.
+
+use crate::address_space::DEFAULT_PAGE_SIZE;
+
+/// A physical frame, identified by its physical address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysFrame(usize);
+
+impl PhysFrame {
+    /// Get the frame starting at physical address `addr`.
+    #[must_use]
+    pub const fn new(addr: usize) -> Self {
+        Self(addr)
+    }
+
+    /// Get the physical address the frame starts at.
+    #[must_use]
+    pub const fn addr(self) -> usize {
+        self.0
+    }
+}
+
+/// Hands out physical frames of at least a page each.
+pub trait FrameAllocator {
+    /// Allocate a frame, or return `None` if there are none free.
+    fn alloc_frame(&mut self) -> Option<PhysFrame>;
+
+    /// Free a frame returned by `alloc_frame`.
+    fn free_frame(&mut self, frame: PhysFrame);
+
+    /// Get the contents of an allocated frame, e.g. through the kernel's direct map of physical
+    /// memory.
+    fn frame_data(&mut self, frame: PhysFrame) -> &mut [u8];
+
+    /// Get the number of free frames, if the allocator keeps count.
+    fn available(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<A: FrameAllocator + ?Sized> FrameAllocator for &mut A {
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        (**self).alloc_frame()
+    }
+
+    fn free_frame(&mut self, frame: PhysFrame) {
+        (**self).free_frame(frame);
+    }
+
+    fn frame_data(&mut self, frame: PhysFrame) -> &mut [u8] {
+        (**self).frame_data(frame)
+    }
+
+    fn available(&self) -> Option<usize> {
+        (**self).available()
+    }
+}
+
+/// An allocator with no frames, for installing pages that already have one.
+pub(crate) struct NoFrames;
+
+impl FrameAllocator for NoFrames {
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+
+    fn free_frame(&mut self, _frame: PhysFrame) {
+        unreachable!("no frames are handed out");
+    }
+
+    fn frame_data(&mut self, _frame: PhysFrame) -> &mut [u8] {
+        unreachable!("no frames are handed out");
+    }
+}
+
+/// Hands out the frames of a region of memory, keeping one flag per frame for whether it's in
+/// use.
+///
+/// This is enough for tests and early boot, where there's a known region of free memory. Frames
+/// are `FRAME_SIZE` bytes, and the `i`th frame of `memory` is at physical address
+/// `base + i * FRAME_SIZE`.
+pub struct BitmapAllocator<'m, const N_FRAMES: usize, const FRAME_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    base: PhysFrame,
+    memory: &'m mut [u8],
+    used: [bool; N_FRAMES],
+}
+
+impl<'m, const N_FRAMES: usize, const FRAME_SIZE: usize> BitmapAllocator<'m, N_FRAMES, FRAME_SIZE> {
+    /// Hand out the frames of `memory`, which starts at `base`, all of them free.
+    ///
+    /// # Panics
+    /// If `memory` is smaller than `N_FRAMES` frames.
+    pub fn new(base: PhysFrame, memory: &'m mut [u8]) -> Self {
+        assert!(
+            memory.len() >= N_FRAMES * FRAME_SIZE,
+            "memory must hold every frame"
+        );
+        Self {
+            base,
+            memory,
+            used: [false; N_FRAMES],
+        }
+    }
+
+    /// Get the number of free frames.
+    #[must_use]
+    pub fn free_frames(&self) -> usize {
+        self.used.iter().filter(|used| !**used).count()
+    }
+
+    /// Get the index of `frame` in `memory`.
+    ///
+    /// # Panics
+    /// If `frame` isn't one of this allocator's frames.
+    fn index_of(&self, frame: PhysFrame) -> usize {
+        let index = frame
+            .addr()
+            .checked_sub(self.base.addr())
+            .map(|offset| offset / FRAME_SIZE)
+            .filter(|index| *index < N_FRAMES);
+        index.expect("frame is from this allocator")
+    }
+}
+
+impl<const N_FRAMES: usize, const FRAME_SIZE: usize> FrameAllocator
+    for BitmapAllocator<'_, N_FRAMES, FRAME_SIZE>
+{
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        let index = self.used.iter().position(|used| !used)?;
+        self.used[index] = true;
+        Some(PhysFrame::new(self.base.addr() + index * FRAME_SIZE))
+    }
+
+    fn free_frame(&mut self, frame: PhysFrame) {
+        let index = self.index_of(frame);
+        self.used[index] = false;
+    }
+
+    fn frame_data(&mut self, frame: PhysFrame) -> &mut [u8] {
+        let start = self.index_of(frame) * FRAME_SIZE;
+        &mut self.memory[start..start + FRAME_SIZE]
+    }
+
+    fn available(&self) -> Option<usize> {
+        Some(self.free_frames())
+    }
+}
+
+/// Something holding onto frames that it can give back when memory runs low, like a cache that
+/// can drop clean pages, write back dirty ones and drop them, or swap out eviction victims; see
+/// `ReclaimingAllocator`.
+pub trait ReclaimHandler {
+    /// Free up to `target` frames to `frames`, returning how many were freed.
+    ///
+    /// This is called in the middle of allocating a frame, so it mustn't use whatever is
+    /// allocating it, like the `AddressSpace` handling a fault.
+    fn reclaim(&self, target: usize, frames: &mut dyn FrameAllocator) -> usize;
+}
+
+/// A `FrameAllocator` that asks its `ReclaimHandler`s for frames back whenever the number of free
+/// frames of the allocator `F` it wraps drops below a watermark.
+///
+/// Before each allocation, if fewer than `watermark` frames are free, the handlers are asked in
+/// turn to free enough to bring it back up to the watermark, stopping once they have. If the
+/// allocation fails anyway, or `F` doesn't count its free frames, they're asked for one more
+/// frame, and the allocation is retried.
+pub struct ReclaimingAllocator<'r, F> {
+    inner: F,
+    watermark: usize,
+    handlers: &'r [&'r dyn ReclaimHandler],
+}
+
+impl<'r, F: FrameAllocator> ReclaimingAllocator<'r, F> {
+    /// Wrap `inner`, asking `handlers` for frames back when fewer than `watermark` are free.
+    pub const fn new(inner: F, watermark: usize, handlers: &'r [&'r dyn ReclaimHandler]) -> Self {
+        Self {
+            inner,
+            watermark,
+            handlers,
+        }
+    }
+
+    /// Get the watermark below which frames are reclaimed.
+    #[must_use]
+    pub const fn watermark(&self) -> usize {
+        self.watermark
+    }
+
+    /// Unwrap the allocator.
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Ask the handlers to free `target` frames, returning how many they freed.
+    pub fn reclaim(&mut self, target: usize) -> usize {
+        let mut freed = 0;
+        for handler in self.handlers {
+            if freed >= target {
+                break;
+            }
+            freed += handler.reclaim(target - freed, &mut self.inner);
+        }
+        freed
+    }
+}
+
+impl<F: FrameAllocator> FrameAllocator for ReclaimingAllocator<'_, F> {
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(free) = self.inner.available() {
+            if free < self.watermark {
+                self.reclaim(self.watermark - free);
+            }
+        }
+        self.inner.alloc_frame().or_else(|| {
+            (self.reclaim(1) > 0)
+                .then(|| self.inner.alloc_frame())
+                .flatten()
+        })
+    }
+
+    fn free_frame(&mut self, frame: PhysFrame) {
+        self.inner.free_frame(frame);
+    }
+
+    fn frame_data(&mut self, frame: PhysFrame) -> &mut [u8] {
+        self.inner.frame_data(frame)
+    }
+
+    fn available(&self) -> Option<usize> {
+        self.inner.available()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    /// A cache holding onto frames it can drop.
+    #[derive(Default)]
+    struct Cache {
+        frames: RefCell<[Option<PhysFrame>; 4]>,
+    }
+
+    impl ReclaimHandler for Cache {
+        fn reclaim(&self, target: usize, frames: &mut dyn FrameAllocator) -> usize {
+            let mut held = self.frames.borrow_mut();
+            let mut freed = 0;
+            for frame in held.iter_mut().filter_map(Option::take).take(target) {
+                frames.free_frame(frame);
+                freed += 1;
+            }
+            freed
+        }
+    }
+
+    #[test]
+    fn reclaiming_works() {
+        let mut memory = [0; 64];
+        let cache = Cache::default();
+        let handlers: [&dyn ReclaimHandler; 1] = [&cache];
+        let frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let mut frames = ReclaimingAllocator::new(frames, 2, &handlers);
+
+        // The cache fills up memory, until it's asked to give some back.
+        for i in 0..3 {
+            cache.frames.borrow_mut()[i] = frames.alloc_frame();
+        }
+        assert_eq!(frames.available(), Some(1));
+        let frame = frames.alloc_frame();
+        assert!(frame.is_some());
+        assert_eq!(frames.available(), Some(1));
+        assert_eq!(cache.frames.borrow().iter().flatten().count(), 2);
+
+        // When the cache is empty, allocations fail.
+        assert_eq!(frames.reclaim(4), 2);
+        for _ in 0..3 {
+            assert!(frames.alloc_frame().is_some());
+        }
+        assert_eq!(frames.alloc_frame(), None);
+        assert_eq!(frames.into_inner().free_frames(), 0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Plain-old-data types, which can be copied to and from raw bytes, for reading and writing typed
+//! values through an `AddressSpace` with `read_val` and `write_val`.
+
+use core::mem::size_of;
+
+/// A type that can be safely converted to and from its raw bytes, like `bytemuck::Pod`.
+///
+/// Implemented for the integer types and arrays of `FromBytes` types. User structs, like a
+/// `timespec`, can implement it too, if they meet the requirements below.
+///
+/// # Safety
+/// Every bit pattern must be a valid value of the type, so it can't contain `bool`s, `char`s,
+/// references, or enums, and it must have no padding bytes, so it must be `#[repr(C)]` or
+/// `#[repr(transparent)]` with fields laid out to leave no gaps.
+pub unsafe trait FromBytes: Copy + 'static {
+    /// Create a value whose bytes are all zero.
+    #[must_use]
+    fn zeroed() -> Self {
+        // SAFETY: every bit pattern is a valid `Self`, including all zeros.
+        unsafe { core::mem::zeroed() }
+    }
+
+    /// View the value as its raw bytes.
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: `Self` has no padding, so all `size_of::<Self>()` bytes are initialized.
+        unsafe { core::slice::from_raw_parts(core::ptr::from_ref(self).cast(), size_of::<Self>()) }
+    }
+
+    /// View the value as its raw bytes, to overwrite them.
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and any bytes written leave a valid `Self`.
+        unsafe {
+            core::slice::from_raw_parts_mut(core::ptr::from_mut(self).cast(), size_of::<Self>())
+        }
+    }
+}
+
+macro_rules! impl_from_bytes {
+    ($($t:ty),*) => {
+        $(
+            // SAFETY: integers have no padding, and every bit pattern is a valid integer.
+            unsafe impl FromBytes for $t {}
+        )*
+    };
+}
+
+impl_from_bytes!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+// SAFETY: arrays have no padding between their elements, and their elements are `FromBytes`.
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
@@ -0,0 +1,162 @@
+//! A process heap that grows and shrinks with `brk` and `sbrk`, like the data segment of a Unix
+//! process.
+
+use crate::address_space::{AddressSpace, AsError, Flags, MappingHandle};
+use crate::sources::ZeroSource;
+
+/// A heap of anonymous memory starting at a fixed address, whose end, the program break, moves
+/// up and down with `brk` and `sbrk`.
+///
+/// The heap is a single private mapping of zeros from its start to the break, rounded up to a
+/// whole page, grown and shrunk in place with `AddressSpace::resize_mapping`. While the break is
+/// at the start, there's no mapping at all. The `Heap` doesn't borrow the `AddressSpace`, so
+/// every call takes the one the heap lives in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heap {
+    start: usize,
+    end: usize,
+    flags: Flags,
+    mapping: Option<MappingHandle>,
+}
+
+impl Heap {
+    /// Create an empty heap in `space`, starting at `start`, whose memory will be mapped with
+    /// `flags`, made private.
+    ///
+    /// # Errors
+    /// If `start` isn't page-aligned.
+    pub fn new<
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    >(
+        space: &AddressSpace<'_, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+        start: usize,
+        flags: Flags,
+    ) -> Result<Self, AsError> {
+        if !start.is_multiple_of(space.page_size()) {
+            return Err(AsError::Unaligned);
+        }
+        let flags = flags
+            .into_builder()
+            .set_shared(false)
+            .set_private(true)
+            .try_validate()
+            .expect("valid flags stay valid when private");
+        Ok(Self {
+            start,
+            end: start,
+            flags,
+            mapping: None,
+        })
+    }
+
+    /// Get the first address of the heap.
+    #[must_use]
+    pub const fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Get the program break: one past the last address of the heap.
+    #[must_use]
+    pub const fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Move the program break to `end`, growing or shrinking the heap's mapping in `space` to
+    /// match.
+    ///
+    /// Memory freed by shrinking the heap, and then reallocated by growing it again, reads as
+    /// zeros, except for the rest of the page the break was in.
+    ///
+    /// # Errors
+    /// If `end` is below the start of the heap, there isn't room to grow the heap, or the
+    /// mapping was removed or sealed behind the heap's back. The break doesn't move.
+    pub fn brk<
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    >(
+        &mut self,
+        space: &mut AddressSpace<'_, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+        end: usize,
+    ) -> Result<(), AsError> {
+        let length = end.checked_sub(self.start).ok_or(AsError::NoSpace)?;
+        match (self.mapping, length) {
+            (None, 0) => {}
+            (None, length) => {
+                let handle = space.add_mapping_at(self.start, &ZeroSource, length, self.flags)?;
+                self.mapping = Some(handle);
+            }
+            (Some(handle), 0) => {
+                space.remove_mapping(handle)?;
+                self.mapping = None;
+            }
+            (Some(handle), length) => space.resize_mapping(handle, length)?,
+        }
+        self.end = end;
+
+        Ok(())
+    }
+
+    /// Move the program break by `delta` bytes, returning where it was; see `brk`.
+    ///
+    /// # Errors
+    /// As for `brk`, or if the new break would overflow.
+    pub fn sbrk<
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    >(
+        &mut self,
+        space: &mut AddressSpace<'_, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+        delta: isize,
+    ) -> Result<usize, AsError> {
+        let old = self.end;
+        let end = old.checked_add_signed(delta).ok_or(AsError::Overflow)?;
+        self.brk(space, end)?;
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags;
+
+    #[test]
+    fn brk_works() -> Result<(), AsError> {
+        let mut space = AddressSpace::<20>::new("test space");
+        let mut heap = Heap::new(&space, 0x4000, flags![read, write, user])?;
+        assert_eq!(
+            Heap::new(&space, 0x4001, flags![read]),
+            Err(AsError::Unaligned)
+        );
+        assert_eq!(heap.sbrk(&mut space, 100)?, 0x4000);
+        assert_eq!(heap.end(), 0x4064);
+        let info = space.mappings_in_range(0x4000, 0x4001).next();
+        assert!(info.is_some_and(|m| m.length == 0x1000
+            && m.requested_length == 100
+            && m.flags == flags![read, write, user, private]));
+
+        heap.brk(&mut space, 0x6000)?;
+        assert!(space.is_mapped(0x5fff) && !space.is_mapped(0x6000));
+        space.add_mapping_at(0x7000, &ZeroSource, 0x1000, flags![read])?;
+        // The heap keeps its guard gap.
+        assert_eq!(heap.brk(&mut space, 0x7000), Err(AsError::NoSpace));
+        assert_eq!(heap.end(), 0x6000);
+        assert_eq!(heap.sbrk(&mut space, -0x1fff)?, 0x6000);
+        assert!(space.is_mapped(0x4000) && !space.is_mapped(0x5000));
+
+        // The mapping goes away entirely when the heap is empty.
+        heap.brk(&mut space, 0x4000)?;
+        assert!(!space.is_mapped(0x4000));
+        assert_eq!(heap.sbrk(&mut space, -1), Err(AsError::NoSpace));
+        assert_eq!(heap.sbrk(&mut space, isize::MIN), Err(AsError::Overflow));
+
+        Ok(())
+    }
+}
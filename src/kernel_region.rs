@@ -0,0 +1,210 @@
+//! A region of kernel mappings shared by every `AddressSpace`, like the higher half of a
+//! higher-half kernel.
+
+use crate::address_space::{
+    AccessType, AddressSpace, AsError, FaultResolution, Flags, MappingHandle, DEFAULT_PAGE_SIZE,
+};
+use crate::data_source::SourceRef;
+use crate::frame::FrameAllocator;
+use crate::page_table::PageTable;
+use crate::tlb::TlbMaintenance;
+use core::cell::RefCell;
+
+/// The kernel's mappings, from `start` to the top of the address space, shared by reference
+/// among every `AddressSpace` given it with `AddressSpace::with_kernel_region`.
+///
+/// Its mappings are all global, and changes to them are made once, here, and seen by every
+/// `AddressSpace` sharing the region. Kernels usually share the tables translating the region
+/// between every page table too, so `install_into` and `sync_into` only need to write them once.
+pub struct KernelRegion<'a, const N_PAGES: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    start: usize,
+    space: RefCell<AddressSpace<'a, N_PAGES, PAGE_SIZE>>,
+}
+
+impl<'a, const N_PAGES: usize, const PAGE_SIZE: usize> KernelRegion<'a, N_PAGES, PAGE_SIZE> {
+    /// Create an empty kernel region starting at `start`.
+    ///
+    /// # Errors
+    /// If `start` isn't `PAGE_SIZE`-aligned or is beyond the end of the address space.
+    pub fn new(start: usize) -> Result<Self, AsError> {
+        let space = AddressSpace::new("kernel");
+        if !start.is_multiple_of(space.page_size()) {
+            return Err(AsError::Unaligned);
+        }
+        if start >= space.ceiling() {
+            return Err(AsError::CeilingTooHigh);
+        }
+        Ok(Self {
+            start,
+            space: RefCell::new(space),
+        })
+    }
+
+    /// Invalidate stale TLB entries through `tlb` when the region changes; see
+    /// `AddressSpace::with_tlb_maintenance`. The region has no ASID, so every translation is
+    /// invalidated.
+    #[must_use]
+    pub fn with_tlb_maintenance(self, tlb: &'a dyn TlbMaintenance) -> Self {
+        Self {
+            space: RefCell::new(self.space.into_inner().with_tlb_maintenance(tlb)),
+            ..self
+        }
+    }
+
+    /// Get the first address of the region.
+    #[must_use]
+    pub const fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Add a global mapping at `addr`; see `AddressSpace::add_mapping_at`.
+    ///
+    /// # Errors
+    /// If `addr` is below the start of the region, or as for `AddressSpace::add_mapping_at`.
+    pub fn add_mapping(
+        &self,
+        addr: usize,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        if addr < self.start {
+            return Err(AsError::NoSpace);
+        }
+        self.space
+            .borrow_mut()
+            .add_mapping_at(addr, source, length, global(flags))
+    }
+
+    /// Remove a mapping; see `AddressSpace::remove_mapping`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::remove_mapping`.
+    pub fn remove_mapping(&self, handle: MappingHandle) -> Result<(), AsError> {
+        self.space.borrow_mut().remove_mapping(handle)
+    }
+
+    /// Change the access flags of a mapping, keeping it global; see `AddressSpace::protect`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::protect`.
+    pub fn protect(&self, handle: MappingHandle, flags: Flags) -> Result<(), AsError> {
+        self.space.borrow_mut().protect(handle, global(flags))
+    }
+
+    /// Check whether `addr` falls inside some mapping of the region.
+    #[must_use]
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.space.borrow().is_mapped(addr)
+    }
+
+    /// Work out how to resolve a page fault in the region; see `AddressSpace::handle_fault`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::handle_fault`.
+    pub fn handle_fault(
+        &self,
+        addr: usize,
+        access: AccessType,
+    ) -> Result<FaultResolution, AsError> {
+        self.space.borrow_mut().handle_fault(addr, access)
+    }
+
+    /// Resolve a page fault in the region into a frame; see `AddressSpace::fault_in`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::fault_in`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn fault_in(
+        &self,
+        addr: usize,
+        access: AccessType,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<FaultResolution, AsError> {
+        self.space.borrow_mut().fault_in(addr, access, frames)
+    }
+
+    /// Write the region's resident pages into `pt`; see `AddressSpace::install_into`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::install_into`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn install_into<P: PageTable, F: FrameAllocator>(
+        &self,
+        pt: &mut P,
+        frames: &mut F,
+    ) -> Result<usize, AsError> {
+        self.space.borrow_mut().install_into(pt, frames)
+    }
+
+    /// Bring `pt` up to date with the region; see `AddressSpace::sync_into`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::sync_into`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn sync_into<P: PageTable, F: FrameAllocator>(
+        &self,
+        pt: &mut P,
+        frames: &mut F,
+    ) -> Result<usize, AsError> {
+        self.space.borrow_mut().sync_into(pt, frames)
+    }
+
+    /// Call `f` on the `AddressSpace` holding the region's mappings, e.g. to look through them
+    /// with `AddressSpace::mappings_in_range`.
+    pub fn inspect<R>(&self, f: impl FnOnce(&AddressSpace<'a, N_PAGES, PAGE_SIZE>) -> R) -> R {
+        f(&self.space.borrow())
+    }
+}
+
+/// The parts of a `KernelRegion` an `AddressSpace` sharing it uses, without its type parameters.
+pub(crate) trait KernelMappings {
+    fn start(&self) -> usize;
+    fn is_mapped(&self, addr: usize) -> bool;
+    fn handle_fault(&self, addr: usize, access: AccessType) -> Result<FaultResolution, AsError>;
+    fn fault_in(
+        &self,
+        addr: usize,
+        access: AccessType,
+        frames: &mut dyn FrameAllocator,
+    ) -> Result<FaultResolution, AsError>;
+}
+
+impl<const N_PAGES: usize, const PAGE_SIZE: usize> KernelMappings
+    for KernelRegion<'_, N_PAGES, PAGE_SIZE>
+{
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn is_mapped(&self, addr: usize) -> bool {
+        self.is_mapped(addr)
+    }
+
+    fn handle_fault(&self, addr: usize, access: AccessType) -> Result<FaultResolution, AsError> {
+        self.handle_fault(addr, access)
+    }
+
+    fn fault_in(
+        &self,
+        addr: usize,
+        access: AccessType,
+        mut frames: &mut dyn FrameAllocator,
+    ) -> Result<FaultResolution, AsError> {
+        self.fault_in(addr, access, &mut frames)
+    }
+}
+
+fn global(flags: Flags) -> Flags {
+    flags
+        .into_builder()
+        .set_global(true)
+        .try_validate()
+        .expect("global doesn't affect validity")
+}
@@ -1,10 +1,40 @@
-#![feature(int_roundings)]
 #![allow(dead_code, unused_variables)]
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
 pub mod address_space;
-mod cacher;
+pub mod asid;
+pub mod cacher;
 mod data_source;
+#[cfg(feature = "elf")]
+pub mod elf;
+pub mod frame;
+pub mod from_bytes;
+pub mod heap;
+pub mod kernel_region;
+pub mod page_table;
+pub mod shared;
+pub mod sources;
+pub mod stack;
+pub mod swap;
+pub mod tlb;
 
-pub use address_space::{AddressSpace, Flags};
-pub use data_source::DataSource;
+pub use address_space::{
+    AccessType, AddressSpace, AsError, FaultHandler, FaultResolution, FaultStats, Flags, Limits,
+    MemoryUsage, MmapRequest,
+};
+pub use cacher::{EvictionPolicy, Invalidator, PageCache, ShardLock, ShardedCache, SourceId};
+pub use data_source::{Capabilities, DataSource, DsError, SourceRef, WritePolicy};
+pub use frame::{BitmapAllocator, FrameAllocator, PhysFrame, ReclaimHandler, ReclaimingAllocator};
+pub use from_bytes::FromBytes;
+pub use heap::Heap;
+pub use kernel_region::KernelRegion;
+pub use page_table::{PageTable, Sv39};
+pub use shared::{PageLocks, SharedAddressSpace, TranslationCache};
+pub use stack::InitialStack;
+pub use swap::{SwapBackend, SwapSlot};
+pub use tlb::TlbMaintenance;
@@ -0,0 +1,517 @@
+//! Hardware page tables, the translation structures an `AddressSpace` is materialized into.
+
+use crate::address_space::{AsError, Flags};
+use crate::asid::Asid;
+use crate::frame::{FrameAllocator, PhysFrame};
+
+/// A hardware page table, translating pages of virtual addresses to physical frames.
+pub trait PageTable {
+    /// The size of the pages the table maps, in bytes.
+    const PAGE_SIZE: usize;
+
+    /// The size of the huge pages the table can map with a single entry, in bytes, if it has
+    /// any; see `map_huge`.
+    const HUGE_PAGE_SIZE: Option<usize> = None;
+
+    /// Map the page at `vaddr` to `frame` with `flags`, replacing any existing mapping of it.
+    ///
+    /// A huge page containing `vaddr` is split first, with `split_huge`.
+    ///
+    /// # Errors
+    /// If `vaddr` or `frame` isn't page-aligned, `vaddr` can't be translated by the table, the
+    /// flags don't permit any access, or there are no frames for a new table.
+    fn map(&mut self, vaddr: usize, frame: PhysFrame, flags: Flags) -> Result<(), AsError>;
+
+    /// Unmap the page containing `vaddr`, returning the frame and flags it was mapped with, if it
+    /// was mapped.
+    ///
+    /// If `vaddr` is in a huge page, the whole huge page is unmapped; split it first with
+    /// `split_huge` to unmap a single page.
+    fn unmap(&mut self, vaddr: usize) -> Option<(PhysFrame, Flags)>;
+
+    /// Map the `HUGE_PAGE_SIZE` bytes at `vaddr` to the physically contiguous frames starting at
+    /// `frame` with `flags`, using a single entry, replacing any existing mappings of the pages in
+    /// it.
+    ///
+    /// # Errors
+    /// If the table has no huge pages, `vaddr` or `frame` isn't aligned to `HUGE_PAGE_SIZE`,
+    /// `vaddr` can't be translated by the table, the flags don't permit any access, or there are
+    /// no frames for a new table.
+    fn map_huge(&mut self, _vaddr: usize, _frame: PhysFrame, _flags: Flags) -> Result<(), AsError> {
+        Err(AsError::BadPageSize)
+    }
+
+    /// Split the huge page containing `vaddr` into pages mapped to the same frames with the same
+    /// flags, returning whether there was one.
+    ///
+    /// # Errors
+    /// If there are no frames for the new table.
+    fn split_huge(&mut self, _vaddr: usize) -> Result<bool, AsError> {
+        Ok(false)
+    }
+
+    /// Look up the frame and flags the page containing `vaddr` is mapped with, if it's mapped.
+    ///
+    /// Only the flags the hardware knows about are recovered; in particular, copy-on-write
+    /// mappings come back as read-only.
+    fn query(&mut self, vaddr: usize) -> Option<(PhysFrame, Flags)>;
+
+    /// Get the physical address of the root table, to load into the root register.
+    fn root_pa(&self) -> usize;
+}
+
+const SV39_PAGE_SIZE: usize = 4096;
+const SV39_HUGE_PAGE_SIZE: usize = SV39_PAGE_SIZE << SV39_INDEX_BITS;
+const SV39_LEVELS: usize = 3;
+const SV39_VA_BITS: u32 = 39;
+// Each level of the table translates 9 bits of the virtual page number.
+const SV39_INDEX_BITS: u32 = 9;
+const SV39_ENTRIES: usize = 1 << SV39_INDEX_BITS;
+const PTE_SIZE: usize = 8;
+const PTE_V: u64 = 1;
+const PTE_RWX: u64 = 0b1110;
+const PTE_PPN_SHIFT: u32 = 10;
+const PTE_PPN_MASK: u64 = ((1 << 44) - 1) << PTE_PPN_SHIFT;
+
+/// A RISC-V Sv39 page table: three levels of 512-entry tables, translating 39-bit virtual
+/// addresses to 56-bit physical ones in 4 KiB pages, or 2 MiB huge pages.
+///
+/// Tables are allocated from `F`, whose frames must be at least 4 KiB, and accessed through
+/// `FrameAllocator::frame_data`. To share an allocator with the rest of the kernel, use a
+/// `&mut` reference to it as `F`.
+pub struct Sv39<F: FrameAllocator> {
+    root: PhysFrame,
+    frames: F,
+}
+
+impl<F: FrameAllocator> Sv39<F> {
+    /// Create an empty page table, allocating its root table from `frames`.
+    ///
+    /// # Errors
+    /// If there are no free frames.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than 4 KiB.
+    pub fn new(mut frames: F) -> Result<Self, AsError> {
+        let root = Self::alloc_table(&mut frames)?;
+        Ok(Self { root, frames })
+    }
+
+    /// Get the allocator the tables come from.
+    pub fn frames(&mut self) -> &mut F {
+        &mut self.frames
+    }
+
+    /// Free every table back to the allocator, returning it. The frames mapped by the table
+    /// aren't freed.
+    pub fn free_tables(mut self) -> F {
+        self.free_table(self.root, SV39_LEVELS - 1);
+        self.frames
+    }
+
+    fn free_table(&mut self, table: PhysFrame, level: usize) {
+        if level > 0 {
+            for index in 0..SV39_ENTRIES {
+                let pte = self.entry(table, index);
+                if pte & PTE_V != 0 && pte & PTE_RWX == 0 {
+                    self.free_table(Self::pte_frame(pte), level - 1);
+                }
+            }
+        }
+        self.frames.free_frame(table);
+    }
+
+    fn alloc_table(frames: &mut F) -> Result<PhysFrame, AsError> {
+        let table = frames.alloc_frame().ok_or(AsError::NoFrames)?;
+        frames.frame_data(table)[..SV39_PAGE_SIZE].fill(0);
+        Ok(table)
+    }
+
+    fn entry(&mut self, table: PhysFrame, index: usize) -> u64 {
+        let start = index * PTE_SIZE;
+        let bytes = &self.frames.frame_data(table)[start..start + PTE_SIZE];
+        u64::from_le_bytes(bytes.try_into().expect("a PTE is 8 bytes"))
+    }
+
+    fn set_entry(&mut self, table: PhysFrame, index: usize, pte: u64) {
+        let start = index * PTE_SIZE;
+        self.frames.frame_data(table)[start..start + PTE_SIZE].copy_from_slice(&pte.to_le_bytes());
+    }
+
+    #[allow(clippy::cast_possible_truncation)] // physical addresses fit in a `usize`
+    const fn pte_frame(pte: u64) -> PhysFrame {
+        PhysFrame::new(((pte & PTE_PPN_MASK) >> PTE_PPN_SHIFT << 12) as usize)
+    }
+
+    const fn pte_for(frame: PhysFrame) -> u64 {
+        (frame.addr() as u64 >> 12) << PTE_PPN_SHIFT | PTE_V
+    }
+
+    /// Check whether `vaddr` can be translated: bits 63 through 38 must all be equal.
+    const fn is_canonical(vaddr: usize) -> bool {
+        let high = (vaddr as u64) >> (SV39_VA_BITS - 1);
+        high == 0 || high == u64::MAX >> (SV39_VA_BITS - 1)
+    }
+
+    const fn index(vaddr: usize, level: usize) -> usize {
+        (vaddr >> (12 + SV39_INDEX_BITS as usize * level)) & (SV39_ENTRIES - 1)
+    }
+
+    /// Find the table at `level` translating `vaddr`, creating any missing tables on the way, and
+    /// splitting any superpages, if `create`.
+    fn table(
+        &mut self,
+        vaddr: usize,
+        level: usize,
+        create: bool,
+    ) -> Result<Option<PhysFrame>, AsError> {
+        let mut table = self.root;
+        for level in (level + 1..SV39_LEVELS).rev() {
+            let index = Self::index(vaddr, level);
+            let pte = self.entry(table, index);
+            table = if pte & PTE_V == 0 {
+                if !create {
+                    return Ok(None);
+                }
+                let next = Self::alloc_table(&mut self.frames)?;
+                self.set_entry(table, index, Self::pte_for(next));
+                next
+            } else if pte & PTE_RWX != 0 {
+                // A superpage maps the whole range, so there's no table below it.
+                if !create {
+                    return Ok(None);
+                }
+                self.split(table, index, level)?
+            } else {
+                Self::pte_frame(pte)
+            };
+        }
+        Ok(Some(table))
+    }
+
+    /// Find the leaf entry translating `vaddr`, returning the table it's in, its index, and its
+    /// level.
+    fn leaf_entry(&mut self, vaddr: usize) -> Option<(PhysFrame, usize, usize)> {
+        let mut table = self.root;
+        for level in (0..SV39_LEVELS).rev() {
+            let index = Self::index(vaddr, level);
+            let pte = self.entry(table, index);
+            if pte & PTE_V == 0 {
+                return None;
+            }
+            if pte & PTE_RWX != 0 {
+                return Some((table, index, level));
+            }
+            table = Self::pte_frame(pte);
+        }
+        None
+    }
+
+    /// Replace the superpage entry at `index` of `table`, at `level`, with a table of entries
+    /// mapping the same frames with the same flags, returning the new table.
+    fn split(
+        &mut self,
+        table: PhysFrame,
+        index: usize,
+        level: usize,
+    ) -> Result<PhysFrame, AsError> {
+        let pte = self.entry(table, index);
+        let next = Self::alloc_table(&mut self.frames)?;
+        // Each entry of the new table maps this many pages further on.
+        let step = 1 << (SV39_INDEX_BITS as usize * (level - 1));
+        for i in 0..SV39_ENTRIES {
+            self.set_entry(next, i, pte + (((i * step) as u64) << PTE_PPN_SHIFT));
+        }
+        self.set_entry(table, index, Self::pte_for(next));
+        Ok(next)
+    }
+}
+
+impl<F: FrameAllocator> PageTable for Sv39<F> {
+    const PAGE_SIZE: usize = SV39_PAGE_SIZE;
+
+    fn map(&mut self, vaddr: usize, frame: PhysFrame, flags: Flags) -> Result<(), AsError> {
+        if !vaddr.is_multiple_of(SV39_PAGE_SIZE) || !frame.addr().is_multiple_of(SV39_PAGE_SIZE) {
+            return Err(AsError::Unaligned);
+        }
+        if !Self::is_canonical(vaddr) {
+            return Err(AsError::NotCanonical);
+        }
+        let bits = flags.to_riscv_pte_bits();
+        if bits & PTE_RWX == 0 {
+            return Err(AsError::NoAccess);
+        }
+
+        let table = self.table(vaddr, 0, true)?.ok_or(AsError::NotCanonical)?;
+        self.set_entry(table, Self::index(vaddr, 0), Self::pte_for(frame) | bits);
+        Ok(())
+    }
+
+    fn unmap(&mut self, vaddr: usize) -> Option<(PhysFrame, Flags)> {
+        let mapped = self.query(vaddr)?;
+        let (table, index, _) = self.leaf_entry(vaddr)?;
+        self.set_entry(table, index, 0);
+        Some(mapped)
+    }
+
+    fn query(&mut self, vaddr: usize) -> Option<(PhysFrame, Flags)> {
+        if !Self::is_canonical(vaddr) {
+            return None;
+        }
+        let (table, index, level) = self.leaf_entry(vaddr)?;
+        let pte = self.entry(table, index);
+        // A superpage maps `vaddr`'s page at the same offset into its frames.
+        let offset = vaddr & ((SV39_PAGE_SIZE << (SV39_INDEX_BITS as usize * level)) - 1);
+        let frame = Self::pte_frame(pte).addr() + offset - offset % SV39_PAGE_SIZE;
+        Some((PhysFrame::new(frame), Flags::from_riscv_pte_bits(pte)?))
+    }
+
+    fn map_huge(&mut self, vaddr: usize, frame: PhysFrame, flags: Flags) -> Result<(), AsError> {
+        if !vaddr.is_multiple_of(SV39_HUGE_PAGE_SIZE)
+            || !frame.addr().is_multiple_of(SV39_HUGE_PAGE_SIZE)
+        {
+            return Err(AsError::Unaligned);
+        }
+        if !Self::is_canonical(vaddr) {
+            return Err(AsError::NotCanonical);
+        }
+        let bits = flags.to_riscv_pte_bits();
+        if bits & PTE_RWX == 0 {
+            return Err(AsError::NoAccess);
+        }
+
+        let table = self.table(vaddr, 1, true)?.ok_or(AsError::NotCanonical)?;
+        let index = Self::index(vaddr, 1);
+        let pte = self.entry(table, index);
+        if pte & PTE_V != 0 && pte & PTE_RWX == 0 {
+            self.free_table(Self::pte_frame(pte), 0);
+        }
+        self.set_entry(table, index, Self::pte_for(frame) | bits);
+        Ok(())
+    }
+
+    fn split_huge(&mut self, vaddr: usize) -> Result<bool, AsError> {
+        if !Self::is_canonical(vaddr) {
+            return Ok(false);
+        }
+        match self.leaf_entry(vaddr) {
+            Some((table, index, 1)) => self.split(table, index, 1).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+
+    fn root_pa(&self) -> usize {
+        self.root.addr()
+    }
+
+    const HUGE_PAGE_SIZE: Option<usize> = Some(SV39_HUGE_PAGE_SIZE);
+}
+
+impl<F: FrameAllocator> Sv39<F> {
+    /// Get the value to load into `satp` to activate this table, tagged with `asid` if given.
+    ///
+    /// Returns `None` if the ASID doesn't fit; see `riscv_satp`.
+    #[must_use]
+    pub fn satp(&self, asid: Option<Asid>) -> Option<u64> {
+        riscv_satp(SatpMode::Sv39, self.root_pa(), asid)
+    }
+}
+
+/// A RISC-V translation mode, as encoded in the MODE field of `satp`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SatpMode {
+    /// No translation.
+    Bare = 0,
+    /// Three-level translation of 39-bit virtual addresses.
+    Sv39 = 8,
+    /// Four-level translation of 48-bit virtual addresses.
+    Sv48 = 9,
+    /// Five-level translation of 57-bit virtual addresses.
+    Sv57 = 10,
+}
+
+/// Get the value of the RISC-V `satp` register that translates with `mode`, starting from the
+/// root table at `root_pa`, tagged with `asid`, or ASID 0 if there's none.
+///
+/// Returns `None` if `root_pa` isn't page-aligned or is beyond the 56-bit physical address
+/// space, or the ASID is wider than 16 bits.
+///
+/// ```
+/// # use reedos_address_space::page_table::{riscv_satp, SatpMode};
+/// let satp = riscv_satp(SatpMode::Sv39, 0x8020_0000, None);
+/// assert_eq!(satp, Some(8 << 60 | 0x80200));
+/// ```
+#[must_use]
+pub const fn riscv_satp(mode: SatpMode, root_pa: usize, asid: Option<Asid>) -> Option<u64> {
+    let asid = match asid {
+        Some(asid) => asid.id() as u64,
+        None => 0,
+    };
+    let root_pa = root_pa as u64;
+    if !root_pa.is_multiple_of(4096) || root_pa >> 56 != 0 || asid >> 16 != 0 {
+        return None;
+    }
+    Some((mode as u64) << 60 | asid << 44 | root_pa >> 12)
+}
+
+/// Get the value of the x86_64 `CR3` register that translates starting from the top-level table
+/// at `root_pa`, tagged with `pcid` if given, which needs `CR4.PCIDE`.
+///
+/// With a PCID, `no_flush` sets bit 63, so that loading the value keeps the TLB entries already
+/// tagged with it.
+///
+/// Returns `None` if `root_pa` isn't page-aligned or is beyond the 52-bit physical address
+/// space, or the PCID is wider than 12 bits.
+#[must_use]
+pub const fn x86_64_cr3(root_pa: usize, pcid: Option<Asid>, no_flush: bool) -> Option<u64> {
+    let root_pa = root_pa as u64;
+    if !root_pa.is_multiple_of(4096) || root_pa >> 52 != 0 {
+        return None;
+    }
+    match pcid {
+        Some(pcid) if pcid.id() >> 12 != 0 => None,
+        Some(pcid) => Some(root_pa | pcid.id() as u64 | (no_flush as u64) << 63),
+        None => Some(root_pa),
+    }
+}
+
+/// Get the value of an AArch64 `TTBR0_EL1` or `TTBR1_EL1` register that translates starting
+/// from the table at `root_pa`, tagged with `asid`, or ASID 0 if there's none.
+///
+/// Returns `None` if `root_pa` isn't aligned to 64 bytes, the smallest table alignment, or is
+/// beyond the 48-bit physical address space, or the ASID is wider than 16 bits, which also
+/// needs `TCR_EL1.AS`.
+#[must_use]
+pub const fn aarch64_ttbr(root_pa: usize, asid: Option<Asid>) -> Option<u64> {
+    let asid = match asid {
+        Some(asid) => asid.id() as u64,
+        None => 0,
+    };
+    let root_pa = root_pa as u64;
+    if !root_pa.is_multiple_of(64) || root_pa >> 48 != 0 || asid >> 16 != 0 {
+        return None;
+    }
+    Some(asid << 48 | root_pa)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asid::AsidAllocator;
+    use crate::flags;
+    use crate::frame::BitmapAllocator;
+
+    const FRAMES: usize = 6;
+
+    #[test]
+    fn sv39_works() -> Result<(), AsError> {
+        let mut memory = [0xff; FRAMES * SV39_PAGE_SIZE];
+        let frames = BitmapAllocator::<FRAMES>::new(PhysFrame::new(0x8000_0000), &mut memory);
+        let mut table = Sv39::new(frames)?;
+        assert_eq!(table.root_pa(), 0x8000_0000);
+        assert_eq!(table.satp(None), Some(8 << 60 | 0x80000));
+
+        let flags = flags![read, write, user];
+        let frame = PhysFrame::new(0x9000_0000);
+        table.map(0x4000_1000, frame, flags)?;
+        assert_eq!(table.query(0x4000_1234), Some((frame, flags)));
+        assert_eq!(table.query(0x4000_2000), None);
+        // The root table, plus one table at each of the two lower levels.
+        assert_eq!(table.frames().free_frames(), FRAMES - 3);
+
+        // The upper half is canonical too.
+        table.map(usize::MAX - 0xfff, frame, flags![read, global])?;
+        assert_eq!(table.query(usize::MAX), Some((frame, flags![read, global])));
+        assert_eq!(table.map(1 << 40, frame, flags), Err(AsError::NotCanonical));
+        assert_eq!(
+            table.map(0x1000, frame, flags![user]),
+            Err(AsError::NoAccess)
+        );
+        assert_eq!(table.map(0x1001, frame, flags), Err(AsError::Unaligned));
+
+        assert_eq!(table.unmap(0x4000_1000), Some((frame, flags)));
+        assert_eq!(table.query(0x4000_1000), None);
+        assert_eq!(table.unmap(0x4000_1000), None);
+
+        let frames = table.free_tables();
+        assert_eq!(frames.free_frames(), FRAMES);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sv39_huge_pages_work() -> Result<(), AsError> {
+        let mut memory = [0xff; FRAMES * SV39_PAGE_SIZE];
+        let frames = BitmapAllocator::<FRAMES>::new(PhysFrame::new(0x8000_0000), &mut memory);
+        let mut table = Sv39::new(frames)?;
+        let flags = flags![read, write];
+        let frame = PhysFrame::new(0x9000_0000);
+        table.map(0x4000_1000, frame, flags)?;
+
+        // The page's table is replaced by a single entry.
+        table.map_huge(0x4000_0000, frame, flags![read, execute])?;
+        assert_eq!(table.frames().free_frames(), FRAMES - 2);
+        assert_eq!(
+            table.query(0x4000_5678),
+            Some((PhysFrame::new(0x9000_5000), flags![read, execute]))
+        );
+        assert_eq!(
+            table.map_huge(0x4000_1000, frame, flags),
+            Err(AsError::Unaligned)
+        );
+        assert_eq!(
+            table.map_huge(0x4020_0000, PhysFrame::new(0x9000_1000), flags),
+            Err(AsError::Unaligned)
+        );
+
+        // Mapping a single page splits the huge page around it.
+        table.map(0x4000_1000, frame, flags)?;
+        assert_eq!(table.query(0x4000_1000), Some((frame, flags)));
+        assert_eq!(
+            table.query(0x401f_f000),
+            Some((PhysFrame::new(0x901f_f000), flags![read, execute]))
+        );
+        assert!(!table.split_huge(0x4000_0000)?);
+        table.map_huge(0x4020_0000, PhysFrame::new(0x9020_0000), flags)?;
+        assert!(table.split_huge(0x4020_0000)?);
+        assert_eq!(
+            table.query(0x4030_0000),
+            Some((PhysFrame::new(0x9030_0000), flags))
+        );
+        assert_eq!(table.frames().free_frames(), FRAMES - 4);
+
+        let frames = table.free_tables();
+        assert_eq!(frames.free_frames(), FRAMES);
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_registers_work() {
+        let mut asids = AsidAllocator::<{ 1 << 17 }>::new();
+        let asid = asids.alloc();
+        let satp = riscv_satp(SatpMode::Sv48, 0x8000_0000, Some(asid));
+        assert_eq!(satp, Some(9 << 60 | 1 << 44 | 0x80000));
+        assert_eq!(riscv_satp(SatpMode::Sv39, 0x8000_0010, None), None);
+        assert_eq!(
+            x86_64_cr3(0x10_0000, Some(asid), true),
+            Some(1 << 63 | 0x10_0001)
+        );
+        assert_eq!(x86_64_cr3(0x10_0000, Some(asid), false), Some(0x10_0001));
+        assert_eq!(x86_64_cr3(0x10_0000, None, true), Some(0x10_0000));
+        assert_eq!(
+            aarch64_ttbr(0x4000_0040, Some(asid)),
+            Some(1 << 48 | 0x4000_0040)
+        );
+        assert_eq!(aarch64_ttbr(1 << 48, None), None);
+
+        // ASIDs must fit in the register.
+        let wide = (2..1 << 16).map(|_| asids.alloc()).last();
+        assert!(wide.is_some_and(|asid| asid.id() == 0xffff));
+        assert!(riscv_satp(SatpMode::Sv39, 0, wide).is_some());
+        assert_eq!(x86_64_cr3(0, wide, false), None);
+        let too_wide = asids.alloc();
+        assert_eq!(riscv_satp(SatpMode::Sv39, 0, Some(too_wide)), None);
+        assert_eq!(aarch64_ttbr(0, Some(too_wide)), None);
+    }
+}
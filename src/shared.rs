@@ -0,0 +1,507 @@
+//! Sharing an `AddressSpace` between the paths of a kernel that all need it, like the page fault
+//! handler and the system calls of a process, behind whatever lock the kernel provides.
+
+use crate::address_space::{
+    AccessType, AddressSpace, AsError, FaultResolution, FaultStep, Flags, MappingHandle,
+    MmapRequest, DEFAULT_PAGE_SIZE, DEFAULT_RESIDENT_PAGES,
+};
+use crate::cacher::ShardLock;
+use crate::data_source::SourceRef;
+use crate::frame::FrameAllocator;
+use core::marker::PhantomData;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+type VirtualAddress = usize;
+
+/// A translation cached by a `TranslationCache`: a mapping's address range, the offset in its
+/// source that its start maps to, its flags, and the source.
+#[derive(Clone)]
+struct CachedTranslation<'a> {
+    range: Range<VirtualAddress>,
+    offset: usize,
+    flags: Flags,
+    source: SourceRef<'a>,
+}
+
+/// A small cache of the mappings a `SharedAddressSpace` translated addresses in, so that looking
+/// up the same mappings again doesn't take the lock, for each core to keep its own of.
+///
+/// It holds up to `N` mappings, replacing them round-robin, and remembers the generation of the
+/// `SharedAddressSpace` it was filled at; it's emptied as soon as the mappings change.
+pub struct TranslationCache<'a, const N: usize> {
+    generation: usize,
+    entries: [Option<CachedTranslation<'a>>; N],
+    // The entry to replace next.
+    next: usize,
+}
+
+impl<const N: usize> Default for TranslationCache<'_, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> TranslationCache<'a, N> {
+    /// Create an empty cache.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            generation: 0,
+            entries: core::array::from_fn(|_| None),
+            next: 0,
+        }
+    }
+
+    /// Empty the cache if it was filled at a generation other than `generation`.
+    fn validate(&mut self, generation: usize) {
+        if self.generation != generation {
+            self.entries = core::array::from_fn(|_| None);
+            self.generation = generation;
+        }
+    }
+
+    fn lookup(&self, addr: VirtualAddress) -> Option<&CachedTranslation<'a>> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.range.contains(&addr))
+    }
+
+    fn insert(&mut self, entry: CachedTranslation<'a>) {
+        if N == 0 {
+            return;
+        }
+        self.entries[self.next] = Some(entry);
+        self.next = (self.next + 1) % N;
+    }
+}
+
+/// Locks for the pages of a `SharedAddressSpace`, each of `N` locks `L` covering the pages whose
+/// index is equal to it modulo `N`, so that faults on different pages can be resolved at once; see
+/// `SharedAddressSpace::fault_in_concurrently`.
+pub struct PageLocks<L, const N: usize> {
+    locks: [L; N],
+}
+
+impl<L: ShardLock<()>, const N: usize> Default for PageLocks<L, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: ShardLock<()>, const N: usize> PageLocks<L, N> {
+    /// Create the locks.
+    ///
+    /// # Panics
+    /// If `N` is zero.
+    #[must_use]
+    pub fn new() -> Self {
+        assert!(N > 0, "page locks need locks");
+        Self {
+            locks: core::array::from_fn(|_| L::new(())),
+        }
+    }
+
+    /// Run `f` holding the lock for the page with index `index`.
+    fn with_page<R>(&self, index: usize, f: impl FnOnce() -> R) -> R {
+        self.locks[index % N].with(|()| f())
+    }
+}
+
+/// An `AddressSpace` behind a lock `L`, so it can be used through a shared reference.
+///
+/// `L` is any `ShardLock`, like a `RefCell` on a single core, or a spinlock wrapping the kernel's
+/// `lock_api`-style raw mutex on several. Each method takes the lock for just the one operation it
+/// forwards to, so a page fault on one core and an `mmap` on another are serialized, but neither
+/// holds the lock any longer than it needs to. Use `with` to do several operations atomically.
+///
+/// Looking up the mapping an address is in with `translate` needn't take the lock at all. The
+/// `SharedAddressSpace` keeps a copy of the address space's generation, which every change to its
+/// mappings bumps, and each core's `TranslationCache` is only trusted while the generation it was
+/// filled at is current.
+pub struct SharedAddressSpace<
+    'a,
+    L,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+    const MIN_GAP_SIZE: usize = PAGE_SIZE,
+    const N_RESIDENT: usize = DEFAULT_RESIDENT_PAGES,
+> {
+    lock: L,
+    // The address space's generation, copied while holding the lock after every operation.
+    generation: AtomicUsize,
+    // Fixed once the address space is shared, since it can't change with mappings in it.
+    page_size: usize,
+    _space: PhantomData<fn() -> AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>>,
+}
+
+impl<
+        'a,
+        L: ShardLock<AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>>,
+        const N_PAGES: usize,
+        const PAGE_SIZE: usize,
+        const MIN_GAP_SIZE: usize,
+        const N_RESIDENT: usize,
+    > SharedAddressSpace<'a, L, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>
+{
+    /// Put `space` behind a new lock.
+    #[must_use]
+    pub fn new(space: AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>) -> Self {
+        Self {
+            page_size: space.page_size(),
+            generation: AtomicUsize::new(space.generation()),
+            lock: L::new(space),
+            _space: PhantomData,
+        }
+    }
+
+    /// Run `f` on the address space, holding the lock until it returns.
+    ///
+    /// If `f` changes the mappings, every `TranslationCache` is invalidated afterwards. `f` must
+    /// not use this `SharedAddressSpace` itself, which would deadlock or panic, depending on `L`.
+    pub fn with<R>(
+        &self,
+        f: impl FnOnce(&mut AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>) -> R,
+    ) -> R {
+        self.lock.with(|space| {
+            let result = f(space);
+            self.generation.store(space.generation(), Ordering::Release);
+            result
+        })
+    }
+
+    /// Get the address space's generation, as of the end of the last operation on it; see
+    /// `AddressSpace::generation`.
+    #[must_use]
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Look up the `DataSource` and offset within it for `addr`, like
+    /// `AddressSpace::get_source_for_addr`, without taking the lock if the mapping is in `cache`.
+    ///
+    /// On a miss, or if the mappings have changed since `cache` was filled, the lock is taken
+    /// and the mapping is added to `cache`. A translation cached before a change may still be
+    /// used while the change is in progress, as with a TLB, but not once it's complete.
+    ///
+    /// # Errors
+    /// If `addr` isn't mapped, or the mapping doesn't permit `access`.
+    pub fn translate<const N: usize>(
+        &self,
+        addr: VirtualAddress,
+        access: AccessType,
+        cache: &mut TranslationCache<'a, N>,
+    ) -> Result<(SourceRef<'a>, usize), AsError> {
+        cache.validate(self.generation());
+        let entry = match cache.lookup(addr) {
+            Some(entry) => entry.clone(),
+            None => {
+                let (generation, found) =
+                    self.with(|space| (space.generation(), space.mapping_source(addr)));
+                let (range, offset, flags, source) = found.ok_or(AsError::NotMapped)?;
+                let entry = CachedTranslation {
+                    range,
+                    offset,
+                    flags,
+                    source,
+                };
+                cache.validate(generation);
+                cache.insert(entry.clone());
+                entry
+            }
+        };
+        if !entry.flags.permits(access) {
+            return Err(AsError::PermissionDenied);
+        }
+        Ok((entry.source, entry.offset + (addr - entry.range.start)))
+    }
+
+    /// Check whether `addr` is mapped; see `AddressSpace::is_mapped`.
+    #[must_use]
+    pub fn is_mapped(&self, addr: VirtualAddress) -> bool {
+        self.with(|space| space.is_mapped(addr))
+    }
+
+    /// Add a mapping wherever there's room; see `AddressSpace::add_mapping`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::add_mapping`.
+    pub fn add_mapping(
+        &self,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        self.with(|space| space.add_mapping(source, length, flags))
+    }
+
+    /// Add a mapping at `addr`; see `AddressSpace::add_mapping_at`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::add_mapping_at`.
+    pub fn add_mapping_at(
+        &self,
+        addr: VirtualAddress,
+        source: impl Into<SourceRef<'a>>,
+        length: usize,
+        flags: Flags,
+    ) -> Result<MappingHandle, AsError> {
+        self.with(|space| space.add_mapping_at(addr, source, length, flags))
+    }
+
+    /// Map memory as described by `req`; see `AddressSpace::mmap`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::mmap`.
+    pub fn mmap(&self, req: MmapRequest<'a>) -> Result<VirtualAddress, AsError> {
+        self.with(|space| space.mmap(req))
+    }
+
+    /// Remove a mapping; see `AddressSpace::remove_mapping`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::remove_mapping`.
+    pub fn remove_mapping(&self, handle: MappingHandle) -> Result<(), AsError> {
+        self.with(|space| space.remove_mapping(handle))
+    }
+
+    /// Unmap every page in a range; see `AddressSpace::unmap_range`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::unmap_range`.
+    pub fn unmap_range(&self, start: VirtualAddress, length: usize) -> Result<(), AsError> {
+        self.with(|space| space.unmap_range(start, length))
+    }
+
+    /// Change a mapping's flags; see `AddressSpace::protect`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::protect`.
+    pub fn protect(&self, handle: MappingHandle, flags: Flags) -> Result<(), AsError> {
+        self.with(|space| space.protect(handle, flags))
+    }
+
+    /// Decide how to resolve a page fault; see `AddressSpace::handle_fault`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::handle_fault`.
+    pub fn handle_fault(
+        &self,
+        addr: VirtualAddress,
+        access: AccessType,
+    ) -> Result<FaultResolution, AsError> {
+        self.with(|space| space.handle_fault(addr, access))
+    }
+
+    /// Resolve a page fault, populating a frame if needed; see `AddressSpace::fault_in`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::fault_in`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn fault_in(
+        &self,
+        addr: VirtualAddress,
+        access: AccessType,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<FaultResolution, AsError> {
+        self.with(|space| space.fault_in(addr, access, frames))
+    }
+
+    /// Resolve a page fault like `fault_in`, but without holding the lock while the page is read
+    /// from its `DataSource`, so that faults on other pages can be resolved meanwhile.
+    ///
+    /// The fault is resolved holding the page's lock from `locks`, so faults on the same page are
+    /// still resolved one at a time. The address space's lock is only held to work out how to
+    /// resolve the fault, and to record the frame the page was read into; copy-on-write copies
+    /// are still made holding it. If the page's mapping changes while the page is being read, the
+    /// frame is freed and the fault resolved with `fault_in` instead.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::fault_in`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn fault_in_concurrently<PL: ShardLock<()>, const LOCKS: usize>(
+        &self,
+        addr: VirtualAddress,
+        access: AccessType,
+        frames: &mut impl FrameAllocator,
+        locks: &PageLocks<PL, LOCKS>,
+    ) -> Result<FaultResolution, AsError> {
+        locks.with_page(addr / self.page_size, || {
+            match self.with(|space| space.begin_fault_in(addr, access, frames))? {
+                FaultStep::Done(resolution) => Ok(resolution),
+                FaultStep::Populate(pending) => {
+                    let frame = pending.populate(frames)?;
+                    match self.with(|space| space.finish_fault_in(&pending, frame)) {
+                        Some(resolution) => Ok(resolution),
+                        None => {
+                            frames.free_frame(frame);
+                            self.fault_in(addr, access, frames)
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Copy bytes out of the address space; see `AddressSpace::read_bytes`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::read_bytes`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn read_bytes(
+        &self,
+        addr: VirtualAddress,
+        buffer: &mut [u8],
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        self.with(|space| space.read_bytes(addr, buffer, required, frames))
+    }
+
+    /// Copy bytes into the address space; see `AddressSpace::write_bytes`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::write_bytes`.
+    ///
+    /// # Panics
+    /// If the frames from `frames` are smaller than a page.
+    pub fn write_bytes(
+        &self,
+        addr: VirtualAddress,
+        bytes: &[u8],
+        required: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), AsError> {
+        self.with(|space| space.write_bytes(addr, bytes, required, frames))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags;
+    use crate::frame::{BitmapAllocator, PhysFrame};
+    use crate::sources::ZeroSource;
+    use core::cell::RefCell;
+
+    #[test]
+    fn shared_address_space_works() -> Result<(), AsError> {
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let shared = SharedAddressSpace::<RefCell<_>, 20, 16>::new(AddressSpace::new("test space"));
+        let flags = flags![read, write, user, private];
+
+        // The syscall path and the fault handler only need a shared reference.
+        let (syscalls, faults) = (&shared, &shared);
+        let handle = syscalls.add_mapping(&ZeroSource, 32, flags)?;
+        let addr = handle.addr();
+        assert!(matches!(
+            faults.fault_in(addr, AccessType::Write, &mut frames),
+            Ok(FaultResolution::MapFrame { .. })
+        ));
+        syscalls.write_bytes(addr + 4, &[7; 4], flags![write], &mut frames)?;
+        let mut buffer = [0; 8];
+        faults.read_bytes(addr, &mut buffer, flags![read], &mut frames)?;
+        assert_eq!(buffer, [0, 0, 0, 0, 7, 7, 7, 7]);
+
+        syscalls.protect(handle, flags![read, user, private])?;
+        assert_eq!(
+            faults.handle_fault(addr, AccessType::Write),
+            Ok(FaultResolution::Deliver(AsError::PermissionDenied))
+        );
+        assert_eq!(shared.with(|space| space.resident_pages(handle)), Ok(1));
+        syscalls.unmap_range(addr, 32)?;
+        assert!(!shared.is_mapped(addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_faults_work() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
+
+        let data = [9; 32];
+        let source = SliceSource::new(&data);
+        let mut memory = [0; 64];
+        let mut frames = BitmapAllocator::<4, 16>::new(PhysFrame::new(0x1000), &mut memory);
+        let locks = PageLocks::<RefCell<()>, 2>::new();
+        let shared = SharedAddressSpace::<RefCell<_>, 20, 16>::new(AddressSpace::new("test space"));
+        let flags = flags![read, user, private];
+        let addr = shared.add_mapping(&source, 32, flags)?.addr();
+        assert!(matches!(
+            shared.fault_in_concurrently(addr + 20, AccessType::Read, &mut frames, &locks),
+            Ok(FaultResolution::MapFrame { page, .. }) if page == addr + 16
+        ));
+        assert_eq!(frames.free_frames(), 3);
+        // Once populated, the page's frame is reused.
+        assert!(matches!(
+            shared.fault_in_concurrently(addr + 16, AccessType::Read, &mut frames, &locks),
+            Ok(FaultResolution::MapFrame { page, .. }) if page == addr + 16
+        ));
+        assert_eq!(frames.free_frames(), 3);
+
+        // If the mapping changes while the page is being read, nothing is recorded.
+        let step =
+            shared.with(|space| space.begin_fault_in(addr, AccessType::Read, &mut frames))?;
+        let FaultStep::Populate(pending) = step else {
+            panic!("the page isn't populated yet");
+        };
+        let frame = pending.populate(&mut frames)?;
+        shared.unmap_range(addr, 16)?;
+        assert_eq!(
+            shared.with(|space| space.finish_fault_in(&pending, frame)),
+            None
+        );
+        frames.free_frame(frame);
+        assert_eq!(frames.free_frames(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cached_translations_work() -> Result<(), AsError> {
+        use crate::sources::SliceSource;
+
+        let data = [0; 64];
+        let source = SliceSource::new(&data);
+        let shared = SharedAddressSpace::<RefCell<_>, 20, 16>::new(AddressSpace::new("test space"));
+        let handle = shared.add_mapping(&source, 32, flags![read, user])?;
+        let addr = handle.addr();
+        let mut cache = TranslationCache::<2>::new();
+        let (_, offset) = shared.translate(addr + 20, AccessType::Read, &mut cache)?;
+        assert_eq!(offset, 20);
+
+        // Cached translations don't take the lock, so they work even while it's held.
+        shared.with(|_| {
+            assert!(shared
+                .translate(addr + 3, AccessType::Read, &mut cache)
+                .is_ok_and(|(_, offset)| offset == 3));
+            assert_eq!(
+                shared
+                    .translate(addr, AccessType::Write, &mut cache)
+                    .map(|(_, offset)| offset),
+                Err(AsError::PermissionDenied)
+            );
+        });
+
+        // Changing the mappings invalidates them.
+        let generation = shared.generation();
+        shared.remove_mapping(handle)?;
+        assert_ne!(shared.generation(), generation);
+        assert_eq!(
+            shared
+                .translate(addr, AccessType::Read, &mut cache)
+                .map(|(_, offset)| offset),
+            Err(AsError::NotMapped)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2031 @@
+//! Ready-made `DataSource` implementations.
+
+use crate::address_space::DEFAULT_PAGE_SIZE;
+use crate::data_source::{assume_init_mut, Capabilities, DataSource, DsError, WritePolicy};
+use core::any::Any;
+use core::cell::{Cell, RefCell};
+use core::mem::MaybeUninit;
+use core::ops::Range;
+
+/// Get the first `length` bytes of a buffer passed to `DataSource::read`.
+fn prefix_mut(buffer: &mut [u8], length: usize) -> Result<&mut [u8], DsError> {
+    buffer.get_mut(..length).ok_or("buffer too small")
+}
+
+/// Get the first `length` bytes of a buffer passed to `DataSource::write`.
+fn prefix(buffer: &[u8], length: usize) -> Result<&[u8], DsError> {
+    buffer.get(..length).ok_or("buffer too small")
+}
+
+/// Split `[offset, offset + length)` at page boundaries, calling `f` with each piece's page
+/// index, its offset within that page, and its range within the whole.
+fn for_each_page(
+    offset: usize,
+    length: usize,
+    page_size: usize,
+    mut f: impl FnMut(usize, usize, Range<usize>) -> Result<(), DsError>,
+) -> Result<(), DsError> {
+    offset.checked_add(length).ok_or("offset overflow")?;
+    let mut done = 0;
+    while done < length {
+        let position = offset + done;
+        let page_offset = position % page_size;
+        let piece = (page_size - page_offset).min(length - done);
+        f(position / page_size, page_offset, done..done + piece)?;
+        done += piece;
+    }
+    Ok(())
+}
+
+/// A fixed number of pages of data, each stored under the page index it holds.
+struct PageStore<const PAGES: usize, const PAGE_SIZE: usize> {
+    indices: [Option<usize>; PAGES],
+    data: [[u8; PAGE_SIZE]; PAGES],
+}
+
+impl<const PAGES: usize, const PAGE_SIZE: usize> PageStore<PAGES, PAGE_SIZE> {
+    const fn new() -> Self {
+        Self {
+            indices: [None; PAGES],
+            data: [[0; PAGE_SIZE]; PAGES],
+        }
+    }
+
+    fn slot_of(&self, page: usize) -> Option<usize> {
+        self.indices.iter().position(|&index| index == Some(page))
+    }
+
+    fn get(&self, page: usize) -> Option<&[u8; PAGE_SIZE]> {
+        self.slot_of(page).map(|slot| &self.data[slot])
+    }
+
+    fn get_mut(&mut self, page: usize) -> Option<&mut [u8; PAGE_SIZE]> {
+        self.slot_of(page).map(|slot| &mut self.data[slot])
+    }
+
+    /// Store a new page, which must not already be stored, returning its zeroed data.
+    fn insert(&mut self, page: usize) -> Result<&mut [u8; PAGE_SIZE], DsError> {
+        let slot = self
+            .indices
+            .iter()
+            .position(Option::is_none)
+            .ok_or("no room for another page")?;
+        self.indices[slot] = Some(page);
+        self.data[slot] = [0; PAGE_SIZE];
+        Ok(&mut self.data[slot])
+    }
+
+    /// Stop storing a page, returning whether it was stored.
+    fn remove(&mut self, page: usize) -> bool {
+        self.slot_of(page)
+            .map(|slot| self.indices[slot] = None)
+            .is_some()
+    }
+
+    fn len(&self) -> usize {
+        self.indices.iter().flatten().count()
+    }
+
+    /// Get the indices of the stored pages, in no particular order.
+    fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.indices.iter().flatten().copied()
+    }
+}
+
+/// Get the range `[offset, offset + length)` of a source of `len` bytes, if it's in bounds.
+fn range_within(offset: usize, length: usize, len: usize) -> Result<Range<usize>, DsError> {
+    offset
+        .checked_add(length)
+        .filter(|&end| end <= len)
+        .map(|end| offset..end)
+        .ok_or("out of bounds")
+}
+
+/// A `DataSource` of endless zeros, for anonymous memory.
+///
+/// Writes fail, so a writable anonymous mapping needs somewhere else to put its pages once
+/// they're written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ZeroSource;
+
+impl DataSource for ZeroSource {
+    fn read(&self, _offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        prefix_mut(buffer, length)?.fill(0);
+        Ok(())
+    }
+
+    fn write(&self, _offset: usize, _length: usize, _buffer: &[u8]) -> Result<(), DsError> {
+        Err("cannot write to a zero source")
+    }
+
+    fn read_uninit<'b>(
+        &self,
+        _offset: usize,
+        buffer: &'b mut [MaybeUninit<u8>],
+    ) -> Result<&'b [u8], DsError> {
+        for byte in &mut *buffer {
+            byte.write(0);
+        }
+        // SAFETY: every byte was just initialized.
+        Ok(unsafe { assume_init_mut(buffer) })
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "<zero>"
+    }
+
+    fn has_data(&self, _offset: usize, _length: usize) -> bool {
+        false
+    }
+
+    fn next_data(&self, _offset: usize) -> Option<Range<usize>> {
+        None
+    }
+
+    fn discard(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn write_zeros(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            sparse: true,
+            ..Capabilities::READ_ONLY
+        }
+    }
+}
+
+/// The error a `NullSource` returns for every access.
+pub const ACCESS_VIOLATION: DsError = "access violation";
+
+/// A `DataSource` that fails every access with `ACCESS_VIOLATION`, for explicitly mapping guard
+/// pages and poisoned regions that must trap when touched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NullSource;
+
+impl DataSource for NullSource {
+    fn read(&self, _offset: usize, _length: usize, _buffer: &mut [u8]) -> Result<(), DsError> {
+        Err(ACCESS_VIOLATION)
+    }
+
+    fn write(&self, _offset: usize, _length: usize, _buffer: &[u8]) -> Result<(), DsError> {
+        Err(ACCESS_VIOLATION)
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Err(ACCESS_VIOLATION)
+    }
+
+    fn name(&self) -> &str {
+        "<null>"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            write: false,
+            execute: false,
+            flush: false,
+            sparse: false,
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// A read-only `DataSource` over a byte slice, so that, for example, a boot image or an initrd
+/// can be mapped in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SliceSource<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SliceSource<'a> {
+    #[must_use]
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl DataSource for SliceSource<'_> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let range = range_within(offset, length, self.data.len())?;
+        prefix_mut(buffer, length)?.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&self, _offset: usize, _length: usize, _buffer: &[u8]) -> Result<(), DsError> {
+        Err("cannot write to a read-only slice")
+    }
+
+    fn read_uninit<'b>(
+        &self,
+        offset: usize,
+        buffer: &'b mut [MaybeUninit<u8>],
+    ) -> Result<&'b [u8], DsError> {
+        let data = &self.data[range_within(offset, buffer.len(), self.data.len())?];
+        for (byte, &value) in buffer.iter_mut().zip(data) {
+            byte.write(value);
+        }
+        // SAFETY: every byte was just initialized, since `data` is as long as `buffer`.
+        Ok(unsafe { assume_init_mut(buffer) })
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::READ_ONLY
+    }
+}
+
+/// A writable `DataSource` over a byte buffer, which it modifies in place.
+///
+/// Use `Cell::from_mut(buffer).as_slice_of_cells()` to borrow a `&mut [u8]` as a `&[Cell<u8>]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CellSliceSource<'a> {
+    data: &'a [Cell<u8>],
+}
+
+impl<'a> CellSliceSource<'a> {
+    #[must_use]
+    pub const fn new(data: &'a [Cell<u8>]) -> Self {
+        Self { data }
+    }
+}
+
+impl DataSource for CellSliceSource<'_> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let range = range_within(offset, length, self.data.len())?;
+        for (byte, cell) in prefix_mut(buffer, length)?
+            .iter_mut()
+            .zip(&self.data[range])
+        {
+            *byte = cell.get();
+        }
+        Ok(())
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let range = range_within(offset, length, self.data.len())?;
+        for (cell, &byte) in self.data[range].iter().zip(prefix(buffer, length)?) {
+            cell.set(byte);
+        }
+        Ok(())
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            flush: false,
+            ..Capabilities::READ_WRITE
+        }
+    }
+}
+
+/// A `DataSource` for device memory, like memory-mapped I/O registers.
+///
+/// Every access is volatile, and as wide as possible: each access is split into naturally
+/// aligned 8-, 4-, 2-, or 1-byte reads or writes, using the widest width that `offset` and
+/// `length` are both multiples of, so that a 4-byte access to a 32-bit register is a single
+/// 32-bit load or store. It can't be cached, so it can only be mapped with the `no_cache` flag.
+#[derive(Debug)]
+pub struct MmioSource {
+    base: *mut u8,
+    len: usize,
+}
+
+impl MmioSource {
+    /// Create a `MmioSource` for the `len` bytes of device memory starting at `base`.
+    ///
+    /// # Safety
+    /// `base` must be valid for volatile reads and writes of `len` bytes, aligned to 8 bytes,
+    /// for as long as the `MmioSource` exists.
+    #[must_use]
+    pub const unsafe fn new(base: *mut u8, len: usize) -> Self {
+        Self { base, len }
+    }
+
+    /// Get the widest access width that `offset` and `length` are both multiples of.
+    const fn width(offset: usize, length: usize) -> usize {
+        let mut width = 8;
+        while width > 1 && !(offset.is_multiple_of(width) && length.is_multiple_of(width)) {
+            width /= 2;
+        }
+        width
+    }
+}
+
+/// Copy between device memory and a buffer, one volatile access of type `T` at a time.
+///
+/// # Safety
+/// `device` must be valid for volatile accesses of `buffer.len()` bytes and aligned for `T`, and
+/// `buffer.len()` must be a multiple of the size of `T`.
+unsafe fn copy_volatile<T: Copy>(device: *mut u8, buffer: &mut [u8], to_device: bool) {
+    let size = core::mem::size_of::<T>();
+    for (i, chunk) in buffer.chunks_exact_mut(size).enumerate() {
+        // SAFETY: the caller guarantees this is in bounds and aligned.
+        let register = unsafe { device.add(i * size) }.cast::<T>();
+        let chunk = chunk.as_mut_ptr().cast::<T>();
+        if to_device {
+            // SAFETY: `chunk` is `size` bytes long, and `register` is valid as above.
+            unsafe { register.write_volatile(chunk.read_unaligned()) };
+        } else {
+            // SAFETY: as above.
+            unsafe { chunk.write_unaligned(register.read_volatile()) };
+        }
+    }
+}
+
+impl MmioSource {
+    /// Copy between the device memory at `offset` and `buffer`, in either direction.
+    fn transfer(&self, offset: usize, buffer: &mut [u8], to_device: bool) -> Result<(), DsError> {
+        let range = range_within(offset, buffer.len(), self.len)?;
+        // SAFETY: the range is in bounds, `new` guarantees the region is valid, and `width`
+        // divides both the offset and the length, so every access is aligned.
+        unsafe {
+            let device = self.base.add(range.start);
+            match Self::width(offset, buffer.len()) {
+                8 => copy_volatile::<u64>(device, buffer, to_device),
+                4 => copy_volatile::<u32>(device, buffer, to_device),
+                2 => copy_volatile::<u16>(device, buffer, to_device),
+                _ => copy_volatile::<u8>(device, buffer, to_device),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DataSource for MmioSource {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        self.transfer(offset, prefix_mut(buffer, length)?, false)
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        // Stage through a small buffer, so `transfer` can take a mutable buffer either way.
+        let buffer = prefix(buffer, length)?;
+        let mut staged = [0; 64];
+        for (i, chunk) in buffer.chunks(staged.len()).enumerate() {
+            let staged = &mut staged[..chunk.len()];
+            staged.copy_from_slice(chunk);
+            self.transfer(offset + i * 64, staged, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    fn name(&self) -> &str {
+        "<mmio>"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            execute: false,
+            flush: false,
+            ..Capabilities::READ_WRITE
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        WritePolicy::WriteThrough
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// A `DataSource` backed by a file, for hosted tests, emulators, and user-mode kernels.
+///
+/// Needs the `std` feature, and a Unix-like host for positioned I/O.
+#[cfg(all(feature = "std", unix))]
+#[derive(Debug)]
+pub struct FileSource {
+    file: std::fs::File,
+}
+
+#[cfg(all(feature = "std", unix))]
+impl FileSource {
+    #[must_use]
+    pub const fn new(file: std::fs::File) -> Self {
+        Self { file }
+    }
+
+    /// Get the underlying file back.
+    #[must_use]
+    pub fn into_inner(self) -> std::fs::File {
+        self.file
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl DataSource for FileSource {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        use std::os::unix::fs::FileExt;
+
+        let offset = u64::try_from(offset).map_err(|_| "offset too large")?;
+        self.file
+            .read_exact_at(prefix_mut(buffer, length)?, offset)
+            .map_err(|_| "failed to read file")
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        use std::os::unix::fs::FileExt;
+
+        let offset = u64::try_from(offset).map_err(|_| "offset too large")?;
+        self.file
+            .write_all_at(prefix(buffer, length)?, offset)
+            .map_err(|_| "failed to write file")
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        self.file.sync_data().map_err(|_| "failed to sync file")
+    }
+
+    /// The current length of the file, which can change as it's written to.
+    fn len(&self) -> Option<usize> {
+        let len = self.file.metadata().ok()?.len();
+        usize::try_from(len).ok()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+/// A copy-on-write `DataSource`: reads go through to `base` until a page is written, and writes
+/// go to a private copy of the page instead, which later reads then see. `base` is never
+/// written, so a private file mapping or a forked process's memory can share it.
+///
+/// Holds up to `PAGES` copied pages of `PAGE_SIZE` bytes. Flushing does nothing, since copies
+/// are private.
+pub struct CowSource<D, const PAGES: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    base: D,
+    copies: RefCell<PageStore<PAGES, PAGE_SIZE>>,
+}
+
+impl<D: DataSource, const PAGES: usize, const PAGE_SIZE: usize> CowSource<D, PAGES, PAGE_SIZE> {
+    #[must_use]
+    pub const fn new(base: D) -> Self {
+        Self {
+            base,
+            copies: RefCell::new(PageStore::new()),
+        }
+    }
+
+    /// Get the source that pages are copied from.
+    pub const fn base(&self) -> &D {
+        &self.base
+    }
+
+    /// Get how many pages have been copied.
+    #[must_use]
+    pub fn copied_pages(&self) -> usize {
+        self.copies.borrow().len()
+    }
+
+    /// Check whether the `page_index`th page has been copied.
+    #[must_use]
+    pub fn is_copied(&self, page_index: usize) -> bool {
+        self.copies.borrow().get(page_index).is_some()
+    }
+
+    /// Discard the copy of the `page_index`th page, so reads see `base` again, returning whether
+    /// there was one.
+    pub fn discard_copy(&self, page_index: usize) -> bool {
+        self.copies.borrow_mut().remove(page_index)
+    }
+}
+
+impl<D: DataSource, const PAGES: usize, const PAGE_SIZE: usize> DataSource
+    for CowSource<D, PAGES, PAGE_SIZE>
+{
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let buffer = prefix_mut(buffer, length)?;
+        let copies = self.copies.borrow();
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            let piece = &mut buffer[range.clone()];
+            match copies.get(page) {
+                Some(data) => {
+                    piece.copy_from_slice(&data[page_offset..page_offset + piece.len()]);
+                    Ok(())
+                }
+                None => self.base.read(offset + range.start, piece.len(), piece),
+            }
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let buffer = prefix(buffer, length)?;
+        if let Some(len) = self.base.len() {
+            range_within(offset, length, len)?;
+        }
+        let mut copies = self.copies.borrow_mut();
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            let data = match copies.slot_of(page) {
+                Some(_) => copies.get_mut(page).expect("page is copied"),
+                None => {
+                    let data = copies.insert(page)?;
+                    if let Err(e) = self.base.read_page(page, data) {
+                        // Don't leave a copy behind that never got the base's data.
+                        copies.remove(page);
+                        return Err(e);
+                    }
+                    data
+                }
+            };
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buffer[range]);
+            Ok(())
+        })
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.base.len()
+    }
+
+    fn name(&self) -> &str {
+        self.base.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            write: true,
+            flush: false,
+            ..self.base.capabilities()
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        self.base.cacheable()
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        let copies = self.copies.borrow();
+        let mut pages = offset / PAGE_SIZE..offset.saturating_add(length).div_ceil(PAGE_SIZE);
+        (length > 0 && pages.any(|page| copies.get(page).is_some()))
+            || self.base.has_data(offset, length)
+    }
+}
+
+/// A layered `DataSource`, like overlayfs or a chain of qcow backing images: each page is read
+/// from the topmost layer that has data for it, and writes go to the top layer.
+///
+/// Before part of a page is written to the top layer, the rest of the page is copied up from
+/// the layers below, so the top layer should be sparse, like a `CowSource` over a `ZeroSource`.
+/// Pages are `PAGE_SIZE` bytes.
+pub struct OverlaySource<'a, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    layers: &'a [&'a dyn DataSource],
+}
+
+impl<'a, const PAGE_SIZE: usize> OverlaySource<'a, PAGE_SIZE> {
+    /// Create an `OverlaySource` from its layers, topmost first.
+    ///
+    /// # Panics
+    /// If there are no layers.
+    #[must_use]
+    pub const fn new(layers: &'a [&'a dyn DataSource]) -> Self {
+        assert!(!layers.is_empty(), "an overlay needs at least one layer");
+        Self { layers }
+    }
+
+    /// Get the layers, topmost first.
+    #[must_use]
+    pub const fn layers(&self) -> &'a [&'a dyn DataSource] {
+        self.layers
+    }
+
+    const fn top(&self) -> &'a dyn DataSource {
+        self.layers[0]
+    }
+
+    /// Get the topmost layer with data for `[offset, offset + length)`, or the bottom layer if
+    /// none has any.
+    fn layer_for(&self, offset: usize, length: usize) -> &'a dyn DataSource {
+        let bottom = self.layers[self.layers.len() - 1];
+        self.layers
+            .iter()
+            .copied()
+            .find(|layer| layer.has_data(offset, length))
+            .unwrap_or(bottom)
+    }
+}
+
+impl<const PAGE_SIZE: usize> DataSource for OverlaySource<'_, PAGE_SIZE> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let buffer = prefix_mut(buffer, length)?;
+        for_each_page(offset, length, PAGE_SIZE, |_, _, range| {
+            let offset = offset + range.start;
+            let piece = &mut buffer[range];
+            self.layer_for(offset, piece.len())
+                .read(offset, piece.len(), piece)
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let buffer = prefix(buffer, length)?;
+        let top = self.top();
+        for_each_page(offset, length, PAGE_SIZE, |page, _, range| {
+            let page_start = page * PAGE_SIZE;
+            if range.len() < PAGE_SIZE && !top.has_data(page_start, PAGE_SIZE) {
+                let mut data = [0; PAGE_SIZE];
+                self.layer_for(page_start, PAGE_SIZE)
+                    .read_page(page, &mut data)?;
+                top.write_page(page, &data)?;
+            }
+            top.write(offset + range.start, range.len(), &buffer[range])
+        })
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.top().flush(offset, length)
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.layers
+            .iter()
+            .try_fold(0, |len, layer| Some(len.max(layer.len()?)))
+    }
+
+    fn name(&self) -> &str {
+        self.top().name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let top = self.top().capabilities();
+        Capabilities {
+            execute: self.layers.iter().all(|layer| layer.capabilities().execute),
+            sparse: self.layers.iter().all(|layer| layer.capabilities().sparse),
+            ..top
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        self.layers.iter().all(|layer| layer.cacheable())
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        self.layers
+            .iter()
+            .any(|layer| layer.has_data(offset, length))
+    }
+}
+
+/// A sparse, writable `DataSource` of `len` bytes, which only stores the pages that have been
+/// written: the rest are holes, which read as zeros, and pages can be discarded to make holes
+/// again. For example, swap space or a large anonymous region that is mostly untouched.
+///
+/// Holds up to `PAGES` pages of `PAGE_SIZE` bytes.
+pub struct SparseSource<const PAGES: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    len: usize,
+    pages: RefCell<PageStore<PAGES, PAGE_SIZE>>,
+}
+
+impl<const PAGES: usize, const PAGE_SIZE: usize> SparseSource<PAGES, PAGE_SIZE> {
+    #[must_use]
+    pub const fn new(len: usize) -> Self {
+        Self {
+            len,
+            pages: RefCell::new(PageStore::new()),
+        }
+    }
+
+    /// Get how many pages are stored, rather than holes.
+    #[must_use]
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.borrow().len()
+    }
+}
+
+impl<const PAGES: usize, const PAGE_SIZE: usize> DataSource for SparseSource<PAGES, PAGE_SIZE> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        let buffer = prefix_mut(buffer, length)?;
+        let pages = self.pages.borrow();
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            let piece = &mut buffer[range];
+            match pages.get(page) {
+                Some(data) => piece.copy_from_slice(&data[page_offset..page_offset + piece.len()]),
+                None => piece.fill(0),
+            }
+            Ok(())
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        let buffer = prefix(buffer, length)?;
+        let mut pages = self.pages.borrow_mut();
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            let data = match pages.slot_of(page) {
+                Some(_) => pages.get_mut(page).expect("page is stored"),
+                None => pages.insert(page)?,
+            };
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buffer[range]);
+            Ok(())
+        })
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            flush: false,
+            sparse: true,
+            ..Capabilities::READ_WRITE
+        }
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        self.next_data(offset)
+            .is_some_and(|data| data.start < offset.saturating_add(length))
+    }
+
+    fn next_data(&self, offset: usize) -> Option<Range<usize>> {
+        let pages = self.pages.borrow();
+        let first = pages
+            .pages()
+            .filter(|&page| (page + 1) * PAGE_SIZE > offset)
+            .min()?;
+        let mut last = first;
+        while pages.get(last + 1).is_some() {
+            last += 1;
+        }
+        let range = (first * PAGE_SIZE).max(offset)..((last + 1) * PAGE_SIZE).min(self.len);
+        (!range.is_empty()).then_some(range)
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+
+    /// Discards the range, which zeroes it without storing any new pages.
+    fn write_zeros(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.discard(offset, length)
+    }
+
+    fn discard(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        let mut pages = self.pages.borrow_mut();
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            if range.len() == PAGE_SIZE {
+                pages.remove(page);
+            } else if let Some(data) = pages.get_mut(page) {
+                data[page_offset..page_offset + range.len()].fill(0);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A `no_std` compression algorithm for `CompressedSource`.
+pub trait Compressor {
+    /// Compress `input` into `output`, returning the compressed length, or `None` if it doesn't
+    /// fit.
+    fn compress(&self, input: &[u8], output: &mut [u8]) -> Option<usize>;
+
+    /// Decompress `input` into `output`, which is exactly the decompressed length.
+    ///
+    /// # Errors
+    /// If `input` is corrupt.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DsError>;
+}
+
+/// A `Compressor` that stores runs of the same byte as a count and the byte, which is fast and
+/// shrinks mostly-zero pages well.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunLengthCompressor;
+
+impl Compressor for RunLengthCompressor {
+    fn compress(&self, input: &[u8], output: &mut [u8]) -> Option<usize> {
+        let mut length = 0;
+        for run in input.chunk_by(|a, b| a == b) {
+            for chunk in run.chunks(usize::from(u8::MAX)) {
+                let pair = output.get_mut(length..length + 2)?;
+                pair[0] = u8::try_from(chunk.len()).expect("chunks are at most u8::MAX long");
+                pair[1] = chunk[0];
+                length += 2;
+            }
+        }
+        Some(length)
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DsError> {
+        let mut position = 0;
+        for pair in input.chunks(2) {
+            let &[count, byte] = pair else {
+                return Err("corrupt compressed data");
+            };
+            output
+                .get_mut(position..position + usize::from(count))
+                .ok_or("corrupt compressed data")?
+                .fill(byte);
+            position += usize::from(count);
+        }
+        if position == output.len() {
+            Ok(())
+        } else {
+            Err("corrupt compressed data")
+        }
+    }
+}
+
+/// Where a page of a `CompressedSource` is stored in its arena.
+#[derive(Clone, Copy)]
+struct CompressedPage {
+    page: usize,
+    start: usize,
+    length: usize,
+    // Whether the page is stored as is, because it didn't compress.
+    raw: bool,
+}
+
+/// The pages of a `CompressedSource`, packed into an arena of `BYTES` bytes.
+struct CompressedStore<const PAGES: usize, const BYTES: usize> {
+    pages: [Option<CompressedPage>; PAGES],
+    arena: [u8; BYTES],
+}
+
+impl<const PAGES: usize, const BYTES: usize> CompressedStore<PAGES, BYTES> {
+    fn get(&self, page: usize) -> Option<CompressedPage> {
+        self.pages
+            .iter()
+            .flatten()
+            .copied()
+            .find(|p| p.page == page)
+    }
+
+    fn remove(&mut self, page: usize) {
+        for slot in &mut self.pages {
+            if slot.is_some_and(|p| p.page == page) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Store `data` as the given page, which must not already be stored.
+    fn insert(&mut self, page: usize, data: &[u8], raw: bool) -> Result<(), DsError> {
+        let slot = self
+            .pages
+            .iter()
+            .position(Option::is_none)
+            .ok_or("no room for another page")?;
+        // First fit: skip past whatever overlaps until nothing does.
+        let mut start = 0;
+        while let Some(overlapping) = self
+            .pages
+            .iter()
+            .flatten()
+            .find(|p| p.start < start + data.len() && start < p.start + p.length)
+        {
+            start = overlapping.start + overlapping.length;
+        }
+        self.arena
+            .get_mut(start..start + data.len())
+            .ok_or("no room to store the page")?
+            .copy_from_slice(data);
+        self.pages[slot] = Some(CompressedPage {
+            page,
+            start,
+            length: data.len(),
+            raw,
+        });
+        Ok(())
+    }
+}
+
+/// A sparse, writable `DataSource` of `len` bytes that stores its pages compressed, like zram:
+/// pages are compressed with `C` when written and decompressed when read, and pages that were
+/// never written read as zeros.
+///
+/// Holds up to `PAGES` pages of `PAGE_SIZE` bytes, packed into `BYTES` bytes of storage. Pages
+/// that don't compress are stored as is.
+pub struct CompressedSource<
+    C,
+    const PAGES: usize,
+    const BYTES: usize,
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE,
+> {
+    compressor: C,
+    len: usize,
+    store: RefCell<CompressedStore<PAGES, BYTES>>,
+}
+
+impl<C: Compressor, const PAGES: usize, const BYTES: usize, const PAGE_SIZE: usize>
+    CompressedSource<C, PAGES, BYTES, PAGE_SIZE>
+{
+    #[must_use]
+    pub const fn new(compressor: C, len: usize) -> Self {
+        Self {
+            compressor,
+            len,
+            store: RefCell::new(CompressedStore {
+                pages: [None; PAGES],
+                arena: [0; BYTES],
+            }),
+        }
+    }
+
+    /// Get how many pages are stored, rather than holes.
+    #[must_use]
+    pub fn allocated_pages(&self) -> usize {
+        self.store.borrow().pages.iter().flatten().count()
+    }
+
+    /// Get how many bytes of storage the stored pages take up.
+    #[must_use]
+    pub fn stored_bytes(&self) -> usize {
+        self.store
+            .borrow()
+            .pages
+            .iter()
+            .flatten()
+            .map(|p| p.length)
+            .sum()
+    }
+
+    /// Decompress the `page`th page into `data`, or zero it if it isn't stored.
+    fn load(
+        &self,
+        store: &CompressedStore<PAGES, BYTES>,
+        page: usize,
+        data: &mut [u8; PAGE_SIZE],
+    ) -> Result<(), DsError> {
+        let Some(stored) = store.get(page) else {
+            data.fill(0);
+            return Ok(());
+        };
+        let bytes = &store.arena[stored.start..stored.start + stored.length];
+        if stored.raw {
+            data.copy_from_slice(bytes);
+            Ok(())
+        } else {
+            self.compressor.decompress(bytes, data)
+        }
+    }
+}
+
+impl<C: Compressor, const PAGES: usize, const BYTES: usize, const PAGE_SIZE: usize> DataSource
+    for CompressedSource<C, PAGES, BYTES, PAGE_SIZE>
+{
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        let buffer = prefix_mut(buffer, length)?;
+        let store = self.store.borrow();
+        let mut data = [0; PAGE_SIZE];
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            self.load(&store, page, &mut data)?;
+            buffer[range.clone()].copy_from_slice(&data[page_offset..page_offset + range.len()]);
+            Ok(())
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        let buffer = prefix(buffer, length)?;
+        let mut store = self.store.borrow_mut();
+        let (mut data, mut compressed) = ([0; PAGE_SIZE], [0; PAGE_SIZE]);
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            self.load(&store, page, &mut data)?;
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buffer[range]);
+            // Free the old copy first, so the new one can reuse its space, but keep it if the new
+            // one doesn't fit: inserting fails before overwriting anything.
+            let old = store.get(page);
+            store.remove(page);
+            let result = match self.compressor.compress(&data, &mut compressed) {
+                Some(length) if length < PAGE_SIZE => {
+                    store.insert(page, &compressed[..length], false)
+                }
+                _ => store.insert(page, &data, true),
+            };
+            if let (Err(_), Some(old)) = (result, old) {
+                let slot = store.pages.iter().position(Option::is_none);
+                store.pages[slot.expect("the old copy's slot is free")] = Some(old);
+            }
+            result
+        })
+    }
+
+    fn flush(&self, _offset: usize, _length: usize) -> Result<(), DsError> {
+        Ok(())
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    fn name(&self) -> &str {
+        "<compressed>"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            flush: false,
+            sparse: true,
+            ..Capabilities::READ_WRITE
+        }
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        let store = self.store.borrow();
+        let mut pages = offset / PAGE_SIZE..offset.saturating_add(length).div_ceil(PAGE_SIZE);
+        length > 0 && offset < self.len && pages.any(|page| store.get(page).is_some())
+    }
+
+    /// Discards the range, which zeroes it without storing any new pages.
+    fn write_zeros(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.discard(offset, length)
+    }
+
+    fn discard(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        range_within(offset, length, self.len)?;
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            if range.len() == PAGE_SIZE {
+                self.store.borrow_mut().remove(page);
+                Ok(())
+            } else if self.store.borrow().get(page).is_some() {
+                self.write(page * PAGE_SIZE + page_offset, range.len(), &[0; PAGE_SIZE])
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+/// A checksum algorithm for `IntegritySource`.
+pub trait PageHasher {
+    fn hash(&self, data: &[u8]) -> u64;
+}
+
+/// The 64-bit FNV-1a hash: simple and fast, though not cryptographic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fnv1a;
+
+impl PageHasher for Fnv1a {
+    fn hash(&self, data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// The error an `IntegritySource` returns when a page doesn't match its checksum.
+pub const CHECKSUM_MISMATCH: DsError = "checksum mismatch";
+
+/// A `DataSource` wrapper that keeps a checksum of each page of `inner`, hashed with `H`, and
+/// verifies it on every read, failing with `CHECKSUM_MISMATCH` if the page changed behind its
+/// back, like from a stray DMA or a cache bug.
+///
+/// A page's checksum is recorded when it's first read or written. Checksums are kept for up to
+/// `PAGES` pages of `PAGE_SIZE` bytes, and any more pages aren't verified.
+pub struct IntegritySource<D, H, const PAGES: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    inner: D,
+    hasher: H,
+    checksums: RefCell<[Option<(usize, u64)>; PAGES]>,
+}
+
+impl<D: DataSource, H: PageHasher, const PAGES: usize, const PAGE_SIZE: usize>
+    IntegritySource<D, H, PAGES, PAGE_SIZE>
+{
+    #[must_use]
+    pub const fn new(inner: D, hasher: H) -> Self {
+        Self {
+            inner,
+            hasher,
+            checksums: RefCell::new([None; PAGES]),
+        }
+    }
+
+    /// Get the wrapped source.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Get how many pages have checksums.
+    #[must_use]
+    pub fn checked_pages(&self) -> usize {
+        self.checksums.borrow().iter().flatten().count()
+    }
+
+    /// Record the checksum of `page`'s data, if there's room.
+    fn record(&self, page: usize, data: &[u8]) {
+        let checksum = self.hasher.hash(data);
+        let mut checksums = self.checksums.borrow_mut();
+        let slot = checksums
+            .iter()
+            .position(|entry| entry.is_some_and(|(index, _)| index == page))
+            .or_else(|| checksums.iter().position(Option::is_none));
+        if let Some(slot) = slot {
+            checksums[slot] = Some((page, checksum));
+        }
+    }
+
+    /// Read and verify the `page`th page into `data`.
+    fn read_verified(&self, page: usize, data: &mut [u8; PAGE_SIZE]) -> Result<(), DsError> {
+        self.inner.read_page(page, data)?;
+        let expected = self
+            .checksums
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|&&(index, _)| index == page)
+            .map(|&(_, checksum)| checksum);
+        match expected {
+            Some(checksum) if checksum != self.hasher.hash(data) => Err(CHECKSUM_MISMATCH),
+            Some(_) => Ok(()),
+            None => {
+                self.record(page, data);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<D: DataSource, H: PageHasher, const PAGES: usize, const PAGE_SIZE: usize> DataSource
+    for IntegritySource<D, H, PAGES, PAGE_SIZE>
+{
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let buffer = prefix_mut(buffer, length)?;
+        let mut data = [0; PAGE_SIZE];
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            self.read_verified(page, &mut data)?;
+            buffer[range.clone()].copy_from_slice(&data[page_offset..page_offset + range.len()]);
+            Ok(())
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let buffer = prefix(buffer, length)?;
+        let mut data = [0; PAGE_SIZE];
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            // Verify the rest of the page before vouching for it with a new checksum.
+            if range.len() < PAGE_SIZE {
+                self.read_verified(page, &mut data)?;
+            }
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buffer[range.clone()]);
+            self.inner
+                .write(offset + range.start, range.len(), &buffer[range])?;
+            self.record(page, &data);
+            Ok(())
+        })
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.inner.flush(offset, length)
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.inner.len()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn cacheable(&self) -> bool {
+        self.inner.cacheable()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        self.inner.has_data(offset, length)
+    }
+}
+
+/// A cipher for `EncryptedSource`, which encrypts and decrypts whole pages in place.
+///
+/// The page index is passed as a tweak, like in XTS mode, so identical pages at different
+/// indices encrypt differently.
+pub trait PageCipher {
+    fn encrypt(&self, page_index: usize, page: &mut [u8]);
+    fn decrypt(&self, page_index: usize, page: &mut [u8]);
+}
+
+/// A `DataSource` wrapper that encrypts pages with `C` before writing them to `inner`, and
+/// decrypts them when reading them back, so pages never reach `inner`, like a swap device, in
+/// plaintext.
+///
+/// `inner` is always accessed in whole pages of `PAGE_SIZE` bytes, so its length should be a
+/// whole number of pages. Pages of `inner` that were never written through the
+/// `EncryptedSource` decrypt to garbage, so write them before reading them.
+pub struct EncryptedSource<D, C, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    inner: D,
+    cipher: C,
+}
+
+impl<D: DataSource, C: PageCipher, const PAGE_SIZE: usize> EncryptedSource<D, C, PAGE_SIZE> {
+    #[must_use]
+    pub const fn new(inner: D, cipher: C) -> Self {
+        Self { inner, cipher }
+    }
+
+    /// Get the wrapped source, which holds the encrypted pages.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D: DataSource, C: PageCipher, const PAGE_SIZE: usize> DataSource
+    for EncryptedSource<D, C, PAGE_SIZE>
+{
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let buffer = prefix_mut(buffer, length)?;
+        let mut data = [0; PAGE_SIZE];
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            self.inner.read_page(page, &mut data)?;
+            self.cipher.decrypt(page, &mut data);
+            buffer[range.clone()].copy_from_slice(&data[page_offset..page_offset + range.len()]);
+            Ok(())
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let buffer = prefix(buffer, length)?;
+        let mut data = [0; PAGE_SIZE];
+        for_each_page(offset, length, PAGE_SIZE, |page, page_offset, range| {
+            if range.len() < PAGE_SIZE {
+                self.inner.read_page(page, &mut data)?;
+                self.cipher.decrypt(page, &mut data);
+            }
+            data[page_offset..page_offset + range.len()].copy_from_slice(&buffer[range]);
+            self.cipher.encrypt(page, &mut data);
+            self.inner.write_page(page, &data)
+        })
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.inner.flush(offset, length)
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.inner.len()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            // Holes would decrypt to garbage rather than zeros.
+            sparse: false,
+            ..self.inner.capabilities()
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        self.inner.cacheable()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        self.inner.has_data(offset, length)
+    }
+}
+
+/// One segment of a `ChainSource`: `length` bytes of `source`, starting at `offset`.
+#[derive(Clone, Copy)]
+pub struct Segment<'a> {
+    pub source: &'a dyn DataSource,
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl<'a> Segment<'a> {
+    #[must_use]
+    pub const fn new(source: &'a dyn DataSource, offset: usize, length: usize) -> Self {
+        Self {
+            source,
+            offset,
+            length,
+        }
+    }
+}
+
+/// A `DataSource` that concatenates segments of other sources into one contiguous range, so that
+/// a multi-part image, like a kernel followed by an initrd, can be mapped as a single mapping.
+#[derive(Clone, Copy)]
+pub struct ChainSource<'a> {
+    segments: &'a [Segment<'a>],
+}
+
+impl<'a> ChainSource<'a> {
+    #[must_use]
+    pub const fn new(segments: &'a [Segment<'a>]) -> Self {
+        Self { segments }
+    }
+
+    /// Get the segments, in order.
+    #[must_use]
+    pub const fn segments(&self) -> &'a [Segment<'a>] {
+        self.segments
+    }
+
+    /// Split `[offset, offset + length)` at segment boundaries, calling `f` with each piece's
+    /// segment, its offset within the segment's source, and its range within the whole.
+    fn for_each_segment(
+        &self,
+        offset: usize,
+        length: usize,
+        mut f: impl FnMut(&Segment<'a>, usize, Range<usize>) -> Result<(), DsError>,
+    ) -> Result<(), DsError> {
+        let total = self.len().ok_or("chain is too long")?;
+        let range = range_within(offset, length, total)?;
+        let mut start = 0;
+        for segment in self.segments {
+            let end = start + segment.length;
+            let piece = range.start.max(start)..range.end.min(end);
+            if !piece.is_empty() {
+                let source_offset = segment.offset + (piece.start - start);
+                f(
+                    segment,
+                    source_offset,
+                    piece.start - offset..piece.end - offset,
+                )?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+impl DataSource for ChainSource<'_> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        let buffer = prefix_mut(buffer, length)?;
+        self.for_each_segment(offset, length, |segment, source_offset, range| {
+            let piece = &mut buffer[range];
+            segment.source.read(source_offset, piece.len(), piece)
+        })
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        let buffer = prefix(buffer, length)?;
+        self.for_each_segment(offset, length, |segment, source_offset, range| {
+            segment
+                .source
+                .write(source_offset, range.len(), &buffer[range])
+        })
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.for_each_segment(offset, length, |segment, source_offset, range| {
+            segment.source.flush(source_offset, range.len())
+        })
+    }
+
+    /// Get the total length of the segments, or `None` if it overflows.
+    fn len(&self) -> Option<usize> {
+        self.segments
+            .iter()
+            .try_fold(0_usize, |len, segment| len.checked_add(segment.length))
+    }
+
+    fn name(&self) -> &str {
+        "<chain>"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let capabilities = self.segments.iter().map(|s| s.source.capabilities());
+        let all = |f: fn(Capabilities) -> bool| capabilities.clone().all(f);
+        Capabilities {
+            write: all(|c| c.write),
+            execute: all(|c| c.execute),
+            flush: capabilities.clone().any(|c| c.flush),
+            sparse: all(|c| c.sparse),
+        }
+    }
+
+    fn cacheable(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| segment.source.cacheable())
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        let mut has_data = false;
+        // Out-of-bounds ranges have no data.
+        let _ = self.for_each_segment(offset, length, |segment, source_offset, range| {
+            has_data |= segment.source.has_data(source_offset, range.len());
+            Ok(())
+        });
+        has_data
+    }
+}
+
+/// Something to tell when part of a `NotifyingSource` is modified, like a page cache or another
+/// `AddressSpace` that has to invalidate its copies of the data.
+pub trait ModificationListener {
+    /// Called with the range of the source that was modified.
+    fn modified(&self, range: Range<usize>);
+}
+
+impl<F: Fn(Range<usize>)> ModificationListener for F {
+    fn modified(&self, range: Range<usize>) {
+        self(range);
+    }
+}
+
+/// A listener registered with a `NotifyingSource`, and the range it listens to.
+type Registration<'a> = (Range<usize>, &'a dyn ModificationListener);
+
+/// A `DataSource` wrapper that tells registered listeners when `inner` is modified through it,
+/// by a write or discard, so that caches of shared mappings can be kept coherent. All writers
+/// must go through the `NotifyingSource` for this to work.
+///
+/// Holds up to `LISTENERS` listeners.
+pub struct NotifyingSource<'a, D, const LISTENERS: usize> {
+    inner: D,
+    listeners: RefCell<[Option<Registration<'a>>; LISTENERS]>,
+}
+
+impl<'a, D: DataSource, const LISTENERS: usize> NotifyingSource<'a, D, LISTENERS> {
+    #[must_use]
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            listeners: RefCell::new([const { None }; LISTENERS]),
+        }
+    }
+
+    /// Get the wrapped source.
+    pub const fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Call `listener` whenever any of `range` is modified, returning an ID to remove it with.
+    ///
+    /// # Errors
+    /// If there's no room for another listener.
+    pub fn on_modified(
+        &self,
+        range: Range<usize>,
+        listener: &'a dyn ModificationListener,
+    ) -> Result<usize, DsError> {
+        let mut listeners = self.listeners.borrow_mut();
+        let id = listeners
+            .iter()
+            .position(Option::is_none)
+            .ok_or("no room for another listener")?;
+        listeners[id] = Some((range, listener));
+        Ok(id)
+    }
+
+    /// Stop calling the listener with the given ID, returning whether there was one.
+    pub fn remove_listener(&self, id: usize) -> bool {
+        self.listeners
+            .borrow_mut()
+            .get_mut(id)
+            .and_then(Option::take)
+            .is_some()
+    }
+
+    /// Tell the listeners whose ranges overlap `[offset, offset + length)` that it was modified.
+    fn notify(&self, offset: usize, length: usize) {
+        let modified = offset..offset.saturating_add(length);
+        // Copy the listeners out, so they can register or remove listeners themselves.
+        let listeners = self.listeners.borrow().clone();
+        for (range, listener) in listeners.into_iter().flatten() {
+            let overlap = modified.start.max(range.start)..modified.end.min(range.end);
+            if !overlap.is_empty() {
+                listener.modified(overlap);
+            }
+        }
+    }
+}
+
+impl<D: DataSource, const LISTENERS: usize> DataSource for NotifyingSource<'_, D, LISTENERS> {
+    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), DsError> {
+        self.inner.read(offset, length, buffer)
+    }
+
+    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), DsError> {
+        self.inner.write(offset, length, buffer)?;
+        self.notify(offset, length);
+        Ok(())
+    }
+
+    fn flush(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.inner.flush(offset, length)
+    }
+
+    fn len(&self) -> Option<usize> {
+        self.inner.len()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn cacheable(&self) -> bool {
+        self.inner.cacheable()
+    }
+
+    fn write_policy(&self) -> WritePolicy {
+        self.inner.write_policy()
+    }
+
+    fn has_data(&self, offset: usize, length: usize) -> bool {
+        self.inner.has_data(offset, length)
+    }
+
+    fn next_data(&self, offset: usize) -> Option<Range<usize>> {
+        self.inner.next_data(offset)
+    }
+
+    fn discard(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.inner.discard(offset, length)?;
+        self.notify(offset, length);
+        Ok(())
+    }
+
+    fn write_zeros(&self, offset: usize, length: usize) -> Result<(), DsError> {
+        self.inner.write_zeros(offset, length)?;
+        self.notify(offset, length);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_source_works() -> Result<(), DsError> {
+        let mut buffer = [0xff; 8];
+        ZeroSource.read(1 << 40, 6, &mut buffer)?;
+        assert_eq!(buffer, [0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+
+        assert!(ZeroSource.read(0, 9, &mut buffer).is_err());
+        assert!(ZeroSource.write(0, 8, &buffer).is_err());
+        assert_eq!(ZeroSource.len(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn slice_source_works() -> Result<(), DsError> {
+        let source = SliceSource::new(b"hello, world");
+        let mut buffer = [0; 5];
+        source.read(7, 5, &mut buffer)?;
+        assert_eq!(&buffer, b"world");
+
+        assert!(source.read(8, 5, &mut buffer).is_err());
+        assert!(source.write(0, 5, &buffer).is_err());
+        assert_eq!(source.len(), Some(12));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cell_slice_source_works() -> Result<(), DsError> {
+        let mut data = *b"hello, world";
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+
+        source.write(0, 5, b"howdy")?;
+        let mut buffer = [0; 12];
+        source.read(0, 12, &mut buffer)?;
+        assert_eq!(&buffer, b"howdy, world");
+
+        assert!(source.write(8, 5, b"there").is_err());
+        assert!(source.write(0, 5, b"hi").is_err());
+        assert_eq!(&data, b"howdy, world");
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "std", unix))]
+    #[test]
+    fn file_source_works() -> Result<(), DsError> {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("reedos_address_space_file_source_works");
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|_| "failed to create file")?;
+        file.write_all(b"hello, world")
+            .map_err(|_| "failed to fill file")?;
+
+        let source = FileSource::new(file);
+        assert_eq!(source.len(), Some(12));
+        source.write(0, 5, b"howdy")?;
+        source.flush(0, 12)?;
+        let mut buffer = [0; 12];
+        source.read(0, 12, &mut buffer)?;
+        assert_eq!(&buffer, b"howdy, world");
+        assert!(source.read(8, 5, &mut buffer).is_err());
+
+        std::fs::remove_file(path).map_err(|_| "failed to remove file")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn mmio_source_works() -> Result<(), DsError> {
+        #[repr(align(8))]
+        struct Registers([u8; 16]);
+
+        let mut registers = Registers([0; 16]);
+        // SAFETY: the registers are valid and aligned, and outlive the source.
+        let source = unsafe { MmioSource::new(registers.0.as_mut_ptr(), 16) };
+        assert_eq!(MmioSource::width(8, 8), 8);
+        assert_eq!(MmioSource::width(4, 12), 4);
+        assert_eq!(MmioSource::width(6, 2), 2);
+        assert_eq!(MmioSource::width(3, 4), 1);
+
+        source.write(4, 4, &0xdead_beef_u32.to_ne_bytes())?;
+        let mut buffer = [0; 4];
+        source.read(4, 4, &mut buffer)?;
+        assert_eq!(u32::from_ne_bytes(buffer), 0xdead_beef);
+        source.read(3, 2, &mut buffer[..2])?;
+        assert_eq!(buffer[1], 0xdead_beef_u32.to_ne_bytes()[0]);
+
+        assert!(source.read(14, 4, &mut buffer).is_err());
+        assert!(!source.cacheable());
+        assert_eq!(registers.0[4..8], 0xdead_beef_u32.to_ne_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn vectored_io_works() -> Result<(), DsError> {
+        let mut data = [0; 8];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+
+        source.write_vectored(1, &[&[1, 2], &[], &[3, 4, 5]])?;
+        let (mut first, mut second) = ([0; 3], [0; 3]);
+        source.read_vectored(0, &mut [&mut first, &mut second])?;
+        assert_eq!((first, second), ([0, 1, 2], [3, 4, 5]));
+        assert!(source
+            .read_vectored(4, &mut [&mut first, &mut second])
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn page_io_works() -> Result<(), DsError> {
+        let mut data = [0; 10];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+
+        source.write_page(1, &[1, 2, 3, 4])?;
+        source.write_page(2, &[5, 6, 7, 8])?;
+        let mut page = [9; 4];
+        source.read_page(2, &mut page)?;
+        assert_eq!(page, [5, 6, 0, 0]);
+        assert!(source.read_page(3, &mut page).is_err());
+        assert!(source.write_page(usize::MAX, &page).is_err());
+
+        let mut page = [9; 4];
+        ZeroSource.read_page(1 << 20, &mut page)?;
+        assert_eq!(page, [0; 4]);
+        assert_eq!(data, [0, 0, 0, 0, 1, 2, 3, 4, 5, 6]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cow_source_works() -> Result<(), DsError> {
+        let mut data = [1; 40];
+        let base = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        let source = CowSource::<_, 2, 16>::new(base);
+
+        source.write(14, 4, &[2; 4])?;
+        assert_eq!(source.copied_pages(), 2);
+        assert!(source.is_copied(0) && source.is_copied(1) && !source.is_copied(2));
+        let mut buffer = [0; 8];
+        source.read(12, 8, &mut buffer)?;
+        assert_eq!(buffer, [1, 1, 2, 2, 2, 2, 1, 1]);
+        assert_eq!(source.write(32, 1, &[2]), Err("no room for another page"));
+        assert!(source.write(38, 4, &[2; 4]).is_err());
+        assert!(source.discard_copy(1));
+        source.write(32, 8, &[3; 8])?;
+        source.read(30, 4, &mut buffer)?;
+        assert_eq!(buffer[..4], [1, 1, 3, 3]);
+        assert!(source.capabilities().write);
+        assert!(data.iter().all(|&byte| byte == 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cow_source_keeps_no_copy_of_failed_reads() {
+        let source = CowSource::<_, 1, 16>::new(NullSource);
+        assert_eq!(source.write(4, 4, &[2; 4]), Err(ACCESS_VIOLATION));
+        assert_eq!(source.copied_pages(), 0);
+        let mut buffer = [0; 4];
+        assert_eq!(source.read(4, 4, &mut buffer), Err(ACCESS_VIOLATION));
+    }
+
+    #[test]
+    fn overlay_source_works() -> Result<(), DsError> {
+        let base = SliceSource::new(&[1; 48]);
+        let middle = CowSource::<_, 1, 16>::new(ZeroSource);
+        let top = CowSource::<_, 2, 16>::new(ZeroSource);
+        middle.write(16, 4, &[2; 4])?;
+        let layers: [&dyn DataSource; 3] = [&top, &middle, &base];
+        let source = OverlaySource::<16>::new(&layers);
+
+        let mut buffer = [0; 48];
+        source.read(0, 48, &mut buffer)?;
+        assert_eq!(buffer[14..22], [1, 1, 2, 2, 2, 2, 0, 0]);
+
+        // Writing part of a page copies the rest of it up first.
+        source.write(40, 2, &[3; 2])?;
+        assert!(top.is_copied(2) && !top.is_copied(0));
+        source.read(0, 48, &mut buffer)?;
+        assert_eq!(buffer[38..44], [1, 1, 3, 3, 1, 1]);
+        source.write(18, 4, &[4; 4])?;
+        source.read(14, 10, &mut buffer)?;
+        assert_eq!(buffer[..10], [1, 1, 2, 2, 4, 4, 4, 4, 0, 0]);
+        assert_eq!(source.len(), None);
+        assert!(source.has_data(0, 1) && source.capabilities().write);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_source_works() -> Result<(), DsError> {
+        let source = SparseSource::<3, 16>::new(100);
+        assert_eq!(source.next_data(0), None);
+
+        source.write(20, 20, &[1; 20])?;
+        source.write(70, 1, &[2])?;
+        assert_eq!(source.allocated_pages(), 3);
+        assert_eq!(source.next_data(0), Some(16..48));
+        assert_eq!(source.next_data(40), Some(40..48));
+        assert_eq!(source.next_data(48), Some(64..80));
+        assert!(source.has_data(10, 10) && !source.has_data(48, 16));
+        assert_eq!(source.write(90, 1, &[3]), Err("no room for another page"));
+
+        // Discarding part of a page zeroes it, and discarding a whole page frees it.
+        source.discard(20, 28)?;
+        assert_eq!(source.allocated_pages(), 2);
+        let mut buffer = [9; 24];
+        source.read(16, 24, &mut buffer)?;
+        assert!(buffer.iter().all(|&byte| byte == 0));
+        source.write(90, 1, &[3])?;
+        assert!(source.read(90, 11, &mut buffer).is_err());
+        assert_eq!(ZeroSource.next_data(0), None);
+        assert_eq!(SliceSource::new(&[1; 4]).next_data(1), Some(1..4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_source_works() -> Result<(), DsError> {
+        let mut compressed = [0; 8];
+        let length = RunLengthCompressor
+            .compress(&[0, 0, 0, 5, 5], &mut compressed)
+            .ok_or("doesn't fit")?;
+        assert_eq!(compressed[..length], [3, 0, 2, 5]);
+        let mut decompressed = [0; 5];
+        RunLengthCompressor.decompress(&compressed[..length], &mut decompressed)?;
+        assert_eq!(decompressed, [0, 0, 0, 5, 5]);
+        assert!(RunLengthCompressor
+            .decompress(&[3, 0, 2], &mut decompressed)
+            .is_err());
+        assert_eq!(
+            RunLengthCompressor.compress(&[1, 2, 3], &mut compressed[..4]),
+            None
+        );
+
+        let source = CompressedSource::<_, 3, 32, 16>::new(RunLengthCompressor, 64);
+        // A page that doesn't compress is stored as is.
+        let incompressible: [u8; 16] = core::array::from_fn(|i| i as u8);
+        source.write(16, 16, &incompressible)?;
+        source.write(4, 2, &[7, 7])?;
+        assert_eq!(source.stored_bytes(), 22);
+        let mut buffer = [0; 32];
+        source.read(0, 32, &mut buffer)?;
+        assert_eq!(buffer[..8], [0, 0, 0, 0, 7, 7, 0, 0]);
+        assert_eq!(buffer[16..], incompressible);
+        assert_eq!(
+            source.write(32, 16, &incompressible),
+            Err("no room to store the page")
+        );
+
+        source.discard(16, 16)?;
+        source.write(32, 16, &incompressible)?;
+        assert_eq!(source.allocated_pages(), 2);
+        assert!(!source.has_data(16, 16) && source.has_data(40, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn integrity_source_works() -> Result<(), DsError> {
+        assert_eq!(Fnv1a.hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Fnv1a.hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let mut data = [0; 40];
+        let cells = Cell::from_mut(&mut data[..]).as_slice_of_cells();
+        let source = IntegritySource::<_, _, 2, 16>::new(CellSliceSource::new(cells), Fnv1a);
+
+        source.write(14, 4, &[1; 4])?;
+        assert_eq!(source.checked_pages(), 2);
+        let mut buffer = [0; 8];
+        source.read(12, 8, &mut buffer)?;
+        assert_eq!(buffer, [0, 0, 1, 1, 1, 1, 0, 0]);
+
+        // Changing the data behind the source's back is caught, but only on checked pages.
+        cells[20].set(2);
+        assert_eq!(source.read(16, 1, &mut buffer), Err(CHECKSUM_MISMATCH));
+        assert_eq!(source.write(30, 1, &[3]), Err(CHECKSUM_MISMATCH));
+        cells[36].set(2);
+        source.read(32, 8, &mut buffer)?;
+        assert_eq!(buffer[4], 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_source_works() -> Result<(), DsError> {
+        // Not a real cipher, but enough to tell ciphertext from plaintext.
+        struct XorCipher(u8);
+        impl PageCipher for XorCipher {
+            fn encrypt(&self, page_index: usize, page: &mut [u8]) {
+                for byte in page {
+                    *byte ^= self.0 ^ page_index as u8;
+                }
+            }
+            fn decrypt(&self, page_index: usize, page: &mut [u8]) {
+                self.encrypt(page_index, page);
+            }
+        }
+
+        let mut data = [0; 32];
+        let cells = Cell::from_mut(&mut data[..]).as_slice_of_cells();
+        let source = EncryptedSource::<_, _, 16>::new(CellSliceSource::new(cells), XorCipher(0xa0));
+
+        // Storage that was never written through the source decrypts to garbage.
+        source.write(0, 32, &[0; 32])?;
+        source.write(14, 4, &[1; 4])?;
+        let mut buffer = [0; 8];
+        source.read(12, 8, &mut buffer)?;
+        assert_eq!(buffer, [0, 0, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(cells[0].get(), 0xa0);
+        assert_eq!(cells[15].get(), 0xa1);
+        assert_eq!(cells[16].get(), 0xa0);
+        assert!(source.write(30, 4, &[1; 4]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn chain_source_works() -> Result<(), DsError> {
+        let kernel = SliceSource::new(&[1, 2, 3, 4, 5, 6]);
+        let mut initrd = [0; 4];
+        let initrd = CellSliceSource::new(Cell::from_mut(&mut initrd[..]).as_slice_of_cells());
+        let segments = [
+            Segment::new(&kernel, 2, 3),
+            Segment::new(&ZeroSource, 0, 2),
+            Segment::new(&initrd, 0, 4),
+        ];
+        let source = ChainSource::new(&segments);
+        assert_eq!(source.len(), Some(9));
+
+        source.write(6, 2, &[7, 8])?;
+        let mut buffer = [0; 9];
+        source.read(0, 9, &mut buffer)?;
+        assert_eq!(buffer, [3, 4, 5, 0, 0, 0, 7, 8, 0]);
+        assert!(source.write(2, 2, &[9, 9]).is_err());
+        assert!(source.read(8, 2, &mut buffer).is_err());
+        assert!(!source.has_data(3, 2) && source.has_data(3, 3));
+        assert!(!source.capabilities().write);
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifying_source_works() -> Result<(), DsError> {
+        let modified = Cell::new(0..0);
+        let listener = |range| modified.set(range);
+        let source = NotifyingSource::<_, 1>::new(SparseSource::<2, 16>::new(32));
+
+        let id = source.on_modified(8..24, &listener)?;
+        assert!(source.on_modified(0..1, &listener).is_err());
+        source.write(4, 8, &[1; 8])?;
+        assert_eq!(modified.replace(0..0), 8..12);
+        source.write(0, 4, &[1; 4])?;
+        assert_eq!(modified.replace(0..0), 0..0);
+        source.discard(16, 16)?;
+        assert_eq!(modified.replace(0..0), 16..24);
+        assert!(source.write(30, 4, &[1; 4]).is_err());
+        assert_eq!(modified.replace(0..0), 0..0);
+
+        assert!(source.remove_listener(id) && !source.remove_listener(id));
+        source.write(8, 1, &[1])?;
+        assert_eq!(modified.replace(0..0), 0..0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_uninit_works() -> Result<(), DsError> {
+        let mut buffer = [MaybeUninit::uninit(); 4];
+        let data = SliceSource::new(&[1, 2, 3, 4, 5]).read_uninit(1, &mut buffer)?;
+        assert_eq!(data, [2, 3, 4, 5]);
+        assert!(SliceSource::new(&[1]).read_uninit(0, &mut buffer).is_err());
+        assert_eq!(ZeroSource.read_uninit(7, &mut buffer)?, [0; 4]);
+
+        // The default zeroes the buffer, then reads into it.
+        let source = SparseSource::<1, 16>::new(16);
+        source.write(2, 1, &[6])?;
+        assert_eq!(source.read_uninit(0, &mut buffer)?, [0, 0, 6, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_zeros_works() -> Result<(), DsError> {
+        // The default writes zeros in chunks.
+        let mut data = [1; 1100];
+        let source = CellSliceSource::new(Cell::from_mut(&mut data[..]).as_slice_of_cells());
+        source.write_zeros(10, 1080)?;
+        assert!(source.write_zeros(1000, 101).is_err());
+        assert!(data[..10].iter().all(|&byte| byte == 1));
+        assert!(data[10..1090].iter().all(|&byte| byte == 0));
+        assert!(data[1090..].iter().all(|&byte| byte == 1));
+
+        // A sparse source discards instead.
+        let source = SparseSource::<2, 16>::new(32);
+        source.write(0, 32, &[1; 32])?;
+        source.write_zeros(8, 24)?;
+        assert_eq!(source.allocated_pages(), 1);
+        ZeroSource.write_zeros(0, 1 << 40)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn null_source_works() {
+        let mut buffer = [0; 4];
+        assert_eq!(NullSource.read(0, 4, &mut buffer), Err(ACCESS_VIOLATION));
+        assert_eq!(NullSource.write(0, 4, &buffer), Err(ACCESS_VIOLATION));
+        assert_eq!(NullSource.flush(0, 4), Err(ACCESS_VIOLATION));
+        assert_eq!(NullSource.write_zeros(0, 4), Err(ACCESS_VIOLATION));
+        assert_eq!(NullSource.read_page(0, &mut buffer), Err(ACCESS_VIOLATION));
+    }
+}
@@ -0,0 +1,228 @@
+//! Building the initial stack of a new process, as laid out by the System V ABI, for `execve`.
+
+use crate::address_space::{AddressSpace, AsError, Flags};
+use crate::data_source::{DataSource, SourceRef};
+
+/// The auxiliary vector entry ending the vector.
+pub const AT_NULL: usize = 0;
+/// The auxiliary vector entry holding the address of the program headers.
+pub const AT_PHDR: usize = 3;
+/// The auxiliary vector entry holding the size of a program header.
+pub const AT_PHENT: usize = 4;
+/// The auxiliary vector entry holding the number of program headers.
+pub const AT_PHNUM: usize = 5;
+/// The auxiliary vector entry holding the page size.
+pub const AT_PAGESZ: usize = 6;
+/// The auxiliary vector entry holding the entry point of the program.
+pub const AT_ENTRY: usize = 9;
+
+// The stack pointer is aligned to this many bytes on entry.
+const STACK_ALIGN: usize = 16;
+const WORD: usize = core::mem::size_of::<usize>();
+
+/// What goes on the initial stack of a new process: its arguments, its environment, and the
+/// auxiliary vector the kernel passes it, like `(AT_PAGESZ, 4096)`.
+///
+/// Arguments and environment variables are copied onto the stack with a nul terminator added.
+/// The auxiliary vector is ended with `AT_NULL` automatically.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InitialStack<'s> {
+    /// The arguments, starting with the program name.
+    pub argv: &'s [&'s [u8]],
+    /// The environment variables, like `b"HOME=/"`.
+    pub envp: &'s [&'s [u8]],
+    /// The auxiliary vector's entries, as key-value pairs.
+    pub auxv: &'s [(usize, usize)],
+}
+
+impl InitialStack<'_> {
+    /// Get the number of bytes the strings take up, with their terminators.
+    fn strings_size(&self) -> usize {
+        self.argv
+            .iter()
+            .chain(self.envp)
+            .map(|string| string.len() + 1)
+            .sum()
+    }
+
+    /// Get the number of words from `argc` to the end of the auxiliary vector.
+    const fn vector_words(&self) -> usize {
+        1 + (self.argv.len() + 1) + (self.envp.len() + 1) + 2 * (self.auxv.len() + 1)
+    }
+}
+
+/// Writes words and strings into a stack mapping's `DataSource`, at the offsets of addresses in
+/// the mapping.
+struct StackWriter<'s> {
+    source: &'s dyn DataSource,
+    start: usize,
+}
+
+impl StackWriter<'_> {
+    fn write(&self, addr: usize, bytes: &[u8]) -> Result<(), AsError> {
+        self.source
+            .write(addr - self.start, bytes.len(), bytes)
+            .map_err(AsError::Source)
+    }
+
+    fn write_word(&self, addr: usize, word: usize) -> Result<(), AsError> {
+        self.write(addr, &word.to_ne_bytes())
+    }
+
+    /// Write `contents` into a stack whose strings start at `strings` and whose stack pointer is
+    /// `sp`.
+    fn write_contents(
+        &self,
+        contents: &InitialStack,
+        mut strings: usize,
+        sp: usize,
+    ) -> Result<(), AsError> {
+        let mut vector = sp;
+        let mut push = |word| {
+            let written = self.write_word(vector, word);
+            vector += WORD;
+            written
+        };
+        push(contents.argv.len())?;
+        for list in [contents.argv, contents.envp] {
+            for string in list {
+                self.write(strings, string)?;
+                self.write(strings + string.len(), &[0])?;
+                push(strings)?;
+                strings += string.len() + 1;
+            }
+            push(0)?;
+        }
+        for &(key, value) in contents.auxv.iter().chain([&(AT_NULL, 0)]) {
+            push(key)?;
+            push(value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Map a stack of `length` bytes of `source` ending at `top` into `space`, with `flags`, and lay
+/// out `contents` at the top of it, returning the initial stack pointer.
+///
+/// From the stack pointer up, the stack holds `argc`, the `argv` pointers and a null pointer, the
+/// `envp` pointers and a null pointer, and the auxiliary vector, followed by the strings they
+/// point to. Everything is written through `source`, which must be writable, at offset 0 for the
+/// bottom of the stack, and the stack pointer is aligned to 16 bytes.
+///
+/// # Errors
+/// If `top` or `length` isn't page-aligned, `contents` doesn't fit, the mapping is invalid, as
+/// for `MappingBuilder::commit`, or writing to `source` fails, in which case the mapping is
+/// removed again.
+pub fn map_initial_stack<
+    'a,
+    const N_PAGES: usize,
+    const PAGE_SIZE: usize,
+    const MIN_GAP_SIZE: usize,
+    const N_RESIDENT: usize,
+>(
+    space: &mut AddressSpace<'a, N_PAGES, PAGE_SIZE, MIN_GAP_SIZE, N_RESIDENT>,
+    source: impl Into<SourceRef<'a>>,
+    top: usize,
+    length: usize,
+    flags: Flags,
+    contents: &InitialStack<'_>,
+) -> Result<usize, AsError> {
+    if !top.is_multiple_of(space.page_size()) || !length.is_multiple_of(space.page_size()) {
+        return Err(AsError::Unaligned);
+    }
+    let start = top.checked_sub(length).ok_or(AsError::Overflow)?;
+    let strings = top
+        .checked_sub(contents.strings_size())
+        .ok_or(AsError::NoSpace)?;
+    let sp = contents
+        .vector_words()
+        .checked_mul(WORD)
+        .and_then(|size| strings.checked_sub(size))
+        .map(|sp| sp - sp % STACK_ALIGN)
+        .filter(|sp| *sp >= start)
+        .ok_or(AsError::NoSpace)?;
+
+    let source = source.into();
+    let handle = space
+        .map(source.clone())
+        .at(start)
+        .length(length)
+        .flags(flags)
+        .commit()?;
+    let writer = StackWriter {
+        source: &*source,
+        start,
+    };
+    if let Err(e) = writer.write_contents(contents, strings, sp) {
+        space.remove_mapping(handle)?;
+        return Err(e);
+    }
+
+    Ok(sp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags;
+    use crate::sources::{SparseSource, ZeroSource};
+
+    fn word_at(source: &dyn DataSource, offset: usize) -> usize {
+        let mut word = [0; WORD];
+        source
+            .read(offset, WORD, &mut word)
+            .expect("offset is in the stack");
+        usize::from_ne_bytes(word)
+    }
+
+    #[test]
+    fn initial_stack_works() -> Result<(), AsError> {
+        let source = SparseSource::<2>::new(8192);
+        let mut space = AddressSpace::<20>::new("test space");
+        let contents = InitialStack {
+            argv: &[b"sh", b"-c"],
+            envp: &[b"HOME=/"],
+            auxv: &[(AT_PAGESZ, 4096)],
+        };
+        let flags = flags![read, write, user, private];
+        let sp = map_initial_stack(&mut space, &source, 0x10000, 8192, flags, &contents)?;
+        assert!(space.is_mapped(0xe000) && space.is_mapped(0xffff));
+        assert_eq!(sp % 16, 0);
+
+        // The strings are at the very top, and the vector below them.
+        let offset = |addr: usize| addr - 0xe000;
+        let words: [usize; 9] = core::array::from_fn(|i| word_at(&source, offset(sp) + i * WORD));
+        let [argc, arg0, arg1, 0, env0, 0, AT_PAGESZ, 4096, AT_NULL] = words else {
+            panic!("malformed vector {words:?}");
+        };
+        assert_eq!(argc, 2);
+        assert_eq!(
+            (arg0, arg1, env0),
+            (0x10000 - 13, 0x10000 - 10, 0x10000 - 7)
+        );
+        let mut strings = [0; 13];
+        source
+            .read(offset(arg0), 13, &mut strings)
+            .map_err(AsError::Source)?;
+        assert_eq!(&strings, b"sh\0-c\0HOME=/\0");
+        assert_eq!(word_at(&source, offset(sp) + 9 * WORD), 0);
+
+        // Everything has to fit, and the source has to be writable.
+        let big = [0; 8192];
+        let too_big = InitialStack {
+            argv: &[&big[..]],
+            ..contents
+        };
+        assert_eq!(
+            map_initial_stack(&mut space, &source, 0x13000, 8192, flags, &too_big),
+            Err(AsError::NoSpace)
+        );
+        assert!(matches!(
+            map_initial_stack(&mut space, &ZeroSource, 0x13000, 8192, flags, &contents),
+            Err(AsError::Source(_))
+        ));
+        assert!(!space.is_mapped(0x11000));
+
+        Ok(())
+    }
+}
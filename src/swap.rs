@@ -0,0 +1,85 @@
+//! Swap space, where private pages that have no `DataSource` to go back to are kept while their
+//! frames are used for something else.
+
+use crate::address_space::DEFAULT_PAGE_SIZE;
+use crate::data_source::{DataSource, DsError};
+
+/// A slot in a `SwapBackend`, holding one page.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SwapSlot(usize);
+
+impl SwapSlot {
+    /// Get the index of the slot on its device.
+    #[must_use]
+    pub const fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Swap space on a `DataSource`, like a swap partition, split into `SLOTS` slots of a page each.
+pub struct SwapBackend<D, const SLOTS: usize, const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE> {
+    device: D,
+    used: [bool; SLOTS],
+}
+
+impl<D: DataSource, const SLOTS: usize, const PAGE_SIZE: usize> SwapBackend<D, SLOTS, PAGE_SIZE> {
+    /// Use the first `SLOTS` pages of `device` as swap space, all of it free.
+    ///
+    /// # Errors
+    /// If `device` is too small to hold `SLOTS` pages.
+    pub fn new(device: D) -> Result<Self, DsError> {
+        let length = SLOTS.checked_mul(PAGE_SIZE).ok_or("swap space overflows")?;
+        if device.len().is_some_and(|len| len < length) {
+            return Err("device is too small for swap space");
+        }
+        Ok(Self {
+            device,
+            used: [false; SLOTS],
+        })
+    }
+
+    /// Get the swap device.
+    pub const fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Get the number of free slots.
+    #[must_use]
+    pub fn free_slots(&self) -> usize {
+        self.used.iter().filter(|used| !**used).count()
+    }
+
+    /// Allocate a slot, or return `None` if they're all in use.
+    pub fn alloc_slot(&mut self) -> Option<SwapSlot> {
+        let index = self.used.iter().position(|used| !used)?;
+        self.used[index] = true;
+        Some(SwapSlot(index))
+    }
+
+    /// Free a slot returned by `alloc_slot`.
+    pub fn free_slot(&mut self, slot: SwapSlot) {
+        self.used[slot.0] = false;
+    }
+
+    /// Write `page` to `slot`.
+    ///
+    /// # Errors
+    /// If `page` isn't a page long, or writing to the device fails.
+    pub fn write_slot(&self, slot: SwapSlot, page: &[u8]) -> Result<(), DsError> {
+        if page.len() != PAGE_SIZE {
+            return Err("wrong page size for swap");
+        }
+        self.device.write(slot.0 * PAGE_SIZE, PAGE_SIZE, page)
+    }
+
+    /// Read `slot` into `page`.
+    ///
+    /// # Errors
+    /// If `page` isn't a page long, or reading from the device fails.
+    pub fn read_slot(&self, slot: SwapSlot, page: &mut [u8]) -> Result<(), DsError> {
+        if page.len() != PAGE_SIZE {
+            return Err("wrong page size for swap");
+        }
+        self.device.read(slot.0 * PAGE_SIZE, PAGE_SIZE, page)
+    }
+}
@@ -0,0 +1,19 @@
+//! TLB maintenance, so that changes to an `AddressSpace` don't leave stale translations behind.
+
+use crate::asid::Asid;
+
+/// Invalidates TLB entries, e.g. with `sfence.vma` on RISC-V or `invlpg` on x86_64, shooting
+/// them down on other cores as needed; see `AddressSpace::with_tlb_maintenance`.
+///
+/// The methods take `&self`, since one implementation is usually shared by every
+/// `AddressSpace`.
+pub trait TlbMaintenance {
+    /// Invalidate the translation of the page containing `vaddr` tagged with `asid`.
+    fn invalidate_page(&self, asid: Asid, vaddr: usize);
+
+    /// Invalidate every translation tagged with `asid`.
+    fn invalidate_asid(&self, asid: Asid);
+
+    /// Invalidate every translation, whatever its ASID.
+    fn invalidate_all(&self);
+}